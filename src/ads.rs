@@ -0,0 +1,114 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+// NTFS alternate data streams: named contents a file carries besides its
+// main one (Zone.Identifier, Mac resource forks copied over SMB, or things
+// hidden on purpose). With --ads each is its own entry, `file:stream`, the
+// form Windows itself opens them by.
+
+/// The entry `path` names the main contents of, `path` itself unless it's
+/// `file:stream`. Only on Windows, elsewhere `:` is just part of a name.
+pub fn base(path: &str) -> &str {
+    if !cfg!(windows) {
+        return path;
+    }
+    let name_start = path.rfind(['/', '\\']).map_or(0, |i| i + 1);
+    match path[name_start..].find(':') {
+        // `C:` is a drive, not a stream
+        Some(colon) if name_start > 0 || colon > 1 => &path[..name_start + colon],
+        _ => path,
+    }
+}
+
+/// Whether `path` names an alternate data stream.
+pub fn is_stream(path: &str) -> bool {
+    base(path).len() != path.len()
+}
+
+/// The alternate data streams of the file at `path`, as paths that open
+/// them.
+#[cfg(windows)]
+pub fn streams(path: &Path) -> io::Result<Vec<PathBuf>> {
+    use std::{ffi::c_void, os::windows::ffi::OsStrExt};
+
+    const FIND_STREAM_INFO_STANDARD: i32 = 0;
+    const INVALID_HANDLE_VALUE: isize = -1;
+    const ERROR_HANDLE_EOF: i32 = 38;
+    // MAX_PATH + 36, room for `:` + 255 characters + `:$DATA`
+    const NAME_LEN: usize = 260 + 36;
+
+    #[repr(C)]
+    struct Win32FindStreamData {
+        _stream_size: i64,
+        stream_name: [u16; NAME_LEN],
+    }
+
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn FindFirstStreamW(
+            file_name: *const u16,
+            info_level: i32,
+            find_stream_data: *mut c_void,
+            flags: u32,
+        ) -> isize;
+        fn FindNextStreamW(find_stream: isize, find_stream_data: *mut c_void) -> i32;
+        fn FindClose(find_file: isize) -> i32;
+    }
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut data = Win32FindStreamData {
+        _stream_size: 0,
+        stream_name: [0; NAME_LEN],
+    };
+    let data_ptr = &mut data as *mut Win32FindStreamData as *mut c_void;
+    // SAFETY: `wide` is NUL terminated and `data` is a WIN32_FIND_STREAM_DATA
+    let handle = unsafe { FindFirstStreamW(wide.as_ptr(), FIND_STREAM_INFO_STANDARD, data_ptr, 0) };
+    if handle == INVALID_HANDLE_VALUE {
+        let e = io::Error::last_os_error();
+        // No streams at all, a directory or a filesystem without them
+        return if e.raw_os_error() == Some(ERROR_HANDLE_EOF) {
+            Ok(Vec::new())
+        } else {
+            Err(e)
+        };
+    }
+
+    let mut streams = Vec::new();
+    loop {
+        let len = data
+            .stream_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(NAME_LEN);
+        let name = String::from_utf16_lossy(&data.stream_name[..len]);
+        // `::$DATA` is the main contents, the rest are `:name:$DATA`
+        if let Some(name) = name.strip_suffix(":$DATA")
+            && !name.is_empty()
+        {
+            let mut stream = path.as_os_str().to_owned();
+            stream.push(name);
+            streams.push(PathBuf::from(stream));
+        }
+        // SAFETY: `handle` is open and `data` as above
+        if unsafe { FindNextStreamW(handle, data_ptr) } == 0 {
+            break;
+        }
+    }
+    let e = io::Error::last_os_error();
+    // SAFETY: `handle` is open and not used after this
+    unsafe { FindClose(handle) };
+    match e.raw_os_error() {
+        Some(ERROR_HANDLE_EOF) => Ok(streams),
+        _ => Err(e),
+    }
+}
+
+#[cfg(not(windows))]
+pub fn streams(_path: &Path) -> io::Result<Vec<PathBuf>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--ads is only supported on Windows",
+    ))
+}
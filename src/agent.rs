@@ -0,0 +1,344 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Lines, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdout, Command, Stdio},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{AgentArgs, FilterArgs, HashArgs, RunArgs},
+    hash::{FileMeta, HashOptions},
+    logging,
+    manifest::Special,
+    progress::Progress,
+    scan::{self, Trouble},
+};
+
+// `checkyoself agent` walks and hashes a tree where it is and streams the
+// entries back, so verifying a remote file server costs a line of JSON per
+// file on the network instead of its contents. It talks JSON lines on stdin
+// and stdout and leaves getting there to whatever starts it: `ssh`, or
+// `socat` with TLS on both ends. The client sends one `Request`, the agent
+// answers `Walked`, a `File` per file hashed and `Done`, or an `Error`.
+
+const VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Request {
+    version: u32,
+    dir: PathBuf,
+    filter: FilterArgs,
+    hashing: HashArgs,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+    Walked {
+        files: usize,
+        special: BTreeMap<String, Special>,
+    },
+    File {
+        path: String,
+        meta: Box<FileMeta>,
+    },
+    Done {
+        trouble: Trouble,
+    },
+    Error {
+        message: String,
+    },
+}
+
+fn send(out: &mut impl Write, reply: &Reply) -> io::Result<()> {
+    let mut line = serde_json::to_vec(reply)?;
+    line.push(b'\n');
+    out.write_all(&line)?;
+    out.flush()
+}
+
+/// Whether the agent may hash `dir`: anything without --allow, otherwise
+/// only what's under one of them.
+fn allowed(dir: &Path, allow: &[PathBuf]) -> io::Result<()> {
+    if allow.is_empty() {
+        return Ok(());
+    }
+    let dir = fs::canonicalize(dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", dir.display())))?;
+    let under = allow
+        .iter()
+        .filter_map(|a| fs::canonicalize(a).ok())
+        .any(|a| dir.starts_with(a));
+    if under {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} isn't under any --allow", dir.display()),
+        ))
+    }
+}
+
+/// Answers one request on stdin, on stdout.
+fn serve(args: &AgentArgs) -> io::Result<()> {
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let request: Request = serde_json::from_str(&line)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("bad request: {e}")))?;
+    if request.version != VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the client speaks version {}, this agent {VERSION}",
+                request.version
+            ),
+        ));
+    }
+    allowed(&request.dir, &args.allow)?;
+    if !request.dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", request.dir.display()),
+        ));
+    }
+    let opts = HashOptions::from_args(&request.hashing, &request.filter);
+    if opts.mac_metadata && !cfg!(target_os = "macos") {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--mac-metadata is only supported on macOS",
+        ));
+    }
+    if let Some(threads) = request.hashing.threads {
+        let _ = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global();
+    }
+
+    let filter = scan::Filter::new(&request.filter)?;
+    let (files, special) = scan::walk(&request.dir, &filter);
+    debug!(event = "agent_walked", files = files.len(); "Found {} files in {}", files.len(), request.dir.display());
+    let mut out = io::stdout();
+    send(
+        &mut out,
+        &Reply::Walked {
+            files: files.len(),
+            special,
+        },
+    )?;
+    // The first write error, the client having gone away, stops further
+    // writes; the hashing itself can't be called off
+    let out = Mutex::new((out, None));
+    let trouble = scan::hash_files_each(&files, &opts, &Progress::hidden(), |path, meta| {
+        let (out, failed) = &mut *out.lock().unwrap();
+        if failed.is_none()
+            && let Err(e) = send(
+                out,
+                &Reply::File {
+                    path,
+                    meta: Box::new(meta),
+                },
+            )
+        {
+            *failed = Some(e);
+        }
+    });
+    let (mut out, failed) = out.into_inner().unwrap();
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    send(&mut out, &Reply::Done { trouble })
+}
+
+/// `agent`: exit code 0, or 1 on errors, which the client is told about
+/// too.
+pub fn run(args: &AgentArgs) -> i32 {
+    // Whatever it has to say goes to stderr, stdout is for the client
+    logging::console_to_stderr();
+    match serve(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            let told = send(
+                &mut io::stdout(),
+                &Reply::Error {
+                    message: e.to_string(),
+                },
+            );
+            if told.is_err() {
+                eprintln!("Error: {e}");
+            }
+            1
+        }
+    }
+}
+
+/// An agent hashing a tree for us, started with --agent.
+pub struct Agent {
+    command: String,
+    child: Child,
+    replies: Lines<BufReader<ChildStdout>>,
+    files: usize,
+}
+
+impl Agent {
+    /// Runs `command` through the shell and has it walk `dir` with the
+    /// filters and hashing options of `run`. Returns once the walk is done,
+    /// with the special files it found.
+    pub fn start(
+        command: &str,
+        dir: &Path,
+        run: &RunArgs,
+    ) -> io::Result<(Agent, BTreeMap<String, Special>)> {
+        let mut shell = if cfg!(windows) {
+            let mut c = Command::new("cmd");
+            c.arg("/C");
+            c
+        } else {
+            let mut c = Command::new("sh");
+            c.arg("-c");
+            c
+        };
+        let mut child = shell
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("can't run agent '{command}': {e}")))?;
+
+        let request = Request {
+            version: VERSION,
+            dir: dir.to_path_buf(),
+            filter: run.filter.clone(),
+            hashing: run.hashing.clone(),
+        };
+        let mut stdin = child.stdin.take().expect("piped stdin");
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        stdin.write_all(&line)?;
+        drop(stdin);
+
+        let stdout = child.stdout.take().expect("piped stdout");
+        let mut agent = Agent {
+            command: command.to_string(),
+            child,
+            replies: BufReader::new(stdout).lines(),
+            files: 0,
+        };
+        match agent.next()? {
+            Reply::Walked { files, special } => {
+                agent.files = files;
+                Ok((agent, special))
+            }
+            _ => Err(agent.unexpected()),
+        }
+    }
+
+    fn next(&mut self) -> io::Result<Reply> {
+        let line = match self.replies.next() {
+            Some(line) => line?,
+            None => {
+                let status = self.child.wait()?;
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "agent '{}' exited before it was done ({status})",
+                        self.command
+                    ),
+                ));
+            }
+        };
+        match serde_json::from_str(&line) {
+            Ok(Reply::Error { message }) => Err(io::Error::other(format!("agent: {message}"))),
+            Ok(reply) => Ok(reply),
+            Err(e) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("agent '{}' said something unexpected: {e}", self.command),
+            )),
+        }
+    }
+
+    fn unexpected(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("agent '{}' answered out of turn", self.command),
+        )
+    }
+
+    /// Hands each file's entry to `on_file` as the agent sends it, until
+    /// `stop` is set. Returns the files the agent had trouble with.
+    pub fn hash_until(
+        mut self,
+        progress: &Progress,
+        stop: &AtomicBool,
+        on_file: impl Fn(String, FileMeta),
+    ) -> io::Result<Trouble> {
+        progress.set_total(self.files as u64);
+        progress.set_phase("hash");
+        loop {
+            match self.next()? {
+                Reply::File { path, meta } => {
+                    let bytes = meta.size.max(0) as u64;
+                    on_file(path, *meta);
+                    progress.finish_file(bytes);
+                    // What it had trouble with is only told at the end
+                    if stop.load(Ordering::Relaxed) {
+                        let _ = self.child.kill();
+                        self.child.wait()?;
+                        progress.finish("Hashing stopped");
+                        return Ok(Trouble::default());
+                    }
+                }
+                Reply::Done { trouble } => {
+                    self.child.wait()?;
+                    progress.finish("Hashing complete");
+                    return Ok(trouble);
+                }
+                _ => return Err(self.unexpected()),
+            }
+        }
+    }
+}
+
+/// The files of a scan: walked here as they're hashed, or by an agent
+/// along with its special files.
+pub enum Files<'a> {
+    Local(scan::Walk<'a>),
+    Agent(Agent, BTreeMap<String, Special>),
+}
+
+impl Files<'_> {
+    /// Hashes the files, handing each file's entry to `on_file` as soon as
+    /// it is done. Returns the files hashing had trouble with, and the
+    /// special files.
+    pub fn hash_each(
+        self,
+        opts: &HashOptions,
+        progress: &Progress,
+        on_file: impl Fn(String, FileMeta) + Sync,
+    ) -> io::Result<(Trouble, BTreeMap<String, Special>)> {
+        self.hash_until(opts, progress, &AtomicBool::new(false), on_file)
+    }
+
+    /// `hash_each`, leaving the rest of the files alone once `stop` is set.
+    pub fn hash_until(
+        self,
+        opts: &HashOptions,
+        progress: &Progress,
+        stop: &AtomicBool,
+        on_file: impl Fn(String, FileMeta) + Sync,
+    ) -> io::Result<(Trouble, BTreeMap<String, Special>)> {
+        match self {
+            Files::Local(walk) => Ok(walk.hash_until(opts, progress, stop, on_file)),
+            Files::Agent(agent, special) => {
+                Ok((agent.hash_until(progress, stop, on_file)?, special))
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use std::{
+    fs, io,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+
+use colored::*;
+use indicatif::HumanBytes;
+use log::info;
+use rayon::prelude::*;
+
+use crate::{
+    cli::BenchArgs,
+    hash::{HashOptions, IoMode, hash_file},
+    scan::{Filter, walk_files},
+};
+
+const BUFFER_SIZES: [usize; 4] = [8 * 1024, 64 * 1024, 1024 * 1024, 8 * 1024 * 1024];
+
+/// One measured configuration.
+struct Sample {
+    threads: usize,
+    opts: HashOptions,
+    bytes: u64,
+    elapsed: Duration,
+}
+
+impl Sample {
+    fn rate(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    fn report(&self, kind: &str) {
+        let io_mode = match self.opts.io_mode {
+            IoMode::Read => "read",
+            IoMode::Mmap => "mmap",
+        };
+        let buffer = match self.opts.io_mode {
+            IoMode::Read => HumanBytes(self.opts.buffer_size as u64).to_string(),
+            IoMode::Mmap => "-".to_string(),
+        };
+        info!(
+            event = "bench",
+            kind = kind,
+            threads = self.threads,
+            io = io_mode,
+            buffer_size = self.opts.buffer_size,
+            bytes_per_sec = self.rate();
+            "{:<10} {:>7} {:>5} {:>10} {:>12}/s",
+            kind,
+            self.threads,
+            io_mode,
+            buffer,
+            HumanBytes(self.rate() as u64)
+        );
+    }
+}
+
+/// 1, 2, 4, ... up to the number of cores, always including the core count.
+fn thread_counts() -> Vec<usize> {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut counts: Vec<usize> = std::iter::successors(Some(1), |n| Some(n * 2))
+        .take_while(|&n| n < cores)
+        .collect();
+    counts.push(cores);
+    counts
+}
+
+fn pool(threads: usize) -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("Failed to build thread pool")
+}
+
+/// Incompressible test data, generated quickly from BLAKE3's XOF.
+fn synthetic_data(mib: usize) -> Vec<u8> {
+    let mut data = vec![0u8; mib * 1024 * 1024];
+    blake3::Hasher::new()
+        .update(b"checkyoself bench")
+        .finalize_xof()
+        .fill(&mut data);
+    data
+}
+
+fn hash_memory(data: &[u8], buffer_size: usize) {
+    let mut hasher = blake3::Hasher::new();
+    for chunk in data.chunks(buffer_size) {
+        hasher.update(chunk);
+    }
+    std::hint::black_box(hasher.finalize());
+}
+
+fn bench_synthetic(mib: usize) -> Vec<Sample> {
+    let data = synthetic_data(mib);
+    let mut samples = Vec::new();
+
+    // Raw single core hashing speed for each buffer size
+    for buffer_size in BUFFER_SIZES {
+        let start = Instant::now();
+        hash_memory(&data, buffer_size);
+        samples.push(Sample {
+            threads: 1,
+            opts: HashOptions {
+                buffer_size,
+                io_mode: IoMode::Read,
+                ..Default::default()
+            },
+            bytes: data.len() as u64,
+            elapsed: start.elapsed(),
+        });
+    }
+
+    // Many "files" hashed at once, to see how far it scales across cores
+    let buffer_size = 64 * 1024;
+    for threads in thread_counts() {
+        let start = Instant::now();
+        pool(threads).install(|| {
+            (0..threads)
+                .into_par_iter()
+                .for_each(|_| hash_memory(&data, buffer_size))
+        });
+        samples.push(Sample {
+            threads,
+            opts: HashOptions {
+                buffer_size,
+                io_mode: IoMode::Read,
+                ..Default::default()
+            },
+            bytes: (data.len() * threads) as u64,
+            elapsed: start.elapsed(),
+        });
+    }
+
+    samples
+}
+
+/// Picks up to `count` files spread evenly over the tree, stopping once
+/// `max_bytes` worth have been selected.
+fn sample_files(dir: &std::path::Path, count: usize, max_bytes: u64) -> Vec<PathBuf> {
+    let files = walk_files(dir, &Filter::default());
+    let step = (files.len() / count.max(1)).max(1);
+
+    let mut total = 0;
+    let mut picked = Vec::new();
+    for path in files.into_iter().step_by(step).take(count) {
+        let Ok(meta) = fs::metadata(&path) else {
+            continue;
+        };
+        if total + meta.len() > max_bytes && !picked.is_empty() {
+            break;
+        }
+        total += meta.len();
+        picked.push(path);
+    }
+    picked
+}
+
+fn hash_sample(files: &[PathBuf], opts: &HashOptions) -> u64 {
+    files
+        .par_iter()
+        .filter_map(|path| {
+            hash_file(path, opts).ok()?;
+            fs::metadata(path).ok().map(|m| m.len())
+        })
+        .sum()
+}
+
+fn bench_files(files: &[PathBuf]) -> Vec<Sample> {
+    // Warm up the page cache so every configuration sees the same conditions
+    hash_sample(files, &HashOptions::default());
+
+    let mut configs: Vec<HashOptions> = BUFFER_SIZES
+        .iter()
+        .map(|&buffer_size| HashOptions {
+            buffer_size,
+            io_mode: IoMode::Read,
+            ..Default::default()
+        })
+        .collect();
+    configs.push(HashOptions {
+        io_mode: IoMode::Mmap,
+        ..Default::default()
+    });
+
+    let mut samples = Vec::new();
+    for threads in thread_counts() {
+        let pool = pool(threads);
+        for opts in &configs {
+            let start = Instant::now();
+            let bytes = pool.install(|| hash_sample(files, opts));
+            samples.push(Sample {
+                threads,
+                opts: *opts,
+                bytes,
+                elapsed: start.elapsed(),
+            });
+        }
+    }
+    samples
+}
+
+fn header(title: &str) {
+    info!("\n=== {} ===", title.bold().underline());
+    info!(
+        "{:<10} {:>7} {:>5} {:>10} {:>14}",
+        "data", "threads", "io", "buffer", "throughput"
+    );
+}
+
+pub fn run(args: &BenchArgs) -> io::Result<()> {
+    header("SYNTHETIC");
+    let synthetic = bench_synthetic(args.synthetic_mib);
+    for s in &synthetic {
+        s.report("synthetic");
+    }
+
+    let mut best = synthetic
+        .iter()
+        .max_by(|a, b| a.rate().total_cmp(&b.rate()));
+
+    let real;
+    if let Some(dir) = &args.dir {
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} is not a directory", dir.display()),
+            ));
+        }
+        let files = sample_files(dir, args.sample, args.sample_mib * 1024 * 1024);
+        info!("\nSampled {} files from {}", files.len(), dir.display());
+        header("REAL FILES");
+        real = bench_files(&files);
+        for s in &real {
+            s.report("files");
+        }
+        info!("(the sample was read once up front, so these are page cache numbers)");
+        // Real files are what the scan hashes, prefer them for the advice
+        if let Some(b) = real.iter().max_by(|a, b| a.rate().total_cmp(&b.rate())) {
+            best = Some(b);
+        }
+    }
+
+    if let Some(best) = best {
+        let mut flags = format!("--threads {}", best.threads);
+        match best.opts.io_mode {
+            IoMode::Read => flags += &format!(" --io read --buffer-size {}", best.opts.buffer_size),
+            IoMode::Mmap => flags += " --io mmap",
+        }
+        info!(
+            event = "bench_best",
+            flags = flags.as_str();
+            "\n{} {}",
+            "Fastest:".green().bold(),
+            flags
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,110 @@
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::{collections::HashSet, fs, os::unix::fs::MetadataExt};
+
+// With --skip-busy files another process has open for writing are left
+// alone rather than hashed half written, which would only mismatch on the
+// next run. There's no one way to tell, so it's heuristics per platform:
+//
+// - Linux: the descriptors under /proc/<pid>/fd opened for writing, as far
+//   as we may look, taken once before hashing starts
+// - Unix: an exclusive flock(2) someone else holds
+// - Windows: opening the file while denying others write access fails
+//   with a sharing violation
+
+/// What's open for writing elsewhere, looked up by `holds`.
+#[derive(Default)]
+pub struct InUse {
+    /// Device and inode of the files open for writing
+    #[cfg(target_os = "linux")]
+    written: HashSet<(u64, u64)>,
+}
+
+impl InUse {
+    /// Looks at what the other processes have open, where that takes a
+    /// look up front.
+    pub fn scan() -> InUse {
+        #[cfg(target_os = "linux")]
+        return InUse {
+            written: open_for_writing(),
+        };
+        #[cfg(not(target_os = "linux"))]
+        return InUse::default();
+    }
+
+    /// Whether another process has the file at `path` open for writing, or
+    /// locked.
+    pub fn holds(&self, path: &Path) -> bool {
+        #[cfg(target_os = "linux")]
+        if fs::metadata(path).is_ok_and(|m| self.written.contains(&(m.dev(), m.ino()))) {
+            return true;
+        }
+        is_locked(path)
+    }
+}
+
+/// The files the other processes have descriptors open for writing on.
+/// Processes of other users can't be looked at without privileges, they're
+/// passed over.
+#[cfg(target_os = "linux")]
+fn open_for_writing() -> HashSet<(u64, u64)> {
+    // O_ACCMODE, and O_RDONLY being 0
+    const ACCESS_MODE: u32 = 0o3;
+    let mut written = HashSet::new();
+    let Ok(processes) = fs::read_dir("/proc") else {
+        return written;
+    };
+    let own = std::process::id().to_string();
+    for process in processes.flatten() {
+        let pid = process.file_name();
+        let pid = pid.to_string_lossy();
+        if !pid.bytes().all(|b| b.is_ascii_digit()) || pid == own {
+            continue;
+        }
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let info = process.path().join("fdinfo").join(fd.file_name());
+            let flags = fs::read_to_string(info).ok().and_then(|info| {
+                info.lines()
+                    .find_map(|line| line.strip_prefix("flags:"))
+                    .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+            });
+            if flags.is_none_or(|flags| flags & ACCESS_MODE == 0) {
+                continue;
+            }
+            // Follows the link to what's open
+            if let Ok(meta) = fs::metadata(fd.path())
+                && meta.is_file()
+            {
+                written.insert((meta.dev(), meta.ino()));
+            }
+        }
+    }
+    written
+}
+
+#[cfg(unix)]
+fn is_locked(path: &Path) -> bool {
+    // Our own shared lock goes with the file
+    std::fs::File::open(path)
+        .is_ok_and(|f| matches!(f.try_lock_shared(), Err(std::fs::TryLockError::WouldBlock)))
+}
+
+#[cfg(windows)]
+fn is_locked(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_SHARE_READ: u32 = 0x1;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+        .is_err_and(|e| e.raw_os_error() == Some(ERROR_SHARING_VIOLATION))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_locked(_path: &Path) -> bool {
+    false
+}
@@ -0,0 +1,171 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use indicatif::HumanBytes;
+
+// --compare-bytes <replica> compares a mismatched file byte by byte with
+// the same relative path under a replica of the tree, for workflows that
+// won't take a hash's word for it. Where the bytes start to differ and in
+// how many separate runs tells a flipped bit (one range of a byte) from a
+// torn write (one range to the end) from a file rewritten as a whole.
+// Nothing is kept of the ranges but their count, the files can be big.
+
+/// Bytes read from each file at a time.
+const BLOCK: usize = 1024 * 1024;
+
+/// How a mismatched file compares with the replica's copy.
+pub enum ByteDiff {
+    /// The replica has the same bytes, it doesn't have the recorded ones either
+    Same,
+    Differ {
+        /// Offset of the first byte that differs
+        first: u64,
+        /// Runs of differing bytes, the longer file's tail being one
+        ranges: u64,
+        bytes: u64,
+    },
+    Failed(io::Error),
+}
+
+impl ByteDiff {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ByteDiff::Same => "same",
+            ByteDiff::Differ { .. } => "differs",
+            ByteDiff::Failed(_) => "failed",
+        }
+    }
+
+    pub fn first(&self) -> Option<u64> {
+        match self {
+            ByteDiff::Differ { first, .. } => Some(*first),
+            _ => None,
+        }
+    }
+
+    pub fn ranges(&self) -> Option<u64> {
+        match self {
+            ByteDiff::Differ { ranges, .. } => Some(*ranges),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ByteDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ByteDiff::Same => write!(f, "\n  replica:  the same bytes, not the recorded ones"),
+            ByteDiff::Differ {
+                first,
+                ranges,
+                bytes,
+            } => write!(
+                f,
+                "\n  replica:  differs from byte {first} ({first:#x}) on, {ranges} {} ({})",
+                if *ranges == 1 { "range" } else { "ranges" },
+                HumanBytes(*bytes)
+            ),
+            ByteDiff::Failed(e) => write!(f, "\n  replica:  can't compare, {e}"),
+        }
+    }
+}
+
+/// The replica mismatched files are compared with.
+pub struct CompareBytes {
+    replica: PathBuf,
+    /// The tree as given, and its manifest key that entries start with
+    dir: PathBuf,
+    dir_key: String,
+}
+
+impl CompareBytes {
+    pub fn new(replica: &Path, dir: &Path, dir_key: String) -> Self {
+        CompareBytes {
+            replica: replica.to_path_buf(),
+            dir: dir.to_path_buf(),
+            dir_key,
+        }
+    }
+
+    /// Compares the file recorded as `key` with the replica's. None when
+    /// it's not under the tree.
+    pub fn compare(&self, key: &str) -> Option<ByteDiff> {
+        let relative = Path::new(key).strip_prefix(&self.dir_key).ok()?;
+        Some(
+            diff(&self.dir.join(relative), &self.replica.join(relative))
+                .unwrap_or_else(ByteDiff::Failed),
+        )
+    }
+}
+
+/// Reads until `buf` is full or the end of the file, how much it read.
+fn fill(file: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+fn diff(here: &Path, there: &Path) -> io::Result<ByteDiff> {
+    let mut here = File::open(here)?;
+    let mut there = File::open(there)?;
+    let mut a = vec![0; BLOCK];
+    let mut b = vec![0; BLOCK];
+    let mut offset = 0u64;
+    let mut first = None;
+    let mut ranges = 0;
+    let mut bytes = 0;
+    // Whether the byte before `offset` differed
+    let mut differing = false;
+    loop {
+        let read_here = fill(&mut here, &mut a)?;
+        let read_there = fill(&mut there, &mut b)?;
+        let n = read_here.min(read_there);
+        if a[..n] == b[..n] {
+            differing &= n == 0;
+        } else {
+            for (i, (x, y)) in a[..n].iter().zip(&b[..n]).enumerate() {
+                let differs = x != y;
+                if differs && !differing {
+                    first.get_or_insert(offset + i as u64);
+                    ranges += 1;
+                }
+                bytes += u64::from(differs);
+                differing = differs;
+            }
+        }
+        offset += n as u64;
+        if n < BLOCK {
+            // One of them ended, what the other has beyond is the last range
+            let longer = match read_here > read_there {
+                true => &mut here,
+                false => &mut there,
+            };
+            let tail = (read_here.max(read_there) - n) as u64 + io::copy(longer, &mut io::sink())?;
+            if tail > 0 {
+                first.get_or_insert(offset);
+                ranges += u64::from(!differing);
+                bytes += tail;
+            }
+            break;
+        }
+    }
+    Ok(match first {
+        None => ByteDiff::Same,
+        Some(first) => ByteDiff::Differ {
+            first,
+            ranges,
+            bytes,
+        },
+    })
+}
@@ -0,0 +1,252 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use colored::*;
+use indicatif::HumanBytes;
+use log::{error, info};
+
+use crate::{
+    cli::{CatalogAddArgs, CatalogListArgs, CatalogVerifyArgs},
+    execute,
+    manifest::{Manifest, Volume},
+    store::{self, with_path},
+    style, volume,
+};
+
+// A catalog directory holds one manifest per removable drive, `<name>.json`,
+// with the drive's volume UUID and label in its header. Paths are recorded
+// under `<name>/` rather than wherever the drive happened to be mounted, so
+// the drive verifies the same from any mount point, and a path in any of
+// the manifests says which drive it's on.
+
+/// The names of the drives in the catalog at `dir` with their manifests,
+/// by name.
+pub fn entries(dir: &Path) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut entries: Vec<(String, PathBuf)> = fs::read_dir(dir)
+        .map_err(|e| with_path(dir, e))?
+        .filter_map(Result::ok)
+        .filter_map(|e| {
+            let name = e.file_name().to_str()?.strip_suffix(".json")?.to_string();
+            Some((name, e.path()))
+        })
+        .collect();
+    entries.sort();
+    Ok(entries)
+}
+
+pub fn load(path: &Path, identity: Option<&Path>) -> io::Result<Manifest> {
+    let data = fs::read(path).map_err(|e| with_path(path, e))?;
+    store::decode(path, data, identity)
+}
+
+/// The catalog entry of the drive with `volume`'s UUID.
+pub fn find(dir: &Path, volume: &Volume, identity: Option<&Path>) -> io::Result<Option<String>> {
+    if volume.uuid.is_none() {
+        return Ok(None);
+    }
+    for (name, path) in entries(dir)? {
+        let manifest = load(&path, identity)?;
+        if manifest.volume.is_some_and(|v| v.uuid == volume.uuid) {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
+
+/// Refuses a --name that wouldn't stay a file in the catalog directory.
+fn check_name(name: &str) -> io::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{name}' can't be a catalog name"),
+        ));
+    }
+    Ok(())
+}
+
+/// A catalog name made of a label or UUID.
+fn name_of(id: &str) -> String {
+    id.chars()
+        .map(|c| {
+            if matches!(c, '/' | '\\' | ':') {
+                '-'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+/// The name to catalog the drive of `args` under: --name, the entry the
+/// drive already has, or its label or UUID.
+fn add_name(args: &CatalogAddArgs, volume: Option<&Volume>) -> io::Result<String> {
+    let identity = args.run.encryption.identity.as_deref();
+    if let Some(name) = &args.name {
+        check_name(name)?;
+        // A name stands for one drive
+        let path = args.catalog.join(format!("{name}.json"));
+        let existing = match path.exists() {
+            true => load(&path, identity)?.volume,
+            false => None,
+        };
+        let was = existing.and_then(|v| v.uuid);
+        let now = volume.and_then(|v| v.uuid.as_ref());
+        if let (Some(was), Some(now)) = (&was, now)
+            && was != now
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!(
+                    "{name} is the catalog entry of another drive ({was}), pick another --name"
+                ),
+            ));
+        }
+        return Ok(name.clone());
+    }
+    let Some(volume) = volume else {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "can't tell which drive {} is, give it a --name",
+                args.drive.display()
+            ),
+        ));
+    };
+    if let Some(name) = find(&args.catalog, volume, identity)? {
+        return Ok(name);
+    }
+    let id = volume.label.as_ref().or(volume.uuid.as_ref());
+    Ok(name_of(id.expect("a volume has a label or UUID")))
+}
+
+/// `catalog add`: catalogs the drive at `drive`, again if it's in there.
+pub fn add(args: &CatalogAddArgs) -> i32 {
+    let volume = volume::of(&args.drive);
+    let name = fs::create_dir_all(&args.catalog)
+        .map_err(|e| with_path(&args.catalog, e))
+        .and_then(|_| add_name(args, volume.as_ref()));
+    let name = match name {
+        Ok(name) => name,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+
+    let path = args.catalog.join(format!("{name}.json"));
+    let mut run = args.run.clone();
+    run.record_as = Some(PathBuf::from(&name));
+    let code = execute(&args.drive, &run, &[], Some(&path));
+    if code == 0 {
+        let volume = volume.map(|v| v.to_string());
+        let drive = volume
+            .clone()
+            .unwrap_or_else(|| args.drive.display().to_string());
+        info!(
+            event = "cataloged",
+            name = name.as_str(),
+            volume = volume.as_deref();
+            "{} {drive} cataloged as {name}",
+            style::pick("💾", "*").bold()
+        );
+    }
+    code
+}
+
+/// `catalog verify`: verifies the drive at `drive` against its catalog
+/// entry, found by its volume UUID unless named.
+pub fn verify(args: &CatalogVerifyArgs) -> i32 {
+    let identity = args.run.encryption.identity.as_deref();
+    let name = match (&args.name, volume::of(&args.drive)) {
+        (Some(name), _) => check_name(name).map(|_| name.clone()),
+        (None, Some(volume)) => find(&args.catalog, &volume, identity).and_then(|name| {
+            name.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{volume} isn't in the catalog, add it with `catalog add`"),
+                )
+            })
+        }),
+        (None, None) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "can't tell which drive {} is, give its catalog --name",
+                args.drive.display()
+            ),
+        )),
+    };
+    let name = match name {
+        Ok(name) => name,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+
+    info!(
+        "{} Verifying against catalog entry {name}",
+        style::pick("💾", "*").bold()
+    );
+    let mut run = args.run.clone();
+    run.record_as = Some(PathBuf::from(&name));
+    let path = args.catalog.join(format!("{name}.json"));
+    execute(&args.drive, &run, &[path], None)
+}
+
+/// `catalog list`: every drive with its volume, file count, size and when it
+/// was cataloged. Exit code 1 if any of them can't be read.
+pub fn list(args: &CatalogListArgs) -> i32 {
+    let entries = match entries(&args.catalog) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+    if entries.is_empty() {
+        info!("No drives in {}", args.catalog.display());
+    }
+
+    let mut code = 0;
+    for (name, path) in &entries {
+        match load(path, args.identity.as_deref()) {
+            Ok(manifest) => {
+                let bytes: u64 = manifest.files.values().map(|m| m.size.max(0) as u64).sum();
+                let volume = manifest.volume.as_ref().map(|v| v.to_string());
+                let cataloged = manifest.created.map(|c| {
+                    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(c))
+                        .to_string()
+                });
+                info!(
+                    event = "drive",
+                    name = name.as_str(),
+                    volume = volume.as_deref(),
+                    files = manifest.files.len(),
+                    bytes = bytes,
+                    cataloged = cataloged.as_deref();
+                    "{}  {}, {} files, {}, cataloged {}",
+                    name.bold(),
+                    volume.as_deref().unwrap_or("unknown volume"),
+                    manifest.files.len(),
+                    HumanBytes(bytes),
+                    cataloged.as_deref().unwrap_or("at an unknown time")
+                );
+            }
+            Err(e) => {
+                code = 1;
+                error!(
+                    event = "drive",
+                    name = name.as_str(),
+                    error:% = e;
+                    "{}  {} {e}",
+                    name.bold(),
+                    style::pick("⚠️", "ERROR").red()
+                );
+            }
+        }
+    }
+    code
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use log::{error, info};
+
+use crate::{
+    cli::ChainArgs,
+    manifest::Manifest,
+    store::{self, Loaded},
+    style,
+};
+
+// Every --update keeps the manifest it replaces, byte for byte, as
+// `<manifest>.chain/<sequence>.json`, and the new manifest records that
+// file's BLAKE3 hash. Changing or deleting any old manifest breaks a link.
+
+/// Where the manifests replaced by --update are kept.
+fn chain_dir(manifest: &Path) -> PathBuf {
+    let mut name = OsString::from(manifest.as_os_str());
+    name.push(".chain");
+    PathBuf::from(name)
+}
+
+fn link_path(manifest: &Path, sequence: u64) -> PathBuf {
+    chain_dir(manifest).join(format!("{sequence}.json"))
+}
+
+/// Makes `next` the successor of `previous`, archiving `previous` first.
+pub fn extend(next: &mut Manifest, previous: &Loaded, manifest: &Path) -> io::Result<()> {
+    let sequence = previous.manifest.sequence.unwrap_or(0);
+    let dir = chain_dir(manifest);
+    fs::create_dir_all(&dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?;
+
+    let archived = link_path(manifest, sequence);
+    match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&archived)
+    {
+        Ok(mut f) => f.write_all(&previous.raw)?,
+        // Only an identical copy may already be there, history is never
+        // overwritten
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            if fs::read(&archived)? != previous.raw {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} already holds a different manifest, refusing to overwrite history",
+                        archived.display()
+                    ),
+                ));
+            }
+        }
+        Err(e) => {
+            return Err(io::Error::new(
+                e.kind(),
+                format!("{}: {}", archived.display(), e),
+            ));
+        }
+    }
+
+    next.sequence = Some(sequence + 1);
+    next.previous = Some(blake3::hash(&previous.raw).to_hex().to_string());
+    Ok(())
+}
+
+/// Sequence numbers of the archived manifests.
+fn archived_sequences(manifest: &Path) -> BTreeSet<u64> {
+    let Ok(entries) = fs::read_dir(chain_dir(manifest)) else {
+        return BTreeSet::new();
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.file_name().to_str()?.strip_suffix(".json")?.parse().ok())
+        .collect()
+}
+
+fn describe(m: &Manifest) -> String {
+    let created = m
+        .created
+        .map(|c| {
+            humantime::format_rfc3339_seconds(
+                std::time::UNIX_EPOCH + std::time::Duration::from_secs(c),
+            )
+            .to_string()
+        })
+        .unwrap_or_else(|| "unknown time".to_string());
+    format!("{} files, created {created}", m.files.len())
+}
+
+/// Walks the chain back from the current manifest. Returns the number of
+/// broken links.
+fn walk(manifest: &Path, identity: Option<&Path>) -> io::Result<usize> {
+    let data = fs::read(manifest)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", manifest.display(), e)))?;
+    let mut current = store::decode(manifest, data, identity)?;
+    let head = current.sequence.unwrap_or(0);
+    let mut problems = 0;
+
+    let mut fail = |msg: String| {
+        problems += 1;
+        error!(event = "chain", status = "fail"; "{} {}", style::pick("❌ BROKEN", "BROKEN").red(), msg);
+    };
+
+    info!(
+        event = "chain",
+        sequence = head;
+        "{} #{head} {} ({})",
+        style::pick("🔗", "LINK").bold(),
+        manifest.display(),
+        describe(&current)
+    );
+
+    // A newer link in the archive means the current manifest was swapped
+    // for an older one
+    let archived = archived_sequences(manifest);
+    for newer in archived.range(head..) {
+        fail(format!(
+            "{} is newer than the current manifest (#{head}), was it rolled back?",
+            link_path(manifest, *newer).display()
+        ));
+    }
+
+    let mut sequence = head;
+    while sequence > 0 {
+        let Some(expected) = current.previous.clone() else {
+            fail(format!(
+                "#{sequence} doesn't record the hash of #{}",
+                sequence - 1
+            ));
+            break;
+        };
+        let path = link_path(manifest, sequence - 1);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                fail(format!(
+                    "#{} ({}) is gone: {e}",
+                    sequence - 1,
+                    path.display()
+                ));
+                break;
+            }
+        };
+        if blake3::hash(&data).to_hex().as_str() != expected {
+            fail(format!(
+                "#{} ({}) was altered, its hash doesn't match the one #{sequence} recorded",
+                sequence - 1,
+                path.display()
+            ));
+            break;
+        }
+        let previous = match store::decode(&path, data, identity) {
+            Ok(m) => m,
+            Err(e) => {
+                fail(format!(
+                    "#{} ({}) doesn't parse: {e}",
+                    sequence - 1,
+                    path.display()
+                ));
+                break;
+            }
+        };
+        if previous.sequence.unwrap_or(0) != sequence - 1 {
+            fail(format!(
+                "{} says it is #{}, expected #{}",
+                path.display(),
+                previous.sequence.unwrap_or(0),
+                sequence - 1
+            ));
+            break;
+        }
+
+        info!(
+            event = "chain",
+            sequence = sequence - 1;
+            "{} #{} {} ({})",
+            style::pick("🔗", "LINK").bold(),
+            sequence - 1,
+            path.display(),
+            describe(&previous)
+        );
+        current = previous;
+        sequence -= 1;
+    }
+    if sequence == 0 && current.previous.is_some() {
+        fail("#0 records a previous manifest, the start of the chain is missing".to_string());
+    }
+
+    Ok(problems)
+}
+
+/// `chain verify`: exit code 0 when every link holds, 1 otherwise.
+pub fn verify(args: &ChainArgs) -> i32 {
+    match walk(&args.manifest, args.identity.as_deref()) {
+        Ok(0) => {
+            info!(
+                event = "chain_ok";
+                "{} Chain of {} is intact",
+                style::pick("✅", "OK").green(),
+                args.manifest.display()
+            );
+            0
+        }
+        Ok(problems) => {
+            error!(
+                event = "chain_broken",
+                problems = problems;
+                "{} Chain of {} is broken ({problems} problem(s))",
+                style::pick("❌", "BROKEN").red().bold(),
+                args.manifest.display()
+            );
+            1
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,1431 @@
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::{Args, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    export::ExportFormat,
+    hash::IoMode,
+    logging::LogFormat,
+    manifest::{JsonStyle, ManifestFormat, PathLayout},
+    progress::ProgressFormat,
+    store::OnConflict,
+    style::ColorChoice,
+    verify::GroupBy,
+};
+
+/// Records each file's BLAKE3 hash, size and mtime, and later checks that
+/// nothing changed behind your back.
+#[derive(Parser, Debug)]
+#[command(
+    name = "checkyoself",
+    version,
+    args_conflicts_with_subcommands = true,
+    override_usage = "checkyoself [OPTIONS] <DIRECTORY> <OUTPUT.json>\n       \
+                      checkyoself [OPTIONS] <DIRECTORY> --verify <REF.json> [--update]\n       \
+                      checkyoself <COMMAND>"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    #[command(flatten)]
+    pub output: OutputArgs,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Measure hashing throughput to pick --threads, --buffer-size and --io
+    Bench(BenchArgs),
+    /// Sanity check a manifest and the environment it will be used in
+    Doctor(DoctorArgs),
+    /// Print a shell completion script to stdout
+    Completions(CompletionsArgs),
+    /// Create the manifest of configured jobs
+    Create(JobArgs),
+    /// Verify configured jobs against their manifests
+    Verify(JobArgs),
+    /// List the jobs in the config file
+    Jobs,
+    /// Generate an ed25519 key pair for signing manifests
+    Keygen(KeygenArgs),
+    /// Work with the history kept by --update
+    #[command(subcommand)]
+    Chain(ChainCommand),
+    /// Print the JSON Schema of the manifest or of JSON log records
+    Schema(SchemaArgs),
+    /// Keep timestamped manifests of a tree, list and compare them
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+    /// Keep one manifest per removable drive, recognized by its volume UUID
+    #[command(subcommand)]
+    Catalog(CatalogCommand),
+    /// Search every drive in a catalog for files by path pattern or contents
+    Find(FindArgs),
+    /// Check that a copy (after rsync, robocopy...) matches its source's
+    /// manifest
+    SyncCheck(Box<SyncCheckArgs>),
+    /// Walk and hash a tree for another checkyoself's --agent, talking JSON
+    /// lines on stdin and stdout
+    Agent(AgentArgs),
+    /// Collect the reports hosts send with --report-to
+    Server(ServerArgs),
+    /// Show the latest report of every host, or one host's history
+    Fleet(FleetArgs),
+    /// Offer jobs, drive verification and their results on the D-Bus
+    /// session bus, for desktop integrations (Linux)
+    Dbus(DbusArgs),
+    /// Compare a manifest with an rclone listing of a cloud copy, without
+    /// downloading it
+    CloudCheck(CloudCheckArgs),
+    /// Print the Go dirhash (`h1:`, as in go.sum) of a manifest's files,
+    /// from the SHA-256 recorded with --sha256
+    TreeDigest(TreeDigestArgs),
+    /// Compare a manifest with a container image layer (a tar), and print
+    /// the layer's DiffID
+    LayerCheck(LayerCheckArgs),
+    /// Verify files against an .sfv file (CRC-32) and upgrade it to a
+    /// manifest, or write one from a manifest
+    #[command(subcommand)]
+    Sfv(SfvCommand),
+    /// Split a manifest into one per top-level directory, or by --glob
+    Split(Box<SplitArgs>),
+    /// List files modified since the manifest was created, without hashing
+    WhatsNew(Box<WhatsNewArgs>),
+    /// Show the changes --journal recorded, for some paths or all
+    Journal(JournalArgs),
+    /// Estimate deduplication from the chunk fingerprints recorded with --cdc
+    Dedup(DedupArgs),
+    /// List files with the same contents, and how much keeping one copy of
+    /// each would free
+    Dupes(DupesArgs),
+    /// Show how many files a manifest has and how big, from its header
+    Stats(StatsArgs),
+    /// Tag and annotate manifest entries, or list their tags and notes
+    Tag(Box<TagArgs>),
+    /// Index a manifest, so verify looks its entries up on disk instead of
+    /// loading it
+    Index(IndexArgs),
+    /// Export a manifest, or the findings of a --log-format json report, as
+    /// CSV for a spreadsheet
+    Export(ExportArgs),
+    /// Check installed files against the package database, and the rest of
+    /// the host against a manifest
+    Integrity(Box<IntegrityArgs>),
+}
+
+/// A recorded attribute that `--check` makes count as a failure when it
+/// differs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Check {
+    /// The contents
+    Hash,
+    Size,
+    /// The modification time, within --mtime-tolerance
+    Mtime,
+    /// Permission bits (Unix)
+    Perms,
+    /// User and group (Unix)
+    Owner,
+}
+
+impl Check {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Check::Hash => "hash",
+            Check::Size => "size",
+            Check::Mtime => "mtime",
+            Check::Perms => "perms",
+            Check::Owner => "owner",
+        }
+    }
+}
+
+/// Which copy of a group of duplicates `dupes` keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum KeepRule {
+    /// The first by path
+    First,
+    /// The one modified longest ago
+    Oldest,
+    /// The one with the shortest path
+    Shortest,
+}
+
+/// What a `dupes --emit-script` script does with a redundant copy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ScriptAction {
+    /// Remove it
+    Delete,
+    /// Replace it with a hard link to the kept copy
+    Hardlink,
+    /// Replace it with a reflink of the kept copy (btrfs, XFS)
+    Reflink,
+}
+
+/// What an --emit-restore script copies files back with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RestoreTool {
+    /// cp -p
+    Cp,
+    /// rsync -a
+    Rsync,
+}
+
+/// Which format `schema` describes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SchemaKind {
+    /// Manifest files
+    Manifest,
+    /// Lines of --log-format json
+    Report,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ChainCommand {
+    /// Check that no earlier manifest was altered or removed
+    Verify(ChainArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapshotCommand {
+    /// Scan a directory into a new snapshot, then prune old ones
+    Create(Box<SnapshotCreateArgs>),
+    /// List snapshots with their file counts and sizes
+    List(SnapshotListArgs),
+    /// Show what changed between two snapshots
+    Diff(SnapshotDiffArgs),
+    /// Tag a snapshot, to pick it by that name later
+    Tag(SnapshotTagArgs),
+    /// Verify a directory against a snapshot
+    Verify(Box<SnapshotVerifyArgs>),
+    /// Delete the snapshots the retention policy doesn't keep
+    Prune(SnapshotPruneArgs),
+    /// Prune, then clear out what deleted snapshots left behind
+    Gc(SnapshotPruneArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CatalogCommand {
+    /// Scan a drive into the catalog, replacing its entry if it has one
+    Add(Box<CatalogAddArgs>),
+    /// Verify a drive against its catalog entry
+    Verify(Box<CatalogVerifyArgs>),
+    /// List the cataloged drives with their volumes, file counts and sizes
+    List(CatalogListArgs),
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SfvCommand {
+    /// Check the files an .sfv file lists, then write those that match
+    /// into a manifest with --upgrade
+    Verify(Box<SfvVerifyArgs>),
+    /// Write an .sfv file of a manifest made with --crc32
+    Export(SfvExportArgs),
+}
+
+/// Creating or verifying a manifest.
+#[derive(Args, Debug)]
+pub struct ScanArgs {
+    /// Directory to scan
+    #[arg(value_name = "DIRECTORY", value_hint = ValueHint::DirPath)]
+    pub dir: Option<PathBuf>,
+
+    /// Where to write the manifest
+    #[arg(value_name = "OUTPUT.json", value_hint = ValueHint::FilePath, conflicts_with = "verify")]
+    pub output_file: Option<PathBuf>,
+
+    /// Compare the directory against this manifest, or the union of
+    /// several (repeatable)
+    #[arg(long, value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub verify: Vec<PathBuf>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+/// Options for anything that walks and hashes a tree.
+#[derive(Args, Debug, Clone)]
+pub struct RunArgs {
+    /// Update the manifest to reflect recent changes (with --verify)
+    #[arg(long)]
+    pub update: bool,
+
+    /// Roll verify findings up per directory, extension, size class and/or
+    /// device instead of listing every extra, missing or moved file (e.g.
+    /// ext,size)
+    #[arg(long, value_enum, value_name = "BY", value_delimiter = ',')]
+    pub group_by: Vec<GroupBy>,
+
+    /// With --group-by dir, how many directory levels to keep
+    #[arg(long, value_name = "N", requires = "group_by")]
+    pub depth: Option<usize>,
+
+    /// Treat modification times this many seconds apart as equal (2 for
+    /// FAT/exFAT)
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub mtime_tolerance: u64,
+
+    /// Also treat modification times a whole number of hours apart (up to
+    /// 14) as equal, for DST and timezone shifts
+    #[arg(long)]
+    pub mtime_tz_shifts: bool,
+
+    /// Match paths regardless of case, for a tree copied to or from
+    /// Windows or macOS (with --verify)
+    #[arg(long)]
+    pub ignore_case: bool,
+
+    /// Match paths that only differ in Unicode normalization (NFC or NFD,
+    /// `é` or `e` + accent), for a tree copied to or from macOS
+    #[arg(long)]
+    pub normalize_unicode: bool,
+
+    /// After verifying, list the N biggest mismatched files and the N
+    /// failed entries recorded longest ago, to restore first
+    #[arg(long, value_name = "N")]
+    pub offenders: Option<usize>,
+
+    /// Stop hashing at the first mismatch and exit 2, and fail on a missing
+    /// file too; for a quick yes or no
+    #[arg(long, conflicts_with = "update")]
+    pub fail_fast: bool,
+
+    /// After verifying, ask btrfs or ZFS whether they flagged the
+    /// mismatched files too, or the change came from above them (Linux)
+    #[arg(long)]
+    pub fs_check: bool,
+
+    /// Run this command through the shell before scanning, to mount a
+    /// drive or take a snapshot; the scan is off if it fails (repeatable)
+    #[arg(long, value_name = "CMD")]
+    pub pre: Vec<String>,
+
+    /// Run this command through the shell after scanning, whatever came
+    /// of it, with CHECKYOSELF_EXIT_CODE set (repeatable)
+    #[arg(long, value_name = "CMD")]
+    pub post: Vec<String>,
+
+    /// Run this command through the shell for each mismatched file, with
+    /// CHECKYOSELF_PATH, CHECKYOSELF_OLD_HASH, CHECKYOSELF_NEW_HASH and
+    /// the like set (with --verify)
+    #[arg(long, value_name = "CMD")]
+    pub on_mismatch: Option<String>,
+
+    /// Run this command for each extra file, as --on-mismatch
+    #[arg(long, value_name = "CMD")]
+    pub on_extra: Option<String>,
+
+    /// Run this command for each missing file, as --on-mismatch
+    #[arg(long, value_name = "CMD")]
+    pub on_missing: Option<String>,
+
+    /// Before reporting a mismatch, read the file again (from the disk, not
+    /// the cache, on Linux): right the second time, the file is fine and
+    /// the first read went wrong on the way
+    #[arg(long, conflicts_with_all = ["agent", "vss"])]
+    pub reread: bool,
+
+    /// Also read mismatched files from this other mount or replica of
+    /// DIRECTORY, implies --reread
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with_all = ["agent", "vss"])]
+    pub reread_from: Option<PathBuf>,
+
+    /// Compare mismatched files byte by byte with the same path under this
+    /// replica of DIRECTORY: where they first differ, in how many ranges
+    #[arg(long, value_name = "REPLICA", value_hint = ValueHint::DirPath, conflicts_with_all = ["agent", "vss"])]
+    pub compare_bytes: Option<PathBuf>,
+
+    /// After verifying, print a shell script to stdout that copies every
+    /// mismatched or missing file back from this replica of DIRECTORY, to
+    /// review and run
+    #[arg(long, value_name = "REPLICA", value_hint = ValueHint::DirPath)]
+    pub emit_restore: Option<PathBuf>,
+
+    /// What the --emit-restore script copies with
+    #[arg(long, value_enum, default_value = "rsync", requires = "emit_restore")]
+    pub restore_with: RestoreTool,
+
+    /// Verify a random sample of the manifest instead, as many files as it
+    /// takes for this confidence (e.g. 99) that fewer than
+    /// --sample-tolerance of them are bad
+    #[arg(long, value_name = "PCT", conflicts_with_all = ["update", "agent"])]
+    pub sample_confidence: Option<f64>,
+
+    /// The share of bad files --sample-confidence rules out, in percent
+    #[arg(
+        long,
+        value_name = "PCT",
+        default_value_t = 1.0,
+        requires = "sample_confidence"
+    )]
+    pub sample_tolerance: f64,
+
+    /// Print TAP (Test Anything Protocol) to stdout, a test point per
+    /// reference entry, for prove and other TAP harnesses; everything else
+    /// goes to stderr (with --verify)
+    #[arg(long)]
+    pub tap: bool,
+
+    /// Build the new manifest on top of this one: it only records what
+    /// differs, and includes the rest (repeatable)
+    #[arg(long, value_name = "BASE.json", value_hint = ValueHint::FilePath)]
+    pub include: Vec<PathBuf>,
+
+    /// What to do when several --verify manifests disagree about a path
+    #[arg(long, value_enum, value_name = "HOW", default_value = "error")]
+    pub on_conflict: OnConflict,
+
+    /// Warn when more than this share of the files still where they were
+    /// changed contents, as ransomware leaves a tree, and refuse --update
+    #[arg(long, value_name = "PCT", default_value_t = 30.0)]
+    pub mass_change: f64,
+
+    /// Update the reference, with --update or `auto-update` rules, even
+    /// though --mass-change was exceeded
+    #[arg(long)]
+    pub allow_mass_change: bool,
+
+    /// Append the changes --update makes to this file (JSON lines)
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub journal: Option<PathBuf>,
+
+    /// Only verify this directory, relative to DIRECTORY, against the
+    /// matching part of the manifest (with --verify)
+    #[arg(long, value_name = "PATH", value_hint = ValueHint::DirPath)]
+    pub subdir: Option<PathBuf>,
+
+    /// Record paths as if DIRECTORY were PATH, and verify them that way,
+    /// for a tree mounted or restored somewhere else than usual
+    #[arg(long, value_name = "PATH")]
+    pub record_as: Option<PathBuf>,
+
+    /// Verify as a copy of the manifest's tree, see `sync-check`
+    #[arg(skip)]
+    pub copy: bool,
+
+    /// Have an agent walk and hash DIRECTORY where it is, started with this
+    /// shell command (e.g. 'ssh fileserver checkyoself agent')
+    #[arg(long, value_name = "COMMAND")]
+    pub agent: Option<String>,
+
+    /// After verifying, send the summary to the `checkyoself server` at URL
+    #[arg(long, value_name = "URL")]
+    pub report_to: Option<String>,
+
+    /// File with the server's token, or keyring:<name>, for --report-to
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, requires = "report_to")]
+    pub report_token: Option<PathBuf>,
+
+    /// Report as NAME instead of the host name, for --report-to
+    #[arg(long, value_name = "NAME", requires = "report_to")]
+    pub host: Option<String>,
+
+    /// Read files from a Volume Shadow Copy made for the run, so files
+    /// other programs have locked can be hashed (Windows, elevated)
+    #[arg(long)]
+    pub vss: bool,
+
+    /// Show a progress bar
+    #[arg(long)]
+    pub progress: bool,
+
+    /// Progress style, implies --progress
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    pub progress_format: Option<ProgressFormat>,
+
+    /// Write JSON progress records to this file descriptor instead of stderr
+    #[arg(long, value_name = "N")]
+    pub progress_fd: Option<i32>,
+
+    /// How written manifests are formatted
+    #[arg(
+        long = "json",
+        value_enum,
+        value_name = "STYLE",
+        default_value = "pretty"
+    )]
+    pub json_style: JsonStyle,
+
+    /// How paths are written in JSON manifests: flat, each in full, or tree,
+    /// nested by directory so each is written once (needs a checkyoself
+    /// that reads version 2 manifests)
+    #[arg(long, value_enum, value_name = "LAYOUT", default_value = "flat")]
+    pub paths: PathLayout,
+
+    /// What to write the manifest as
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "json")]
+    pub format: ManifestFormat,
+
+    /// Record a digest per directory, made of the names and hashes of what
+    /// is in it, so whole branches that didn't change are told at once
+    #[arg(long, conflicts_with = "include")]
+    pub dir_digests: bool,
+
+    /// Tolerate changes to paths matching GLOB, relative to the root (e.g.
+    /// 'logs/**'); recorded in created manifests for later verifies
+    /// (repeatable)
+    #[arg(long, value_name = "GLOB")]
+    pub ignore_changes: Vec<String>,
+
+    /// Per-path rules, lines of 'GLOB = BEHAVIOR, ...' where behaviors are
+    /// skip, auto-update, strict and never-update
+    #[arg(long, value_name = "FILE")]
+    pub policy: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+
+    #[command(flatten)]
+    pub hashing: HashArgs,
+
+    #[command(flatten)]
+    pub signing: SignArgs,
+
+    #[command(flatten)]
+    pub encryption: EncryptArgs,
+}
+
+/// Which files a walk takes. Manifest entries the filters leave out aren't
+/// verified either.
+#[derive(Args, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FilterArgs {
+    /// Skip directories with this name (repeatable)
+    #[arg(long = "skip", value_name = "DIR")]
+    pub skip_dirs: Vec<String>,
+
+    /// Skip files and directories whose name starts with a dot
+    #[arg(long)]
+    pub skip_hidden: bool,
+
+    /// With --skip-hidden, still take hidden files and directories whose
+    /// name matches this glob (repeatable, e.g. '.htaccess')
+    #[arg(long, value_name = "GLOB", requires = "skip_hidden")]
+    pub keep_hidden: Vec<String>,
+
+    /// Only take files with one of these extensions (e.g. jpg,png,raw)
+    #[arg(long, value_name = "EXT", value_delimiter = ',')]
+    pub ext: Vec<String>,
+
+    /// Skip files with one of these extensions (e.g. tmp,partial)
+    #[arg(long, value_name = "EXT", value_delimiter = ',')]
+    pub skip_ext: Vec<String>,
+
+    /// Only take files modified after this: a date (2024-01-31), a UTC
+    /// time (2024-01-31 08:00:00) or how long ago (30days, 12h)
+    #[arg(long, value_name = "WHEN", value_parser = parse_time)]
+    pub newer_than: Option<u64>,
+
+    /// Only take files modified before this, same forms as --newer-than
+    /// (30days to leave out anything that changed recently)
+    #[arg(long, value_name = "WHEN", value_parser = parse_time)]
+    pub older_than: Option<u64>,
+
+    /// Only take files whose contents are of one of these MIME types,
+    /// whatever their extension (e.g. image/*,video/*,application/pdf);
+    /// records each file's type as --magic does
+    #[arg(long, value_name = "TYPE", value_delimiter = ',')]
+    #[serde(default)]
+    pub only_type: Vec<String>,
+
+    /// Skip files installed by the package manager (dpkg, pacman or rpm),
+    /// which can verify them itself
+    #[arg(long)]
+    pub skip_packaged: bool,
+
+    /// Also hash each file's NTFS alternate data streams, as separate
+    /// `file:stream` entries (Windows only)
+    #[arg(long)]
+    pub ads: bool,
+}
+
+/// A point in time for --newer-than and --older-than, in seconds since the
+/// epoch.
+fn parse_time(s: &str) -> Result<u64, String> {
+    let time = if let Ok(ago) = humantime::parse_duration(s) {
+        SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| format!("{s} ago is too long ago"))?
+    } else {
+        // A bare date is its midnight
+        let s = if s.len() == 10 {
+            format!("{s} 00:00:00")
+        } else {
+            s.to_string()
+        };
+        humantime::parse_rfc3339_weak(&s).map_err(|_| {
+            "expected a date (2024-01-31), a UTC time (2024-01-31 08:00:00) or a duration (30days)"
+                .to_string()
+        })?
+    };
+    Ok(time
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "before 1970".to_string())?
+        .as_secs())
+}
+
+/// Detached manifest signatures and timestamps, see `sign`, `gpg` and
+/// `timestamp`.
+#[derive(Args, Debug, Clone)]
+pub struct SignArgs {
+    /// Sign written manifests with this secret key (from `keygen`), a file
+    /// or keyring:NAME
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub sign_key: Option<PathBuf>,
+
+    /// Refuse to verify unless the manifest is signed by this public key
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub verify_key: Option<PathBuf>,
+
+    /// Also sign written manifests with gpg, as KEYID or the default key
+    #[arg(long, value_name = "KEYID", num_args = 0..=1, default_missing_value = "")]
+    pub gpg_sign: Option<String>,
+
+    /// Refuse to verify unless the manifest's .asc/.sig OpenPGP signature
+    /// is good according to this keyring
+    #[arg(long, value_name = "KEYRING", value_hint = ValueHint::FilePath)]
+    pub gpg_keyring: Option<PathBuf>,
+
+    /// Get an RFC 3161 timestamp for written manifests from this TSA
+    #[arg(long, value_name = "URL", value_hint = ValueHint::Url)]
+    pub tsa_url: Option<String>,
+
+    /// Refuse to verify unless the manifest's timestamp token checks out
+    /// against these CA certificates (PEM)
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub tsa_ca: Option<PathBuf>,
+}
+
+/// Keeping manifests encrypted at rest, see `crypt`.
+#[derive(Args, Debug, Clone)]
+pub struct EncryptArgs {
+    /// Encrypt written manifests to this age recipient, age1... (repeatable)
+    #[arg(long, value_name = "RECIPIENT")]
+    pub encrypt_to: Vec<String>,
+
+    /// age identity file for reading encrypted manifests, or keyring:NAME
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+/// Knobs for how files are read and hashed.
+#[derive(Args, Debug, Clone, Serialize, Deserialize)]
+pub struct HashArgs {
+    /// Number of hashing threads (defaults to one per core)
+    #[arg(long, value_name = "N")]
+    pub threads: Option<usize>,
+
+    /// Read buffer size in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = crate::hash::DEFAULT_BUFFER_SIZE)]
+    pub buffer_size: usize,
+
+    /// How file contents are read
+    #[arg(long = "io", value_enum, value_name = "MODE", default_value = "read")]
+    pub io_mode: IoMode,
+
+    /// Also record a hash of every BYTES piece of large files, so a
+    /// mismatch shows which ranges changed (e.g. 16777216)
+    #[arg(long, value_name = "BYTES")]
+    pub chunk_size: Option<u64>,
+
+    /// Files bigger than this get chunk hashes
+    #[arg(long, value_name = "BYTES", default_value_t = crate::hash::DEFAULT_CHUNK_THRESHOLD)]
+    pub chunk_threshold: u64,
+
+    /// Also split files into content-defined chunks of about BYTES and
+    /// record their fingerprints, for `dedup` (e.g. 1048576)
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(256..=4_194_304))]
+    pub cdc: Option<u32>,
+
+    /// Hash a file that changed while it was being hashed once more before
+    /// reporting it as volatile
+    #[arg(long)]
+    pub rehash_volatile: bool,
+
+    /// Retry reads that fail in a way that may go away (EIO, timeouts) up
+    /// to N times
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retries: u32,
+
+    /// Wait before the first retry, doubled for each one after
+    #[arg(long, value_name = "MS", default_value_t = 200, requires = "retries")]
+    pub retry_delay: u64,
+
+    /// Leave files another process has open for writing or locked alone,
+    /// reported as busy, rather than hash them half written
+    #[arg(long)]
+    #[serde(default)]
+    pub skip_busy: bool,
+
+    /// Also record a hash of each file's Finder info, resource fork,
+    /// quarantine flag, tags and download origin (macOS)
+    #[arg(long)]
+    pub mac_metadata: bool,
+
+    /// Also record each file's MD5, computed in the same pass, for
+    /// `cloud-check` against providers that only keep MD5 (S3 ETags)
+    #[arg(long)]
+    pub md5: bool,
+
+    /// Also record each file's SHA-256, computed in the same pass, for
+    /// `tree-digest` and `cloud-check` against what other tools hash with
+    #[arg(long)]
+    #[serde(default)]
+    pub sha256: bool,
+
+    /// Also record each file's CRC-32, computed in the same pass, to write
+    /// an .sfv file for older tools with `sfv export`
+    #[arg(long)]
+    #[serde(default)]
+    pub crc32: bool,
+
+    /// Also record how random each file's contents look (Shannon entropy),
+    /// and flag changed files that became near random, as encryption
+    /// leaves them
+    #[arg(long)]
+    #[serde(default)]
+    pub entropy: bool,
+
+    /// Also record what type each file's contents are (a MIME type, from
+    /// how they start), and point out changed files whose type changed
+    #[arg(long)]
+    #[serde(default)]
+    pub magic: bool,
+
+    /// Also record the device (filesystem) each file was read from, for
+    /// --group-by device when a tree spans several (Unix)
+    #[arg(long, conflicts_with = "agent")]
+    #[serde(default)]
+    pub devices: bool,
+
+    /// Also record when each file was created (its birth time), where the
+    /// filesystem keeps it, and point out files with the same contents
+    /// created anew: deleted and written back rather than edited
+    #[arg(long)]
+    #[serde(default)]
+    pub btime: bool,
+
+    /// The recorded attributes that fail a verify when they differ, each
+    /// reported on its own, instead of contents failing unless the
+    /// modification time owns up to them. perms and owner are recorded
+    /// when creating with them
+    #[arg(long, value_enum, value_name = "ATTR,...", value_delimiter = ',')]
+    #[serde(default)]
+    pub check: Vec<Check>,
+}
+
+/// Console and log output, accepted anywhere on the command line.
+#[derive(Args, Debug)]
+pub struct OutputArgs {
+    /// Only print mismatches
+    #[arg(short, long, alias = "q", global = true)]
+    pub quiet: bool,
+
+    /// Print more (-v matched files, -vv every hashed file)
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Console output format
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "text",
+        global = true
+    )]
+    pub log_format: LogFormat,
+
+    /// Append a timestamped record of the run to this file
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, global = true)]
+    pub log_file: Option<PathBuf>,
+
+    /// Print each line about a file from this template instead, e.g.
+    /// '{status} {path} {expected} {found}' (also {size}, {modified},
+    /// {age}, {tags} and {note})
+    #[arg(long, value_name = "TEMPLATE", global = true)]
+    pub line_format: Option<String>,
+
+    /// Also write warnings, errors and summaries to the Windows Event Log,
+    /// source `checkyoself`
+    #[arg(long, global = true)]
+    pub event_log: bool,
+
+    /// When to use colors
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        default_value = "auto",
+        global = true
+    )]
+    pub color: ColorChoice,
+
+    /// Plain ASCII tags instead of emoji
+    #[arg(long, global = true)]
+    pub ascii: bool,
+
+    /// Config file [default: ~/.config/checkyoself/config.toml]
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, global = true)]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// Also benchmark a sample of real files from this directory
+    #[arg(value_name = "DIRECTORY", value_hint = ValueHint::DirPath)]
+    pub dir: Option<PathBuf>,
+
+    /// Maximum number of real files to sample
+    #[arg(long, value_name = "N", default_value_t = 200)]
+    pub sample: usize,
+
+    /// Maximum MiB of real file data to sample
+    #[arg(long, value_name = "MIB", default_value_t = 512)]
+    pub sample_mib: u64,
+
+    /// MiB of synthetic data to hash
+    #[arg(long, value_name = "MIB", default_value_t = 256)]
+    pub synthetic_mib: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct DoctorArgs {
+    /// Manifest to check
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// Directory the manifest will be verified against
+    #[arg(value_name = "DIRECTORY", value_hint = ValueHint::DirPath)]
+    pub dir: Option<PathBuf>,
+
+    /// age identity file, for encrypted manifests
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct CompletionsArgs {
+    /// Shell to generate completions for
+    #[arg(value_enum)]
+    pub shell: Shell,
+}
+
+#[derive(Args, Debug)]
+pub struct ChainArgs {
+    /// Current manifest, its history is in REF.json.chain/
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// age identity file, for encrypted manifests
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+/// Which snapshots survive pruning. Without any, all of them do.
+#[derive(Args, Debug)]
+pub struct KeepArgs {
+    /// Keep the N most recent snapshots
+    #[arg(long, value_name = "N")]
+    pub keep_last: Option<usize>,
+
+    /// Keep the most recent snapshot of each of the last N days
+    #[arg(long, value_name = "N")]
+    pub keep_daily: Option<usize>,
+
+    /// Keep the most recent snapshot of each of the last N weeks
+    #[arg(long, value_name = "N")]
+    pub keep_weekly: Option<usize>,
+
+    /// Keep the most recent snapshot of each of the last N months
+    #[arg(long, value_name = "N")]
+    pub keep_monthly: Option<usize>,
+
+    /// Keep the most recent snapshot of each of the last N years
+    #[arg(long, value_name = "N")]
+    pub keep_yearly: Option<usize>,
+
+    /// Keep every snapshot taken within this long (30days, 2weeks)
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub keep_within: Option<std::time::Duration>,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotCreateArgs {
+    /// Directory to scan
+    #[arg(value_name = "DIRECTORY", value_hint = ValueHint::DirPath)]
+    pub dir: PathBuf,
+
+    /// Directory the snapshots are kept in
+    #[arg(value_name = "SNAPSHOTS", value_hint = ValueHint::DirPath)]
+    pub snapshots: PathBuf,
+
+    /// Tag the new snapshot (pre-migration, 2024-Q4), to pick it by that
+    /// name later (repeatable)
+    #[arg(long, value_name = "TAG")]
+    pub tag: Vec<String>,
+
+    #[command(flatten)]
+    pub keep: KeepArgs,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotVerifyArgs {
+    /// Directory to verify
+    #[arg(value_name = "DIRECTORY", value_hint = ValueHint::DirPath)]
+    pub dir: PathBuf,
+
+    /// Directory the snapshots are kept in
+    #[arg(value_name = "SNAPSHOTS", value_hint = ValueHint::DirPath)]
+    pub snapshots: PathBuf,
+
+    /// Snapshot to verify against: a name from `snapshot list`, a tag,
+    /// latest or latest~N
+    #[arg(value_name = "SNAPSHOT", default_value = "latest")]
+    pub snapshot: String,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CatalogAddArgs {
+    /// Where the drive is mounted
+    #[arg(value_name = "DRIVE", value_hint = ValueHint::DirPath)]
+    pub drive: PathBuf,
+
+    /// Directory the catalog is kept in
+    #[arg(value_name = "CATALOG", value_hint = ValueHint::DirPath)]
+    pub catalog: PathBuf,
+
+    /// Catalog the drive under this name (disk07) instead of its label
+    #[arg(long, value_name = "NAME")]
+    pub name: Option<String>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CatalogVerifyArgs {
+    /// Where the drive is mounted
+    #[arg(value_name = "DRIVE", value_hint = ValueHint::DirPath)]
+    pub drive: PathBuf,
+
+    /// Directory the catalog is kept in
+    #[arg(value_name = "CATALOG", value_hint = ValueHint::DirPath)]
+    pub catalog: PathBuf,
+
+    /// Verify against this entry instead of the one with the drive's UUID
+    #[arg(long, value_name = "NAME")]
+    pub name: Option<String>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CatalogListArgs {
+    /// Directory the catalog is kept in
+    #[arg(value_name = "CATALOG", value_hint = ValueHint::DirPath)]
+    pub catalog: PathBuf,
+
+    /// age identity file, for encrypted catalog entries
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct FindArgs {
+    /// Directory the catalog is kept in
+    #[arg(value_name = "CATALOG", value_hint = ValueHint::DirPath)]
+    pub catalog: PathBuf,
+
+    /// A glob: a file name (`IMG_0042.*`) or a path on the drive
+    /// (`photos/**/*.raw`), or a BLAKE3 hash
+    #[arg(value_name = "PATTERN", required_unless_present = "file")]
+    pub pattern: Option<String>,
+
+    /// Find drives that have a file with the contents of this one
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, conflicts_with = "pattern")]
+    pub file: Option<PathBuf>,
+
+    /// age identity file, for encrypted catalog entries
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SyncCheckArgs {
+    /// Manifest of the source tree
+    #[arg(value_name = "SOURCE.json", value_hint = ValueHint::FilePath)]
+    pub source: PathBuf,
+
+    /// Where it was copied to
+    #[arg(value_name = "DESTINATION", value_hint = ValueHint::DirPath)]
+    pub destination: PathBuf,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct AgentArgs {
+    /// Only hash trees under this directory (repeatable), for an agent
+    /// others can reach
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub allow: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ServerArgs {
+    /// Directory to keep the reports in, one directory per host
+    #[arg(value_name = "STORE", value_hint = ValueHint::DirPath)]
+    pub store: PathBuf,
+
+    /// Address to listen on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:7879")]
+    pub listen: String,
+
+    /// File with the token hosts have to send, or keyring:<name>
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub token: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct FleetArgs {
+    /// The server's URL, or its STORE directory
+    #[arg(value_name = "URL|STORE")]
+    pub source: String,
+
+    /// Show this host's history instead
+    #[arg(long, value_name = "NAME")]
+    pub host: Option<String>,
+
+    /// File with the server's token, or keyring:<name>
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub token: Option<PathBuf>,
+
+    /// Flag hosts that haven't reported in this long
+    #[arg(long, value_name = "DURATION", default_value = "2days", value_parser = humantime::parse_duration)]
+    pub stale: std::time::Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct DbusArgs {
+    /// Verify drives in this catalog when they're mounted
+    #[arg(long, value_name = "CATALOG", value_hint = ValueHint::DirPath)]
+    pub catalog: Option<PathBuf>,
+
+    /// age identity file, for encrypted catalog entries
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, requires = "catalog")]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct CloudCheckArgs {
+    /// Manifest of the local tree
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// Output of `rclone lsjson -R --hash` or `rclone hashsum` of the copy
+    #[arg(value_name = "LISTING", value_hint = ValueHint::FilePath)]
+    pub listing: PathBuf,
+
+    /// Which hash an `rclone hashsum` listing has, as rclone names it
+    #[arg(long, value_name = "NAME")]
+    pub hash: Option<String>,
+
+    /// Only look at listed paths under PREFIX, relative to it (e.g. the
+    /// S3 key prefix the tree was uploaded under)
+    #[arg(long, value_name = "PREFIX")]
+    pub prefix: Option<String>,
+
+    /// age identity file, for an encrypted manifest
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct TreeDigestArgs {
+    /// Manifest created with --sha256
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// Only the files under this directory, relative to the root (a module
+    /// in a bigger repository)
+    #[arg(long, value_name = "DIR")]
+    pub subdir: Option<String>,
+
+    /// Name the files PREFIX/<path>, as Go names those of a module:
+    /// `module@version`
+    #[arg(long, value_name = "PREFIX")]
+    pub prefix: Option<String>,
+
+    /// The digest to expect, e.g. the `h1:` of go.sum; exit code 2 if it
+    /// differs
+    #[arg(long, value_name = "DIGEST")]
+    pub expect: Option<String>,
+
+    /// age identity file, for an encrypted manifest
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct LayerCheckArgs {
+    /// Manifest of the tree
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// The layer, an uncompressed tar
+    #[arg(value_name = "LAYER.tar", value_hint = ValueHint::FilePath)]
+    pub layer: PathBuf,
+
+    /// Only look at the layer's files under PREFIX, relative to it (where
+    /// the tree is in the image)
+    #[arg(long, value_name = "PREFIX")]
+    pub prefix: Option<String>,
+
+    /// The DiffID to expect, `sha256:<hex>` from the image config's
+    /// rootfs.diff_ids; exit code 2 if it differs
+    #[arg(long, value_name = "DIGEST")]
+    pub diff_id: Option<String>,
+
+    /// age identity file, for an encrypted manifest
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SfvVerifyArgs {
+    /// The .sfv file, the paths in it are relative to where it is
+    #[arg(value_name = "FILE.sfv", value_hint = ValueHint::FilePath)]
+    pub sfv: PathBuf,
+
+    /// Write the files that match into a new manifest, checked by BLAKE3
+    /// from then on
+    #[arg(long, value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub upgrade: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SfvExportArgs {
+    /// Manifest created with --crc32
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// Where to write it, stdout if not given
+    #[arg(value_name = "OUT.sfv", value_hint = ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+
+    /// age identity file, for an encrypted manifest
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotListArgs {
+    /// Directory the snapshots are kept in
+    #[arg(value_name = "SNAPSHOTS", value_hint = ValueHint::DirPath)]
+    pub snapshots: PathBuf,
+
+    /// age identity file, for encrypted snapshots
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotDiffArgs {
+    /// Directory the snapshots are kept in
+    #[arg(value_name = "SNAPSHOTS", value_hint = ValueHint::DirPath)]
+    pub snapshots: PathBuf,
+
+    /// Older snapshot: a name from `snapshot list`, a tag, latest or
+    /// latest~N
+    #[arg(value_name = "FROM", default_value = "latest~1")]
+    pub from: String,
+
+    /// Newer snapshot
+    #[arg(value_name = "TO", default_value = "latest")]
+    pub to: String,
+
+    /// age identity file, for encrypted snapshots
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotTagArgs {
+    /// Directory the snapshots are kept in
+    #[arg(value_name = "SNAPSHOTS", value_hint = ValueHint::DirPath)]
+    pub snapshots: PathBuf,
+
+    /// Snapshot to tag: a name from `snapshot list`, a tag, latest or
+    /// latest~N
+    #[arg(value_name = "SNAPSHOT")]
+    pub snapshot: String,
+
+    /// Tags to put on it
+    #[arg(value_name = "TAG", required_unless_present = "delete")]
+    pub tags: Vec<String>,
+
+    /// Move a tag that's on another snapshot
+    #[arg(long)]
+    pub force: bool,
+
+    /// Take the tags off instead: `snapshot tag --delete SNAPSHOTS TAG...`
+    #[arg(long, conflicts_with = "force")]
+    pub delete: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct SnapshotPruneArgs {
+    /// Directory the snapshots are kept in
+    #[arg(value_name = "SNAPSHOTS", value_hint = ValueHint::DirPath)]
+    pub snapshots: PathBuf,
+
+    #[command(flatten)]
+    pub keep: KeepArgs,
+
+    /// Only show what would be deleted
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct SplitArgs {
+    /// Manifest to split
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// Directory the parts are written to, as NAME.json
+    #[arg(value_name = "OUT_DIR", value_hint = ValueHint::DirPath)]
+    pub out_dir: PathBuf,
+
+    /// Put paths (relative to the root) matching PATTERN into NAME.json
+    /// instead, first match wins, the rest go to _rest.json (repeatable)
+    #[arg(long = "glob", value_name = "NAME=PATTERN")]
+    pub globs: Vec<String>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct WhatsNewArgs {
+    /// Directory to look at
+    #[arg(value_name = "DIRECTORY", value_hint = ValueHint::DirPath)]
+    pub dir: PathBuf,
+
+    /// Manifest whose creation time is the cutoff
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct IntegrityArgs {
+    /// Directory to verify against --verify as well, / for the whole host
+    #[arg(value_name = "DIRECTORY", value_hint = ValueHint::DirPath, requires = "verify")]
+    pub dir: Option<PathBuf>,
+
+    /// Manifest of what the packages don't cover, created with
+    /// --skip-packaged
+    #[arg(long, value_name = "REF.json", value_hint = ValueHint::FilePath, requires = "dir")]
+    pub verify: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct JournalArgs {
+    /// Journal written by --journal
+    #[arg(value_name = "JOURNAL", value_hint = ValueHint::FilePath)]
+    pub journal: PathBuf,
+
+    /// Only changes to these paths or anything under them, as in the
+    /// manifest
+    #[arg(value_name = "PATH")]
+    pub paths: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct TagArgs {
+    /// Manifest whose entries to tag
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// Entries to tag, by path as in the manifest or by glob pattern
+    /// (`photos/2021/**`)
+    #[arg(value_name = "PATH", required = true)]
+    pub paths: Vec<String>,
+
+    /// Add TAG to the entries (repeatable)
+    #[arg(long = "add", value_name = "TAG")]
+    pub add: Vec<String>,
+
+    /// Remove TAG from the entries (repeatable)
+    #[arg(long = "remove", value_name = "TAG")]
+    pub remove: Vec<String>,
+
+    /// Set the entries' note, an empty one removes it
+    #[arg(long, value_name = "TEXT")]
+    pub note: Option<String>,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct IndexArgs {
+    /// Manifest to index, into <REF.json>.index
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// Manifest, or JSON lines written by --log-format json or a JSON
+    /// --log-file
+    #[arg(value_name = "REF.json|REPORT", value_hint = ValueHint::FilePath)]
+    pub input: PathBuf,
+
+    /// What to write
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "csv")]
+    pub format: ExportFormat,
+
+    /// Write here instead of stdout
+    #[arg(short, long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub output: Option<PathBuf>,
+
+    /// Start with a UTF-8 byte order mark, for Excel to read non-ASCII
+    /// paths right
+    #[arg(long)]
+    pub bom: bool,
+
+    /// age identity file, for encrypted manifests
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct DedupArgs {
+    /// Manifest created with --cdc
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// age identity file, for encrypted manifests
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+
+    /// How many groups of files sharing content to list
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub top: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct DupesArgs {
+    /// Manifest to look for copies in
+    #[arg(value_name = "REF.json", value_hint = ValueHint::FilePath)]
+    pub manifest: PathBuf,
+
+    /// age identity file, for encrypted manifests
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+
+    /// How many groups of copies, and directories, to list
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub top: usize,
+
+    /// How many directory levels below the root to break copies down by
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    pub depth: usize,
+
+    /// Compare with another manifest instead (another machine, a backup):
+    /// which files have a copy there, and which are only here
+    #[arg(long, value_name = "OTHER.json", value_hint = ValueHint::FilePath)]
+    pub against: Option<PathBuf>,
+
+    /// Which copy of each group to keep
+    #[arg(long, value_enum, default_value = "first")]
+    pub keep: KeepRule,
+
+    /// Keep the copy under this directory, where a group has one there,
+    /// whatever --keep says
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub prefer: Option<PathBuf>,
+
+    /// Write a shell script that does away with the redundant copies, to
+    /// review and run ('-' for stdout)
+    #[arg(long, value_name = "FILE", conflicts_with = "against")]
+    pub emit_script: Option<PathBuf>,
+
+    /// What the script does with a redundant copy
+    #[arg(long, value_enum, default_value = "delete", requires = "emit_script")]
+    pub action: ScriptAction,
+
+    /// Write the redundant copies NUL separated instead of a script, for
+    /// `xargs -0`
+    #[arg(long, requires = "emit_script", conflicts_with = "action")]
+    pub null: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct StatsArgs {
+    /// Manifest, or --format kv manifest directory, to look at
+    #[arg(value_name = "REF.json", value_hint = ValueHint::AnyPath)]
+    pub manifest: PathBuf,
+
+    /// age identity file, for encrypted manifests
+    #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+    pub identity: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct SchemaArgs {
+    #[arg(value_enum, default_value = "manifest")]
+    pub kind: SchemaKind,
+}
+
+#[derive(Args, Debug)]
+pub struct KeygenArgs {
+    /// Writes PREFIX.key (secret) and PREFIX.pub
+    #[arg(value_name = "PREFIX", value_hint = ValueHint::FilePath)]
+    pub prefix: PathBuf,
+
+    /// Keep the secret key in the OS keyring under NAME instead of
+    /// PREFIX.key, then sign with --sign-key keyring:NAME
+    #[arg(long, value_name = "NAME")]
+    pub keyring: Option<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct JobArgs {
+    /// Names of jobs from the config file
+    #[arg(value_name = "JOB", required_unless_present = "all")]
+    pub jobs: Vec<String>,
+
+    /// Run every configured job
+    #[arg(long, conflicts_with = "jobs")]
+    pub all: bool,
+
+    #[command(flatten)]
+    pub run: RunArgs,
+}
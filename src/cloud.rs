@@ -0,0 +1,292 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+use colored::*;
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+
+use crate::{
+    cli::CloudCheckArgs,
+    hash::FileMeta,
+    store::{self, with_path},
+    style,
+};
+
+// A copy in the cloud can be checked without downloading it: rclone lists
+// what the provider already knows about every object, its size and
+// whatever hashes it keeps. Those are compared with a local manifest by
+// path relative to the manifest's root, which is what the listing's paths
+// are relative to when the same directory was copied. S3 keeps the MD5 of
+// an object uploaded in one part as its ETag, so `aws s3api
+// list-objects-v2` is a listing too, for manifests made with --md5.
+
+/// What a listing says about one object.
+#[derive(Debug, Default)]
+struct Object {
+    /// None if the provider doesn't know
+    size: Option<i64>,
+    /// Lowercase hex by lowercase rclone hash name (`md5`, `sha1`, `sha256`,
+    /// `blake3`)
+    hashes: BTreeMap<String, String>,
+}
+
+/// One entry of `rclone lsjson --hash`.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct LsEntry {
+    path: String,
+    #[serde(default)]
+    size: i64,
+    #[serde(default)]
+    is_dir: bool,
+    #[serde(default)]
+    hashes: BTreeMap<String, String>,
+}
+
+/// `aws s3api list-objects-v2` output.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct S3Listing {
+    #[serde(default)]
+    contents: Vec<S3Object>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct S3Object {
+    key: String,
+    size: i64,
+    #[serde(rename = "ETag")]
+    etag: String,
+}
+
+impl From<S3Object> for (String, Object) {
+    fn from(object: S3Object) -> Self {
+        let mut hashes = BTreeMap::new();
+        // The ETag of a multipart upload is the MD5 of its parts' MD5s
+        // followed by `-<parts>`, not the object's
+        let etag = object.etag.trim_matches('"');
+        if !etag.contains('-') {
+            hashes.insert("md5".to_string(), etag.to_lowercase());
+        }
+        let size = Some(object.size);
+        (object.key, Object { size, hashes })
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Objects by path from `rclone lsjson -R --hash` (a JSON array),
+/// `aws s3api list-objects-v2` (a JSON object) or `rclone hashsum <hash>`
+/// (`<hex>  <path>` lines, `hash` says which).
+fn parse(data: &str, hash: Option<&str>) -> io::Result<BTreeMap<String, Object>> {
+    if data.trim_start().starts_with('{') {
+        let listing: S3Listing = serde_json::from_str(data)
+            .map_err(|e| invalid(format!("aws s3api list-objects-v2: {e}")))?;
+        return Ok(listing.contents.into_iter().map(Into::into).collect());
+    }
+    if data.trim_start().starts_with('[') {
+        let entries: Vec<LsEntry> =
+            serde_json::from_str(data).map_err(|e| invalid(format!("rclone lsjson: {e}")))?;
+        return Ok(entries
+            .into_iter()
+            .filter(|e| !e.is_dir)
+            .map(|e| {
+                let hashes = e
+                    .hashes
+                    .into_iter()
+                    .filter(|(_, v)| !v.is_empty())
+                    .map(|(k, v)| (k.to_lowercase(), v.to_lowercase()))
+                    .collect();
+                // rclone says -1 when the size isn't known
+                let size = (e.size >= 0).then_some(e.size);
+                (e.path, Object { size, hashes })
+            })
+            .collect());
+    }
+
+    let Some(hash) = hash else {
+        return Err(invalid(
+            "an rclone hashsum listing doesn't say which hash it has, give --hash".to_string(),
+        ));
+    };
+    let mut objects = BTreeMap::new();
+    for (n, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // Objects the provider has no hash for get a blank one
+        if line.starts_with(' ') {
+            objects.insert(line.trim_start().to_string(), Object::default());
+            continue;
+        }
+        let Some((hex, path)) = line.split_once("  ").or_else(|| line.split_once(" *")) else {
+            return Err(invalid(format!(
+                "rclone hashsum, line {}: expected `<hash>  <path>`",
+                n + 1
+            )));
+        };
+        // Or UNSUPPORTED, or ERROR
+        let mut object = Object::default();
+        if hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            object
+                .hashes
+                .insert(hash.to_lowercase(), hex.to_lowercase());
+        }
+        objects.insert(path.to_string(), object);
+    }
+    Ok(objects)
+}
+
+/// The local hash `name`, an rclone hash name, if the manifest has it.
+fn local_hash<'a>(meta: &'a FileMeta, name: &str) -> Option<Cow<'a, str>> {
+    match name {
+        "blake3" => Some(Cow::Owned(meta.hash.to_string())),
+        "md5" => meta.md5.as_deref().map(Cow::Borrowed),
+        "sha256" => meta.sha256.as_deref().map(Cow::Borrowed),
+        "crc32" => meta.crc32.as_deref().map(Cow::Borrowed),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    matched: usize,
+    no_hash: usize,
+    mismatched: usize,
+    missing: usize,
+    extra: usize,
+}
+
+fn check(args: &CloudCheckArgs) -> io::Result<Counts> {
+    let data = fs::read(&args.manifest).map_err(|e| with_path(&args.manifest, e))?;
+    let manifest = store::decode(&args.manifest, data, args.identity.as_deref())?;
+    let mut objects = fs::read_to_string(&args.listing)
+        .and_then(|listing| parse(&listing, args.hash.as_deref()))
+        .map_err(|e| with_path(&args.listing, e))?;
+    if let Some(prefix) = &args.prefix {
+        let prefix = format!("{}/", prefix.trim_end_matches('/'));
+        objects = objects
+            .into_iter()
+            .filter_map(|(path, object)| Some((path.strip_prefix(&prefix)?.to_string(), object)))
+            .collect();
+    }
+
+    let root = manifest.root.clone().unwrap_or_default();
+    let mut files: Vec<(&str, &FileMeta)> = manifest
+        .files
+        .iter()
+        .map(|(path, meta)| {
+            let relative = Path::new(path)
+                .strip_prefix(&root)
+                .ok()
+                .and_then(|p| p.to_str())
+                .unwrap_or(path);
+            (relative, meta)
+        })
+        .collect();
+    files.sort_unstable_by_key(|(path, _)| *path);
+
+    let mut counts = Counts::default();
+    let mut seen = BTreeSet::new();
+    for (path, meta) in files {
+        let Some(object) = objects.get(path) else {
+            counts.missing += 1;
+            error!(event = "missing", path = path; "{} {path}", style::pick("❓ MISSING", "MISSING").magenta());
+            continue;
+        };
+        seen.insert(path);
+        if let Some(size) = object.size.filter(|&size| size != meta.size) {
+            counts.mismatched += 1;
+            error!(
+                event = "mismatch",
+                path = path,
+                expected_size = meta.size,
+                found_size = size;
+                "{} {path}\n  expected: {} bytes\n  found:    {size} bytes",
+                style::pick("❌ MISMATCH", "MISMATCH").red(),
+                meta.size
+            );
+            continue;
+        }
+        let comparable = object
+            .hashes
+            .iter()
+            .find_map(|(name, remote)| Some((name, local_hash(meta, name)?, remote)));
+        match comparable {
+            Some((_, local, remote)) if local == *remote => {
+                counts.matched += 1;
+                debug!(event = "matched", path = path; "{} {path}", style::pick("✅ MATCHED", "MATCHED").green());
+            }
+            Some((name, local, remote)) => {
+                counts.mismatched += 1;
+                error!(
+                    event = "mismatch",
+                    path = path,
+                    hash = name.as_str(),
+                    expected = local,
+                    found = remote.as_str();
+                    "{} {path}\n  expected: {local}\n  found:    {remote} ({name})",
+                    style::pick("❌ MISMATCH", "MISMATCH").red()
+                );
+            }
+            None => {
+                counts.no_hash += 1;
+                debug!(event = "no_hash", path = path; "{} {path} (no hash to compare)", style::pick("🔍 NO HASH", "NO HASH").yellow());
+            }
+        }
+    }
+    for path in objects.keys() {
+        if !seen.contains(path.as_str()) {
+            counts.extra += 1;
+            info!(event = "extra", path = path.as_str(); "{} {path}", style::pick("⚠️ EXTRA", "EXTRA").blue());
+        }
+    }
+
+    info!(
+        event = "cloud_summary",
+        matched = counts.matched,
+        no_hash = counts.no_hash,
+        mismatched = counts.mismatched,
+        missing = counts.missing,
+        extra = counts.extra;
+        "\n=== {} ===\n{} {}\n{} {}\n{} {}\n{} {}\n{} {}",
+        "CLOUD".bold().underline(),
+        style::pick("✅ Matched:", "Matched:").green(),
+        counts.matched,
+        style::pick("🔍 No hash to compare:", "No hash to compare:").yellow(),
+        counts.no_hash,
+        style::pick("❌ Mismatched:", "Mismatched:").red(),
+        counts.mismatched,
+        style::pick("❓ Missing:", "Missing:").magenta(),
+        counts.missing,
+        style::pick("⚠️ Extra:", "Extra:").blue(),
+        counts.extra
+    );
+    if counts.no_hash > 0 {
+        warn!(
+            "{} file(s) couldn't be compared by contents, the listing has no hash the manifest has",
+            counts.no_hash
+        );
+    }
+    Ok(counts)
+}
+
+/// `cloud-check`: exit code 0, 2 if anything is missing or differs, 1 on
+/// errors.
+pub fn run(args: &CloudCheckArgs) -> i32 {
+    match check(args) {
+        Ok(counts) if counts.mismatched > 0 || counts.missing > 0 => 2,
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::smart::Disks;
+
+/// Settings read from `config.toml`.
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub jobs: BTreeMap<String, Job>,
+}
+
+/// A named tree to scan, e.g. `[jobs.photos]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    /// Directory to scan
+    pub root: PathBuf,
+    /// Where the job's manifest lives
+    pub manifest: PathBuf,
+    /// Directory names to skip, like --skip
+    #[serde(default)]
+    pub skip: Vec<String>,
+    /// Paths whose changes are tolerated, like --ignore-changes
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// Per-path rules, like --policy
+    #[serde(default)]
+    pub policy: Option<PathBuf>,
+    /// When the job is meant to run, for the benefit of whoever schedules
+    /// it (cron, systemd timers); shown by `checkyoself jobs`.
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// Shell commands run when the job fails, see `jobs::notify`
+    #[serde(default)]
+    pub notify: Vec<String>,
+    /// Shell commands run before and after the scan, like --pre and --post
+    #[serde(default)]
+    pub pre: Vec<String>,
+    #[serde(default)]
+    pub post: Vec<String>,
+    /// Read the SMART data of the disks under `root` before and after the
+    /// scan, see `smart`
+    #[serde(default)]
+    pub smart: Option<Disks>,
+}
+
+/// `$XDG_CONFIG_HOME/checkyoself/config.toml`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .or_else(|| env::var_os("USERPROFILE"))
+                .map(|home| PathBuf::from(home).join(".config"))
+        })?;
+    Some(base.join("checkyoself").join("config.toml"))
+}
+
+impl Config {
+    /// Loads `path`, or the default location when `None`. A missing default
+    /// config is just an empty one; a missing explicit one is an error.
+    pub fn load(path: Option<&Path>) -> io::Result<Self> {
+        let (path, explicit) = match path {
+            Some(p) => (p.to_path_buf(), true),
+            None => match default_path() {
+                Some(p) => (p, false),
+                None => return Ok(Config::default()),
+            },
+        };
+
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !explicit => {
+                return Ok(Config::default());
+            }
+            Err(e) => {
+                return Err(io::Error::new(
+                    e.kind(),
+                    format!("{}: {}", path.display(), e),
+                ));
+            }
+        };
+
+        toml::from_str(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    pub fn job(&self, name: &str) -> io::Result<&Job> {
+        self.jobs.get(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no job named '{name}' in the config"),
+            )
+        })
+    }
+}
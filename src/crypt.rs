@@ -0,0 +1,93 @@
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::secrets;
+
+use age::{
+    Decryptor, Encryptor, IdentityFile, Recipient, armor::ArmoredReader, stream::StreamWriter,
+    x25519,
+};
+
+const BINARY_MAGIC: &[u8] = b"age-encryption.org/";
+const ARMOR_MAGIC: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+fn invalid(path: &Path, what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), what),
+    )
+}
+
+/// Whether `data` is an age file, binary or armored.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(BINARY_MAGIC) || data.trim_ascii_start().starts_with(ARMOR_MAGIC)
+}
+
+/// Parses `age1...` recipients as given to --encrypt-to.
+pub fn parse_recipients(recipients: &[String]) -> io::Result<Vec<x25519::Recipient>> {
+    recipients
+        .iter()
+        .map(|r| {
+            x25519::Recipient::from_str(r).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("bad age recipient '{r}': {e}"),
+                )
+            })
+        })
+        .collect()
+}
+
+/// Wraps `out` so everything written to it is encrypted for any of
+/// `recipients`. Call `finish()` on it when done.
+pub fn encrypting_writer<W: Write>(
+    out: W,
+    recipients: &[x25519::Recipient],
+) -> io::Result<StreamWriter<W>> {
+    Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn Recipient))
+        .map_err(io::Error::other)?
+        .wrap_output(out)
+}
+
+/// Returns `data`, read from `path`, decrypted with the identities in
+/// `identity` (a file or `keyring:<name>`) if it's an age file, or
+/// unchanged if it isn't.
+pub fn decrypt_if_needed(
+    path: &Path,
+    data: Vec<u8>,
+    identity: Option<&Path>,
+) -> io::Result<Vec<u8>> {
+    if !is_encrypted(&data) {
+        return Ok(data);
+    }
+    let Some(identity) = identity else {
+        return Err(invalid(
+            path,
+            "is encrypted, pass --identity with a key that can decrypt it",
+        ));
+    };
+
+    let identities = IdentityFile::from_buffer(secrets::read(identity)?.as_bytes())
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", identity.display(), e)))?
+        .into_identities()
+        .map_err(|e| invalid(identity, e))?;
+
+    let decryptor =
+        Decryptor::new(ArmoredReader::new(data.as_slice())).map_err(|e| invalid(path, e))?;
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|i| i.as_ref()))
+        .map_err(|e| {
+            invalid(
+                path,
+                format!("can't decrypt with {}: {e}", identity.display()),
+            )
+        })?;
+    let mut plaintext = Vec::new();
+    reader
+        .read_to_end(&mut plaintext)
+        .map_err(|e| invalid(path, format!("decryption failed: {e}")))?;
+    Ok(plaintext)
+}
@@ -0,0 +1,335 @@
+use crate::cli::DbusArgs;
+
+// `checkyoself dbus` puts checkyoself on the session bus for desktop
+// integrations (a GNOME extension, a KDE widget): they list the jobs, start
+// one, see how each last went, and hear when a run starts and finishes.
+// With --catalog it also watches for drives being mounted and verifies the
+// ones in the catalog. Runs are separate `checkyoself` processes, one at a
+// time, so a crash or a long scan never takes the bus name down with it.
+//
+//   bus name   org.checkyoself.Checkyoself
+//   object     /org/checkyoself/Checkyoself
+//   interface  org.checkyoself.Checkyoself1
+//
+//   Jobs() -> as                       configured job names
+//   Verify(s job)                      starts verifying a job
+//   VerifyDrive(s mount)               starts verifying a drive against --catalog
+//   Status() -> (b busy, s current)
+//   Results() -> a(sist)               (name, exit code, result, finished)
+//   signal Started(s name)
+//   signal Finished(s name, i exit_code, s result)
+//   signal DriveAttached(s name, s mount)
+
+#[cfg(target_os = "linux")]
+mod service {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        env, fs,
+        path::{Path, PathBuf},
+        process::Command,
+        sync::{Arc, Mutex, OnceLock},
+        thread,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use log::{info, warn};
+    use zbus::{blocking::Connection, fdo, interface};
+
+    use crate::{catalog, cli::DbusArgs, config::Config, health, style, volume};
+
+    const NAME: &str = "org.checkyoself.Checkyoself";
+    const PATH: &str = "/org/checkyoself/Checkyoself";
+    const INTERFACE: &str = "org.checkyoself.Checkyoself1";
+    /// How often the mount table is looked at, for --catalog
+    const POLL: Duration = Duration::from_secs(5);
+
+    /// How a run ended: its exit code and what that means.
+    #[derive(Clone)]
+    struct Outcome {
+        exit_code: i32,
+        finished: u64,
+    }
+
+    fn result(exit_code: i32) -> &'static str {
+        match exit_code {
+            0 => "ok",
+            2 => "mismatch",
+            _ => "error",
+        }
+    }
+
+    #[derive(Default)]
+    struct State {
+        /// What's running
+        current: Option<String>,
+        /// The last outcome of each job or drive, by name
+        results: BTreeMap<String, Outcome>,
+    }
+
+    #[derive(Clone)]
+    struct Service {
+        config: Option<PathBuf>,
+        catalog: Option<PathBuf>,
+        identity: Option<PathBuf>,
+        state: Arc<Mutex<State>>,
+        connection: Arc<OnceLock<Connection>>,
+    }
+
+    impl Service {
+        fn emit<B>(&self, signal: &str, body: &B)
+        where
+            B: serde::Serialize + zbus::zvariant::DynamicType,
+        {
+            let Some(connection) = self.connection.get() else {
+                return;
+            };
+            if let Err(e) = connection.emit_signal(None::<&str>, PATH, INTERFACE, signal, body) {
+                warn!(event = "dbus_error", error:% = e; "can't send {signal}: {e}");
+            }
+        }
+
+        /// Runs `checkyoself <args>` for `name` in the background, unless
+        /// something is running already.
+        fn start(&self, name: String, args: Vec<String>) -> fdo::Result<()> {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(current) = &state.current {
+                    return Err(fdo::Error::Failed(format!("{current} is running")));
+                }
+                state.current = Some(name.clone());
+            }
+            info!(event = "started", name = name.as_str(); "{} Starting {name}", style::pick("▶️", "*"));
+            self.emit("Started", &(name.as_str(),));
+
+            let service = self.clone();
+            thread::spawn(move || {
+                let mut command = Command::new(env::current_exe().unwrap_or("checkyoself".into()));
+                command.args(&args);
+                if let Some(config) = &service.config {
+                    command.arg("--config").arg(config);
+                }
+                let exit_code = match command.status() {
+                    Ok(status) => status.code().unwrap_or(1),
+                    Err(e) => {
+                        warn!(event = "dbus_error", error:% = e; "can't run checkyoself: {e}");
+                        1
+                    }
+                };
+                let finished = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                {
+                    let mut state = service.state.lock().unwrap();
+                    state.current = None;
+                    state.results.insert(
+                        name.clone(),
+                        Outcome {
+                            exit_code,
+                            finished,
+                        },
+                    );
+                }
+                info!(
+                    event = "finished",
+                    name = name.as_str(),
+                    exit_code = exit_code;
+                    "{} {name}: {}",
+                    style::pick("⏹️", "*"),
+                    result(exit_code)
+                );
+                service.emit("Finished", &(name.as_str(), exit_code, result(exit_code)));
+            });
+            Ok(())
+        }
+
+        /// Verifies the drive mounted at `mount` against its catalog entry,
+        /// named `name`.
+        fn start_drive(&self, name: String, mount: &Path) -> fdo::Result<()> {
+            let Some(catalog) = &self.catalog else {
+                return Err(fdo::Error::Failed("started without --catalog".to_string()));
+            };
+            let mut args = vec!["catalog".to_string(), "verify".to_string()];
+            args.push(mount.display().to_string());
+            args.push(catalog.display().to_string());
+            args.extend(["--name".to_string(), name.clone()]);
+            if let Some(identity) = &self.identity {
+                args.extend(["--identity".to_string(), identity.display().to_string()]);
+            }
+            self.start(name, args)
+        }
+
+        /// The catalog entry of the drive mounted at `mount`.
+        fn cataloged(&self, mount: &Path) -> Option<String> {
+            let volume = volume::of(mount)?;
+            catalog::find(self.catalog.as_deref()?, &volume, self.identity.as_deref())
+                .inspect_err(|e| warn!(event = "dbus_error", error:% = e; "catalog: {e}"))
+                .ok()?
+        }
+    }
+
+    #[interface(name = "org.checkyoself.Checkyoself1")]
+    impl Service {
+        /// The names of the configured jobs.
+        fn jobs(&self) -> fdo::Result<Vec<String>> {
+            let config = Config::load(self.config.as_deref())
+                .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+            Ok(config.jobs.into_keys().collect())
+        }
+
+        /// Starts verifying the job `job`.
+        fn verify(&self, job: String) -> fdo::Result<()> {
+            let config = Config::load(self.config.as_deref())
+                .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+            config
+                .job(&job)
+                .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+            self.start(job.clone(), vec!["verify".to_string(), job])
+        }
+
+        /// Starts verifying the drive mounted at `mount` against its entry
+        /// in the catalog.
+        fn verify_drive(&self, mount: String) -> fdo::Result<()> {
+            let mount = PathBuf::from(mount);
+            let name = self.cataloged(&mount).ok_or_else(|| {
+                fdo::Error::InvalidArgs(format!("{} isn't in the catalog", mount.display()))
+            })?;
+            self.start_drive(name, &mount)
+        }
+
+        /// Whether something is running, and what.
+        fn status(&self) -> (bool, String) {
+            let state = self.state.lock().unwrap();
+            (
+                state.current.is_some(),
+                state.current.clone().unwrap_or_default(),
+            )
+        }
+
+        /// A job or drive started being verified.
+        #[zbus(signal)]
+        async fn started(ctxt: &zbus::SignalContext<'_>, name: &str) -> zbus::Result<()>;
+
+        /// A job or drive was verified: exit code 0 (`ok`), 2 (`mismatch`)
+        /// or anything else (`error`).
+        #[zbus(signal)]
+        async fn finished(
+            ctxt: &zbus::SignalContext<'_>,
+            name: &str,
+            exit_code: i32,
+            result: &str,
+        ) -> zbus::Result<()>;
+
+        /// A drive in the catalog was mounted.
+        #[zbus(signal)]
+        async fn drive_attached(
+            ctxt: &zbus::SignalContext<'_>,
+            name: &str,
+            mount: &str,
+        ) -> zbus::Result<()>;
+
+        /// How each job and drive last went: name, exit code, `ok`,
+        /// `mismatch` or `error`, and when it finished.
+        fn results(&self) -> Vec<(String, i32, String, u64)> {
+            let state = self.state.lock().unwrap();
+            state
+                .results
+                .iter()
+                .map(|(name, o)| {
+                    (
+                        name.clone(),
+                        o.exit_code,
+                        result(o.exit_code).to_string(),
+                        o.finished,
+                    )
+                })
+                .collect()
+        }
+    }
+
+    /// Mount points in /proc/self/mounts, which escapes spaces and such as
+    /// octal (`\040`).
+    fn mounts() -> BTreeSet<PathBuf> {
+        let table = fs::read_to_string("/proc/self/mounts").unwrap_or_default();
+        table
+            .lines()
+            .filter_map(|line| line.split(' ').nth(1))
+            .map(health::mount_point)
+            .collect()
+    }
+
+    /// Verifies cataloged drives as they get mounted.
+    fn watch(service: &Service) {
+        let mut known = mounts();
+        loop {
+            thread::sleep(POLL);
+            let now = mounts();
+            for mount in now.difference(&known) {
+                let Some(name) = service.cataloged(mount) else {
+                    continue;
+                };
+                info!(
+                    event = "drive_attached",
+                    name = name.as_str(),
+                    mount:% = mount.display();
+                    "{} {name} mounted at {}",
+                    style::pick("💽", "*"),
+                    mount.display()
+                );
+                service.emit(
+                    "DriveAttached",
+                    &(name.as_str(), mount.display().to_string()),
+                );
+                if let Err(e) = service.start_drive(name.clone(), mount) {
+                    warn!(event = "dbus_error", error:% = e; "can't verify {name}: {e}");
+                }
+            }
+            known = now;
+        }
+    }
+
+    pub fn run(args: &DbusArgs, config: Option<&Path>) -> zbus::Result<()> {
+        let service = Service {
+            config: config.map(Path::to_path_buf),
+            catalog: args.catalog.clone(),
+            identity: args.identity.clone(),
+            state: Arc::default(),
+            connection: Arc::default(),
+        };
+        let connection = zbus::blocking::connection::Builder::session()?
+            .name(NAME)?
+            .serve_at(PATH, service.clone())?
+            .build()?;
+        let _ = service.connection.set(connection);
+        info!(
+            event = "dbus",
+            name = NAME;
+            "{} On the session bus as {NAME}",
+            style::pick("🖥️", "*")
+        );
+        if service.catalog.is_some() {
+            watch(&service);
+        }
+        loop {
+            thread::park();
+        }
+    }
+}
+
+/// `dbus`: runs until killed, exit code 1 if it can't get on the bus.
+pub fn run(args: &DbusArgs, config: Option<&std::path::Path>) -> i32 {
+    #[cfg(target_os = "linux")]
+    let result = service::run(args, config).map_err(|e| e.to_string());
+    #[cfg(not(target_os = "linux"))]
+    let result: Result<(), String> = {
+        let _ = (args, config);
+        Err("the D-Bus service is only supported on Linux".to_string())
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+};
+
+use colored::*;
+use indicatif::HumanBytes;
+use log::{info, warn};
+
+use crate::{cli::DedupArgs, hash::FileMeta, store, style};
+
+// Estimates what a deduplicating backup would store, from the content-defined
+// chunk fingerprints recorded with --cdc. Files that share any chunk are
+// grouped together, so related files (edited copies, VM images of the same
+// base, re-encoded archives) show up as one group with its shared fraction.
+
+/// Files linked by shared chunks.
+struct Group<'a> {
+    paths: Vec<&'a str>,
+    total: u64,
+    unique: u64,
+}
+
+impl Group<'_> {
+    fn saved(&self) -> u64 {
+        self.total - self.unique
+    }
+
+    fn shared_percent(&self) -> f64 {
+        percent(self.saved(), self.total)
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+/// The length of a `<fingerprint>:<length>` chunk.
+fn chunk_len(chunk: &str) -> u64 {
+    chunk
+        .rsplit_once(':')
+        .and_then(|(_, len)| len.parse().ok())
+        .unwrap_or(0)
+}
+
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Groups files that share at least one chunk, biggest savings first.
+fn groups<'a>(files: &[(&'a str, &'a [String])]) -> Vec<Group<'a>> {
+    let mut parent: Vec<usize> = (0..files.len()).collect();
+    let mut owner: HashMap<&str, usize> = HashMap::new();
+    for (i, (_, chunks)) in files.iter().enumerate() {
+        for chunk in chunks.iter() {
+            match owner.get(chunk.as_str()) {
+                Some(&j) => {
+                    let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                    parent[a] = b;
+                }
+                None => {
+                    owner.insert(chunk, i);
+                }
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..files.len() {
+        let root = find(&mut parent, i);
+        members.entry(root).or_default().push(i);
+    }
+
+    let mut groups: Vec<Group> = members
+        .into_values()
+        .filter(|m| m.len() > 1)
+        .map(|m| {
+            let mut seen = HashSet::new();
+            let mut group = Group {
+                paths: Vec::with_capacity(m.len()),
+                total: 0,
+                unique: 0,
+            };
+            for i in m {
+                let (path, chunks) = files[i];
+                group.paths.push(path);
+                for chunk in chunks {
+                    let len = chunk_len(chunk);
+                    group.total += len;
+                    if seen.insert(chunk.as_str()) {
+                        group.unique += len;
+                    }
+                }
+            }
+            group.paths.sort_unstable();
+            group
+        })
+        .filter(|g| g.saved() > 0)
+        .collect();
+    groups.sort_by(|a, b| b.saved().cmp(&a.saved()).then(a.paths.cmp(&b.paths)));
+    groups
+}
+
+fn list(paths: &[&str], shown: usize) -> String {
+    let mut list = paths
+        .iter()
+        .take(shown)
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ");
+    if paths.len() > shown {
+        list.push_str(&format!(" and {} more", paths.len() - shown));
+    }
+    list
+}
+
+fn report(args: &DedupArgs) -> io::Result<()> {
+    let data = fs::read(&args.manifest)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", args.manifest.display(), e)))?;
+    let manifest = store::decode(&args.manifest, data, args.identity.as_deref())?;
+
+    let mut files: Vec<(&str, &[String])> = Vec::new();
+    let mut without = 0;
+    for (path, meta) in &manifest.files {
+        match meta {
+            FileMeta { cdc: Some(cdc), .. } => files.push((path, cdc)),
+            _ => without += 1,
+        }
+    }
+    files.sort_unstable_by_key(|(path, _)| *path);
+
+    if without > 0 {
+        warn!(
+            event = "dedup_skipped",
+            files = without;
+            "{} {without} file(s) have no chunk fingerprints, create the manifest with --cdc",
+            style::pick("⚠️", "WARNING").yellow()
+        );
+    }
+
+    let groups = groups(&files);
+    for group in groups.iter().take(args.top) {
+        info!(
+            event = "dedup_group",
+            files = group.paths.len(),
+            bytes = group.total,
+            unique_bytes = group.unique,
+            shared_percent = group.shared_percent();
+            "{} {} files share {:.0}% of their content ({}, {} unique): {}",
+            style::pick("🧩", "GROUP").bold(),
+            group.paths.len(),
+            group.shared_percent(),
+            HumanBytes(group.total),
+            HumanBytes(group.unique),
+            list(&group.paths, 5)
+        );
+    }
+
+    let mut seen = HashSet::new();
+    let (mut total, mut unique) = (0, 0);
+    for (_, chunks) in &files {
+        for chunk in chunks.iter() {
+            let len = chunk_len(chunk);
+            total += len;
+            if seen.insert(chunk.as_str()) {
+                unique += len;
+            }
+        }
+    }
+    info!(
+        event = "dedup_summary",
+        files = files.len(),
+        chunks = seen.len(),
+        groups = groups.len(),
+        bytes = total,
+        unique_bytes = unique;
+        "{} {} files, {}; a deduplicating backup would need about {} ({:.0}%), {} saved",
+        style::pick("📦", "DEDUP").bold(),
+        files.len(),
+        HumanBytes(total),
+        HumanBytes(unique),
+        percent(unique, total),
+        HumanBytes(total - unique)
+    );
+    Ok(())
+}
+
+/// `dedup`: exit code 0, or 1 if the manifest can't be read.
+pub fn run(args: &DedupArgs) -> i32 {
+    match report(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
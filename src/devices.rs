@@ -0,0 +1,86 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{hash::FileMeta, manifest::Filesystem};
+
+// --devices records which device each file was read from, as the number
+// the system gives it, and once per number in the manifest's `devices`
+// what filesystem that was: its source and UUID. A tree spanning mounts (a
+// second disk mounted under it, a pool of drives merged into one
+// directory) can then have its findings rolled up by device with
+// --group-by device, where a failing disk stands out as the one with the
+// mismatches. Numbers alone would be cheap but can change between boots,
+// what they were is what the table is for.
+
+/// The devices files were read from, each with the first file read from
+/// it. Safe to add to from several hashing threads at once.
+#[derive(Default)]
+pub struct Seen(Mutex<BTreeMap<u64, PathBuf>>);
+
+impl Seen {
+    /// Notes the device of the file at `path`, as `meta` has it.
+    pub fn add(&self, path: &str, meta: &FileMeta) {
+        if let Some(device) = meta.device {
+            self.0
+                .lock()
+                .unwrap()
+                .entry(device)
+                .or_insert_with(|| PathBuf::from(path));
+        }
+    }
+
+    /// What each device seen is, and those of `kept`, entries kept from an
+    /// earlier manifest whose `devices` was `known`.
+    pub fn describe<'a>(
+        self,
+        kept: impl IntoIterator<Item = &'a FileMeta>,
+        known: &BTreeMap<u64, Filesystem>,
+    ) -> BTreeMap<u64, Filesystem> {
+        let mut devices: BTreeMap<u64, Filesystem> = self
+            .0
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|(device, path)| (device, of(device, Some(&path))))
+            .collect();
+        for device in kept.into_iter().filter_map(|meta| meta.device) {
+            devices.entry(device).or_insert_with(|| {
+                known
+                    .get(&device)
+                    .cloned()
+                    .unwrap_or_else(|| of(device, None))
+            });
+        }
+        devices
+    }
+}
+
+/// What device `device` is called in a report: as `known` (a manifest's
+/// `devices`) has it, unless something else is mounted from it now.
+pub fn label(device: u64, known: &BTreeMap<u64, Filesystem>) -> String {
+    let now = of(device, None);
+    match known.get(&device) {
+        Some(then) if now.source.is_none() || now.source == then.source => then.to_string(),
+        _ => now.to_string(),
+    }
+}
+
+/// Filesystem `device`, its volume found through `path` on it if given.
+fn of(device: u64, path: Option<&Path>) -> Filesystem {
+    #[cfg(unix)]
+    let id = crate::health::device_number(device);
+    #[cfg(not(unix))]
+    let id = device.to_string();
+    #[cfg(target_os = "linux")]
+    let source = crate::health::mount_source(&id);
+    #[cfg(not(target_os = "linux"))]
+    let source = None;
+    Filesystem {
+        id,
+        source,
+        volume: path.and_then(crate::volume::of),
+    }
+}
@@ -0,0 +1,109 @@
+use std::{fs, io, path::Path};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use colored::*;
+use log::{error, info};
+use sha2::{Digest, Sha256};
+
+use crate::{cli::TreeDigestArgs, manifest::Manifest, store, style};
+
+// `tree-digest` adds up a manifest's files the way Go's dirhash does (the
+// `h1:` hashes of go.sum, golang.org/x/mod/sumdb/dirhash Hash1): the
+// SHA-256 of a line `<sha256 hex>  <name>` per file, sorted by name, in
+// base64. A module's name for its files is `module@version/<path>`, which
+// --prefix gives. Only the manifest is read, its entries need a SHA-256
+// each (--sha256); the tree itself needn't be there any more. Go leaves
+// some files out of a module (VCS directories, nested modules, vendor
+// directories below the top), leaving them out is up to the scan.
+
+/// The `h1:` digest of the files of `manifest` under `subdir` (relative to
+/// its root), named `prefix/<path>`.
+fn hash1(manifest: &Manifest, subdir: Option<&str>, prefix: Option<&str>) -> io::Result<String> {
+    let root = Path::new(manifest.root.as_deref().unwrap_or_default());
+    let under = match subdir {
+        Some(subdir) => root.join(subdir),
+        None => root.to_path_buf(),
+    };
+    let mut files = Vec::new();
+    let mut without = 0;
+    for (path, meta) in &manifest.files {
+        let Some(relative) = Path::new(path).strip_prefix(&under).ok() else {
+            continue;
+        };
+        let relative = relative.to_string_lossy();
+        let name = match prefix.map(|p| p.trim_end_matches('/')) {
+            Some(prefix) if !prefix.is_empty() => format!("{prefix}/{relative}"),
+            _ => relative.into_owned(),
+        };
+        if name.contains('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{path}: dirhash doesn't take file names with line breaks"),
+            ));
+        }
+        match &meta.sha256 {
+            Some(sha256) => files.push((name, sha256)),
+            None => without += 1,
+        }
+    }
+    if without > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{without} entries have no SHA-256, create the manifest with --sha256"),
+        ));
+    }
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no files under {}", under.display()),
+        ));
+    }
+    files.sort_unstable();
+    let mut hasher = Sha256::new();
+    for (name, sha256) in &files {
+        hasher.update(format!("{sha256}  {name}\n").as_bytes());
+    }
+    Ok(format!("h1:{}", STANDARD.encode(hasher.finalize())))
+}
+
+fn digest(args: &TreeDigestArgs) -> io::Result<bool> {
+    let data = fs::read(&args.manifest)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", args.manifest.display(), e)))?;
+    let manifest = store::decode(&args.manifest, data, args.identity.as_deref())?;
+    let digest = hash1(&manifest, args.subdir.as_deref(), args.prefix.as_deref())?;
+    println!("{digest}");
+    let Some(expected) = &args.expect else {
+        return Ok(true);
+    };
+    if *expected == digest {
+        info!(
+            event = "tree_digest_matched",
+            digest = digest.as_str();
+            "{} {}",
+            style::pick("✅ MATCHED", "MATCHED").green(),
+            args.manifest.display()
+        );
+        return Ok(true);
+    }
+    error!(
+        event = "tree_digest_mismatch",
+        expected = expected.as_str(),
+        found = digest.as_str();
+        "{} {}\n  expected: {expected}\n  found:    {digest}",
+        style::pick("❌ MISMATCH", "MISMATCH").red(),
+        args.manifest.display()
+    );
+    Ok(false)
+}
+
+/// `tree-digest`: exit code 0, 2 if it isn't --expect, 1 on errors.
+pub fn run(args: &TreeDigestArgs) -> i32 {
+    match digest(args) {
+        Ok(true) => 0,
+        Ok(false) => 2,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{self, OpenOptions},
+    path::{Component, Path},
+};
+
+use colored::*;
+use log::{error, info, warn};
+use serde::{
+    Deserialize, Deserializer,
+    de::{self, DeserializeSeed},
+};
+
+use crate::{
+    cli::DoctorArgs,
+    crypt,
+    hash::{Digest, FileMeta},
+    manifest::{Checksum, Special, now_secs},
+    style, tree,
+};
+
+// How many offending entries to list per check before summarizing.
+const MAX_LISTED: usize = 10;
+
+/// Manifest entries in file order, duplicates included, which a HashMap
+/// would silently collapse. Flat or nested, see `tree`.
+struct Entries(Vec<(String, Option<FileMeta>)>);
+
+impl<'de> Deserialize<'de> for Entries {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut entries = Vec::new();
+        tree::Entries::new(&mut |path, Checked(meta)| entries.push((path, meta)))
+            .deserialize(deserializer)?;
+        Ok(Entries(entries))
+    }
+}
+
+/// An entry whose hash isn't a BLAKE3 hash is none, for it to be reported
+/// rather than the manifest not loading.
+struct Checked(Option<FileMeta>);
+
+impl<'de> Deserialize<'de> for Checked {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entry = serde_json::Value::deserialize(deserializer)?;
+        let hash = entry.get("hash").and_then(|h| h.as_str());
+        match hash.is_some_and(is_blake3_hex) {
+            true => Ok(Checked(Some(
+                serde_json::from_value(entry).map_err(de::Error::custom)?,
+            ))),
+            false => Ok(Checked(None)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RawManifest {
+    version: u32,
+    root: Option<String>,
+    #[serde(default)]
+    special: BTreeMap<String, Special>,
+    files: Entries,
+    checksum: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawAny {
+    Current(RawManifest),
+    Legacy(Entries),
+}
+
+#[derive(Default)]
+struct Report {
+    problems: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&mut self, msg: impl fmt::Display) {
+        info!(event = "check", status = "ok"; "{} {}", style::pick("✅ OK  ", "OK  ").green(), msg);
+    }
+
+    fn warn(&mut self, msg: impl fmt::Display) {
+        self.warnings += 1;
+        warn!(event = "check", status = "warn"; "{} {}", style::pick("⚠️ WARN", "WARN").yellow(), msg);
+    }
+
+    fn fail(&mut self, msg: impl fmt::Display) {
+        self.problems += 1;
+        error!(event = "check", status = "fail"; "{} {}", style::pick("❌ FAIL", "FAIL").red(), msg);
+    }
+
+    /// Reports `paths` as one finding, listing the first few of them.
+    fn list(&mut self, failing: bool, what: &str, paths: &[&str]) {
+        if paths.is_empty() {
+            return;
+        }
+        let mut msg = format!("{} {}", paths.len(), what);
+        for p in paths.iter().take(MAX_LISTED) {
+            msg += &format!("\n      {p}");
+        }
+        if paths.len() > MAX_LISTED {
+            msg += &format!("\n      ... and {} more", paths.len() - MAX_LISTED);
+        }
+        if failing {
+            self.fail(msg)
+        } else {
+            self.warn(msg)
+        }
+    }
+}
+
+fn is_blake3_hex(hash: &str) -> bool {
+    hash.len() == 64
+        && hash
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+/// Longest common directory of all paths, for manifests without a header.
+fn common_root<'a>(paths: impl Iterator<Item = &'a str>) -> Option<String> {
+    let mut prefix: Option<Vec<Component>> = None;
+    for p in paths {
+        let parent: Vec<Component> = Path::new(p).parent()?.components().collect();
+        prefix = Some(match prefix {
+            None => parent,
+            Some(prev) => prev
+                .into_iter()
+                .zip(parent)
+                .take_while(|(a, b)| a == b)
+                .map(|(a, _)| a)
+                .collect(),
+        });
+    }
+    prefix.map(|c| {
+        c.iter()
+            .collect::<std::path::PathBuf>()
+            .to_string_lossy()
+            .to_string()
+    })
+}
+
+fn check_entries(report: &mut Report, entries: &[(String, Option<FileMeta>)], root: Option<&str>) {
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for (path, _) in entries {
+        *seen.entry(path.as_str()).or_default() += 1;
+    }
+    let mut duplicates: Vec<&str> = seen
+        .iter()
+        .filter(|(_, n)| **n > 1)
+        .map(|(p, _)| *p)
+        .collect();
+    duplicates.sort_unstable();
+    report.list(
+        true,
+        "paths appear more than once (only the last entry is used)",
+        &duplicates,
+    );
+
+    let empty_hash = Digest::from(blake3::hash(b""));
+    let future = now_secs() + 24 * 60 * 60;
+    let mut bad_hash = Vec::new();
+    let mut bad_empty = Vec::new();
+    let mut bad_size = Vec::new();
+    let mut bad_time = Vec::new();
+    let mut unclean = Vec::new();
+    let mut outside = Vec::new();
+
+    for (path, meta) in entries {
+        let p = Path::new(path);
+        if path.is_empty()
+            || path.contains("//")
+            || path.contains("/./")
+            || p.components().any(|c| c == Component::ParentDir)
+        {
+            unclean.push(path.as_str());
+        }
+        if let Some(root) = root
+            && !p.starts_with(root)
+        {
+            outside.push(path.as_str());
+        }
+        let Some(meta) = meta else {
+            bad_hash.push(path.as_str());
+            continue;
+        };
+        if meta.size == 0 && meta.hash != empty_hash {
+            bad_empty.push(path.as_str());
+        }
+        if meta.size < 0 {
+            bad_size.push(path.as_str());
+        }
+        if meta.modified > future {
+            bad_time.push(path.as_str());
+        }
+    }
+
+    for list in [
+        &mut bad_hash,
+        &mut bad_empty,
+        &mut bad_size,
+        &mut bad_time,
+        &mut unclean,
+        &mut outside,
+    ] {
+        list.sort_unstable();
+    }
+    report.list(true, "entries don't have a valid BLAKE3 hash", &bad_hash);
+    report.list(
+        true,
+        "empty files have a hash other than the empty hash",
+        &bad_empty,
+    );
+    report.list(true, "entries have a negative size", &bad_size);
+    report.list(
+        false,
+        "entries were modified more than a day in the future",
+        &bad_time,
+    );
+    report.list(
+        false,
+        "paths are empty or not in normal form ('//', '..')",
+        &unclean,
+    );
+    report.list(true, "paths are outside the recorded root", &outside);
+
+    let clean = duplicates.is_empty()
+        && bad_hash.is_empty()
+        && bad_empty.is_empty()
+        && bad_size.is_empty()
+        && bad_time.is_empty()
+        && unclean.is_empty()
+        && outside.is_empty();
+    if clean {
+        report.ok(format!("{} entries look sane", entries.len()));
+    }
+}
+
+fn check_checksum(
+    report: &mut Report,
+    entries: &[(String, Option<FileMeta>)],
+    special: &BTreeMap<String, Special>,
+    recorded: Option<&str>,
+) {
+    let Some(recorded) = recorded else {
+        report.warn("no checksum recorded, damage to the entries can't be told from changes");
+        return;
+    };
+    // Later duplicates win, as they do when the manifest is loaded. An
+    // entry without a valid hash can't add up, that's reported already
+    let files: HashMap<String, FileMeta> = entries
+        .iter()
+        .filter_map(|(path, meta)| Some((path.clone(), meta.clone()?)))
+        .collect();
+    if Checksum::of(&files, special).hex() == recorded {
+        report.ok("checksum matches the entries");
+    } else {
+        report.fail("checksum doesn't match the entries, the manifest is damaged");
+    }
+}
+
+fn check_root(report: &mut Report, recorded: &str, dir: Option<&Path>) {
+    let Some(dir) = dir else {
+        if Path::new(recorded).is_dir() {
+            report.ok(format!("recorded root {recorded} exists"));
+        } else {
+            report.fail(format!("recorded root {recorded} isn't a directory"));
+        }
+        return;
+    };
+
+    let given = dir.to_string_lossy();
+    let same_dir = match (fs::canonicalize(dir), fs::canonicalize(recorded)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    };
+
+    // A trailing separator doesn't change the walked paths
+    if given.trim_end_matches('/') == recorded.trim_end_matches('/') {
+        report.ok(format!("scan root {given} matches the manifest"));
+    } else if same_dir {
+        report.fail(format!(
+            "{given} is the recorded root spelled differently, entries are keyed by '{recorded}' so scan with exactly that"
+        ));
+    } else {
+        report.fail(format!(
+            "scan root {given} doesn't match the recorded root {recorded}"
+        ));
+    }
+}
+
+fn check_writable(report: &mut Report, manifest: &Path) {
+    match OpenOptions::new().append(true).open(manifest) {
+        Ok(_) => report.ok(format!("{} is writable", manifest.display())),
+        Err(e) => report.warn(format!(
+            "{} isn't writable, --update will fail: {e}",
+            manifest.display()
+        )),
+    }
+
+    let dir = match manifest.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".checkyoself-doctor-{}", std::process::id()));
+    match fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            report.ok(format!("{} is writable", dir.display()));
+        }
+        Err(e) => report.warn(format!("can't create files in {}: {e}", dir.display())),
+    }
+}
+
+/// The SIMD implementation BLAKE3 will pick at runtime, with the CPU
+/// features it detected.
+fn cpu_features() -> (Vec<&'static str>, &'static str) {
+    let mut found = Vec::new();
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        for (name, present) in [
+            ("sse2", std::arch::is_x86_feature_detected!("sse2")),
+            ("sse4.1", std::arch::is_x86_feature_detected!("sse4.1")),
+            ("avx2", std::arch::is_x86_feature_detected!("avx2")),
+            ("avx512f", std::arch::is_x86_feature_detected!("avx512f")),
+            ("avx512vl", std::arch::is_x86_feature_detected!("avx512vl")),
+        ] {
+            if present {
+                found.push(name);
+            }
+        }
+        let has = |f: &str| found.contains(&f);
+        let simd = if has("avx512f") && has("avx512vl") {
+            "AVX-512"
+        } else if has("avx2") {
+            "AVX2"
+        } else if has("sse4.1") {
+            "SSE4.1"
+        } else if has("sse2") {
+            "SSE2"
+        } else {
+            "portable"
+        };
+        (found, simd)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        found.push("neon");
+        (found, "NEON")
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        (found, "portable")
+    }
+}
+
+/// Runs the checks, returning the exit code: 0 when nothing is wrong,
+/// 1 when any check failed.
+pub fn run(args: &DoctorArgs) -> i32 {
+    let mut report = Report::default();
+    let manifest = &args.manifest;
+
+    info!("\n=== {} ===", "MANIFEST".bold().underline());
+    let parsed = fs::read(manifest)
+        .and_then(|data| crypt::decrypt_if_needed(manifest, data, args.identity.as_deref()))
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_slice::<RawAny>(&data).map_err(|e| e.to_string()));
+    match parsed {
+        Err(e) => report.fail(format!("{} doesn't parse: {e}", manifest.display())),
+        Ok(raw) => {
+            let (version, root, entries, special, checksum) = match raw {
+                RawAny::Current(m) => (m.version, m.root, m.files.0, m.special, m.checksum),
+                RawAny::Legacy(files) => (0, None, files.0, BTreeMap::new(), None),
+            };
+            report.ok(format!(
+                "{} parses, format version {version}, {} entries",
+                manifest.display(),
+                entries.len()
+            ));
+            if version == 0 {
+                report.warn("manifest predates the header, the root is inferred from its paths");
+            }
+
+            check_checksum(&mut report, &entries, &special, checksum.as_deref());
+
+            let root = root.or_else(|| common_root(entries.iter().map(|(p, _)| p.as_str())));
+            check_entries(&mut report, &entries, root.as_deref());
+
+            info!("\n=== {} ===", "SCAN ROOT".bold().underline());
+            match &root {
+                Some(root) => check_root(&mut report, root, args.dir.as_deref()),
+                None => report.warn("no root recorded and none could be inferred"),
+            }
+        }
+    }
+
+    info!("\n=== {} ===", "PERMISSIONS".bold().underline());
+    check_writable(&mut report, manifest);
+
+    info!("\n=== {} ===", "CPU".bold().underline());
+    let (features, simd) = cpu_features();
+    report.ok(format!(
+        "BLAKE3 will use {simd} (detected: {})",
+        if features.is_empty() {
+            "none".to_string()
+        } else {
+            features.join(", ")
+        }
+    ));
+
+    info!(
+        event = "doctor",
+        problems = report.problems,
+        warnings = report.warnings;
+        "\n{} problem(s), {} warning(s)",
+        report.problems,
+        report.warnings
+    );
+
+    if report.problems > 0 { 1 } else { 0 }
+}
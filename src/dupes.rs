@@ -0,0 +1,578 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use indicatif::HumanBytes;
+use log::info;
+
+use crate::{
+    cli::{DupesArgs, KeepRule, ScriptAction},
+    extents::Kept,
+    hash::{Digest, FileMeta},
+    manifest::{Manifest, Totals, path_key},
+    store, style,
+};
+
+// Files with the same `hash` are copies of one another whatever they're
+// called. `checkyoself dupes` lists the biggest groups of them, what keeping
+// a single copy of each would free, and where the redundant copies are, to
+// make a case for cleaning up. The copy that would be kept is the first by
+// path unless --keep or --prefer say otherwise, the rest count as
+// redundant. Empty files aren't copies of anything.
+// Copies that are hard links or reflinks of the kept one, where the files
+// are there to look at, are on disk once already and free nothing.
+//
+// With --against it compares two manifests, say a laptop's and the NAS it's
+// backed up to: a file with the same contents on the other side, under
+// whatever path, is safe to delete here, a file without is only here.
+//
+// --emit-script writes the cleanup out as a shell script for a person to
+// read before running it, rather than doing it. Each line compares the
+// copy with the kept one first, a file that changed since the manifest was
+// made is left alone.
+
+/// Files with the same contents.
+struct Group<'a> {
+    size: u64,
+    /// The one kept first, then the copies by path
+    paths: Vec<&'a str>,
+    /// Of each copy, the bytes it shares with the kept one on disk
+    shared: Vec<u64>,
+}
+
+impl Group<'_> {
+    /// What removing all copies but one would free.
+    fn reclaimable(&self) -> u64 {
+        self.size * (self.paths.len() as u64 - 1) - self.shared()
+    }
+
+    /// What the filesystem has saved already.
+    fn shared(&self) -> u64 {
+        self.shared.iter().sum()
+    }
+
+    /// Looks at how much of each copy the filesystem already shares with
+    /// the kept one.
+    fn find_shared(&mut self) {
+        let kept = Kept::new(Path::new(self.paths[0]));
+        self.shared = self
+            .paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| match i {
+                0 => 0,
+                _ => kept.shared_with(Path::new(path), self.size),
+            })
+            .collect();
+    }
+}
+
+/// Which copy of a group is kept.
+struct Keep {
+    rule: KeepRule,
+    /// Manifest key of --prefer
+    prefer: Option<String>,
+}
+
+impl Keep {
+    fn new(args: &DupesArgs) -> Self {
+        Keep {
+            rule: args.keep,
+            prefer: args.prefer.as_deref().map(path_key),
+        }
+    }
+
+    /// Puts the copy to keep of `paths`, sorted, first.
+    fn order(&self, paths: &mut [&str], files: &HashMap<String, FileMeta>) {
+        let preferred = |path: &str| {
+            self.prefer
+                .as_ref()
+                .is_some_and(|dir| Path::new(path).starts_with(dir))
+        };
+        let kept = (0..paths.len())
+            .min_by_key(|&i| {
+                let path = paths[i];
+                let rule = match self.rule {
+                    KeepRule::First => 0,
+                    KeepRule::Oldest => files[path].modified,
+                    KeepRule::Shortest => path.chars().count() as u64,
+                };
+                (!preferred(path), rule, i)
+            })
+            .unwrap_or(0);
+        paths[..=kept].rotate_right(1);
+    }
+}
+
+/// Groups the entries of `files` that have the same contents, the most
+/// reclaimable first.
+fn groups<'a>(files: &'a HashMap<String, FileMeta>, keep: &Keep) -> Vec<Group<'a>> {
+    let mut by_hash: HashMap<Digest, Group> = HashMap::new();
+    for (path, meta) in files.iter().filter(|(_, meta)| meta.size > 0) {
+        by_hash
+            .entry(meta.hash)
+            .or_insert_with(|| Group {
+                size: meta.size as u64,
+                paths: Vec::new(),
+                shared: Vec::new(),
+            })
+            .paths
+            .push(path);
+    }
+    let mut groups: Vec<Group> = by_hash
+        .into_values()
+        .filter(|g| g.paths.len() > 1)
+        .map(|mut g| {
+            g.paths.sort_unstable();
+            keep.order(&mut g.paths, files);
+            g.find_shared();
+            g
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.reclaimable()
+            .cmp(&a.reclaimable())
+            .then(a.paths.cmp(&b.paths))
+    });
+    groups
+}
+
+/// The directory of `path` relative to `root`, cut to `depth` levels.
+fn dir_of(root: &Path, path: &str, depth: usize) -> String {
+    let path = Path::new(path);
+    let dir: PathBuf = path
+        .strip_prefix(root)
+        .unwrap_or(path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .components()
+        .take(depth)
+        .collect();
+    if dir.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        dir.to_string_lossy().to_string()
+    }
+}
+
+fn list(paths: &[&str], shown: usize) -> String {
+    let mut list = paths
+        .iter()
+        .take(shown)
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ");
+    if paths.len() > shown {
+        list.push_str(&format!(" and {} more", paths.len() - shown));
+    }
+    list
+}
+
+/// What the filesystem has already saved, if anything, to go after the
+/// reclaimable bytes.
+fn shared_note(shared: u64) -> String {
+    if shared == 0 {
+        String::new()
+    } else {
+        format!(", {} already shared on disk", HumanBytes(shared))
+    }
+}
+
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / whole as f64
+    }
+}
+
+fn load(path: &Path, args: &DupesArgs) -> io::Result<Manifest> {
+    let data = fs::read(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    store::decode(path, data, args.identity.as_deref())
+}
+
+/// The files of `here` with their contents nowhere in `there`, biggest
+/// first, and the totals of those that have.
+fn only_in<'a>(
+    here: &'a HashMap<String, FileMeta>,
+    there: &HashMap<String, FileMeta>,
+) -> (Vec<(&'a str, &'a FileMeta)>, Totals) {
+    let hashes: HashSet<Digest> = there.values().map(|m| m.hash).collect();
+    let mut copied = Totals::default();
+    let mut only: Vec<(&str, &FileMeta)> = Vec::new();
+    for (path, meta) in here.iter().filter(|(_, meta)| meta.size > 0) {
+        if hashes.contains(&meta.hash) {
+            copied.add(meta);
+        } else {
+            only.push((path, meta));
+        }
+    }
+    only.sort_by(|(a, a_meta), (b, b_meta)| b_meta.size.cmp(&a_meta.size).then(a.cmp(b)));
+    (only, copied)
+}
+
+fn compare(args: &DupesArgs, other: &Path) -> io::Result<()> {
+    let here = load(&args.manifest, args)?;
+    let there = load(other, args)?;
+    let root = PathBuf::from(here.root.clone().unwrap_or_default());
+    let (only_here, copied) = only_in(&here.files, &there.files);
+    let (only_there, _) = only_in(&there.files, &here.files);
+
+    if !only_here.is_empty() {
+        info!(
+            "\n=== {} ===",
+            format!("ONLY IN {}", args.manifest.display())
+                .bold()
+                .underline()
+        );
+    }
+    for (path, meta) in only_here.iter().take(args.top) {
+        info!(
+            event = "only_here",
+            path = *path,
+            bytes = meta.size;
+            "{} {} ({})",
+            style::pick("⚠️ ONLY HERE", "ONLY HERE").yellow(),
+            path,
+            HumanBytes(meta.size.max(0) as u64)
+        );
+    }
+    if only_here.len() > args.top {
+        info!("... and {} more", only_here.len() - args.top);
+    }
+
+    // Directories with nothing only here can go as a whole
+    let mut dirs: HashMap<String, (usize, usize)> = HashMap::new();
+    for path in here
+        .files
+        .iter()
+        .filter(|(_, meta)| meta.size > 0)
+        .map(|(path, _)| path)
+    {
+        dirs.entry(dir_of(&root, path, args.depth)).or_default().0 += 1;
+    }
+    for (path, _) in &only_here {
+        if let Some(dir) = dirs.get_mut(&dir_of(&root, path, args.depth)) {
+            dir.1 += 1;
+        }
+    }
+    let mut dirs: Vec<_> = dirs.into_iter().collect();
+    dirs.sort_by(|(a, (_, a_only)), (b, (_, b_only))| b_only.cmp(a_only).then(a.cmp(b)));
+    if !dirs.is_empty() {
+        info!("\n=== {} ===", "BY DIRECTORY".bold().underline());
+    }
+    for (dir, (files, only)) in dirs.iter().take(args.top) {
+        if *only == 0 {
+            info!(
+                event = "against_dir",
+                dir = dir.as_str(),
+                files = *files,
+                only_here = 0;
+                "{}: {} all {} files have a copy",
+                dir.bold(),
+                style::pick("✅", "OK").green(),
+                files
+            );
+        } else {
+            info!(
+                event = "against_dir",
+                dir = dir.as_str(),
+                files = *files,
+                only_here = *only;
+                "{}: {} of {} files only here",
+                dir.bold(),
+                only,
+                files
+            );
+        }
+    }
+
+    let only_bytes = |only: &[(&str, &FileMeta)]| -> u64 {
+        only.iter().map(|(_, meta)| meta.size.max(0) as u64).sum()
+    };
+    info!(
+        event = "against_summary",
+        copied = copied.files,
+        copied_bytes = copied.bytes,
+        only_here = only_here.len(),
+        only_here_bytes = only_bytes(&only_here),
+        only_there = only_there.len(),
+        only_there_bytes = only_bytes(&only_there);
+        "{} {} files ({}) have a copy in {}, safe to delete here; {} ({}) are only here, {} ({}) only there",
+        style::pick("🔁", "AGAINST").bold(),
+        copied.files,
+        HumanBytes(copied.bytes),
+        other.display(),
+        only_here.len(),
+        HumanBytes(only_bytes(&only_here)),
+        only_there.len(),
+        HumanBytes(only_bytes(&only_there))
+    );
+    Ok(())
+}
+
+/// `path` quoted for sh.
+pub fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
+
+/// `text` to go in a sh comment, line breaks and other control characters
+/// escaped so it can't end the comment.
+pub fn comment(text: &str) -> String {
+    text.chars()
+        .map(|c| match c.is_control() {
+            true => c.escape_default().to_string(),
+            false => c.to_string(),
+        })
+        .collect()
+}
+
+/// The --emit-script script, or list with --null, for `groups`.
+fn script(args: &DupesArgs, groups: &[Group]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if args.null {
+        for path in groups.iter().flat_map(|g| &g.paths[1..]) {
+            out.extend_from_slice(path.as_bytes());
+            out.push(0);
+        }
+        return out;
+    }
+    let (keeps, does) = (
+        match args.keep {
+            KeepRule::First => "the first by path",
+            KeepRule::Oldest => "the oldest",
+            KeepRule::Shortest => "the shortest path",
+        },
+        match args.action {
+            ScriptAction::Delete => "removed",
+            ScriptAction::Hardlink => "hard linked to it",
+            ScriptAction::Reflink => "reflinked to it",
+        },
+    );
+    let prefer = match &args.prefer {
+        Some(dir) => format!(", or one under {}", comment(&quote(&dir.to_string_lossy()))),
+        None => String::new(),
+    };
+    let copies: usize = groups.iter().map(|g| g.paths.len() - 1).sum();
+    let reclaimable: u64 = groups.iter().map(Group::reclaimable).sum();
+    let _ = writeln!(
+        out,
+        "#!/bin/sh\n\
+         # checkyoself dupes --emit-script, from {manifest}\n\
+         # Kept of each group of copies: {keeps}{prefer}\n\
+         # The other {copies} ({bytes}): {does}\n\
+         # Paths are as the manifest has them, run it from where that was made.\n\
+         # A copy that doesn't match the kept one anymore is left alone.\n\
+         set -u",
+        manifest = comment(&quote(&args.manifest.to_string_lossy())),
+        bytes = HumanBytes(reclaimable),
+    );
+    for group in groups {
+        let kept = quote(group.paths[0]);
+        let _ = writeln!(
+            out,
+            "\n# {} copies of {}, keeping {}",
+            group.paths.len(),
+            HumanBytes(group.size),
+            comment(&kept)
+        );
+        for (path, shared) in group.paths.iter().zip(&group.shared).skip(1) {
+            let copy = quote(path);
+            if args.action != ScriptAction::Delete && *shared >= group.size {
+                let _ = writeln!(out, "# {} is on disk once with it already", comment(&copy));
+                continue;
+            }
+            let then = match args.action {
+                ScriptAction::Delete => format!("rm -f -- {copy}"),
+                ScriptAction::Hardlink => format!("ln -f -- {kept} {copy}"),
+                ScriptAction::Reflink => format!("cp --reflink=always -- {kept} {copy}"),
+            };
+            let _ = writeln!(
+                out,
+                "cmp -s -- {kept} {copy} && {then} || echo 'left alone:' {copy} >&2"
+            );
+        }
+    }
+    out
+}
+
+/// Writes the script for `groups` to `out`, `-` for stdout.
+fn emit(args: &DupesArgs, groups: &[Group], out: &Path) -> io::Result<()> {
+    let script = script(args, groups);
+    if out == Path::new("-") {
+        return io::stdout().write_all(&script);
+    }
+    fs::write(out, script)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", out.display(), e)))?;
+    #[cfg(unix)]
+    if !args.null {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(out, fs::Permissions::from_mode(0o755))?;
+    }
+    info!(
+        event = "dupes_script",
+        path:% = out.display(),
+        copies = groups.iter().map(|g| g.paths.len() - 1).sum::<usize>();
+        "\n{} Wrote {} for review: {}",
+        style::pick("📝", "*"),
+        if args.null { "the redundant copies" } else { "a cleanup script" },
+        out.display()
+    );
+    Ok(())
+}
+
+fn report(args: &DupesArgs) -> io::Result<()> {
+    if let Some(other) = &args.against {
+        return compare(args, other);
+    }
+    let manifest = load(&args.manifest, args)?;
+    let root = PathBuf::from(manifest.root.clone().unwrap_or_default());
+
+    let groups = groups(&manifest.files, &Keep::new(args));
+    // A script on stdout is all there is to it
+    if let Some(out) = args
+        .emit_script
+        .as_deref()
+        .filter(|out| *out == Path::new("-"))
+    {
+        return emit(args, &groups, out);
+    }
+    for group in groups.iter().take(args.top) {
+        info!(
+            event = "dupes_group",
+            files = group.paths.len(),
+            bytes = group.size,
+            reclaimable_bytes = group.reclaimable(),
+            shared_bytes = group.shared();
+            "{} {} copies of {} ({} reclaimable{}): {}",
+            style::pick("📑", "GROUP").bold(),
+            group.paths.len(),
+            HumanBytes(group.size),
+            HumanBytes(group.reclaimable()),
+            shared_note(group.shared()),
+            list(&group.paths, 5)
+        );
+    }
+
+    // Where the redundant copies are
+    let mut dirs: HashMap<String, (usize, u64)> = HashMap::new();
+    for group in &groups {
+        for (path, shared) in group.paths.iter().zip(&group.shared).skip(1) {
+            let dir = dirs.entry(dir_of(&root, path, args.depth)).or_default();
+            dir.0 += 1;
+            dir.1 += group.size - shared;
+        }
+    }
+    let mut dirs: Vec<_> = dirs.into_iter().collect();
+    dirs.sort_by(|(a, (_, a_bytes)), (b, (_, b_bytes))| b_bytes.cmp(a_bytes).then(a.cmp(b)));
+    if !dirs.is_empty() {
+        info!("\n=== {} ===", "BY DIRECTORY".bold().underline());
+    }
+    for (dir, (files, bytes)) in dirs.iter().take(args.top) {
+        info!(
+            event = "dupes_dir",
+            dir = dir.as_str(),
+            files = *files,
+            reclaimable_bytes = *bytes;
+            "{}: {} redundant copies, {}",
+            dir.bold(),
+            files,
+            HumanBytes(*bytes)
+        );
+    }
+
+    let total: u64 = manifest.files.values().map(|m| m.size.max(0) as u64).sum();
+    let reclaimable: u64 = groups.iter().map(Group::reclaimable).sum();
+    let shared: u64 = groups.iter().map(Group::shared).sum();
+    let copies: usize = groups.iter().map(|g| g.paths.len()).sum();
+    info!(
+        event = "dupes_summary",
+        files = manifest.files.len(),
+        groups = groups.len(),
+        copies = copies,
+        bytes = total,
+        reclaimable_bytes = reclaimable,
+        shared_bytes = shared;
+        "{} {} files, {}; {} are copies in {} groups, keeping one of each would free {} ({:.0}%){}",
+        style::pick("📦", "DUPES").bold(),
+        manifest.files.len(),
+        HumanBytes(total),
+        copies,
+        groups.len(),
+        HumanBytes(reclaimable),
+        percent(reclaimable, total),
+        shared_note(shared)
+    );
+    match &args.emit_script {
+        Some(out) => emit(args, &groups, out),
+        None => Ok(()),
+    }
+}
+
+/// `dupes`: exit code 0, or 1 if the manifest can't be read.
+pub fn run(args: &DupesArgs) -> i32 {
+    match report(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(action: ScriptAction) -> DupesArgs {
+        DupesArgs {
+            manifest: PathBuf::from("ref.json"),
+            identity: None,
+            top: 10,
+            depth: 1,
+            against: None,
+            keep: KeepRule::First,
+            prefer: None,
+            emit_script: Some(PathBuf::from("-")),
+            action,
+            null: false,
+        }
+    }
+
+    #[test]
+    fn script_comments_keep_paths_in_them() {
+        let kept = "kept\necho PWNED #";
+        let copy = "copy\necho PWNED #";
+        let groups = [
+            Group {
+                size: 10,
+                paths: vec![kept, "plain"],
+                shared: vec![0, 0],
+            },
+            // Shares its blocks already, only gets a comment
+            Group {
+                size: 10,
+                paths: vec!["other", copy],
+                shared: vec![0, 10],
+            },
+        ];
+        for action in [ScriptAction::Delete, ScriptAction::Hardlink] {
+            let script = String::from_utf8(script(&args(action), &groups)).unwrap();
+            assert!(script.contains(r"keeping 'kept\necho PWNED #'"), "{script}");
+            // Command lines may carry the newline inside their quotes, a
+            // comment can't end before its line does
+            let lines: Vec<&str> = script.lines().collect();
+            assert!(
+                !lines
+                    .windows(2)
+                    .any(|w| w[0].starts_with('#') && w[1].starts_with("echo PWNED")),
+                "{script}"
+            );
+        }
+    }
+}
@@ -0,0 +1,60 @@
+// --entropy records how random a file's contents look, as the Shannon
+// entropy of its bytes: 0 bits per byte for one byte over and over, 8 for
+// bytes that are all equally likely. Text and most documents sit well
+// below 8, compressed formats (zip, JPEG, video) just under, and encrypted
+// data is as good as 8. A file that changed and went from well below to
+// near 8 was most likely encrypted, or overwritten with garbage, rather
+// than edited. Small files can't show it: n bytes reach log2(n) bits per
+// byte at most, 7 for 128 of them.
+
+/// Bits per byte from which contents count as near random.
+pub const NEAR_RANDOM: f32 = 7.9;
+
+/// How often each byte value turned up.
+pub struct Histogram {
+    counts: Box<[u64; 256]>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            counts: Box::new([0; 256]),
+            total: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.counts[byte as usize] += 1;
+        }
+        self.total += data.len() as u64;
+    }
+
+    /// The entropy in bits per byte, to three decimals.
+    pub fn bits(&self) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        let total = self.total as f64;
+        let bits: f64 = self
+            .counts
+            .iter()
+            .filter(|&&n| n > 0)
+            .map(|&n| {
+                let p = n as f64 / total;
+                -p * p.log2()
+            })
+            .sum();
+        ((bits * 1000.0).round() / 1000.0) as f32
+    }
+}
+
+/// Whether contents that had entropy `was` (if recorded) and now have
+/// `now` became near random.
+pub fn jumped(was: Option<f32>, now: Option<f32>) -> bool {
+    match now {
+        Some(now) if now >= NEAR_RANDOM => was.is_none_or(|was| was < NEAR_RANDOM),
+        _ => false,
+    }
+}
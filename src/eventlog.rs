@@ -0,0 +1,150 @@
+use std::io;
+
+use log::Level;
+
+// With --event-log, findings, warnings and summaries also go to the Windows
+// Event Log (Application, source `checkyoself`), where enterprise
+// monitoring already looks, for runs as a scheduled task or service with no
+// console anyone reads. Each event's severity follows the record's level and
+// its ID says what kind of record it is, for filters to key on.
+
+/// The source events are reported under.
+#[cfg(windows)]
+const SOURCE: &str = "checkyoself";
+
+/// Event IDs: a run's summary, a warning, and an error (a mismatch, a
+/// missing file, a file that couldn't be read).
+pub const SUMMARY_ID: u32 = 1;
+pub const WARNING_ID: u32 = 2;
+pub const ERROR_ID: u32 = 3;
+
+/// Whether a record goes to the event log: everything from warnings up, and
+/// the summaries (`event` ending in `summary`).
+pub fn wanted(level: Level, event: Option<&str>) -> bool {
+    level <= Level::Warn || event.is_some_and(|e| e.ends_with("summary"))
+}
+
+/// The event ID of a record at `level`.
+pub fn id_for(level: Level) -> u32 {
+    match level {
+        Level::Error => ERROR_ID,
+        Level::Warn => WARNING_ID,
+        _ => SUMMARY_ID,
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::{ffi::c_void, io, ptr};
+
+    use log::Level;
+
+    const EVENTLOG_ERROR_TYPE: u16 = 0x0001;
+    const EVENTLOG_WARNING_TYPE: u16 = 0x0002;
+    const EVENTLOG_INFORMATION_TYPE: u16 = 0x0004;
+    /// ReportEventW takes at most 31839 characters per string
+    const MAX_CHARS: usize = 31_839;
+
+    #[link(name = "advapi32")]
+    unsafe extern "system" {
+        fn RegisterEventSourceW(server_name: *const u16, source_name: *const u16) -> isize;
+        fn ReportEventW(
+            event_log: isize,
+            kind: u16,
+            category: u16,
+            event_id: u32,
+            user_sid: *mut c_void,
+            num_strings: u16,
+            data_size: u32,
+            strings: *const *const u16,
+            raw_data: *mut c_void,
+        ) -> i32;
+        fn DeregisterEventSource(event_log: isize) -> i32;
+    }
+
+    pub struct Handle(isize);
+
+    impl Handle {
+        pub fn open(source: &str) -> io::Result<Handle> {
+            let wide: Vec<u16> = source.encode_utf16().chain([0]).collect();
+            // SAFETY: `wide` is NUL terminated, a null server is this machine
+            let handle = unsafe { RegisterEventSourceW(ptr::null(), wide.as_ptr()) };
+            if handle == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Handle(handle))
+        }
+
+        pub fn report(&self, level: Level, id: u32, msg: &str) -> io::Result<()> {
+            let kind = match level {
+                Level::Error => EVENTLOG_ERROR_TYPE,
+                Level::Warn => EVENTLOG_WARNING_TYPE,
+                _ => EVENTLOG_INFORMATION_TYPE,
+            };
+            let wide: Vec<u16> = msg.encode_utf16().take(MAX_CHARS).chain([0]).collect();
+            let strings = [wide.as_ptr()];
+            // SAFETY: the handle is open, `strings` holds one NUL terminated
+            // string and there's no raw data
+            let ok = unsafe {
+                ReportEventW(
+                    self.0,
+                    kind,
+                    0,
+                    id,
+                    ptr::null_mut(),
+                    1,
+                    0,
+                    strings.as_ptr(),
+                    ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            // SAFETY: the handle is open and not used after this
+            unsafe { DeregisterEventSource(self.0) };
+        }
+    }
+}
+
+/// The Windows Event Log, opened for `SOURCE`.
+pub struct EventLog {
+    #[cfg(windows)]
+    handle: imp::Handle,
+}
+
+impl EventLog {
+    #[cfg(windows)]
+    pub fn open() -> io::Result<EventLog> {
+        Ok(EventLog {
+            handle: imp::Handle::open(SOURCE)?,
+        })
+    }
+
+    #[cfg(not(windows))]
+    pub fn open() -> io::Result<EventLog> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--event-log is only supported on Windows",
+        ))
+    }
+
+    /// Reports `msg`, a record at `level`, with the severity and event ID
+    /// that go with it.
+    #[cfg(windows)]
+    pub fn report(&self, level: Level, msg: &str) -> io::Result<()> {
+        self.handle.report(level, id_for(level), msg)
+    }
+
+    #[cfg(not(windows))]
+    pub fn report(&self, level: Level, msg: &str) -> io::Result<()> {
+        let _ = (level, msg, id_for(level));
+        Ok(())
+    }
+}
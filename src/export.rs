@@ -0,0 +1,170 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use serde_json::{Map, Value as Json};
+
+use crate::{
+    cli::ExportArgs,
+    store::{self, with_path},
+    tags,
+};
+
+// `export` turns a manifest, or the findings of a verify run logged with
+// --log-format json (or to a JSON --log-file), into a table for a
+// spreadsheet: a row per file, with its path, size, status and times.
+
+/// What `export` writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    /// Comma separated values, quoted where needed
+    Csv,
+}
+
+/// `secs` since the epoch as `2024-05-01 12:00:00`, in UTC, which
+/// spreadsheets read as a date.
+fn time(secs: u64) -> String {
+    let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs));
+    time.to_string()
+        .replace('T', " ")
+        .trim_end_matches('Z')
+        .to_string()
+}
+
+/// Writes one CSV row, quoting fields with commas, quotes or line breaks.
+/// A field a spreadsheet would take for a formula (a path or message
+/// starting with `=`, `+`, `-`, `@`, a tab or a carriage return) gets a `'`
+/// in front to stay text.
+fn row<W: Write>(out: &mut W, fields: &[&str]) -> io::Result<()> {
+    let line: Vec<String> = fields
+        .iter()
+        .map(|f| {
+            let f = match f.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+                true => format!("'{f}"),
+                false => f.to_string(),
+            };
+            if f.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", f.replace('"', "\"\""))
+            } else {
+                f
+            }
+        })
+        .collect();
+    // RFC 4180 line ends, which Excel expects
+    write!(out, "{}\r\n", line.join(","))
+}
+
+/// The records of a JSON lines report, or None if `text` isn't one.
+fn records(text: &str) -> Option<Vec<Map<String, Json>>> {
+    let records: Vec<Map<String, Json>> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).ok())
+        .collect::<Option<_>>()?;
+    let is_report = records
+        .first()
+        .is_some_and(|r| r.contains_key("level") && r.contains_key("msg"));
+    is_report.then_some(records)
+}
+
+/// A row per file finding of a report: when, how bad, what (`mismatch`,
+/// `missing`, ...), the path, the hashes compared and the message.
+fn export_report<W: Write>(out: &mut W, records: &[Map<String, Json>]) -> io::Result<()> {
+    let field = |record: &Map<String, Json>, key: &str| match record.get(key) {
+        Some(Json::String(s)) => s.clone(),
+        Some(Json::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    };
+    row(
+        out,
+        &[
+            "time", "level", "status", "path", "expected", "found", "tags", "note", "message",
+        ],
+    )?;
+    // Summaries and the like aren't about a file
+    for record in records.iter().filter(|r| r.contains_key("path")) {
+        let ts = record
+            .get("ts")
+            .and_then(Json::as_f64)
+            .map_or(String::new(), |ts| time(ts as u64));
+        row(
+            out,
+            &[
+                &ts,
+                &field(record, "level"),
+                &field(record, "event"),
+                &field(record, "path"),
+                &field(record, "expected"),
+                &field(record, "found"),
+                &field(record, "tags"),
+                &field(record, "note"),
+                &field(record, "msg"),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn export(args: &ExportArgs) -> io::Result<()> {
+    let data = fs::read(&args.input).map_err(|e| with_path(&args.input, e))?;
+    let (report, manifest) = match std::str::from_utf8(&data).ok().and_then(records) {
+        Some(records) => (Some(records), None),
+        None => {
+            let manifest = store::decode(&args.input, data, args.identity.as_deref())?;
+            (None, Some(manifest))
+        }
+    };
+
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path).map_err(|e| with_path(path, e))?),
+        None => Box::new(io::stdout().lock()),
+    };
+    let mut out = BufWriter::new(out);
+    if args.bom {
+        out.write_all("\u{feff}".as_bytes())?;
+    }
+    match args.format {
+        ExportFormat::Csv => {
+            if let Some(records) = report {
+                export_report(&mut out, &records)?;
+            }
+            if let Some(manifest) = manifest {
+                let mut files: Vec<_> = manifest.files.iter().collect();
+                files.sort_unstable_by_key(|(path, _)| *path);
+                row(
+                    &mut out,
+                    &["path", "size", "modified", "blake3", "md5", "tags", "note"],
+                )?;
+                for (path, meta) in files {
+                    row(
+                        &mut out,
+                        &[
+                            path,
+                            &meta.size.to_string(),
+                            &time(meta.modified),
+                            &meta.hash.to_hex(),
+                            meta.md5.as_deref().unwrap_or_default(),
+                            &tags::joined(meta).unwrap_or_default(),
+                            meta.note.as_deref().unwrap_or_default(),
+                        ],
+                    )?;
+                }
+            }
+        }
+    }
+    out.flush()
+}
+
+/// `export`: exit code 0, or 1 on errors.
+pub fn run(args: &ExportArgs) -> i32 {
+    match export(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{io, path::Path};
+
+// Copies on btrfs and XFS made with `cp --reflink` (or deduplicated after
+// the fact by duperemove and friends) share their extents: the data is on
+// disk once, removing a copy frees next to nothing. FIEMAP tells where on
+// the device a file's extents are, and which are shared, so `dupes` can
+// leave out what the filesystem has already saved. Hard links share
+// everything and are told by their inode, anywhere on Unix.
+
+/// A range of the device, `(physical offset, length)` in bytes.
+type Extent = (u64, u64);
+
+/// The extents of the file at `path` that it shares with other files,
+/// sorted by offset.
+#[cfg(target_os = "linux")]
+fn shared_extents(path: &Path) -> io::Result<Vec<Extent>> {
+    use std::{
+        ffi::{c_int, c_ulong},
+        fs::File,
+        os::fd::AsRawFd,
+    };
+
+    // _IOWR('f', 11, struct fiemap)
+    const FS_IOC_FIEMAP: c_ulong = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+    const FIEMAP_EXTENT_LAST: u32 = 0x1;
+    // Where the data is isn't known, or isn't in an extent of its own
+    const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+    const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+    const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+    const BATCH: usize = 128;
+
+    #[repr(C)]
+    #[derive(Clone, Copy, Default)]
+    struct FiemapExtent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [FiemapExtent; BATCH],
+    }
+
+    unsafe extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+
+    let file = File::open(path)?;
+    let mut shared = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: BATCH as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); BATCH],
+        };
+        // SAFETY: `map` is a struct fiemap with room for `extent_count`
+        // extents, it outlives the call
+        if unsafe { ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut map as *mut Fiemap) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mapped = &map.extents[..(map.mapped_extents as usize).min(BATCH)];
+        for extent in mapped {
+            if extent.flags & FIEMAP_EXTENT_SHARED != 0
+                && extent.flags & (FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DATA_INLINE) == 0
+            {
+                shared.push((extent.physical, extent.length));
+            }
+        }
+        match mapped.last() {
+            Some(last) if last.flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = last.logical + last.length;
+            }
+            _ => break,
+        }
+    }
+    shared.sort_unstable();
+    Ok(shared)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared_extents(_path: &Path) -> io::Result<Vec<Extent>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "extents can only be looked at on Linux",
+    ))
+}
+
+/// How many bytes of `extents` are also in `of`, both sorted.
+fn overlap(extents: &[Extent], of: &[Extent]) -> u64 {
+    let mut bytes = 0;
+    for &(start, len) in extents {
+        let end = start + len;
+        // The first that could reach into it
+        let from = of.partition_point(|&(s, l)| s + l <= start);
+        for &(s, l) in of[from..].iter().take_while(|&&(s, _)| s < end) {
+            bytes += end.min(s + l) - start.max(s);
+        }
+    }
+    bytes
+}
+
+#[cfg(unix)]
+fn same_inode(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_inode(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// The files on disk that a group of copies was recorded from, compared
+/// with one of them, `kept`.
+pub struct Kept<'a> {
+    path: &'a Path,
+    /// None if its extents can't be looked at
+    extents: Option<Vec<Extent>>,
+}
+
+impl<'a> Kept<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        Kept {
+            path,
+            extents: shared_extents(path).ok(),
+        }
+    }
+
+    /// How many bytes of the copy at `path` are on disk once already, with
+    /// the kept file: all of them for a hard link, its extents shared with
+    /// the kept file for a reflinked copy. 0 if it can't be told.
+    pub fn shared_with(&self, path: &Path, size: u64) -> u64 {
+        if same_inode(self.path, path) {
+            return size;
+        }
+        let Some(kept) = self.extents.as_deref().filter(|e| !e.is_empty()) else {
+            return 0;
+        };
+        match shared_extents(path) {
+            Ok(extents) => overlap(&extents, kept).min(size),
+            Err(_) => 0,
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{io, path::Path};
+
+use colored::*;
+use globset::{GlobBuilder, GlobMatcher};
+use indicatif::HumanBytes;
+use log::{error, info};
+
+use crate::{
+    catalog,
+    cli::FindArgs,
+    hash::{Digest, HashOptions, calculate_blake3},
+    style,
+};
+
+// "Which backup drive has this exact file?" Searches every manifest of a
+// catalog by path or by contents, and names the drives.
+
+enum Query {
+    /// A pattern with a `/` goes by the path on the drive, one without by
+    /// the file name
+    Glob {
+        matcher: GlobMatcher,
+        by_name: bool,
+    },
+    Hash(Digest),
+}
+
+impl Query {
+    fn new(args: &FindArgs) -> io::Result<Query> {
+        if let Some(file) = &args.file {
+            let (meta, _) = calculate_blake3(file, &HashOptions::default())
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", file.display())))?;
+            return Ok(Query::Hash(meta.hash));
+        }
+        let pattern = args.pattern.as_deref().unwrap_or_default();
+        if pattern.len() == 64 && pattern.bytes().all(|b| b.is_ascii_hexdigit()) {
+            let hash = pattern.to_lowercase().parse().expect("64 hex digits");
+            return Ok(Query::Hash(hash));
+        }
+        // `*` stays within a directory, `**` crosses them
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{pattern}: {e}")))?;
+        Ok(Query::Glob {
+            matcher: glob.compile_matcher(),
+            by_name: !pattern.contains('/'),
+        })
+    }
+
+    /// Whether the file at `path` (on the drive) with BLAKE3 `hash` is a
+    /// match.
+    fn matches(&self, path: &str, hash: &Digest) -> bool {
+        match self {
+            Query::Hash(wanted) => hash == wanted,
+            Query::Glob {
+                matcher,
+                by_name: true,
+            } => matcher.is_match(path.rsplit('/').next().unwrap_or(path)),
+            Query::Glob {
+                matcher,
+                by_name: false,
+            } => matcher.is_match(path),
+        }
+    }
+}
+
+/// `find`: exit code 0, or 1 if the query is bad or a manifest can't be
+/// read.
+pub fn run(args: &FindArgs) -> i32 {
+    let query = match Query::new(args) {
+        Ok(query) => query,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+    let entries = match catalog::entries(&args.catalog) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+
+    let mut code = 0;
+    let (mut found, mut drives) = (0, 0);
+    for (name, path) in &entries {
+        let manifest = match catalog::load(path, args.identity.as_deref()) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                code = 1;
+                error!(
+                    event = "drive",
+                    name = name.as_str(),
+                    error:% = e;
+                    "{}  {} {e}",
+                    name.bold(),
+                    style::pick("⚠️", "ERROR").red()
+                );
+                continue;
+            }
+        };
+        let volume = manifest.volume.as_ref().map(|v| v.to_string());
+        let root = manifest.root.clone().unwrap_or_default();
+        let mut matches: Vec<(&str, i64)> = manifest
+            .files
+            .iter()
+            .filter_map(|(path, meta)| {
+                // Paths on the drive, without the catalog name
+                let on_drive = Path::new(path)
+                    .strip_prefix(&root)
+                    .ok()
+                    .and_then(|p| p.to_str())
+                    .unwrap_or(path);
+                query
+                    .matches(on_drive, &meta.hash)
+                    .then_some((on_drive, meta.size))
+            })
+            .collect();
+        if matches.is_empty() {
+            continue;
+        }
+        matches.sort_unstable();
+        drives += 1;
+        found += matches.len();
+        for (on_drive, size) in matches {
+            info!(
+                event = "found",
+                drive = name.as_str(),
+                volume = volume.as_deref(),
+                path = on_drive,
+                size = size;
+                "{} {}  {on_drive} ({})",
+                style::pick("📍", "FOUND").green(),
+                name.bold(),
+                HumanBytes(size.max(0) as u64)
+            );
+        }
+    }
+
+    info!(
+        event = "find_summary",
+        found = found,
+        drives = drives;
+        "{found} match(es) on {drives} of {} drive(s)",
+        entries.len()
+    );
+    code
+}
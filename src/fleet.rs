@@ -0,0 +1,523 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use colored::*;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::{FleetArgs, ServerArgs},
+    manifest, secrets,
+    store::with_path,
+    style,
+    verify::Summary,
+};
+
+// Every host verifies its own trees, on its own schedule; `--report-to`
+// sends the summary of each run to a `checkyoself server`, which keeps them
+// per host, and `fleet` shows the latest of every host and tree in one
+// place. The server speaks just enough HTTP for curl, which sends the
+// reports: POST /hosts/<host>/reports, GET /hosts/<host>/reports and
+// GET /status. TLS is left to a reverse proxy in front of it.
+
+/// The biggest report the server takes.
+const MAX_BODY: usize = 1 << 20;
+/// The most the request line and headers together may take, and how many
+/// headers there may be; both are read before the token is checked.
+const MAX_HEAD: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 64;
+/// How many connections are handled at once; more are turned away.
+const MAX_CONNECTIONS: usize = 32;
+
+/// One verify run, as sent to the server.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Report {
+    pub host: String,
+    /// The tree that was verified
+    pub dir: String,
+    /// The manifests it was verified against
+    #[serde(default)]
+    pub manifests: Vec<String>,
+    /// When the run ended, in seconds since the epoch
+    pub finished: u64,
+    /// `ok` or `fail`, as the summary line has it
+    pub result: String,
+    pub matched: usize,
+    pub moved: usize,
+    pub mismatched: usize,
+    pub extra: usize,
+    pub missing: usize,
+    pub errors: usize,
+    pub volatile: usize,
+    pub special: usize,
+}
+
+impl Report {
+    pub fn new(
+        host: String,
+        dir: &Path,
+        manifests: &[PathBuf],
+        summary: &Summary,
+        failed: bool,
+    ) -> Self {
+        Report {
+            host,
+            dir: dir.display().to_string(),
+            manifests: manifests.iter().map(|m| m.display().to_string()).collect(),
+            finished: manifest::now_secs(),
+            result: if failed { "fail" } else { "ok" }.to_string(),
+            matched: summary.matched,
+            moved: summary.moved,
+            mismatched: summary.mismatched,
+            extra: summary.extra,
+            missing: summary.missing,
+            errors: summary.errors,
+            volatile: summary.volatile,
+            special: summary.special,
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.result != "ok"
+    }
+}
+
+/// The name this host reports as.
+pub fn hostname() -> String {
+    let name = fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| {
+            let output = Command::new("hostname").output().ok()?;
+            Some(String::from_utf8_lossy(&output.stdout).to_string())
+        })
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .unwrap_or_default();
+    match name.trim() {
+        "" => "unknown".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// A host name the server keeps a directory for.
+fn valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && !host.starts_with('.')
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+}
+
+/// A string in a curl config file.
+fn curl_quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Runs curl with `config` (a curl config file, which keeps the token off
+/// the command line) on stdin. Returns what the server answered.
+fn curl(config: &str) -> io::Result<Vec<u8>> {
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--config", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("can't run curl: {e}")))?;
+    child
+        .stdin
+        .take()
+        .expect("piped stdin")
+        .write_all(config.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(io::Error::other(format!(
+            "curl failed\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+fn auth_config(token: Option<&Path>) -> io::Result<String> {
+    Ok(match token {
+        Some(token) => format!(
+            "header = {}\n",
+            curl_quote(&format!(
+                "Authorization: Bearer {}",
+                secrets::read(token)?.trim()
+            ))
+        ),
+        None => String::new(),
+    })
+}
+
+/// Sends `report` to the server at `url`.
+pub fn push(url: &str, token: Option<&Path>, report: &Report) -> io::Result<()> {
+    let body = serde_json::to_string(report)?;
+    let config = format!(
+        "{}header = \"Content-Type: application/json\"\ndata-binary = {}\nurl = {}\n",
+        auth_config(token)?,
+        curl_quote(&body),
+        curl_quote(&format!(
+            "{}/hosts/{}/reports",
+            url.trim_end_matches('/'),
+            report.host
+        ))
+    );
+    curl(&config).map(|_| ())
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, reason: &'static str, value: &impl Serialize) -> Self {
+        Response {
+            status,
+            reason,
+            body: serde_json::to_string(value).expect("replies always serialize"),
+        }
+    }
+
+    fn error(status: u16, reason: &'static str) -> Self {
+        Response::json(status, reason, &BTreeMap::from([("error", reason)]))
+    }
+}
+
+/// Every report stored for `host`, oldest first.
+fn history(store: &Path, host: &str) -> io::Result<Vec<Report>> {
+    let dir = store.join(host);
+    let mut reports = Vec::new();
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(reports),
+        Err(e) => return Err(with_path(&dir, e)),
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let data = fs::read(&path).map_err(|e| with_path(&path, e))?;
+        match serde_json::from_slice::<Report>(&data) {
+            Ok(report) => reports.push(report),
+            Err(e) => warn!("{}: {e}, left out", path.display()),
+        }
+    }
+    reports.sort_by_key(|r| r.finished);
+    Ok(reports)
+}
+
+/// The latest report of every host and tree in `store`.
+fn status(store: &Path) -> io::Result<Vec<Report>> {
+    let mut latest = BTreeMap::new();
+    for entry in fs::read_dir(store).map_err(|e| with_path(store, e))? {
+        let entry = entry?;
+        let Some(host) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !valid_host(&host) || !entry.path().is_dir() {
+            continue;
+        }
+        for report in history(store, &host)? {
+            latest.insert((host.clone(), report.dir.clone()), report);
+        }
+    }
+    Ok(latest.into_values().collect())
+}
+
+/// Stores `report` as `<store>/<host>/<finished>.json`, or with a `-<n>`
+/// suffix if the host sent another one that second.
+fn save(store: &Path, report: &Report) -> io::Result<()> {
+    let dir = store.join(&report.host);
+    fs::create_dir_all(&dir).map_err(|e| with_path(&dir, e))?;
+    let data = serde_json::to_vec_pretty(report)?;
+    for n in 0.. {
+        let name = match n {
+            0 => format!("{}.json", report.finished),
+            n => format!("{}-{n}.json", report.finished),
+        };
+        let path = dir.join(name);
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => return file.write_all(&data).map_err(|e| with_path(&path, e)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(with_path(&path, e)),
+        }
+    }
+    unreachable!("some suffix is free")
+}
+
+fn route(store: &Path, method: &str, path: &str, body: &[u8]) -> io::Result<Response> {
+    let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, parts.as_slice()) {
+        ("GET", ["status"]) => Ok(Response::json(200, "OK", &status(store)?)),
+        ("GET", ["hosts", host, "reports"]) if valid_host(host) => {
+            Ok(Response::json(200, "OK", &history(store, host)?))
+        }
+        ("POST", ["hosts", host, "reports"]) if valid_host(host) => {
+            let Ok(mut report) = serde_json::from_slice::<Report>(body) else {
+                return Ok(Response::error(400, "Bad Request"));
+            };
+            report.host = host.to_string();
+            save(store, &report)?;
+            let marker = match report.failed() {
+                true => style::pick("❌", "FAIL").red(),
+                false => style::pick("✅", "OK").green(),
+            };
+            info!(
+                event = "received",
+                host = report.host.as_str(),
+                dir = report.dir.as_str(),
+                result = report.result.as_str();
+                "{marker} {} {}",
+                report.host.bold(),
+                report.dir
+            );
+            Ok(Response::json(
+                201,
+                "Created",
+                &BTreeMap::from([("stored", true)]),
+            ))
+        }
+        ("GET" | "POST", _) => Ok(Response::error(404, "Not Found")),
+        _ => Ok(Response::error(405, "Method Not Allowed")),
+    }
+}
+
+/// Whether the Authorization header sent is the one `token` asks for,
+/// compared in constant time (`blake3::Hash` equality is) so the time taken
+/// says nothing about how much of it was right.
+fn authorized(authorization: Option<&str>, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let expected = blake3::hash(format!("Bearer {token}").as_bytes());
+    authorization.is_some_and(|sent| blake3::hash(sent.as_bytes()) == expected)
+}
+
+/// Reads one request off `stream` and answers it.
+fn handle(stream: TcpStream, store: &Path, token: Option<&str>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // Nobody is authenticated yet, the head is read with a cap on it
+    let mut head = reader.by_ref().take(MAX_HEAD);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut complete = line.ends_with('\n');
+    let mut request = line.split_whitespace();
+    let (method, path) = (
+        request.next().unwrap_or_default().to_string(),
+        request.next().unwrap_or_default().to_string(),
+    );
+
+    let (mut length, mut authorization, mut headers) = (0, None, 0);
+    while complete {
+        line.clear();
+        if head.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        headers += 1;
+        complete = line.ends_with('\n') && headers <= MAX_HEADERS;
+        if let Some((name, value)) = line.split_once(':') {
+            match name.trim().to_ascii_lowercase().as_str() {
+                "content-length" => length = value.trim().parse().unwrap_or(usize::MAX),
+                "authorization" => authorization = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    let response = if !complete {
+        Response::error(431, "Request Header Fields Too Large")
+    } else if !authorized(authorization.as_deref(), token) {
+        Response::error(401, "Unauthorized")
+    } else if length > MAX_BODY {
+        Response::error(413, "Payload Too Large")
+    } else {
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        route(store, &method, &path, &body).unwrap_or_else(|e| {
+            warn!(event = "server_error", error:% = e; "{method} {path}: {e}");
+            Response::error(500, "Internal Server Error")
+        })
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.reason,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+fn serve(args: &ServerArgs) -> io::Result<()> {
+    fs::create_dir_all(&args.store).map_err(|e| with_path(&args.store, e))?;
+    let token = args
+        .token
+        .as_deref()
+        .map(|t| secrets::read(t).map(|t| t.trim().to_string()))
+        .transpose()?;
+    let listener = TcpListener::bind(&args.listen)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", args.listen)))?;
+    info!(
+        event = "listening",
+        address = args.listen.as_str();
+        "{} Collecting reports on {} into {}",
+        style::pick("📡", "*").bold(),
+        args.listen,
+        args.store.display()
+    );
+    let store = Arc::new(args.store.clone());
+    let token = Arc::new(token);
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let Ok(stream) = stream else {
+            continue;
+        };
+        if open.fetch_add(1, Ordering::AcqRel) >= MAX_CONNECTIONS {
+            open.fetch_sub(1, Ordering::AcqRel);
+            warn!(
+                event = "server_busy",
+                peer:? = stream.peer_addr().ok();
+                "{MAX_CONNECTIONS} connections open already, turning one away"
+            );
+            continue;
+        }
+        let (store, token, open) = (Arc::clone(&store), Arc::clone(&token), Arc::clone(&open));
+        thread::spawn(move || {
+            if let Err(e) = handle(stream, &store, token.as_deref()) {
+                warn!(event = "server_error", error:% = e; "{e}");
+            }
+            open.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+    Ok(())
+}
+
+/// `server`: runs until killed, exit code 1 if it can't start.
+pub fn server(args: &ServerArgs) -> i32 {
+    match serve(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+/// Reports from a server's store directory or from the server at a URL.
+fn fetch(args: &FleetArgs) -> io::Result<Vec<Report>> {
+    let source = args.source.as_str();
+    if !(source.starts_with("http://") || source.starts_with("https://")) {
+        let store = Path::new(source);
+        return match &args.host {
+            Some(host) => history(store, host),
+            None => status(store),
+        };
+    }
+    let url = match &args.host {
+        Some(host) => format!("{}/hosts/{host}/reports", source.trim_end_matches('/')),
+        None => format!("{}/status", source.trim_end_matches('/')),
+    };
+    let config = format!(
+        "{}url = {}\n",
+        auth_config(args.token.as_deref())?,
+        curl_quote(&url)
+    );
+    serde_json::from_slice(&curl(&config)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{url}: {e}")))
+}
+
+/// `fleet`: exit code 0, 2 if a host's latest run failed or it hasn't
+/// reported in --stale, 1 on errors.
+pub fn fleet(args: &FleetArgs) -> i32 {
+    let mut reports = match fetch(args) {
+        Ok(reports) => reports,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+    if reports.is_empty() {
+        info!("No reports yet");
+        return 0;
+    }
+    // A host's history newest first
+    if args.host.is_some() {
+        reports.reverse();
+    }
+
+    let now = manifest::now_secs();
+    let mut code = 0;
+    for report in &reports {
+        let age = Duration::from_secs(now.saturating_sub(report.finished));
+        let stale = args.host.is_none() && age > args.stale;
+        let marker = if report.failed() {
+            style::pick("❌", "FAIL").red()
+        } else if stale {
+            style::pick("⏰", "STALE").yellow()
+        } else {
+            style::pick("✅", "OK").green()
+        };
+        if report.failed() || stale {
+            code = 2;
+        }
+        let counts = [
+            (report.mismatched, "mismatched"),
+            (report.missing, "missing"),
+            (report.errors, "unreadable"),
+            (report.volatile, "volatile"),
+            (report.special, "special changed"),
+        ]
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, what)| format!("{n} {what}"))
+        .collect::<Vec<_>>();
+        let outcome = match counts.is_empty() {
+            true => format!("{} verified", report.matched),
+            false => counts.join(", "),
+        };
+        // Whole minutes are plenty
+        let ago = match age.as_secs() / 60 {
+            0 => "just now".to_string(),
+            minutes => format!(
+                "{} ago",
+                humantime::format_duration(Duration::from_secs(minutes * 60))
+            ),
+        };
+        info!(
+            event = "host",
+            host = report.host.as_str(),
+            dir = report.dir.as_str(),
+            result = report.result.as_str(),
+            finished = report.finished,
+            stale = stale;
+            "{marker} {}  {}  {outcome}, {ago}",
+            report.host.bold(),
+            report.dir
+        );
+    }
+    code
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use colored::*;
+use log::{info, warn};
+
+use crate::style;
+
+// btrfs and ZFS checksum every block they store and refuse to hand out one
+// that doesn't match, so a file on them that reads fine but mismatches was
+// changed above the filesystem: by an application, a bad copy, someone. A
+// file ZFS has on its list of permanent errors, or a btrfs filesystem whose
+// device stats count corruption, is the disk's doing. --fs-check asks them
+// about the mismatched files after a verify and says which is which.
+
+/// What the filesystem says about a mismatched file.
+enum Verdict {
+    /// The filesystem found errors in the file itself
+    Flagged(String),
+    /// Its checksums pass, the change came from above
+    Silent(String),
+    /// No checksums of its own, or nothing to ask it with
+    Unknown(String),
+}
+
+/// A mounted filesystem.
+#[derive(Clone)]
+struct Mount {
+    point: PathBuf,
+    fs_type: String,
+    source: String,
+}
+
+/// The filesystem `path` is on: the mount with the longest mount point it's
+/// under, from /proc/self/mountinfo.
+#[cfg(target_os = "linux")]
+fn mount_of(path: &Path) -> Option<Mount> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let (fields, rest) = line.split_once(" - ")?;
+            let point = crate::health::mount_point(fields.split(' ').nth(4)?);
+            let mut rest = rest.split(' ');
+            Some(Mount {
+                point,
+                fs_type: rest.next()?.to_string(),
+                source: rest.next()?.to_string(),
+            })
+        })
+        .filter(|mount| path.starts_with(&mount.point))
+        .max_by_key(|mount| mount.point.as_os_str().len())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mount_of(_path: &Path) -> Option<Mount> {
+    None
+}
+
+/// Runs `command` with `args`, its stdout.
+fn output(command: &str, args: &[&str]) -> io::Result<String> {
+    let output = Command::new(command)
+        .args(args)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("can't run {command}: {e}")))?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{command} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Error counts of `btrfs device stats` other than zero, as
+/// `[/dev/sdb1].corruption_errs 3` lines.
+fn btrfs_errors(mount: &Path) -> io::Result<Vec<String>> {
+    let stats = output("btrfs", &["device", "stats", &mount.to_string_lossy()])?;
+    Ok(stats
+        .lines()
+        .filter(|line| {
+            line.split_whitespace()
+                .nth(1)
+                .is_some_and(|count| count != "0")
+        })
+        .map(str::to_string)
+        .collect())
+}
+
+/// What `zpool status -v` says about `pool`: the files with permanent
+/// errors, and the devices with checksum errors.
+struct PoolStatus {
+    files: Vec<PathBuf>,
+    cksum_errors: u64,
+}
+
+fn zpool_status(pool: &str) -> io::Result<PoolStatus> {
+    let status = output("zpool", &["status", "-v", pool])?;
+    let mut files = Vec::new();
+    let mut cksum_errors = 0;
+    let mut in_config = false;
+    let mut in_errors = false;
+    for line in status.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("NAME") && trimmed.ends_with("CKSUM") {
+            in_config = true;
+        } else if trimmed.starts_with("errors:") {
+            in_config = false;
+            in_errors = trimmed.contains("following files");
+        } else if in_config {
+            // NAME STATE READ WRITE CKSUM, the pool itself sums its devices
+            let fields: Vec<&str> = trimmed.split_whitespace().collect();
+            if fields.len() >= 5 && fields[0] != pool {
+                cksum_errors += fields[4].parse::<u64>().unwrap_or(0);
+            }
+        } else if in_errors && !trimmed.is_empty() {
+            files.push(PathBuf::from(trimmed));
+        }
+    }
+    Ok(PoolStatus {
+        files,
+        cksum_errors,
+    })
+}
+
+/// Asks the filesystems, once each, about the files at `paths`.
+#[derive(Default)]
+struct FsCheck {
+    btrfs: HashMap<PathBuf, Result<Vec<String>, String>>,
+    zfs: HashMap<String, Result<PoolStatus, String>>,
+}
+
+impl FsCheck {
+    fn verdict(&mut self, path: &Path) -> Verdict {
+        let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let Some(mount) = mount_of(&path) else {
+            return Verdict::Unknown("can't tell which filesystem it's on".to_string());
+        };
+        match mount.fs_type.as_str() {
+            "btrfs" => {
+                let errors = self
+                    .btrfs
+                    .entry(mount.point.clone())
+                    .or_insert_with(|| btrfs_errors(&mount.point).map_err(|e| e.to_string()));
+                match errors {
+                    Err(e) => Verdict::Unknown(e.clone()),
+                    Ok(errors) if errors.is_empty() => Verdict::Silent(
+                        "btrfs checksums pass, changed above the filesystem".to_string(),
+                    ),
+                    // It read fine, so its own blocks passed, but the disk
+                    // is worth a scrub
+                    Ok(errors) => Verdict::Silent(format!(
+                        "btrfs checksums pass, changed above the filesystem; its device stats count errors ({}), scrub it",
+                        errors.join(", ")
+                    )),
+                }
+            }
+            "zfs" => {
+                let pool = mount
+                    .source
+                    .split('/')
+                    .next()
+                    .unwrap_or_default()
+                    .to_string();
+                let status = self
+                    .zfs
+                    .entry(pool.clone())
+                    .or_insert_with(|| zpool_status(&pool).map_err(|e| e.to_string()));
+                match status {
+                    Err(e) => Verdict::Unknown(e.clone()),
+                    Ok(status) if status.files.contains(&path) => Verdict::Flagged(format!(
+                        "zpool status lists it with permanent errors in pool {pool}"
+                    )),
+                    Ok(status) if status.cksum_errors > 0 => Verdict::Silent(format!(
+                        "ZFS checksums pass for it, changed above the filesystem; pool {pool} has {} checksum errors elsewhere",
+                        status.cksum_errors
+                    )),
+                    Ok(_) => Verdict::Silent(
+                        "ZFS checksums pass, changed above the filesystem".to_string(),
+                    ),
+                }
+            }
+            other => Verdict::Unknown(format!("{other} keeps no checksums of file data")),
+        }
+    }
+}
+
+/// Reports what the filesystems say about the mismatched files at `paths`.
+pub fn report(paths: &[String]) {
+    if paths.is_empty() {
+        return;
+    }
+    if !cfg!(target_os = "linux") {
+        warn!(event = "fs_check_unsupported"; "--fs-check only works on Linux");
+        return;
+    }
+    info!("\n=== {} ===", "FILESYSTEM".bold().underline());
+    let mut check = FsCheck::default();
+    for path in paths {
+        let (verdict, marker, why) = match check.verdict(Path::new(path)) {
+            Verdict::Flagged(why) => (
+                "flagged",
+                style::pick("🛡️ FLAGGED", "FLAGGED").red().bold(),
+                why,
+            ),
+            Verdict::Silent(why) => ("silent", style::pick("🤫 SILENT", "SILENT").yellow(), why),
+            Verdict::Unknown(why) => (
+                "unknown",
+                style::pick("❔ UNKNOWN", "UNKNOWN").normal(),
+                why,
+            ),
+        };
+        info!(
+            event = "fs_check",
+            path = path.as_str(),
+            verdict = verdict,
+            reason = why.as_str();
+            "{marker} {path}: {why}"
+        );
+    }
+}
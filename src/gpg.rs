@@ -0,0 +1,95 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+/// `<manifest>.asc` if it exists, else `<manifest>.sig`.
+fn find_signature(manifest: &Path) -> io::Result<PathBuf> {
+    for ext in [".asc", ".sig"] {
+        let mut name = OsString::from(manifest.as_os_str());
+        name.push(ext);
+        let path = PathBuf::from(name);
+        if path.exists() {
+            return Ok(path);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "{}: no .asc or .sig OpenPGP signature next to it",
+            manifest.display()
+        ),
+    ))
+}
+
+/// Runs `cmd`, feeding it `data` on stdin. Returns its stderr on failure.
+fn run_with_input(mut cmd: Command, data: &[u8]) -> io::Result<Result<(), String>> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // The child may exit early (e.g. a bad keyring) and close its stdin,
+    // its exit status tells the real story then
+    let _ = child.stdin.take().expect("piped stdin").write_all(data);
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(Ok(()))
+    } else {
+        Ok(Err(String::from_utf8_lossy(&output.stderr)
+            .trim()
+            .to_string()))
+    }
+}
+
+fn spawn_error(tool: &str, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("can't run {tool}: {e}"))
+}
+
+/// Checks `data`, the bytes read from `manifest`, against the detached
+/// OpenPGP signature next to it using `gpgv` and only the keys in `keyring`.
+/// Returns the signature file that was checked.
+pub fn verify_manifest(manifest: &Path, data: &[u8], keyring: &Path) -> io::Result<PathBuf> {
+    let signature = find_signature(manifest)?;
+    // gpgv looks relative keyring names up in its home directory
+    let keyring = fs::canonicalize(keyring)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", keyring.display(), e)))?;
+
+    let mut cmd = Command::new("gpgv");
+    cmd.arg("--keyring").arg(&keyring).arg(&signature).arg("-");
+    match run_with_input(cmd, data).map_err(|e| spawn_error("gpgv", e))? {
+        Ok(()) => Ok(signature),
+        Err(stderr) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: OpenPGP signature doesn't verify, refusing to trust it\n{stderr}",
+                manifest.display()
+            ),
+        )),
+    }
+}
+
+/// Writes an armored detached signature of `data` to `<manifest>.asc` with
+/// `gpg`, using `key` or gpg's default key when it's empty.
+pub fn sign_manifest(manifest: &Path, data: &[u8], key: &str) -> io::Result<PathBuf> {
+    let mut name = OsString::from(manifest.as_os_str());
+    name.push(".asc");
+    let signature = PathBuf::from(name);
+
+    let mut cmd = Command::new("gpg");
+    cmd.args(["--batch", "--yes", "--armor", "--detach-sign"]);
+    if !key.is_empty() {
+        cmd.arg("--local-user").arg(key);
+    }
+    cmd.arg("--output").arg(&signature).arg("-");
+    match run_with_input(cmd, data).map_err(|e| spawn_error("gpg", e))? {
+        Ok(()) => Ok(signature),
+        Err(stderr) => Err(io::Error::other(format!(
+            "gpg failed to sign {}\n{stderr}",
+            manifest.display()
+        ))),
+    }
+}
@@ -0,0 +1,710 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    fs::{self, File},
+    io::{self, Read},
+    path::Path,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use clap::ValueEnum;
+use crc32fast::Hasher as Crc32;
+use log::debug;
+use md5::Md5;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+use crate::{
+    cli::{Check, FilterArgs, HashArgs},
+    entropy::Histogram,
+    health, magic, xattrs,
+};
+
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+pub const DEFAULT_CHUNK_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// A BLAKE3 hash, held as its 32 bytes and written out as lowercase hex,
+/// the only form manifests and logs know.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Digest([u8; 32]);
+
+impl Digest {
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// The lowercase hex, without allocating.
+    pub fn to_hex(self) -> impl std::ops::Deref<Target = str> + fmt::Display {
+        blake3::Hash::from_bytes(self.0).to_hex()
+    }
+}
+
+impl From<blake3::Hash> for Digest {
+    fn from(hash: blake3::Hash) -> Self {
+        Digest(*hash.as_bytes())
+    }
+}
+
+impl std::str::FromStr for Digest {
+    type Err = String;
+
+    /// Exactly 64 lowercase hex digits, as they're written.
+    fn from_str(hex: &str) -> Result<Self, String> {
+        if hex.len() != 64 || hex.bytes().any(|b| b.is_ascii_uppercase()) {
+            return Err(format!(
+                "'{hex}' isn't a BLAKE3 hash (64 lowercase hex digits)"
+            ));
+        }
+        blake3::Hash::from_hex(hex)
+            .map(Digest::from)
+            .map_err(|_| format!("'{hex}' isn't a BLAKE3 hash (64 lowercase hex digits)"))
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct HexVisitor;
+
+        impl serde::de::Visitor<'_> for HexVisitor {
+            type Value = Digest;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a BLAKE3 hash as 64 lowercase hex digits")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, hex: &str) -> Result<Digest, E> {
+                hex.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(HexVisitor)
+    }
+}
+
+impl JsonSchema for Digest {
+    fn schema_name() -> std::borrow::Cow<'static, str> {
+        "Digest".into()
+    }
+
+    fn inline_schema() -> bool {
+        true
+    }
+
+    fn json_schema(_: &mut schemars::SchemaGenerator) -> schemars::Schema {
+        schemars::json_schema!({
+            "type": "string",
+            "pattern": "^[0-9a-f]{64}$"
+        })
+    }
+}
+
+/// What's recorded of a file. There's one per entry of a manifest held in
+/// memory, so what most entries don't have is boxed, to keep it small.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct FileMeta {
+    /// BLAKE3 of the contents, lowercase hex
+    pub hash: Digest,
+    /// Last modification, in seconds since the epoch
+    pub modified: u64,
+    /// Size in bytes
+    pub size: i64,
+    /// Hashes of fixed size pieces of large files, with --chunk-size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<Box<Chunks>>,
+    /// Content-defined chunks with --cdc, each `<fingerprint>:<length>`
+    /// where the fingerprint is the first 16 hex digits of its BLAKE3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cdc: Option<Box<[String]>>,
+    /// BLAKE3 of the file's Finder info, resource fork and other key
+    /// extended attributes by name, with --mac-metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<BTreeMap<String, String>>,
+    /// MD5 of the contents, lowercase hex, with --md5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5: Option<Box<str>>,
+    /// SHA-256 of the contents, lowercase hex, with --sha256
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<Box<str>>,
+    /// CRC-32 of the contents, 8 lowercase hex digits, with --crc32 or
+    /// from an .sfv file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32: Option<Box<str>>,
+    /// Labels given with `checkyoself tag`, kept by --update
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub tags: BTreeSet<String>,
+    /// A note given with `checkyoself tag --note`, kept by --update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<Box<str>>,
+    /// Permission bits, with --check perms (Unix)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// `<uid>:<gid>`, with --check owner (Unix)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<Box<str>>,
+    /// Shannon entropy of the contents in bits per byte, 0 to 8, with
+    /// --entropy; see `entropy`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entropy: Option<f32>,
+    /// MIME type of the contents, from how they start, with --magic; see
+    /// `magic`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime: Option<Box<str>>,
+    /// Number of the device the file was read from, with --devices (Unix);
+    /// the manifest's `devices` says which filesystem that is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<u64>,
+    /// When the file was created (its birth time), in seconds since the
+    /// epoch, with --btime where the filesystem keeps it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub born: Option<u64>,
+}
+
+/// Per-chunk hashes of a file, so a mismatch can be narrowed down to the
+/// byte ranges that changed.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Chunks {
+    /// Chunk length in bytes, the last chunk may be shorter
+    pub size: u64,
+    /// BLAKE3 of each chunk in order, lowercase hex
+    pub hashes: Vec<Digest>,
+}
+
+/// How file contents are read for hashing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IoMode {
+    /// Plain read() calls into a buffer
+    Read,
+    /// Memory map the file (falls back to reading for small files)
+    Mmap,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct HashOptions {
+    pub buffer_size: usize,
+    pub io_mode: IoMode,
+    /// Also hash files larger than `chunk_threshold` in pieces this big
+    pub chunk_size: Option<u64>,
+    pub chunk_threshold: u64,
+    /// Also split every file into content-defined chunks of about this
+    /// many bytes
+    pub cdc_avg: Option<u32>,
+    /// Hash a file that changed while it was hashed once more before
+    /// giving up on it
+    pub rehash_volatile: bool,
+    /// How many times to retry a read that failed in a way that may go away
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each one after
+    pub retry_delay: Duration,
+    /// Also record the key extended attributes (macOS)
+    pub mac_metadata: bool,
+    /// Also hash the contents with MD5, in the same pass
+    pub md5: bool,
+    /// Also hash the contents with SHA-256, in the same pass
+    pub sha256: bool,
+    /// Also compute the CRC-32 of the contents, in the same pass
+    pub crc32: bool,
+    /// Leave files open for writing elsewhere alone, see `busy`
+    pub skip_busy: bool,
+    /// Also record the permission bits, for --check perms
+    pub perms: bool,
+    /// Also record the owner, for --check owner
+    pub owner: bool,
+    /// Also measure how random the contents look, in the same pass
+    pub entropy: bool,
+    /// Also tell what type the contents are
+    pub magic: bool,
+    /// Also record the device the file is on
+    pub device: bool,
+    /// Also record when the file was created
+    pub btime: bool,
+}
+
+impl Default for HashOptions {
+    fn default() -> Self {
+        HashOptions {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            io_mode: IoMode::Read,
+            chunk_size: None,
+            chunk_threshold: DEFAULT_CHUNK_THRESHOLD,
+            cdc_avg: None,
+            rehash_volatile: false,
+            retries: 0,
+            retry_delay: Duration::ZERO,
+            mac_metadata: false,
+            md5: false,
+            sha256: false,
+            crc32: false,
+            skip_busy: false,
+            perms: false,
+            owner: false,
+            entropy: false,
+            magic: false,
+            device: false,
+            btime: false,
+        }
+    }
+}
+
+impl HashOptions {
+    /// As given on the command line, --threads aside. Filtering by type
+    /// records it too.
+    pub fn from_args(args: &HashArgs, filter: &FilterArgs) -> Self {
+        HashOptions {
+            buffer_size: args.buffer_size.max(1),
+            io_mode: args.io_mode,
+            chunk_size: args.chunk_size.map(|s| s.max(1)),
+            chunk_threshold: args.chunk_threshold,
+            cdc_avg: args.cdc,
+            rehash_volatile: args.rehash_volatile,
+            retries: args.retries,
+            retry_delay: Duration::from_millis(args.retry_delay),
+            mac_metadata: args.mac_metadata,
+            md5: args.md5,
+            sha256: args.sha256,
+            crc32: args.crc32,
+            skip_busy: args.skip_busy,
+            perms: args.check.contains(&Check::Perms),
+            owner: args.check.contains(&Check::Owner),
+            entropy: args.entropy,
+            magic: args.magic || !filter.only_type.is_empty(),
+            device: args.devices,
+            btime: args.btime,
+        }
+    }
+}
+
+/// The modification time of the file at `path`, as precise as the
+/// filesystem keeps it, and its size.
+fn stamp(path: &Path) -> io::Result<(SystemTime, u64)> {
+    let metadata = fs::metadata(path)?;
+    Ok((metadata.modified()?, metadata.len()))
+}
+
+/// The modification time, in seconds, and size recorded for `path`.
+pub fn file_metadata(path: &Path) -> std::io::Result<(u64, i64)> {
+    Ok(recorded(stamp(path)?))
+}
+
+/// A stamp as recorded, in whole seconds.
+fn recorded((modified, size): (SystemTime, u64)) -> (u64, i64) {
+    let modified_secs = modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    (modified_secs, size as i64)
+}
+
+/// Hashes the contents of `path`.
+pub fn hash_file(path: &Path, opts: &HashOptions) -> std::io::Result<blake3::Hash> {
+    let mut hasher = blake3::Hasher::new();
+
+    match opts.io_mode {
+        IoMode::Mmap => {
+            hasher.update_mmap(path)?;
+        }
+        IoMode::Read => {
+            let mut file = File::open(path)?;
+            let mut buffer = vec![0u8; opts.buffer_size];
+
+            loop {
+                let n = file.read(&mut buffer)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+            }
+        }
+    }
+
+    Ok(hasher.finalize())
+}
+
+/// Hashes a file's contents as they stream by, and every `chunk_size`
+/// piece of them, their MD5, SHA-256, CRC-32, entropy and type if asked
+/// to.
+struct Digester {
+    hasher: blake3::Hasher,
+    md5: Option<Md5>,
+    sha256: Option<Sha256>,
+    crc32: Option<Crc32>,
+    histogram: Option<Histogram>,
+    /// The first `magic::HEAD` bytes
+    head: Option<Vec<u8>>,
+    chunk_size: Option<u64>,
+    chunk: blake3::Hasher,
+    in_chunk: u64,
+    hashes: Vec<Digest>,
+}
+
+impl Digester {
+    fn new(chunk_size: Option<u64>, opts: &HashOptions) -> Self {
+        Digester {
+            hasher: blake3::Hasher::new(),
+            md5: opts.md5.then(Md5::new),
+            sha256: opts.sha256.then(Sha256::new),
+            crc32: opts.crc32.then(Crc32::new),
+            histogram: opts.entropy.then(Histogram::new),
+            head: opts.magic.then(|| Vec::with_capacity(magic::HEAD)),
+            chunk_size,
+            chunk: blake3::Hasher::new(),
+            in_chunk: 0,
+            hashes: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.hasher.update(data);
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(data);
+        }
+        if let Some(crc32) = &mut self.crc32 {
+            crc32.update(data);
+        }
+        if let Some(histogram) = &mut self.histogram {
+            histogram.update(data);
+        }
+        if let Some(head) = self.head.as_mut().filter(|h| h.len() < magic::HEAD) {
+            let take = data.len().min(magic::HEAD - head.len());
+            head.extend_from_slice(&data[..take]);
+        }
+
+        let Some(chunk_size) = self.chunk_size else {
+            return;
+        };
+        while !data.is_empty() {
+            let take = data.len().min((chunk_size - self.in_chunk) as usize);
+            self.chunk.update(&data[..take]);
+            self.in_chunk += take as u64;
+            data = &data[take..];
+            if self.in_chunk == chunk_size {
+                self.hashes.push(self.chunk.finalize().into());
+                self.chunk.reset();
+                self.in_chunk = 0;
+            }
+        }
+    }
+
+    fn finish(mut self) -> Hashed {
+        if self.in_chunk > 0 {
+            self.hashes.push(self.chunk.finalize().into());
+        }
+        let chunks = self.chunk_size.map(|size| Chunks {
+            size,
+            hashes: self.hashes,
+        });
+        Hashed {
+            hash: self.hasher.finalize(),
+            chunks,
+            md5: self.md5.map(|md5| format!("{:x}", md5.finalize())),
+            sha256: self.sha256.map(|sha256| format!("{:x}", sha256.finalize())),
+            crc32: self.crc32.map(|crc32| format!("{:08x}", crc32.finalize())),
+            entropy: self.histogram.map(|h| h.bits()),
+            mime: self.head.map(|head| magic::detect(&head)),
+        }
+    }
+}
+
+/// The BLAKE3 of a file, with its pieces', MD5, SHA-256, CRC-32, entropy
+/// and type if asked for.
+struct Hashed {
+    hash: blake3::Hash,
+    chunks: Option<Chunks>,
+    md5: Option<String>,
+    sha256: Option<String>,
+    crc32: Option<String>,
+    entropy: Option<f32>,
+    mime: Option<&'static str>,
+}
+
+impl Hashed {
+    fn only(hash: blake3::Hash) -> Self {
+        Hashed {
+            hash,
+            chunks: None,
+            md5: None,
+            sha256: None,
+            crc32: None,
+            entropy: None,
+            mime: None,
+        }
+    }
+}
+
+/// Hashes the contents of `path` with plain reads, along with every
+/// `chunk_size` piece of it, its MD5, SHA-256, CRC-32, entropy and type if
+/// asked to.
+fn hash_file_chunked(
+    path: &Path,
+    opts: &HashOptions,
+    chunk_size: Option<u64>,
+) -> std::io::Result<Hashed> {
+    let mut digest = Digester::new(chunk_size, opts);
+
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0u8; opts.buffer_size];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        digest.update(&buffer[..n]);
+    }
+
+    Ok(digest.finish())
+}
+
+/// Splits `path` into content-defined chunks averaging `avg` bytes (FastCDC)
+/// and fingerprints each, hashing the whole file (and its fixed size pieces,
+/// MD5, SHA-256, CRC-32, entropy and type) in the same pass.
+fn hash_file_cdc(
+    path: &Path,
+    avg: u32,
+    chunk_size: Option<u64>,
+    opts: &HashOptions,
+) -> std::io::Result<(Hashed, Vec<String>)> {
+    let avg = avg.clamp(fastcdc::v2020::AVERAGE_MIN, fastcdc::v2020::AVERAGE_MAX);
+    let min = (avg / 4).max(fastcdc::v2020::MINIMUM_MIN);
+    let max = (avg * 4).min(fastcdc::v2020::MAXIMUM_MAX);
+
+    let mut digest = Digester::new(chunk_size, opts);
+    let mut fingerprints = Vec::new();
+    for chunk in fastcdc::v2020::StreamCDC::new(File::open(path)?, min, avg, max) {
+        let chunk = chunk.map_err(std::io::Error::from)?;
+        digest.update(&chunk.data);
+        fingerprints.push(format!(
+            "{}:{}",
+            &blake3::hash(&chunk.data).to_hex()[..16],
+            chunk.length
+        ));
+    }
+
+    Ok((digest.finish(), fingerprints))
+}
+
+/// A file that kept changing while it was hashed: the hash matches neither
+/// the old nor the new contents, so there is none to report.
+#[derive(Debug)]
+pub struct Volatile;
+
+impl fmt::Display for Volatile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("changed while it was being hashed")
+    }
+}
+
+impl std::error::Error for Volatile {}
+
+/// Whether `calculate_blake3` failed because the file was changing.
+pub fn is_volatile(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<Volatile>())
+}
+
+/// Read errors that may go away when tried again, a USB drive or network
+/// share having a moment.
+fn is_transient(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+    if health::is_medium_error(e) {
+        return true;
+    }
+    matches!(
+        e.kind(),
+        Interrupted
+            | TimedOut
+            | WouldBlock
+            | ResourceBusy
+            | StaleNetworkFileHandle
+            | ConnectionReset
+            | ConnectionAborted
+            | NetworkDown
+            | NetworkUnreachable
+            | HostUnreachable
+    )
+}
+
+/// Whether `e` is another process holding the file: a sharing or lock
+/// violation on Windows, EBUSY or ETXTBSY elsewhere.
+pub fn is_busy(e: &io::Error) -> bool {
+    #[cfg(windows)]
+    {
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+        const ERROR_LOCK_VIOLATION: i32 = 33;
+        if matches!(
+            e.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        ) {
+            return true;
+        }
+    }
+    matches!(
+        e.kind(),
+        io::ErrorKind::ResourceBusy | io::ErrorKind::ExecutableFileBusy
+    )
+}
+
+/// The longest wait between retries, however many there are.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Hashes the file at `path`, retrying transient read errors as configured.
+/// Returns its entry along with the number of retries it took.
+pub fn calculate_blake3(path: &Path, opts: &HashOptions) -> io::Result<(FileMeta, u32)> {
+    let mut delay = opts.retry_delay;
+    let mut retry = 0;
+    loop {
+        match hash_unchanged(path, opts) {
+            Err(e) if retry < opts.retries && is_transient(&e) => {
+                debug!(
+                    event = "retry",
+                    path:% = path.display(),
+                    error:% = e;
+                    "retrying {} in {:?}: {e}",
+                    path.display(),
+                    delay
+                );
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2).min(MAX_RETRY_DELAY);
+                retry += 1;
+            }
+            result => return result.map(|meta| (meta, retry)),
+        }
+    }
+}
+
+/// Hashes the file at `path` and makes sure it didn't change meanwhile.
+fn hash_unchanged(path: &Path, opts: &HashOptions) -> io::Result<FileMeta> {
+    let attempts = if opts.rehash_volatile { 2 } else { 1 };
+    for _ in 0..attempts {
+        let before = stamp(path)?;
+        let meta = hash_once(path, opts, before)?;
+        // The size and mtime have to still hold after it, to the nanosecond
+        // where kept, a write in the same second changes them too
+        if stamp(path)? == before {
+            return Ok(meta);
+        }
+    }
+    Err(io::Error::other(Volatile))
+}
+
+/// Hashes the file at `path`, whose stamp was `stamp` just before.
+fn hash_once(path: &Path, opts: &HashOptions, stamp: (SystemTime, u64)) -> io::Result<FileMeta> {
+    let (modified, size) = recorded(stamp);
+
+    // Chunked files, MD5, SHA-256, CRC-32, entropy and type are always
+    // read, they need the bytes in hand
+    let chunk_size = opts
+        .chunk_size
+        .filter(|_| size as u64 > opts.chunk_threshold)
+        .map(|s| s.max(1));
+    let (hashed, cdc) = match opts.cdc_avg {
+        Some(avg) => {
+            let (hashed, cdc) = hash_file_cdc(path, avg, chunk_size, opts)?;
+            (hashed, Some(cdc))
+        }
+        None if chunk_size.is_some()
+            || opts.md5
+            || opts.sha256
+            || opts.crc32
+            || opts.entropy
+            || opts.magic =>
+        {
+            (hash_file_chunked(path, opts, chunk_size)?, None)
+        }
+        None => (Hashed::only(hash_file(path, opts)?), None),
+    };
+    let xattrs = opts
+        .mac_metadata
+        .then(|| xattrs::key_attrs(path))
+        .transpose()?;
+    let Access {
+        mode,
+        owner,
+        device,
+        born,
+    } = access(path, opts)?;
+
+    Ok(FileMeta {
+        hash: hashed.hash.into(),
+        modified,
+        size,
+        chunks: hashed.chunks.map(Box::new),
+        cdc: cdc.map(Vec::into_boxed_slice),
+        xattrs,
+        md5: hashed.md5.map(String::into_boxed_str),
+        sha256: hashed.sha256.map(String::into_boxed_str),
+        crc32: hashed.crc32.map(String::into_boxed_str),
+        tags: BTreeSet::new(),
+        note: None,
+        mode,
+        owner: owner.map(String::into_boxed_str),
+        entropy: hashed.entropy,
+        mime: hashed.mime.map(Box::from),
+        device,
+        born,
+    })
+}
+
+/// What's recorded of a file besides its contents.
+#[derive(Default)]
+struct Access {
+    mode: Option<u32>,
+    owner: Option<String>,
+    device: Option<u64>,
+    born: Option<u64>,
+}
+
+/// The permission bits, owner, device and birth time of the file at
+/// `path`, as far as `opts` asks for them and the system has them.
+fn access(path: &Path, opts: &HashOptions) -> io::Result<Access> {
+    if !opts.perms && !opts.owner && !opts.device && !opts.btime {
+        return Ok(Access::default());
+    }
+    let meta = fs::metadata(path)?;
+    // Not every filesystem keeps it, nor every kernel tells (statx on Linux)
+    let born = opts
+        .btime
+        .then(|| meta.created().ok())
+        .flatten()
+        .map(|born| {
+            born.duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(Access {
+            mode: opts.perms.then(|| meta.mode() & 0o7777),
+            owner: opts.owner.then(|| format!("{}:{}", meta.uid(), meta.gid())),
+            device: opts.device.then(|| meta.dev()),
+            born,
+        })
+    }
+    #[cfg(not(unix))]
+    Ok(Access {
+        born,
+        ..Access::default()
+    })
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::BTreeMap,
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use log::error;
+
+use crate::style;
+
+// A single EIO can be a cosmic ray; several on one device during one scan
+// usually means the device is on its way out, and that deserves more than a
+// line lost among the UNREADABLE ones.
+
+/// Medium errors on one device from which it is reported as possibly failing.
+pub const FAILING_DISK_ERRORS: usize = 3;
+
+#[cfg(unix)]
+const EIO: i32 = 5;
+#[cfg(windows)]
+const MEDIUM_ERRORS: [i32; 4] = [
+    23,  // ERROR_CRC
+    27,  // ERROR_SECTOR_NOT_FOUND
+    30,  // ERROR_READ_FAULT
+    483, // ERROR_DEVICE_HARDWARE_ERROR
+];
+
+/// Why a file couldn't be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Not allowed to read it
+    Permission,
+    /// The storage failed to deliver the data: EIO, bad sectors
+    Medium,
+    /// Gone between the walk and the read
+    Gone,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn of(e: &io::Error) -> ErrorClass {
+        if is_medium_error(e) {
+            return ErrorClass::Medium;
+        }
+        match e.kind() {
+            io::ErrorKind::PermissionDenied => ErrorClass::Permission,
+            io::ErrorKind::NotFound => ErrorClass::Gone,
+            _ => ErrorClass::Other,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::Permission => "permission",
+            ErrorClass::Medium => "medium",
+            ErrorClass::Gone => "gone",
+            ErrorClass::Other => "other",
+        }
+    }
+
+    /// The marker its report starts with.
+    pub fn marker(self) -> ColoredString {
+        match self {
+            ErrorClass::Permission => style::pick("🔒 DENIED", "DENIED").red(),
+            ErrorClass::Medium => style::pick("💽 MEDIUM ERROR", "MEDIUM ERROR").red().bold(),
+            ErrorClass::Gone | ErrorClass::Other => {
+                style::pick("⚠️ UNREADABLE", "UNREADABLE").red()
+            }
+        }
+    }
+}
+
+/// Whether `e` is the storage failing to read, rather than something about
+/// the file.
+pub fn is_medium_error(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    return e.raw_os_error() == Some(EIO);
+    #[cfg(windows)]
+    return e.raw_os_error().is_some_and(|c| MEDIUM_ERRORS.contains(&c));
+    #[cfg(not(any(unix, windows)))]
+    return false;
+}
+
+/// The device a file lives on: its number (`major:minor` on Linux) and,
+/// on Linux, what is mounted from it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Device {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{name} ({})", self.id),
+            None => f.write_str(&self.id),
+        }
+    }
+}
+
+impl Device {
+    /// The device of `path`, or of the nearest directory above it that can
+    /// still be looked at.
+    pub fn of(path: &Path) -> Option<Device> {
+        let meta = path.ancestors().find_map(|p| p.metadata().ok())?;
+        Self::from_metadata(&meta)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn from_metadata(meta: &std::fs::Metadata) -> Option<Device> {
+        use std::os::unix::fs::MetadataExt;
+        let id = device_number(meta.dev());
+        Some(Device {
+            name: mount_source(&id),
+            id,
+        })
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn from_metadata(meta: &std::fs::Metadata) -> Option<Device> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Device {
+            id: device_number(meta.dev()),
+            name: None,
+        })
+    }
+
+    #[cfg(not(unix))]
+    fn from_metadata(_meta: &std::fs::Metadata) -> Option<Device> {
+        None
+    }
+}
+
+/// A device number as the system shows it, `major:minor` on Linux.
+#[cfg(unix)]
+pub fn device_number(dev: u64) -> String {
+    if cfg!(target_os = "linux") {
+        let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+        let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+        format!("{major}:{minor}")
+    } else {
+        dev.to_string()
+    }
+}
+
+/// What is mounted as device `id` (`8:17` → `/dev/sdb1`), from
+/// /proc/self/mountinfo.
+#[cfg(target_os = "linux")]
+pub fn mount_source(id: &str) -> Option<String> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        let (fields, rest) = line.split_once(" - ")?;
+        if fields.split(' ').nth(2)? != id {
+            return None;
+        }
+        // After the separator: filesystem type, source, super options
+        rest.split(' ').nth(1).map(str::to_string)
+    })
+}
+
+/// A mount point as /proc/self/mounts and mountinfo have it, spaces and
+/// such escaped as octal (`\040`), as a path.
+#[cfg(target_os = "linux")]
+pub fn mount_point(escaped: &str) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut rest = escaped.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let code = tail
+            .get(..3)
+            .filter(|_| b == b'\\')
+            .and_then(|oct| u8::from_str_radix(std::str::from_utf8(oct).ok()?, 8).ok());
+        match code {
+            Some(code) => {
+                bytes.push(code);
+                rest = &tail[3..];
+            }
+            None => {
+                bytes.push(b);
+                rest = tail;
+            }
+        }
+    }
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+/// Collects medium errors by device while hashing.
+#[derive(Default)]
+pub struct MediumErrors {
+    by_device: BTreeMap<Option<Device>, Vec<PathBuf>>,
+}
+
+impl MediumErrors {
+    pub fn add(&mut self, path: &Path) {
+        self.by_device
+            .entry(Device::of(path))
+            .or_default()
+            .push(path.to_path_buf());
+    }
+
+    pub fn count(&self) -> usize {
+        self.by_device.values().map(Vec::len).sum()
+    }
+
+    /// Warns about every device with `FAILING_DISK_ERRORS` or more medium
+    /// errors. Errors on files whose device is unknown are taken together.
+    pub fn report(mut self) {
+        for (device, paths) in &mut self.by_device {
+            if paths.len() < FAILING_DISK_ERRORS {
+                continue;
+            }
+            paths.sort_unstable();
+            let device = device
+                .as_ref()
+                .map_or_else(|| "unknown device".to_string(), |d| d.to_string());
+            let list: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+            error!(
+                event = "failing_disk",
+                device = device.as_str(),
+                errors = paths.len();
+                "\n{} {}: {} medium errors, check its SMART status and back it up\n  {}",
+                style::pick("🚨 POSSIBLE FAILING DISK", "POSSIBLE FAILING DISK")
+                    .red()
+                    .bold(),
+                device,
+                paths.len(),
+                list.join("\n  ")
+            );
+        }
+    }
+}
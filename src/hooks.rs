@@ -0,0 +1,179 @@
+use std::{
+    io,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use log::{debug, warn};
+
+use crate::{cli::RunArgs, hash::FileMeta, logging};
+
+// --on-mismatch, --on-extra and --on-missing run a command of the user's
+// for each such finding, through the shell, as it's found: to copy a file
+// back from a master, open a ticket, anything checkyoself doesn't do
+// itself. What was found is in environment variables:
+//
+//   CHECKYOSELF_FINDING    mismatch, extra or missing
+//   CHECKYOSELF_PATH       the path, as reported
+//   CHECKYOSELF_OLD_HASH   the recorded BLAKE3, and size (not for extra)
+//   CHECKYOSELF_OLD_SIZE
+//   CHECKYOSELF_NEW_HASH   the BLAKE3 found, and size (not for missing)
+//   CHECKYOSELF_NEW_SIZE
+//
+// Mismatches and extras run on the thread that hashed the file, so a slow
+// command holds up the hashing; missing files are known at the end.
+//
+// --pre and --post run around the whole scan, to mount a drive and unmount
+// it, take a snapshot, copy the manifest offsite: one invocation, the whole
+// job. They get CHECKYOSELF_ROOT, CHECKYOSELF_MANIFEST and CHECKYOSELF_MODE
+// (create or verify), --post CHECKYOSELF_EXIT_CODE too.
+
+/// `sh -c command`, or `cmd /C command` on Windows. What it prints goes
+/// to stderr when stdout carries TAP or JSON.
+pub fn shell(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut c = Command::new("cmd");
+        c.arg("/C");
+        c
+    } else {
+        let mut c = Command::new("sh");
+        c.arg("-c");
+        c
+    };
+    shell.arg(command);
+    if logging::is_stderr() || logging::is_json() {
+        shell.stdout(Stdio::from(io::stderr()));
+    }
+    shell
+}
+
+/// What a scan is about, for --pre and --post.
+pub struct Job<'a> {
+    pub root: &'a Path,
+    pub manifest: Option<&'a Path>,
+    pub verify: bool,
+}
+
+impl Job<'_> {
+    fn command(&self, command: &str) -> Command {
+        let mut shell = shell(command);
+        shell.env("CHECKYOSELF_ROOT", self.root).env(
+            "CHECKYOSELF_MODE",
+            if self.verify { "verify" } else { "create" },
+        );
+        if let Some(manifest) = self.manifest {
+            shell.env("CHECKYOSELF_MANIFEST", manifest);
+        }
+        shell
+    }
+
+    /// Runs the `commands` in order, stopping at the first that fails.
+    pub fn pre(&self, commands: &[String]) -> io::Result<()> {
+        for command in commands {
+            debug!(event = "pre", command = command.as_str(); "running --pre '{command}'");
+            let status = self.command(command).status().map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("can't run --pre command '{command}': {e}"),
+                )
+            })?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "--pre command '{command}' exited with {status}, not scanning"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs all the `commands` after a scan that ended with exit code
+    /// `code`, and returns that. A failing one makes a run that went fine
+    /// fail with 1.
+    pub fn post(&self, commands: &[String], mut code: i32) -> i32 {
+        for command in commands {
+            debug!(event = "post", command = command.as_str(); "running --post '{command}'");
+            let status = self
+                .command(command)
+                .env("CHECKYOSELF_EXIT_CODE", code.to_string())
+                .status();
+            let failed = match status {
+                Ok(s) if s.success() => continue,
+                Ok(s) => format!("--post command '{command}' exited with {s}"),
+                Err(e) => format!("can't run --post command '{command}': {e}"),
+            };
+            warn!(event = "post_failed", command = command.as_str(); "{failed}");
+            code = code.max(1);
+        }
+        code
+    }
+}
+
+/// The commands to run per finding.
+#[derive(Default)]
+pub struct Hooks {
+    mismatch: Option<String>,
+    extra: Option<String>,
+    missing: Option<String>,
+}
+
+impl Hooks {
+    pub fn new(run: &RunArgs) -> Self {
+        Hooks {
+            mismatch: run.on_mismatch.clone(),
+            extra: run.on_extra.clone(),
+            missing: run.on_missing.clone(),
+        }
+    }
+
+    pub fn mismatch(&self, path: &str, expected: &FileMeta, found: &FileMeta) {
+        if let Some(command) = &self.mismatch {
+            run(command, "mismatch", path, Some(expected), Some(found));
+        }
+    }
+
+    pub fn extra(&self, path: &str, found: &FileMeta) {
+        if let Some(command) = &self.extra {
+            run(command, "extra", path, None, Some(found));
+        }
+    }
+
+    pub fn missing(&self, path: &str, expected: &FileMeta) {
+        if let Some(command) = &self.missing {
+            run(command, "missing", path, Some(expected), None);
+        }
+    }
+}
+
+/// Runs `command` for a `finding` at `path` and waits for it. One that
+/// fails is warned about, the verify goes on.
+fn run(
+    command: &str,
+    finding: &str,
+    path: &str,
+    expected: Option<&FileMeta>,
+    found: Option<&FileMeta>,
+) {
+    let mut shell = shell(command);
+    shell
+        .env("CHECKYOSELF_FINDING", finding)
+        .env("CHECKYOSELF_PATH", path);
+    if let Some(meta) = expected {
+        shell
+            .env("CHECKYOSELF_OLD_HASH", &*meta.hash.to_hex())
+            .env("CHECKYOSELF_OLD_SIZE", meta.size.to_string());
+    }
+    if let Some(meta) = found {
+        shell
+            .env("CHECKYOSELF_NEW_HASH", &*meta.hash.to_hex())
+            .env("CHECKYOSELF_NEW_SIZE", meta.size.to_string());
+    }
+    match shell.status() {
+        Ok(s) if s.success() => {}
+        Ok(s) => {
+            warn!(event = "hook_failed", path = path, finding = finding; "--on-{finding} command '{command}' for {path} exited with {s}")
+        }
+        Err(e) => {
+            warn!(event = "hook_failed", path = path, finding = finding; "can't run --on-{finding} command '{command}' for {path}: {e}")
+        }
+    }
+}
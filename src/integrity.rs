@@ -0,0 +1,181 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::Mutex,
+};
+
+use colored::*;
+use log::{error, info, warn};
+use md5::Md5;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+
+use crate::{
+    cli::IntegrityArgs,
+    execute,
+    hash::DEFAULT_BUFFER_SIZE,
+    health::ErrorClass,
+    packages::{self, DigestAlgo, PackagedFile},
+    style,
+};
+
+// An AIDE-like host check in one run: installed files against the digests
+// their packages recorded, then, with --verify, everything the packages
+// don't cover against a checkyoself manifest (created with --skip-packaged).
+
+#[derive(Default)]
+struct Counts {
+    verified: usize,
+    mismatched: usize,
+    config_changed: usize,
+    missing: usize,
+    errors: usize,
+}
+
+/// Hashes `path` the way its package did.
+fn digest_file(path: &Path, algo: DigestAlgo) -> io::Result<String> {
+    fn read_all(path: &Path, mut update: impl FnMut(&[u8])) -> io::Result<()> {
+        let mut file = File::open(path)?;
+        let mut buffer = vec![0; DEFAULT_BUFFER_SIZE * 8];
+        loop {
+            match file.read(&mut buffer)? {
+                0 => return Ok(()),
+                n => update(&buffer[..n]),
+            }
+        }
+    }
+    Ok(match algo {
+        DigestAlgo::Md5 => {
+            let mut hasher = Md5::new();
+            read_all(path, |data| hasher.update(data))?;
+            format!("{:x}", hasher.finalize())
+        }
+        DigestAlgo::Sha256 => {
+            let mut hasher = Sha256::new();
+            read_all(path, |data| hasher.update(data))?;
+            format!("{:x}", hasher.finalize())
+        }
+        DigestAlgo::Sha384 => {
+            let mut hasher = Sha384::new();
+            read_all(path, |data| hasher.update(data))?;
+            format!("{:x}", hasher.finalize())
+        }
+        DigestAlgo::Sha512 => {
+            let mut hasher = Sha512::new();
+            read_all(path, |data| hasher.update(data))?;
+            format!("{:x}", hasher.finalize())
+        }
+    })
+}
+
+fn check(file: &PackagedFile, counts: &Mutex<Counts>) {
+    let path = file.path.display();
+    let package = file.package.as_str();
+    match digest_file(&file.path, file.algo) {
+        Ok(found) if found == file.digest => counts.lock().unwrap().verified += 1,
+        Ok(_) if file.config => {
+            counts.lock().unwrap().config_changed += 1;
+            info!(
+                event = "package_config_changed",
+                path:% = path,
+                package = package;
+                "{} {path} ({package})",
+                style::pick("✏️ CONFIG CHANGED", "CONFIG CHANGED").yellow()
+            );
+        }
+        Ok(found) => {
+            counts.lock().unwrap().mismatched += 1;
+            error!(
+                event = "package_mismatch",
+                path:% = path,
+                package = package,
+                expected = file.digest.as_str(),
+                found = found.as_str();
+                "{} {path} ({package})\n  expected: {}\n  found:    {found}",
+                style::pick("❌ MISMATCH", "MISMATCH").red(),
+                file.digest
+            );
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            counts.lock().unwrap().missing += 1;
+            warn!(
+                event = "package_missing",
+                path:% = path,
+                package = package;
+                "{} {path} ({package})",
+                style::pick("❓ MISSING", "MISSING").magenta()
+            );
+        }
+        Err(e) => {
+            counts.lock().unwrap().errors += 1;
+            let class = ErrorClass::of(&e);
+            warn!(
+                event = "error",
+                path:% = path,
+                package = package,
+                class = class.as_str(),
+                error:% = e;
+                "{} {path} ({package}): {e}",
+                class.marker()
+            );
+        }
+    }
+}
+
+/// Checks every installed file the package database has a digest for.
+fn check_packages() -> io::Result<Counts> {
+    let (manager, mut files) = packages::recorded_digests()?;
+    files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    info!(
+        "\n=== {} ===",
+        format!("PACKAGES ({manager})").bold().underline()
+    );
+
+    let counts = Mutex::new(Counts::default());
+    files.par_iter().for_each(|file| check(file, &counts));
+    let counts = counts.into_inner().unwrap();
+
+    info!(
+        event = "package_summary",
+        manager = manager,
+        verified = counts.verified,
+        mismatched = counts.mismatched,
+        config_changed = counts.config_changed,
+        missing = counts.missing,
+        errors = counts.errors;
+        "{} {} packaged files: {} verified, {} mismatched, {} config changed, {} missing, {} unreadable",
+        style::pick("📦", "PACKAGES").bold(),
+        files.len(),
+        counts.verified,
+        counts.mismatched,
+        counts.config_changed,
+        counts.missing,
+        counts.errors
+    );
+    Ok(counts)
+}
+
+/// `integrity`: exit code 0, 2 on mismatches anywhere, or 1 on errors.
+pub fn run(args: &IntegrityArgs) -> i32 {
+    let counts = match check_packages() {
+        Ok(counts) => counts,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+    let package_code = if counts.mismatched > 0 { 2 } else { 0 };
+
+    let (Some(dir), Some(manifest)) = (&args.dir, &args.verify) else {
+        return package_code;
+    };
+    info!("\n=== {} ===", "UNPACKAGED FILES".bold().underline());
+    let mut run = args.run.clone();
+    // The packages were just checked, the manifest covers the rest
+    run.filter.skip_packaged = true;
+    match execute(dir, &run, std::slice::from_ref(manifest), None) {
+        1 => 1,
+        code => code.max(package_code),
+    }
+}
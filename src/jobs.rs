@@ -0,0 +1,168 @@
+use std::{path::Path, process::Command};
+
+use colored::*;
+use log::{info, warn};
+
+use crate::{
+    cli::JobArgs,
+    config::{Config, Job},
+    execute_watched,
+};
+
+fn load(config: Option<&Path>) -> Option<Config> {
+    match Config::load(config) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            None
+        }
+    }
+}
+
+/// Runs each `notify` command of a failed job, or one whose disks got
+/// worse, through the shell, with details in `CHECKYOSELF_*` environment
+/// variables.
+fn notify(name: &str, job: &Job, code: i32, smart_worse: bool) {
+    for cmd in &job.notify {
+        let mut shell = if cfg!(windows) {
+            let mut c = Command::new("cmd");
+            c.arg("/C");
+            c
+        } else {
+            let mut c = Command::new("sh");
+            c.arg("-c");
+            c
+        };
+        let status = shell
+            .arg(cmd)
+            .env("CHECKYOSELF_JOB", name)
+            .env("CHECKYOSELF_ROOT", &job.root)
+            .env("CHECKYOSELF_MANIFEST", &job.manifest)
+            .env("CHECKYOSELF_EXIT_CODE", code.to_string())
+            .env(
+                "CHECKYOSELF_SMART_WORSE",
+                if smart_worse { "1" } else { "0" },
+            )
+            .status();
+        match status {
+            Ok(s) if s.success() => {}
+            Ok(s) => {
+                warn!(event = "notify_failed", job = name; "notify command '{cmd}' for job {name} exited with {s}")
+            }
+            Err(e) => {
+                warn!(event = "notify_failed", job = name; "can't run notify command '{cmd}' for job {name}: {e}")
+            }
+        }
+    }
+}
+
+/// Creates (`verify == false`) or verifies the selected jobs one after the
+/// other. The exit code is the worst of them: 2 beats 1 beats 0.
+pub fn run(config: Option<&Path>, args: &JobArgs, verify: bool) -> i32 {
+    let Some(config) = load(config) else {
+        return 1;
+    };
+
+    let names: Vec<&str> = if args.all {
+        config.jobs.keys().map(String::as_str).collect()
+    } else {
+        args.jobs.iter().map(String::as_str).collect()
+    };
+    if names.is_empty() {
+        eprintln!("Error: no jobs are configured");
+        return 1;
+    }
+
+    let mut worst = 0;
+    for name in names {
+        let job = match config.job(name) {
+            Ok(job) => job,
+            Err(e) => {
+                eprintln!("Error: {e}");
+                worst = worst.max(1);
+                continue;
+            }
+        };
+
+        info!(
+            event = "job",
+            job = name;
+            "\n=== {} {} ===",
+            "JOB".bold().underline(),
+            name.bold()
+        );
+
+        let mut run = args.run.clone();
+        run.filter.skip_dirs.extend(job.skip.iter().cloned());
+        run.ignore_changes.extend(job.ignore.iter().cloned());
+        run.pre.splice(0..0, job.pre.iter().cloned());
+        run.post.extend(job.post.iter().cloned());
+        if job.policy.is_some() {
+            run.policy = job.policy.clone();
+        }
+
+        let smart = job.smart.as_ref().map(|disks| (name, disks));
+        let (code, smart_worse) = if verify {
+            execute_watched(
+                &job.root,
+                &run,
+                std::slice::from_ref(&job.manifest),
+                None,
+                smart,
+            )
+        } else {
+            execute_watched(&job.root, &run, &[], Some(&job.manifest), smart)
+        };
+        if code != 0 || smart_worse {
+            notify(name, job, code, smart_worse);
+        }
+        worst = worst.max(code);
+    }
+    worst
+}
+
+/// Prints the configured jobs.
+pub fn list(config: Option<&Path>) -> i32 {
+    let Some(config) = load(config) else {
+        return 1;
+    };
+
+    if config.jobs.is_empty() {
+        info!("No jobs configured");
+    }
+    for (name, job) in &config.jobs {
+        info!(
+            event = "job",
+            job = name.as_str(),
+            root:% = job.root.display(),
+            manifest:% = job.manifest.display();
+            "{}\n  root:     {}\n  manifest: {}{}{}{}{}{}",
+            name.bold(),
+            job.root.display(),
+            job.manifest.display(),
+            job.schedule
+                .as_ref()
+                .map(|s| format!("\n  schedule: {s}"))
+                .unwrap_or_default(),
+            if job.skip.is_empty() {
+                String::new()
+            } else {
+                format!("\n  skip:     {}", job.skip.join(", "))
+            },
+            if job.ignore.is_empty() {
+                String::new()
+            } else {
+                format!("\n  ignore:   {}", job.ignore.join(", "))
+            },
+            job.policy
+                .as_ref()
+                .map(|p| format!("\n  policy:   {}", p.display()))
+                .unwrap_or_default(),
+            job.smart
+                .as_ref()
+                .map(|d| format!("\n  smart:    {d}"))
+                .unwrap_or_default()
+        );
+    }
+    0
+}
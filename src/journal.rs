@@ -0,0 +1,253 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use colored::*;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::JournalArgs,
+    hash::{Digest, FileMeta},
+    style,
+};
+
+// With --journal, every --update appends what it changed in the reference to
+// a JSON lines file, one `Change` per line, so the history of a path can be
+// looked up long after the manifest moved on.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Moved,
+    Removed,
+}
+
+/// How one path differs between two manifests, and a line of the journal.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Change {
+    /// When the change was recorded, in seconds since the epoch
+    #[serde(default)]
+    pub time: u64,
+    pub change: ChangeKind,
+    pub path: String,
+    /// Hash of the contents now, none once removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Digest>,
+    /// Hash of the contents before, for changed and removed paths
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_hash: Option<Digest>,
+    /// Where a moved file was before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previously: Option<String>,
+}
+
+impl Change {
+    /// The marker, the path and whatever else there is to say about it.
+    fn describe(&self) -> String {
+        let mut line = match self.change {
+            ChangeKind::Added => {
+                format!("{} {}", style::pick("➕ ADDED", "ADDED").green(), self.path)
+            }
+            ChangeKind::Changed => format!(
+                "{} {}",
+                style::pick("✏️ CHANGED", "CHANGED").yellow(),
+                self.path
+            ),
+            ChangeKind::Moved => format!(
+                "{} {}",
+                style::pick("🔀 MOVED", "MOVED").yellow(),
+                self.path
+            ),
+            ChangeKind::Removed => format!(
+                "{} {}",
+                style::pick("➖ REMOVED", "REMOVED").magenta(),
+                self.path
+            ),
+        };
+        if let Some(from) = &self.previously {
+            line.push_str(&format!("\n  previously: {from}"));
+        }
+        if let Some(hash) = &self.previous_hash {
+            line.push_str(&format!("\n  was: {hash}"));
+        }
+        if let (Some(hash), Some(_)) = (&self.hash, &self.previous_hash) {
+            line.push_str(&format!("\n  now: {hash}"));
+        }
+        line
+    }
+
+    fn event(&self) -> &'static str {
+        match self.change {
+            ChangeKind::Added => "added",
+            ChangeKind::Changed => "changed",
+            ChangeKind::Moved => "moved",
+            ChangeKind::Removed => "removed",
+        }
+    }
+
+    /// Logs the change, after `prefix` (a time, say).
+    pub fn log(&self, prefix: &str) {
+        let hash = self.hash.map(|h| h.to_string());
+        let previous_hash = self.previous_hash.map(|h| h.to_string());
+        info!(
+            event = self.event(),
+            time = self.time,
+            path = self.path.as_str(),
+            hash = hash.as_deref(),
+            previous_hash = previous_hash.as_deref(),
+            previously = self.previously.as_deref();
+            "{prefix}{}",
+            self.describe()
+        );
+    }
+}
+
+/// What changed from `old` to `new`, new paths in order and then removed
+/// ones. A path that is gone while a new one has its contents was moved.
+pub fn changes(
+    old: &HashMap<String, FileMeta>,
+    new: &HashMap<String, FileMeta>,
+    time: u64,
+) -> Vec<Change> {
+    let change = |change, path: &str| Change {
+        time,
+        change,
+        path: path.to_string(),
+        hash: None,
+        previous_hash: None,
+        previously: None,
+    };
+
+    // Files of zero size all have the same hash, they don't count as moves
+    let mut gone_by_hash: HashMap<Digest, Vec<&str>> = HashMap::new();
+    for (path, meta) in old {
+        if !new.contains_key(path) && meta.size != 0 {
+            gone_by_hash.entry(meta.hash).or_default().push(path);
+        }
+    }
+    let mut moved_from = HashSet::new();
+
+    let mut changes = Vec::new();
+    let mut paths: Vec<&String> = new.keys().collect();
+    paths.sort_unstable();
+    for path in paths {
+        let meta = &new[path];
+        match old.get(path) {
+            Some(before) if before.hash == meta.hash => {}
+            Some(before) => changes.push(Change {
+                hash: Some(meta.hash),
+                previous_hash: Some(before.hash),
+                ..change(ChangeKind::Changed, path)
+            }),
+            None => match gone_by_hash.get_mut(&meta.hash).and_then(Vec::pop) {
+                Some(from) => {
+                    moved_from.insert(from);
+                    changes.push(Change {
+                        hash: Some(meta.hash),
+                        previously: Some(from.to_string()),
+                        ..change(ChangeKind::Moved, path)
+                    });
+                }
+                None => changes.push(Change {
+                    hash: Some(meta.hash),
+                    ..change(ChangeKind::Added, path)
+                }),
+            },
+        }
+    }
+
+    let mut removed: Vec<(&String, &FileMeta)> = old
+        .iter()
+        .filter(|(p, _)| !new.contains_key(*p) && !moved_from.contains(p.as_str()))
+        .collect();
+    removed.sort_unstable_by_key(|(p, _)| *p);
+    for (path, meta) in removed {
+        changes.push(Change {
+            previous_hash: Some(meta.hash),
+            ..change(ChangeKind::Removed, path)
+        });
+    }
+    changes
+}
+
+/// Appends `changes` to the journal at `path`, creating it if needed.
+pub fn append(path: &Path, changes: &[Change]) -> io::Result<()> {
+    let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(with_path)?;
+    let mut out = io::BufWriter::new(file);
+    for change in changes {
+        let line = serde_json::to_string(change).expect("changes serialize");
+        writeln!(out, "{line}").map_err(with_path)?;
+    }
+    out.into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|f| f.sync_all())
+        .map_err(with_path)
+}
+
+/// Whether `change` is about `path`, or something under it.
+fn concerns(change: &Change, path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    [Some(&change.path), change.previously.as_ref()]
+        .into_iter()
+        .flatten()
+        .any(|p| {
+            p == path
+                || p.strip_prefix(path)
+                    .is_some_and(|rest| rest.starts_with('/') || rest.starts_with('\\'))
+        })
+}
+
+fn query(args: &JournalArgs) -> io::Result<usize> {
+    let with_path =
+        |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", args.journal.display(), e));
+    let file = fs::File::open(&args.journal).map_err(with_path)?;
+
+    let mut found = 0;
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(with_path)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let change: Change = serde_json::from_str(&line).map_err(|e| {
+            with_path(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: {e}", n + 1),
+            ))
+        })?;
+        if !args.paths.is_empty() && !args.paths.iter().any(|p| concerns(&change, p)) {
+            continue;
+        }
+        found += 1;
+        let time = humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(change.time));
+        change.log(&format!("{} ", time.to_string().dimmed()));
+    }
+    Ok(found)
+}
+
+/// `journal`: prints the recorded changes, all of them or those of the
+/// given paths. Exit code 0, or 1 if the journal can't be read.
+pub fn run(args: &JournalArgs) -> i32 {
+    match query(args) {
+        Ok(0) => {
+            info!("No changes recorded");
+            0
+        }
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    borrow::Cow,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    cli::IndexArgs,
+    crypt,
+    hash::{Digest, FileMeta},
+    manifest::{Manifest, Totals},
+    store::{self, with_path},
+    style,
+    verify::{Entry, Reference},
+};
+
+// Manifests written with --format kv are a sled database, a directory,
+// instead of one JSON file, for trees too big to hold a manifest of in
+// memory. Entries are keyed by path in `files` and indexed by contents in
+// `by_hash` (`<hash> NUL <path>`), so `verify` looks each file up as it is
+// hashed, finds moved contents with a prefix scan and walks `files` in path
+// order for what's missing, without ever loading the whole reference. The
+// rest of the manifest (root, creation time, volume, special files) is kept
+// as JSON under `header`, written last: a store without one wasn't
+// finished.
+//
+// `checkyoself index` makes the same kind of store of a JSON manifest,
+// `<manifest>.index`, which `--verify <manifest>` then uses in place of
+// loading it. The BLAKE3 of the manifest it was made of is kept under
+// `source`, an index of an older manifest is ignored.
+
+const HEADER: &[u8] = b"header";
+const SOURCE: &[u8] = b"source";
+
+/// What sled caches of a store at most, instead of its 1 GiB default.
+/// Lookups of a store too big for memory mostly miss anyway.
+const CACHE_BYTES: u64 = 64 * 1024 * 1024;
+
+fn db_error(e: sled::Error) -> io::Error {
+    match e {
+        sled::Error::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Whether `path` is a store rather than a JSON manifest.
+pub fn is_store(path: &Path) -> bool {
+    path.join("conf").is_file() && path.join("db").is_file()
+}
+
+/// Where the index of the JSON manifest at `manifest` goes.
+pub fn index_path(manifest: &Path) -> PathBuf {
+    let mut path = manifest.as_os_str().to_owned();
+    path.push(".index");
+    PathBuf::from(path)
+}
+
+/// The key of `path` in `by_hash`, with the hash in hex as stores have
+/// always had it.
+fn hash_key(hash: &Digest, path: &str) -> Vec<u8> {
+    let hex = hash.to_hex();
+    let mut key = Vec::with_capacity(hex.len() + 1 + path.len());
+    key.extend_from_slice(hex.as_bytes());
+    key.push(0);
+    key.extend_from_slice(path.as_bytes());
+    key
+}
+
+pub struct Store {
+    path: PathBuf,
+    db: sled::Db,
+    files: sled::Tree,
+    by_hash: sled::Tree,
+}
+
+impl Store {
+    fn at(path: &Path) -> io::Result<Store> {
+        let db = sled::Config::new()
+            .path(path)
+            .cache_capacity(CACHE_BYTES)
+            .open()
+            .map_err(db_error)
+            .map_err(|e| with_path(path, e))?;
+        let tree = |name| db.open_tree(name).map_err(db_error);
+        Ok(Store {
+            path: path.to_path_buf(),
+            files: tree("files")?,
+            by_hash: tree("by_hash")?,
+            db,
+        })
+    }
+
+    /// Creates an empty store at `path`, replacing the one there.
+    pub fn create(path: &Path) -> io::Result<Store> {
+        if path.exists() {
+            if !is_store(path) {
+                return Err(with_path(
+                    path,
+                    io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        "exists and isn't a --format kv manifest",
+                    ),
+                ));
+            }
+            fs::remove_dir_all(path).map_err(|e| with_path(path, e))?;
+        }
+        Store::at(path)
+    }
+
+    /// Opens the finished store at `path`, along with its header.
+    pub fn open(path: &Path) -> io::Result<(Store, Manifest)> {
+        let store = Store::at(path)?;
+        let header = store.db.get(HEADER).map_err(db_error)?.ok_or_else(|| {
+            with_path(
+                path,
+                invalid("unfinished manifest, its creation was interrupted".to_string()),
+            )
+        })?;
+        let header = std::str::from_utf8(&header)
+            .map_err(|e| invalid(e.to_string()))
+            .and_then(Manifest::from_json)
+            .map_err(|e| with_path(path, e))?;
+        Ok((store, header))
+    }
+
+    /// Adds the entry of `path`. Safe to call from several threads.
+    pub fn add(&self, path: &str, meta: &FileMeta) -> io::Result<()> {
+        let value = serde_json::to_vec(meta)?;
+        if let Some(old) = self.files.insert(path, value).map_err(db_error)? {
+            let old: FileMeta = serde_json::from_slice(&old)?;
+            self.by_hash
+                .remove(hash_key(&old.hash, path))
+                .map_err(db_error)?;
+        }
+        self.by_hash
+            .insert(hash_key(&meta.hash, path), &[])
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Writes the header, `manifest` without its files, and flushes
+    /// everything to disk.
+    pub fn finish(self, manifest: &Manifest) -> io::Result<()> {
+        let header = serde_json::to_vec(manifest)?;
+        self.db.insert(HEADER, header).map_err(db_error)?;
+        self.db
+            .flush()
+            .map_err(db_error)
+            .map_err(|e| with_path(&self.path, e))?;
+        Ok(())
+    }
+
+    /// The BLAKE3 of the manifest this is an index of.
+    pub fn source(&self) -> io::Result<Option<String>> {
+        let source = self.db.get(SOURCE).map_err(db_error)?;
+        Ok(source.map(|s| String::from_utf8_lossy(&s).into_owned()))
+    }
+
+    fn entry(&self, path: &[u8], value: &[u8]) -> io::Result<(String, FileMeta)> {
+        let path = String::from_utf8(path.to_vec())
+            .map_err(|e| with_path(&self.path, invalid(e.to_string())))?;
+        let meta = serde_json::from_slice(value)
+            .map_err(|e| with_path(&self.path, invalid(format!("{path}: {e}"))))?;
+        Ok((path, meta))
+    }
+}
+
+impl<'a> Reference<'a> for Store {
+    fn get(&self, path: &str) -> io::Result<Option<Entry<'a>>> {
+        let Some(value) = self.files.get(path).map_err(db_error)? else {
+            return Ok(None);
+        };
+        let (path, meta) = self.entry(path.as_bytes(), &value)?;
+        Ok(Some((Cow::Owned(path), Cow::Owned(meta))))
+    }
+
+    fn paths_with_hash(&self, hash: &Digest) -> io::Result<Vec<Cow<'a, str>>> {
+        let prefix = hash_key(hash, "");
+        self.by_hash
+            .scan_prefix(&prefix)
+            .keys()
+            .map(|key| {
+                let key = key.map_err(db_error)?;
+                let path = String::from_utf8(key[prefix.len()..].to_vec())
+                    .map_err(|e| with_path(&self.path, invalid(e.to_string())))?;
+                Ok(Cow::Owned(path))
+            })
+            .collect()
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &FileMeta)) -> io::Result<()> {
+        for item in self.files.iter() {
+            let (path, value) = item.map_err(db_error)?;
+            let (path, meta) = self.entry(&path, &value)?;
+            f(&path, &meta);
+        }
+        Ok(())
+    }
+}
+
+/// Makes the index of the manifest `args.manifest`.
+fn index(args: &IndexArgs) -> io::Result<PathBuf> {
+    let path = &args.manifest;
+    let data = fs::read(path).map_err(|e| with_path(path, e))?;
+    // The index would be its contents in the clear
+    if crypt::is_encrypted(&data) {
+        return Err(with_path(
+            path,
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "is encrypted, an index of it wouldn't be",
+            ),
+        ));
+    }
+    let source = blake3::hash(&data).to_hex();
+    let mut manifest = store::decode(path, data, None)?;
+    // The index is only checked against the manifest itself, an included
+    // one could change under it unnoticed
+    if !manifest.includes.is_empty() {
+        return Err(with_path(
+            path,
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "includes other manifests, only one without includes can be indexed",
+            ),
+        ));
+    }
+
+    let index = index_path(path);
+    let store = Store::create(&index)?;
+    let totals = Totals::of(&manifest.files);
+    for (path, meta) in std::mem::take(&mut manifest.files) {
+        store.add(&path, &meta)?;
+    }
+    store
+        .db
+        .insert(SOURCE, source.as_bytes())
+        .map_err(db_error)?;
+    manifest.totals = Some(totals);
+    manifest.checksum = None;
+    store.finish(&manifest)?;
+    Ok(index)
+}
+
+/// `index`: exit code 0, or 1 on errors.
+pub fn run(args: &IndexArgs) -> i32 {
+    match index(args) {
+        Ok(index) => {
+            info!(
+                event = "indexed",
+                index:% = index.display();
+                "{} Index written to {}",
+                style::pick("📇", "*"),
+                index.display()
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
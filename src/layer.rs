@@ -0,0 +1,299 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+use colored::*;
+use log::{debug, error, info};
+use sha2::{Digest as _, Sha256};
+
+use crate::{cli::LayerCheckArgs, hash::Digest, store, style};
+
+// `layer-check` compares a manifest with a container image layer, an
+// uncompressed tar as `docker save` and OCI image layouts have them (a
+// gzipped one goes through `gunzip` first). The layer's digest is the
+// SHA-256 of the tar as a whole, its DiffID: what the image config lists
+// under rootfs.diff_ids, and --diff-id checks it against one. Files in it
+// are compared with the manifest's entries by BLAKE3, hashed as the tar
+// streams by, by path relative to the root. Whiteouts (`.wh.` files, a
+// deletion in the layers above) and everything but regular files and hard
+// links to them are left out.
+
+const BLOCK: usize = 512;
+
+/// Reads through to the end of `reader`, hashing every byte on the way.
+struct Tee<R> {
+    reader: R,
+    sha256: Sha256,
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        self.sha256.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// A header field, up to its first NUL.
+fn field(header: &[u8]) -> &[u8] {
+    let end = header.iter().position(|&b| b == 0).unwrap_or(header.len());
+    &header[..end]
+}
+
+/// A numeric header field: octal, or big-endian binary when its top bit is
+/// set (GNU, for sizes of 8 GiB and up).
+fn number(header: &[u8]) -> io::Result<u64> {
+    if header.first().is_some_and(|b| b & 0x80 != 0) {
+        return Ok(header[1..]
+            .iter()
+            .fold(0u64, |n, &b| n.wrapping_shl(8) | u64::from(b)));
+    }
+    let digits = String::from_utf8_lossy(field(header));
+    let digits = digits.trim_matches(|c: char| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad number in tar header"))
+}
+
+/// Reads exactly `len` bytes into `out` and then the padding to the next
+/// block.
+fn data(reader: &mut impl Read, len: u64, mut out: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let want = left.min(buffer.len() as u64) as usize;
+        reader.read_exact(&mut buffer[..want])?;
+        out(&buffer[..want]);
+        left -= want as u64;
+    }
+    let padding = (BLOCK as u64 - len % BLOCK as u64) % BLOCK as u64;
+    reader.read_exact(&mut buffer[..padding as usize])
+}
+
+/// The pax records `path` and `size` of an extended header.
+fn pax(records: &[u8]) -> (Option<String>, Option<u64>) {
+    let (mut path, mut size) = (None, None);
+    let mut rest = records;
+    while let Some(space) = rest.iter().position(|&b| b == b' ') {
+        let Some(len) = std::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space && len <= rest.len())
+        else {
+            break;
+        };
+        let record = &rest[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(eq) = record.iter().position(|&b| b == b'=') {
+            let value = String::from_utf8_lossy(&record[eq + 1..]);
+            match &record[..eq] {
+                b"path" => path = Some(value.into_owned()),
+                b"size" => size = value.parse().ok(),
+                _ => {}
+            }
+        }
+        rest = &rest[len..];
+    }
+    (path, size)
+}
+
+/// The regular files of the layer at `path` by BLAKE3, and its DiffID.
+fn read_layer(path: &Path) -> io::Result<(BTreeMap<String, Digest>, String)> {
+    let mut tar = Tee {
+        reader: BufReader::new(File::open(path)?),
+        sha256: Sha256::new(),
+    };
+    let mut files = BTreeMap::new();
+    let mut header = [0u8; BLOCK];
+    // Set by pax and GNU long name headers for the entry after them
+    let (mut long_name, mut long_size) = (None, None);
+    loop {
+        match tar.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            result => result?,
+        }
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let kind = header[156];
+        let size = long_size
+            .take()
+            .map_or_else(|| number(&header[124..136]), Ok)?;
+        match kind {
+            b'x' | b'L' => {
+                let mut records = Vec::new();
+                data(&mut tar, size, |data| records.extend_from_slice(data))?;
+                if kind == b'L' {
+                    long_name = Some(String::from_utf8_lossy(field(&records)).into_owned());
+                } else {
+                    let (path, size) = pax(&records);
+                    long_name = path.or(long_name);
+                    long_size = size;
+                }
+                continue;
+            }
+            _ => {}
+        }
+        let name = long_name.take().unwrap_or_else(|| {
+            let name = String::from_utf8_lossy(field(&header[..100]));
+            let prefix = field(&header[345..500]);
+            match &header[257..262] == b"ustar" && !prefix.is_empty() {
+                true => format!("{}/{name}", String::from_utf8_lossy(prefix)),
+                false => name.into_owned(),
+            }
+        });
+        let name = name.trim_start_matches("./").trim_start_matches('/');
+        let whiteout = Path::new(name)
+            .file_name()
+            .is_some_and(|n| n.to_string_lossy().starts_with(".wh."));
+        if kind == b'1' {
+            // A hard link has the contents of what it links to, listed
+            // before it
+            let target = String::from_utf8_lossy(field(&header[157..257]));
+            let target = target.trim_start_matches("./").trim_start_matches('/');
+            if let Some(&hash) = files.get(target) {
+                files.insert(name.to_string(), hash);
+            }
+            data(&mut tar, size, |_| {})?;
+        } else if matches!(kind, b'0' | 0 | b'7') && !whiteout {
+            let mut hasher = blake3::Hasher::new();
+            data(&mut tar, size, |data| {
+                hasher.update(data);
+            })?;
+            files.insert(name.to_string(), hasher.finalize().into());
+        } else {
+            debug!(event = "layer_skipped", path = name; "{name}: not a regular file, left out");
+            data(&mut tar, size, |_| {})?;
+        }
+    }
+    // The end of archive blocks count too
+    io::copy(&mut tar, &mut io::sink())?;
+    Ok((files, format!("sha256:{:x}", tar.sha256.finalize())))
+}
+
+#[derive(Default)]
+struct Counts {
+    matched: usize,
+    mismatched: usize,
+    missing: usize,
+    extra: usize,
+}
+
+fn check(args: &LayerCheckArgs) -> io::Result<(Counts, bool)> {
+    let with_path =
+        |path: &Path, e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path.display(), e));
+    let data = std::fs::read(&args.manifest).map_err(|e| with_path(&args.manifest, e))?;
+    let manifest = store::decode(&args.manifest, data, args.identity.as_deref())?;
+    let (mut layer, diff_id) = read_layer(&args.layer).map_err(|e| with_path(&args.layer, e))?;
+    if let Some(prefix) = &args.prefix {
+        let prefix = format!("{}/", prefix.trim_matches('/'));
+        layer = layer
+            .into_iter()
+            .filter_map(|(path, hash)| Some((path.strip_prefix(&prefix)?.to_string(), hash)))
+            .collect();
+    }
+
+    info!(
+        event = "layer",
+        diff_id = diff_id.as_str();
+        "{} {}: {diff_id}",
+        style::pick("📦", "LAYER").bold(),
+        args.layer.display()
+    );
+    let diff_id_ok = match &args.diff_id {
+        Some(expected) if *expected != diff_id => {
+            error!(
+                event = "diff_id_mismatch",
+                expected = expected.as_str(),
+                found = diff_id.as_str();
+                "{} DiffID of {}\n  expected: {expected}\n  found:    {diff_id}",
+                style::pick("❌ MISMATCH", "MISMATCH").red(),
+                args.layer.display()
+            );
+            false
+        }
+        _ => true,
+    };
+
+    let root = manifest.root.clone().unwrap_or_default();
+    let mut counts = Counts::default();
+    let mut seen = BTreeSet::new();
+    let mut files: Vec<(&str, &Digest)> = manifest
+        .files
+        .iter()
+        .map(|(path, meta)| {
+            let relative = Path::new(path)
+                .strip_prefix(&root)
+                .ok()
+                .and_then(|p| p.to_str())
+                .unwrap_or(path);
+            (relative, &meta.hash)
+        })
+        .collect();
+    files.sort_unstable_by_key(|(path, _)| *path);
+    for (path, expected) in files {
+        let Some(found) = layer.get(path) else {
+            counts.missing += 1;
+            error!(event = "missing", path = path; "{} {path}", style::pick("❓ MISSING", "MISSING").magenta());
+            continue;
+        };
+        seen.insert(path);
+        if found == expected {
+            counts.matched += 1;
+            debug!(event = "matched", path = path; "{} {path}", style::pick("✅ MATCHED", "MATCHED").green());
+        } else {
+            counts.mismatched += 1;
+            error!(
+                event = "mismatch",
+                path = path,
+                expected:% = expected,
+                found:% = found;
+                "{} {path}\n  expected: {expected}\n  found:    {found}",
+                style::pick("❌ MISMATCH", "MISMATCH").red()
+            );
+        }
+    }
+    for path in layer.keys() {
+        if !seen.contains(path.as_str()) {
+            counts.extra += 1;
+            info!(event = "extra", path = path.as_str(); "{} {path}", style::pick("⚠️ EXTRA", "EXTRA").blue());
+        }
+    }
+
+    info!(
+        event = "layer_summary",
+        matched = counts.matched,
+        mismatched = counts.mismatched,
+        missing = counts.missing,
+        extra = counts.extra;
+        "\n=== {} ===\n{} {}\n{} {}\n{} {}\n{} {}",
+        "LAYER".bold().underline(),
+        style::pick("✅ Matched:", "Matched:").green(),
+        counts.matched,
+        style::pick("❌ Mismatched:", "Mismatched:").red(),
+        counts.mismatched,
+        style::pick("❓ Missing:", "Missing:").magenta(),
+        counts.missing,
+        style::pick("⚠️ Extra:", "Extra:").blue(),
+        counts.extra
+    );
+    Ok((counts, diff_id_ok))
+}
+
+/// `layer-check`: exit code 0, 2 if anything is missing or differs or the
+/// DiffID isn't --diff-id, 1 on errors.
+pub fn run(args: &LayerCheckArgs) -> i32 {
+    match check(args) {
+        Ok((counts, diff_id_ok)) if counts.mismatched > 0 || counts.missing > 0 || !diff_id_ok => 2,
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use log::{
+    Level, LevelFilter, Log, Metadata, Record,
+    kv::{self, Key, Value, VisitSource, VisitValue},
+};
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{Map, Number, Value as Json};
+
+use crate::{
+    eventlog::{self, EventLog},
+    template::Template,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Maps the command line verbosity onto a log level.
+///
+/// `-q` only lets errors (mismatches) through, the default shows findings
+/// and the summary, `-v` adds matched files, `-vv` adds every hashed file.
+pub fn level_for(quiet: bool, verbose: u8) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Error;
+    }
+    match verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+/// Console output goes to stderr, stdout being taken by the `agent` protocol
+/// or --tap
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Records with this target only go to the `--log-file`, they describe the
+/// run itself (start, parameters, exit code) rather than findings.
+pub const RUN_TARGET: &str = "checkyoself::run";
+
+struct Logger {
+    format: LogFormat,
+    console_level: LevelFilter,
+    file: Option<Mutex<File>>,
+    file_level: LevelFilter,
+    /// --event-log
+    event_log: Option<EventLog>,
+    /// --line-format, for text lines about a file
+    template: Option<Template>,
+}
+
+/// Collects a record's key/values into a JSON object.
+struct JsonFields<'a>(&'a mut Map<String, Json>);
+
+/// Spots `None` values, which are left out of JSON records.
+struct IsNull(bool);
+
+impl<'v> VisitValue<'v> for IsNull {
+    fn visit_any(&mut self, _: Value) -> Result<(), kv::Error> {
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = true;
+        Ok(())
+    }
+}
+
+/// The field `key` of a record, as text, unless it has none or it's `None`.
+pub fn field(fields: &dyn kv::Source, key: &str) -> Option<String> {
+    let value = fields.get(Key::from_str(key))?;
+    let mut null = IsNull(false);
+    let _ = value.visit(&mut null);
+    (!null.0).then(|| value.to_string())
+}
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let mut null = IsNull(false);
+        let _ = value.visit(&mut null);
+        if null.0 {
+            return Ok(());
+        }
+        let json = if let Some(v) = value.to_u64() {
+            Json::from(v)
+        } else if let Some(v) = value.to_i64() {
+            Json::from(v)
+        } else if let Some(v) = value.to_f64() {
+            Number::from_f64(v).map_or(Json::Null, Json::Number)
+        } else if let Some(v) = value.to_bool() {
+            Json::from(v)
+        } else if let Some(v) = value.to_borrowed_str() {
+            Json::from(v)
+        } else {
+            Json::from(value.to_string())
+        };
+        self.0.insert(key.to_string(), json);
+        Ok(())
+    }
+}
+
+/// One line of `--log-format json` output (and of a JSON `--log-file`).
+/// Other fields depend on the record: `event` names what happened
+/// (`mismatch`, `moved`, `extra`, `missing`, `summary`, ...) and the rest
+/// describe it, e.g. `path`, `expected` and `found`.
+#[derive(Serialize, JsonSchema)]
+pub struct JsonRecord {
+    /// When the record was made, in seconds since the epoch
+    pub ts: Option<f64>,
+    /// error, warn, info, debug or trace
+    pub level: String,
+    /// The text the console would have shown, without colors
+    pub msg: String,
+    #[serde(flatten)]
+    pub fields: Map<String, Json>,
+}
+
+impl Logger {
+    fn json_line(&self, record: &Record) -> String {
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut fields = Map::new();
+        let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+        let line = JsonRecord {
+            ts: Some(ts).filter(|t| t.is_finite()),
+            level: record.level().as_str().to_lowercase(),
+            msg: strip_ansi(&record.args().to_string()),
+            fields,
+        };
+        serde_json::to_string(&line).expect("JSON records always serialize")
+    }
+
+    /// The text of a record, as --line-format has it if it's about a file.
+    fn text(&self, record: &Record) -> String {
+        self.template
+            .as_ref()
+            .and_then(|t| t.render(record.key_values()))
+            .unwrap_or_else(|| record.args().to_string())
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Libraries (sled) only get to warn, their debugging isn't ours
+        let ours = metadata.target().starts_with(env!("CARGO_CRATE_NAME"));
+        metadata.level() <= log::max_level() && (ours || metadata.level() <= Level::Warn)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let to_console = record.level() <= self.console_level && record.target() != RUN_TARGET;
+        let to_file = self.file.is_some() && record.level() <= self.file_level;
+
+        if to_console {
+            let line = match self.format {
+                LogFormat::Text => self.text(record),
+                LogFormat::Json => self.json_line(record),
+            };
+
+            if STDERR.load(Ordering::Relaxed) {
+                let _ = writeln!(io::stderr(), "{line}");
+            } else {
+                // Findings are the tool's output, so they go to stdout like
+                // they always have; only a broken pipe can stop them.
+                let mut out = io::stdout().lock();
+                let _ = writeln!(out, "{line}");
+                if record.level() <= Level::Warn {
+                    let _ = out.flush();
+                }
+            }
+        }
+
+        if let (true, Some(file)) = (to_file, &self.file) {
+            let line = match self.format {
+                LogFormat::Text => format!(
+                    "{} {:<5} {}",
+                    humantime::format_rfc3339_seconds(SystemTime::now()),
+                    record.level(),
+                    strip_ansi(&self.text(record)).trim_start_matches('\n')
+                ),
+                LogFormat::Json => self.json_line(record),
+            };
+            let _ = writeln!(file.lock().unwrap(), "{line}");
+        }
+
+        if let Some(event_log) = &self.event_log {
+            let event = record.key_values().get(Key::from_str("event"));
+            let event = event.as_ref().and_then(|e| e.to_borrowed_str());
+            if record.target() != RUN_TARGET && eventlog::wanted(record.level(), event) {
+                let msg = strip_ansi(&record.args().to_string());
+                // Nowhere to say the event log is failing but the console
+                let _ = event_log.report(record.level(), msg.trim_start_matches('\n'));
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = io::stdout().flush();
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Removes terminal color escape sequences, which the file log and JSON
+/// output should never contain regardless of what the console does.
+fn strip_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end with a byte in the range '@'..='~'
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) && c != '[' {
+                    break;
+                }
+            }
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+/// True when console output is JSON lines, so nothing else may be printed
+/// to stdout.
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Sends console output to stderr from now on.
+pub fn console_to_stderr() {
+    STDERR.store(true, Ordering::Relaxed);
+}
+
+/// True when console output goes to stderr.
+pub fn is_stderr() -> bool {
+    STDERR.load(Ordering::Relaxed)
+}
+
+/// Installs the logger. `log_file`, when given, is opened for appending and
+/// receives every record from info level up no matter how quiet the console
+/// is; with `event_log` warnings, errors and summaries also go to the
+/// Windows Event Log. Text lines about a file are made from `line_format`
+/// when given, see `template`.
+pub fn init(
+    level: LevelFilter,
+    format: LogFormat,
+    log_file: Option<&Path>,
+    event_log: bool,
+    line_format: Option<&str>,
+) -> io::Result<()> {
+    let template = line_format
+        .map(Template::parse)
+        .transpose()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if format == LogFormat::Json {
+        // Escape codes have no business inside JSON strings
+        colored::control::set_override(false);
+        JSON.store(true, Ordering::Relaxed);
+    }
+
+    let file = log_file
+        .map(|p| OpenOptions::new().create(true).append(true).open(p))
+        .transpose()
+        .map_err(|e| io::Error::new(e.kind(), format!("can't open log file: {e}")))?
+        .map(Mutex::new);
+    let event_log = event_log
+        .then(EventLog::open)
+        .transpose()
+        .map_err(|e| io::Error::new(e.kind(), format!("can't open the event log: {e}")))?;
+    let file_level = level.max(LevelFilter::Info);
+    // Summaries are at info level, the event log needs them however quiet
+    // the console is
+    let max_level = if file.is_some() || event_log.is_some() {
+        file_level
+    } else {
+        level
+    };
+
+    let logger = Logger {
+        format,
+        console_level: level,
+        file,
+        file_level,
+        event_log,
+        template,
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+    Ok(())
+}
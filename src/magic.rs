@@ -0,0 +1,119 @@
+// --magic records what type a file's contents are, as a MIME type, from
+// the few bytes most formats start with ("magic numbers"), whatever its
+// extension says. A JPEG that changed is usually an edited photo; one
+// that no longer starts like a JPEG was overwritten, encrypted or cut
+// short, which says more than the hash alone. Only the start of a file is
+// looked at, and only the formats below are told apart: other contents are
+// text/plain when they read as UTF-8 without NULs, or
+// application/octet-stream.
+
+use std::{fs::File, io::Read, path::Path};
+
+/// How much of the start of a file `detect` needs.
+pub const HEAD: usize = 512;
+
+/// Bytes at an offset, and the type contents that have them are.
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"II*\0", "image/tiff"),
+    (0, b"MM\0*", "image/tiff"),
+    (0, b"BM", "image/bmp"),
+    (0, b"\0\0\x01\0", "image/vnd.microsoft.icon"),
+    (0, b"8BPS", "image/vnd.adobe.photoshop"),
+    (0, b"\0\0\0\x0cjP  \r\n\x87\n", "image/jp2"),
+    (0, b"\x1a\x45\xdf\xa3", "video/x-matroska"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"\xff\xfb", "audio/mpeg"),
+    (0, b"\xff\xf3", "audio/mpeg"),
+    (0, b"\xff\xf2", "audio/mpeg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"MThd", "audio/midi"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"%!PS", "application/postscript"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"PK\x05\x06", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\0", "application/x-xz"),
+    (0, b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (0, b"Rar!\x1a\x07", "application/vnd.rar"),
+    (257, b"ustar", "application/x-tar"),
+    (0, b"\x7fELF", "application/x-executable"),
+    (0, b"MZ", "application/vnd.microsoft.portable-executable"),
+    (
+        0,
+        b"\xd0\xcf\x11\xe0\xa1\xb1\x1a\xe1",
+        "application/x-ole-storage",
+    ),
+    (0, b"SQLite format 3\0", "application/vnd.sqlite3"),
+    (0, b"wOFF", "font/woff"),
+    (0, b"wOF2", "font/woff2"),
+    (0, b"{\\rtf", "text/rtf"),
+    (0, b"<?xml", "application/xml"),
+];
+
+/// The type of contents that start with `head` (`HEAD` bytes of them, or
+/// all of them if fewer).
+pub fn detect(head: &[u8]) -> &'static str {
+    if head.is_empty() {
+        return "application/x-empty";
+    }
+    if let Some((_, _, mime)) = SIGNATURES
+        .iter()
+        .find(|(at, magic, _)| head.get(*at..).is_some_and(|h| h.starts_with(magic)))
+    {
+        return mime;
+    }
+    // RIFF and ISO media files say what they hold a few bytes in
+    if head.starts_with(b"RIFF") {
+        match head.get(8..12) {
+            Some(b"WEBP") => return "image/webp",
+            Some(b"WAVE") => return "audio/wav",
+            Some(b"AVI ") => return "video/x-msvideo",
+            _ => {}
+        }
+    }
+    if head.get(4..8) == Some(b"ftyp") {
+        return match head.get(8..12) {
+            Some(b"heic" | b"heix" | b"mif1" | b"msf1") => "image/heic",
+            Some(b"avif") => "image/avif",
+            Some(b"qt  ") => "video/quicktime",
+            Some(b"M4A ") => "audio/mp4",
+            _ => "video/mp4",
+        };
+    }
+    if head.starts_with(b"\xff\xfe") || head.starts_with(b"\xfe\xff") {
+        return "text/plain";
+    }
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // A character cut off where the head ends is still text
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return "application/octet-stream",
+    };
+    if text.contains('\0') {
+        return "application/octet-stream";
+    }
+    let start = text.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        "text/html"
+    } else {
+        "text/plain"
+    }
+}
+
+/// The type of the file at `path`, None if it can't be read.
+pub fn of_file(path: &Path) -> Option<&'static str> {
+    let mut head = Vec::with_capacity(HEAD);
+    File::open(path)
+        .and_then(|f| f.take(HEAD as u64).read_to_end(&mut head))
+        .ok()?;
+    Some(detect(&head))
+}
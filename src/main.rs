@@ -1,314 +1,924 @@
 use std::{
-    collections::HashMap,
-    env,
-    fs::{self, File},
-    io::{BufReader, Read},
+    collections::{BTreeMap, HashMap, HashSet},
+    io::Write,
     path::{Path, PathBuf},
     process::exit,
-    sync::{Arc, Mutex},
+    sync::Mutex,
 };
 
+use clap::{CommandFactory, Parser};
 use colored::*;
-use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
-use serde::{Deserialize, Serialize};
-use walkdir::WalkDir;
+use log::{debug, info, warn};
+
+mod ads;
+mod agent;
+mod bench;
+mod busy;
+mod bytediff;
+mod catalog;
+mod chain;
+mod cli;
+mod cloud;
+mod config;
+mod crypt;
+mod dbus;
+mod dedup;
+mod devices;
+mod dirhash;
+mod doctor;
+mod dupes;
+mod entropy;
+mod eventlog;
+mod export;
+mod extents;
+mod find;
+mod fleet;
+mod fscheck;
+mod gpg;
+mod hash;
+mod health;
+mod hooks;
+mod integrity;
+mod jobs;
+mod journal;
+mod kv;
+mod layer;
+mod logging;
+mod magic;
+mod manifest;
+mod mass;
+mod merkle;
+mod packages;
+mod policy;
+mod progress;
+mod reread;
+mod restore;
+mod sample;
+mod scan;
+mod secrets;
+mod sfv;
+mod sign;
+mod smart;
+mod snapshot;
+mod spill;
+mod split;
+mod stats;
+mod store;
+mod style;
+mod sync;
+mod tags;
+mod template;
+mod text;
+mod timestamp;
+mod tree;
+mod unicode;
+mod verify;
+mod volume;
+mod vss;
+mod whats_new;
+mod xattrs;
+
+use cli::{
+    CatalogCommand, ChainCommand, Cli, Command, RunArgs, ScanArgs, SchemaKind, SfvCommand,
+    SnapshotCommand,
+};
+use hash::{FileMeta, HashOptions};
+use manifest::{
+    JsonStyle, Manifest, ManifestFormat, ManifestWriter, PathLayout, Special, Totals, Volume,
+};
+use policy::{Behavior, Policy};
+use progress::{Progress, ProgressFormat};
+
+fn main() {
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        Err(e) => {
+            let _ = e.print();
+            // Exit code 2 means "mismatches found", don't reuse it for usage
+            exit(if e.use_stderr() { 1 } else { 0 });
+        }
+    };
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct FileMeta {
-    hash: String,
-    modified: u64, // UNIX timestamp (secs since epoch),
-    size: i64,
-}
+    let out = &cli.output;
+    out.color.apply();
+    style::set_ascii(out.ascii);
+    if let Err(e) = logging::init(
+        logging::level_for(out.quiet, out.verbose),
+        out.log_format,
+        out.log_file.as_deref(),
+        out.event_log,
+        out.line_format.as_deref(),
+    ) {
+        eprintln!("Error: {e}");
+        exit(1);
+    }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct FileHashMap(HashMap<String, FileMeta>);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    info!(
+        target: logging::RUN_TARGET,
+        event = "start",
+        args = args.join(" ");
+        "run started: {}",
+        args.join(" ")
+    );
+
+    let code = match &cli.command {
+        Some(Command::Bench(args)) => match bench::run(args) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error: {e}");
+                1
+            }
+        },
+        Some(Command::Doctor(args)) => doctor::run(args),
+        Some(Command::Completions(args)) => {
+            clap_complete::generate(
+                args.shell,
+                &mut Cli::command(),
+                "checkyoself",
+                &mut std::io::stdout(),
+            );
+            0
+        }
+        Some(Command::Create(args)) => jobs::run(out.config.as_deref(), args, false),
+        Some(Command::Verify(args)) => jobs::run(out.config.as_deref(), args, true),
+        Some(Command::Jobs) => jobs::list(out.config.as_deref()),
+        Some(Command::Chain(ChainCommand::Verify(args))) => chain::verify(args),
+        Some(Command::Snapshot(SnapshotCommand::Create(args))) => snapshot::create(args),
+        Some(Command::Snapshot(SnapshotCommand::List(args))) => snapshot::list(args),
+        Some(Command::Snapshot(SnapshotCommand::Diff(args))) => snapshot::diff_command(args),
+        Some(Command::Snapshot(SnapshotCommand::Tag(args))) => snapshot::tag(args),
+        Some(Command::Snapshot(SnapshotCommand::Verify(args))) => snapshot::verify(args),
+        Some(Command::Snapshot(SnapshotCommand::Prune(args))) => snapshot::prune_command(args),
+        Some(Command::Snapshot(SnapshotCommand::Gc(args))) => snapshot::gc(args),
+        Some(Command::Catalog(CatalogCommand::Add(args))) => catalog::add(args),
+        Some(Command::Catalog(CatalogCommand::Verify(args))) => catalog::verify(args),
+        Some(Command::Catalog(CatalogCommand::List(args))) => catalog::list(args),
+        Some(Command::Find(args)) => find::run(args),
+        Some(Command::SyncCheck(args)) => sync::run(args),
+        Some(Command::Agent(args)) => agent::run(args),
+        Some(Command::Server(args)) => fleet::server(args),
+        Some(Command::Fleet(args)) => fleet::fleet(args),
+        Some(Command::Dbus(args)) => dbus::run(args, out.config.as_deref()),
+        Some(Command::CloudCheck(args)) => cloud::run(args),
+        Some(Command::TreeDigest(args)) => dirhash::run(args),
+        Some(Command::LayerCheck(args)) => layer::run(args),
+        Some(Command::Sfv(SfvCommand::Verify(args))) => sfv::verify_command(args),
+        Some(Command::Sfv(SfvCommand::Export(args))) => sfv::export_command(args),
+        Some(Command::Split(args)) => split::run(args),
+        Some(Command::WhatsNew(args)) => whats_new::run(args),
+        Some(Command::Journal(args)) => journal::run(args),
+        Some(Command::Dedup(args)) => dedup::run(args),
+        Some(Command::Dupes(args)) => dupes::run(args),
+        Some(Command::Stats(args)) => stats::run(args),
+        Some(Command::Export(args)) => export::run(args),
+        Some(Command::Tag(args)) => tags::run(args),
+        Some(Command::Index(args)) => kv::run(args),
+        Some(Command::Integrity(args)) => integrity::run(args),
+        Some(Command::Schema(args)) => {
+            let schema = match args.kind {
+                SchemaKind::Manifest => schemars::schema_for!(Manifest),
+                SchemaKind::Report => schemars::schema_for!(logging::JsonRecord),
+            };
+            let _ = writeln!(
+                std::io::stdout(),
+                "{}",
+                serde_json::to_string_pretty(&schema).expect("schemas serialize")
+            );
+            0
+        }
+        Some(Command::Keygen(args)) => match sign::keygen(&args.prefix, args.keyring.as_deref()) {
+            Ok((secret, public)) => {
+                info!(
+                    "Secret key written to {secret} (keep it private)\nPublic key written to {}",
+                    public.display()
+                );
+                0
+            }
+            Err(e) => {
+                eprintln!("Error: {e}");
+                1
+            }
+        },
+        None => run_scan(&cli.scan),
+    };
 
-fn file_metadata(path: &Path) -> std::io::Result<(u64, i64)> {
-    let metadata = fs::metadata(path)?;
+    info!(target: logging::RUN_TARGET, event = "end", exit_code = code; "run finished, exit code {code}");
+    log::logger().flush();
+    exit(code);
+}
 
-    let modified_secs = metadata
-        .modified()
-        .unwrap()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_secs();
+/// Creates or verifies a manifest, returning the exit code.
+fn run_scan(args: &ScanArgs) -> i32 {
+    let (Some(dir), true) = (
+        &args.dir,
+        args.output_file.is_some() || !args.verify.is_empty(),
+    ) else {
+        let _ = Cli::command().print_help();
+        return 1;
+    };
 
-    let file_size = metadata.len() as i64;
+    if args.run.update && args.verify.is_empty() {
+        eprintln!("Error: --update only makes sense with --verify");
+        return 1;
+    }
+    if args.run.update && args.verify.len() > 1 {
+        eprintln!("Error: --update takes a single --verify, it doesn't know which to update");
+        return 1;
+    }
+    if args.run.subdir.is_some() && args.verify.is_empty() {
+        eprintln!("Error: --subdir only makes sense with --verify");
+        return 1;
+    }
+    if !args.run.include.is_empty() && !args.verify.is_empty() {
+        eprintln!("Error: --include only applies when creating a manifest");
+        return 1;
+    }
+    if args.run.report_to.is_some() && args.verify.is_empty() {
+        eprintln!("Error: --report-to only makes sense with --verify");
+        return 1;
+    }
+    if args.run.tap && args.verify.is_empty() {
+        eprintln!("Error: --tap only makes sense with --verify");
+        return 1;
+    }
+    if (args.run.reread || args.run.reread_from.is_some()) && args.verify.is_empty() {
+        eprintln!("Error: --reread only makes sense with --verify");
+        return 1;
+    }
+    if args.run.compare_bytes.is_some() && args.verify.is_empty() {
+        eprintln!("Error: --compare-bytes only makes sense with --verify");
+        return 1;
+    }
+    if args.run.emit_restore.is_some() && args.verify.is_empty() {
+        eprintln!("Error: --emit-restore only makes sense with --verify");
+        return 1;
+    }
+    if args.run.dir_digests && args.run.format == ManifestFormat::Kv {
+        eprintln!("Error: --dir-digests can't be kept in a kv manifest, its entries stay on disk");
+        return 1;
+    }
+    if args.run.journal.is_some() && !args.run.update {
+        eprintln!("Error: --journal only makes sense with --update");
+        return 1;
+    }
 
-    Ok((modified_secs, file_size))
+    execute(dir, &args.run, &args.verify, args.output_file.as_deref())
 }
 
-fn calculate_blake3(path: &Path) -> std::io::Result<FileMeta> {
-    let (modified, size) = file_metadata(path)?;
-
-    let file = File::open(path)?;
-    let mut reader = BufReader::new(file);
-    let mut hasher = blake3::Hasher::new();
-    let mut buffer = [0u8; 8192];
+/// Scans `dir` and either verifies it against `verify_files` or writes a new
+/// manifest to `output_file`, between the --pre and --post commands.
+/// Returns the exit code.
+fn execute(dir: &Path, run: &RunArgs, verify_files: &[PathBuf], output_file: Option<&Path>) -> i32 {
+    execute_watched(dir, run, verify_files, output_file, None).0
+}
 
-    loop {
-        let n = reader.read(&mut buffer)?;
-        if n == 0 {
-            break;
-        }
-        hasher.update(&buffer[..n]);
+/// `execute`, reading the SMART data of the disks of job `smart` right
+/// before the scan and again after it: inside the --pre and --post
+/// commands, which may be what attaches them. The exit code, and whether
+/// any disk got worse.
+fn execute_watched(
+    dir: &Path,
+    run: &RunArgs,
+    verify_files: &[PathBuf],
+    output_file: Option<&Path>,
+    smart: Option<(&str, &smart::Disks)>,
+) -> (i32, bool) {
+    // Stdout is for the TAP stream, or the restore script
+    if run.tap || run.emit_restore.is_some() {
+        logging::console_to_stderr();
     }
-
-    Ok(FileMeta {
-        hash: hasher.finalize().to_hex().to_string(),
-        modified,
-        size,
-    })
+    let job = hooks::Job {
+        root: dir,
+        manifest: output_file.or(verify_files.first().map(PathBuf::as_path)),
+        verify: output_file.is_none(),
+    };
+    if let Err(e) = job.pre(&run.pre) {
+        eprintln!("Error: {e}");
+        return (1, false);
+    }
+    let before = smart.map(|(name, disks)| (name, smart::Before::read(disks.resolve(dir))));
+    let code = execute_scan(dir, run, verify_files, output_file);
+    let worse = before.is_some_and(|(name, before)| before.report(name, code));
+    (job.post(&run.post, code), worse)
 }
 
-fn walk_files(dir: &Path, skip_dirs: &[String]) -> Vec<PathBuf> {
-    WalkDir::new(dir)
-        .into_iter()
-        .filter_entry(|entry| {
-            // Skip directory if its name matches one of the skip_dirs
-            if entry.file_type().is_dir() {
-                if let Some(name) = entry.file_name().to_str() {
-                    !skip_dirs.iter().any(|skip| name == skip)
-                } else {
-                    true
-                }
-            } else {
-                true
-            }
-        })
-        .filter_map(Result::ok)
-        .filter(|e| e.file_type().is_file())
-        .map(|e| e.path().to_path_buf())
-        .collect()
-}
+fn execute_scan(
+    dir: &Path,
+    run: &RunArgs,
+    verify_files: &[PathBuf],
+    output_file: Option<&Path>,
+) -> i32 {
+    // An agent looks at `dir` where it is
+    if run.agent.is_none() && !dir.is_dir() {
+        eprintln!("Error: {} is not a directory", dir.display());
+        return 1;
+    }
 
-fn hash_files_parallel(paths: Vec<PathBuf>, show_progress: bool) -> HashMap<String, FileMeta> {
-    let map = Arc::new(Mutex::new(HashMap::new()));
+    if let Some(threads) = run.hashing.threads {
+        // Only the first call wins, which is fine as every job shares the flag
+        let _ = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global();
+    }
+    let mut opts = HashOptions::from_args(&run.hashing, &run.filter);
+    // Grouping by device needs the device each file is on now
+    opts.device |= run.group_by.contains(&verify::GroupBy::Device);
+    if opts.mac_metadata && run.agent.is_none() && !cfg!(target_os = "macos") {
+        eprintln!("Error: --mac-metadata is only supported on macOS");
+        return 1;
+    }
 
-    let progress = if show_progress {
-        let bar = ProgressBar::new(paths.len() as u64);
-        bar.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}",
-            )
-            .unwrap()
-            .progress_chars("##-"),
-        );
-        Some(bar)
-    } else {
-        None
+    // --progress-format implies --progress
+    let progress = match run.progress_format {
+        Some(ProgressFormat::Json) => match Progress::json(run.progress_fd) {
+            Ok(progress) => progress,
+            Err(e) => {
+                eprintln!("Error: can't use --progress-fd: {e}");
+                return 1;
+            }
+        },
+        Some(ProgressFormat::Bar) => Progress::bar(),
+        None if run.progress => Progress::bar(),
+        None => Progress::hidden(),
     };
 
-    paths.par_iter().for_each(|path| {
-        if let Ok(meta) = calculate_blake3(path) {
-            let mut map_lock = map.lock().unwrap();
-            map_lock.insert(path.to_string_lossy().to_string(), meta);
+    match scan(dir, run, &opts, &progress, verify_files, output_file) {
+        Ok(false) => 0,
+        Ok(true) => {
+            eprintln!(
+                "{}",
+                style::pick(
+                    "❌ One or more mismatches found!",
+                    "One or more mismatches found!"
+                )
+                .red()
+                .bold()
+            );
+            2
         }
-
-        if let Some(pb) = &progress {
-            pb.inc(1);
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
         }
-    });
-
-    if let Some(pb) = progress {
-        pb.finish_with_message("Hashing complete");
     }
-
-    Arc::try_unwrap(map).unwrap().into_inner().unwrap()
 }
 
-fn get_reference_by_hash(reference: &HashMap<String, FileMeta>) -> HashMap<String, Vec<String>> {
-    let mut reference_by_hash: HashMap<String, Vec<String>> = HashMap::new();
-    for (path, meta) in reference {
-        reference_by_hash
-            .entry(meta.hash.to_string())
-            .or_default()
-            .push(path.clone());
+/// Walks and hashes `dir`, then either verifies against `verify_files`
+/// (unioned, if several) or writes a new manifest to `output_file`. Returns
+/// true on mismatches.
+fn scan(
+    dir: &Path,
+    run: &RunArgs,
+    opts: &HashOptions,
+    progress: &Progress,
+    verify_files: &[PathBuf],
+    output_file: Option<&Path>,
+) -> std::io::Result<bool> {
+    // --subdir walks part of the tree and only checks the entries under it
+    let root = match &run.subdir {
+        Some(subdir) if !verify_files.is_empty() => dir.join(subdir),
+        _ => dir.to_path_buf(),
+    };
+    if run.agent.is_none() && !root.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} is not a directory", root.display()),
+        ));
+    }
+    if run.agent.is_some() && run.vss {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "--vss doesn't work with --agent",
+        ));
     }
-    reference_by_hash
-}
-
-fn verify_and_update(
-    current: &HashMap<String, FileMeta>,
-    reference: &mut HashMap<String, FileMeta>,
-    reference_file: &Path,
-    update: bool,
-    quiet: bool,
-) -> bool {
-    let mut matched = 0;
-    let mut moved = 0;
-    let mut mismatched = 0;
-    let mut extra = 0;
 
-    let reference_by_hash = get_reference_by_hash(reference);
+    progress.set_phase("walk");
+    debug!(event = "scan", root:% = root.display(); "Scanning {}", root.display());
+    let filter = scan::Filter::new(&run.filter)?;
+    // With --vss files are read from a shadow copy but recorded as they are,
+    // with --record-as they're recorded as if `dir` were somewhere else
+    let shadow = run.vss.then(|| vss::Shadow::create(&root)).transpose()?;
+    let record_as = run
+        .record_as
+        .as_deref()
+        .map(|to| manifest::Rebase::new(dir, to));
+    let recorded_dir = run.record_as.as_deref().unwrap_or(dir);
+    let key = |path: String| {
+        let path = match &shadow {
+            Some(shadow) => shadow.key(path),
+            None => path,
+        };
+        match &record_as {
+            Some(record_as) => record_as.key(path),
+            None => path,
+        }
+    };
+    // --policy rules go by manifest key, like --ignore-changes
+    let policy = run
+        .policy
+        .as_deref()
+        .map(|p| Policy::load(p, &key(manifest::path_key(dir))))
+        .transpose()?;
+    let skips = policy.as_ref().is_some_and(|p| p.has(Behavior::Skip));
+    let skipped = |path: &str| policy.as_ref().is_some_and(|p| p.is(path, Behavior::Skip));
+    // Anything modified from here on may or may not be as recorded
+    let started = manifest::now_secs();
+    // The walk goes on while the files it found are hashed
+    let files = match &run.agent {
+        Some(command) => {
+            let (agent, special) = agent::Agent::start(command, &root, run)?;
+            agent::Files::Agent(agent, special)
+        }
+        None => {
+            let walk = scan::Walk::new(shadow.as_ref().map_or(&root, |s| s.root()), &filter);
+            agent::Files::Local(if skips {
+                walk.keep(|path| !skipped(&key(manifest::path_key(path))))
+            } else {
+                walk
+            })
+        }
+    };
 
-    for (path, current_meta) in current {
-        let item = reference.get(path);
+    if !verify_files.is_empty() {
+        // Loaded first, a bad signature shouldn't cost a whole scan
+        // A --format kv manifest stays on disk, entries are looked up in it,
+        // and so does a manifest with an index
+        let (kv, mut references) = match verify_files {
+            [file] if kv::is_store(file) => (Some(store::load_kv(file, run)?), Vec::new()),
+            [file] if let Some(index) = store::load_index(file, run)? => (Some(index), Vec::new()),
+            _ => (
+                None,
+                verify_files
+                    .iter()
+                    .map(|f| store::load(f, run))
+                    .collect::<std::io::Result<Vec<_>>>()?,
+            ),
+        };
+        // Several are merged by moving their entries over, not copying them
+        let merged: HashMap<String, FileMeta>;
+        let all = if let [reference] = references.as_slice() {
+            &reference.manifest.files
+        } else {
+            merged = store::merge(
+                verify_files
+                    .iter()
+                    .zip(&mut references)
+                    .map(|(path, r)| (path.as_path(), std::mem::take(&mut r.manifest.files))),
+                run.on_conflict,
+            )?;
+            &merged
+        };
+
+        // Only entries the walk could have come across are checked
+        let scoped_out =
+            run.subdir.is_some() || filter.narrows() || skips || run.sample_confidence.is_some();
+        let root_key = key(manifest::path_key(&root));
+        let in_scope = |path: &str, recorded: Option<&FileMeta>| {
+            (run.subdir.is_none() || Path::new(path).starts_with(&root_key))
+                && !skipped(path)
+                && filter.takes(recorded_dir, path, recorded)
+        };
+        // Special files have no contents to conflict over, a later --verify wins
+        let expected_special: BTreeMap<String, Special> = references
+            .iter()
+            .map(|r| &r.manifest)
+            .chain(kv.as_ref().map(|(_, header)| header))
+            .flat_map(|m| &m.special)
+            .filter(|(path, _)| in_scope(path, None))
+            .map(|(path, s)| (path.clone(), s.clone()))
+            .collect();
+        let in_memory = verify::InMemory::new(all);
+        let whole: &dyn verify::Reference = match &kv {
+            Some((store, _)) => store,
+            None => &in_memory,
+        };
+        // --sample-confidence checks a random few of those, and only hashes
+        // what it picked
+        let sample = run
+            .sample_confidence
+            .map(|confidence| {
+                sample::Sample::pick(whole, &in_scope, confidence, run.sample_tolerance)
+            })
+            .transpose()?;
+        let in_scope = |path: &str, recorded: Option<&FileMeta>| {
+            in_scope(path, recorded) && sample.as_ref().is_none_or(|s| s.contains(path))
+        };
+        let files = match (files, &sample) {
+            (agent::Files::Local(walk), Some(sample)) => agent::Files::Local(
+                walk.keep(|path| sample.contains(&key(manifest::path_key(path)))),
+            ),
+            (files, _) => files,
+        };
+        let scoped = verify::Scoped {
+            reference: whole,
+            in_scope: &in_scope,
+        };
+        let expected: &dyn verify::Reference = if scoped_out { &scoped } else { whole };
+        // The ignore lists of the manifests, and any given for this run
+        let mut ignore: Vec<String> = references
+            .iter()
+            .map(|r| &r.manifest)
+            .chain(kv.as_ref().map(|(_, header)| header))
+            .flat_map(|m| m.ignore.iter().cloned())
+            .collect();
+        ignore.extend(run.ignore_changes.iter().cloned());
+        ignore.sort_unstable();
+        ignore.dedup();
+        let ignore_list = verify::IgnoreList::new(&key(manifest::path_key(dir)), &ignore)?;
+        let grouping = (!run.group_by.is_empty()).then(|| verify::Grouping {
+            by: run.group_by.clone(),
+            root: dir.to_path_buf(),
+            depth: run.depth,
+            devices: references
+                .iter()
+                .map(|r| &r.manifest)
+                .chain(kv.as_ref().map(|(_, header)| header))
+                .flat_map(|m| m.devices.clone())
+                .collect(),
+        });
+        let verifier = verify::Verifier::new(expected, run.update)
+            .group(grouping)
+            .offenders(run.offenders)
+            .mtime(verify::MtimeRule {
+                tolerance: run.mtime_tolerance,
+                tz_shifts: run.mtime_tz_shifts,
+            })
+            .special(&expected_special)
+            .copy(run.copy)
+            .tap(run.tap)
+            .ignore(ignore_list)
+            .policy(policy.as_ref())
+            .fail_fast(run.fail_fast)
+            .fs_check(run.fs_check)
+            .checks(&run.hashing.check)
+            .hooks(hooks::Hooks::new(run))
+            .seen_on_disk(kv.is_some())
+            .reread((run.reread || run.reread_from.is_some()).then(|| {
+                reread::Reread::new(
+                    dir,
+                    key(manifest::path_key(dir)),
+                    run.reread_from.as_deref(),
+                    *opts,
+                )
+            }))
+            .compare_bytes(run.compare_bytes.as_deref().map(|replica| {
+                bytediff::CompareBytes::new(replica, dir, key(manifest::path_key(dir)))
+            }))
+            .restore(run.emit_restore.as_deref().map(|replica| {
+                restore::Restore::new(replica, dir, key(manifest::path_key(dir)), run.restore_with)
+            }))
+            .fold(unicode::PathFold {
+                ignore_case: run.ignore_case,
+                normalize: run.normalize_unicode,
+            });
+        // What's coming is known unless scoped: everything in the manifest,
+        // from the header of one whose entries stay on disk
+        let totals = match &kv {
+            Some((_, header)) => header.totals,
+            None => Some(Totals::of(all)),
+        };
+        if let (false, Some(totals)) = (scoped_out, totals) {
+            progress.expect(totals);
+        }
+        // Only --update needs the current tree as a whole, to write it out,
+        // without it `auto-update` rules need what's under them
+        let current = run.update.then(|| Mutex::new(HashMap::new()));
+        let auto_update = policy
+            .as_ref()
+            .filter(|p| !run.update && p.has(Behavior::AutoUpdate));
+        let auto_updated = auto_update.map(|_| Mutex::new(HashMap::new()));
+        // A reference with directory digests has the tree as found added
+        // up the same way, to tell the branches that changed
+        let digested = match (references.as_slice(), scoped_out) {
+            ([reference], false) if !reference.manifest.dirs.is_empty() => {
+                Some(Mutex::new(Vec::new()))
+            }
+            _ => None,
+        };
+        let seen = devices::Seen::default();
+        let (mut trouble, special) =
+            files.hash_until(opts, progress, verifier.stop(), |path, meta| {
+                seen.add(&path, &meta);
+                let path = key(path);
+                // An agent walks the whole tree
+                if skipped(&path) {
+                    return;
+                }
+                verifier.check(&path, &meta);
+                if let Some(digested) = &digested {
+                    digested.lock().unwrap().push((path.clone(), meta.hash));
+                }
+                if let Some(current) = &current {
+                    current.lock().unwrap().insert(path, meta);
+                } else if let (Some(policy), Some(auto_updated)) = (auto_update, &auto_updated)
+                    && policy.is(&path, Behavior::AutoUpdate)
+                {
+                    auto_updated.lock().unwrap().insert(path, meta);
+                }
+            })?;
+        trouble.busy = trouble.busy.into_iter().map(key).collect();
+        // Busy files weren't looked at, their entries stay as they were
+        let busy: HashSet<String> = trouble.busy.iter().cloned().collect();
+        let special: BTreeMap<String, Special> = special
+            .into_iter()
+            .map(|(path, s)| (key(path), s))
+            .filter(|(path, _)| !skipped(path))
+            .collect();
+        let summary = verifier.finish(trouble, &special)?;
+        if let (Some(found), [reference], false) =
+            (digested, references.as_slice(), summary.stopped)
+        {
+            merkle::report(&reference.manifest, &found.into_inner().unwrap());
+        }
+        let mass = mass::MassChange::detect(&summary, run.mass_change);
+        // `auto-update` rules would record the damage just the same
+        let refused =
+            mass.is_some() && (run.update || auto_update.is_some()) && !run.allow_mass_change;
+        if let Some(mass) = &mass {
+            mass.report();
+        }
 
-        match item {
-            Some(expected_meta) => {
-                if current_meta.hash == expected_meta.hash {
-                    //println!("{} {}", "✅ MATCHED".green(), path);
-                    matched += 1;
-                } else if current_meta.modified == expected_meta.modified {
-                    println!(
-                        "{} {}\n  expected: {}\n  found:    {}",
-                        "❌ MISMATCH".red(),
-                        path,
-                        expected_meta.hash,
-                        current_meta.hash
-                    );
-                    mismatched += 1;
-                } else {
-                    if !quiet {
-                        println!(
-                            "{} {} (modified time differs, hash ignored)",
-                            "ℹ️ SKIPPED".blue(),
-                            path
-                        );
+        // --update takes a single --verify, checked up front
+        if let (true, false, [reference], [verify_file]) =
+            (run.update, refused, references.as_slice(), verify_files)
+        {
+            info!(
+                "\n{} Updating reference file: {}",
+                style::pick("💾", "*").bold(),
+                verify_file.display()
+            );
+            let mut current = current.map(|c| c.into_inner().unwrap()).unwrap_or_default();
+            // Entries outside --subdir or filtered out weren't looked at,
+            // they stay as they were
+            let mut special = special.clone();
+            if scoped_out {
+                for (path, meta) in &reference.manifest.files {
+                    if !in_scope(path, Some(meta)) {
+                        current.insert(path.clone(), meta.clone());
                     }
-                    if update {
-                        if !quiet {
-                            println!("{} Added to reference list", "➕".cyan());
-                        }
-                        reference.insert(path.clone(), current_meta.clone());
+                }
+                for (path, s) in &reference.manifest.special {
+                    if !in_scope(path, None) {
+                        special.insert(path.clone(), s.clone());
                     }
                 }
             }
-            None => {
-                if let Some(prev_paths) = reference_by_hash.get(&current_meta.hash) {
-                    // Files of zero size have same hash ...
-                    if current_meta.size != 0 {
-                        let c_paths: Vec<String> =
-                            prev_paths.iter().map(|p| p.to_string()).collect();
-
-                        if !quiet && prev_paths.len() < 3 {
-                            println!(
-                                "{} {}\n  previously: {}",
-                                "🔀 MOVED".yellow(),
-                                path,
-                                c_paths.join(", ")
-                            );
-                        }
-                        moved += 1;
-
-                        if update {
-                            reference.insert(path.clone(), current_meta.clone());
-                        }
-                    }
-                } else {
-                    if !quiet {
-                        println!("{} {}", "⚠️ EXTRA".blue(), path);
+            for path in &busy {
+                if let Some(meta) = reference.manifest.files.get(path) {
+                    current.insert(path.clone(), meta.clone());
+                }
+            }
+            // Entries under `never-update` stay as they were, whatever the
+            // tree has now
+            if let Some(policy) = policy.as_ref().filter(|p| p.has(Behavior::NeverUpdate)) {
+                current.retain(|path, _| !policy.is(path, Behavior::NeverUpdate));
+                for (path, meta) in &reference.manifest.files {
+                    if policy.is(path, Behavior::NeverUpdate) {
+                        current.insert(path.clone(), meta.clone());
                     }
-
-                    extra += 1;
-                    if update {
-                        if !quiet {
-                            println!("{} Added to reference list", "➕".cyan());
-                        }
-                        reference.insert(path.clone(), current_meta.clone());
+                }
+            }
+            tags::carry(&reference.manifest.files, &mut current);
+            let mut updated = Manifest::new(recorded_dir, current, special, started);
+            updated.ignore = ignore;
+            updated.volume = run.agent.is_none().then(|| volume::of(dir)).flatten();
+            updated.devices = seen.describe(updated.files.values(), &reference.manifest.devices);
+            if run.dir_digests || !reference.manifest.dirs.is_empty() {
+                updated.digest_dirs();
+            }
+            chain::extend(&mut updated, reference, verify_file)?;
+            // A text manifest stays text
+            store::save_as(&updated, verify_file, run, reference.format)?;
+            if let Some(journal) = &run.journal {
+                let changes = journal::changes(
+                    &reference.manifest.files,
+                    &updated.files,
+                    updated.created.unwrap_or(0),
+                );
+                journal::append(journal, &changes)?;
+            }
+        }
+        // What's under `auto-update` wasn't all looked at if checking stopped,
+        // and a mass change isn't recorded
+        if let (Some(policy), Some(auto_updated), false) =
+            (auto_update, auto_updated, summary.stopped || refused)
+        {
+            let auto_updated = auto_updated.into_inner().unwrap();
+            match (references.as_slice(), verify_files) {
+                ([reference], [verify_file]) => match policy::auto_update(
+                    policy,
+                    reference,
+                    verify_file,
+                    recorded_dir,
+                    started,
+                    auto_updated,
+                    &|path, recorded| in_scope(path, recorded) && !busy.contains(path),
+                    run,
+                ) {
+                    Ok(0) => {}
+                    Ok(changed) => info!(
+                        event = "auto_updated",
+                        manifest:% = verify_file.display(),
+                        entries = changed;
+                        "{} Auto-updated {} entries in {}",
+                        style::pick("🔄", "*"),
+                        changed,
+                        verify_file.display()
+                    ),
+                    Err(e) => {
+                        warn!(event = "auto_update_failed", error:% = e; "can't auto-update: {e}")
                     }
+                },
+                _ => warn!(
+                    event = "auto_update_skipped";
+                    "auto-update rules only update a single JSON or text manifest, changes were tolerated but not recorded"
+                ),
+            }
+        }
+        if let Some(sample) = &sample {
+            sample.report(&summary);
+        }
+        if run.copy {
+            summary.print_transfer();
+        }
+        summary.print_line();
+        let failed = if run.copy {
+            summary.copy_failed()
+        } else {
+            summary.failed()
+        };
+        if let Some(url) = &run.report_to {
+            let host = run.host.clone().unwrap_or_else(fleet::hostname);
+            let report = fleet::Report::new(host, dir, verify_files, &summary, failed);
+            match fleet::push(url, run.report_token.as_deref(), &report) {
+                Ok(()) => debug!(event = "reported", url = url.as_str(); "Summary sent to {url}"),
+                Err(e) => {
+                    warn!(event = "report_failed", url = url.as_str(), error:% = e; "can't send the summary to {url}: {e}")
                 }
             }
         }
-    }
-
-    if !quiet {
-        println!("\n=== {} ===", "SUMMARY".bold().underline());
-        println!("{} {}", "✅ Verified:".green(), matched);
-        println!("{} {}", "🔀 Moved:".yellow(), moved);
-        println!("{} {}", "❌ Mismatched:".red(), mismatched);
-        println!("{} {}", "⚠️ Extra:".blue(), extra);
-    }
-
-    if update {
-        if !quiet {
-            println!(
-                "\n{} Updating reference file: {}",
-                "💾".bold(),
-                reference_file.display()
+        if refused {
+            return Err(std::io::Error::other(
+                "not updating the reference after a mass change, look at a few of the files first; --allow-mass-change updates anyway",
+            ));
+        }
+        Ok(failed)
+    } else {
+        if let Some(output_file) = output_file {
+            let volume = run.agent.is_none().then(|| volume::of(dir)).flatten();
+            create(
+                recorded_dir,
+                volume.as_ref(),
+                files,
+                started,
+                &|path| Some(key(path)).filter(|path| !skipped(path)),
+                run,
+                opts,
+                progress,
+                output_file,
+            )?;
+
+            info!(
+                event = "written",
+                output:% = output_file.display();
+                "Hash table written to {}",
+                output_file.display()
             );
         }
-        let json = serde_json::to_string_pretty(&FileHashMap(current.clone()))
-            .expect("Serialization failed");
-        fs::write(reference_file, json).expect("Failed to write updated reference");
+        Ok(false)
     }
-
-    mismatched > 0
 }
 
-fn main() -> std::io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 3 {
-        eprintln!("Usage:");
-        eprintln!(
-            "  {} <directory> <output.json> [--progress] [--skip <dir>...] [--q]",
-            args[0]
-        );
-        eprintln!(
-            "  {} <directory> --verify <ref.json> [--update] [--progress] [--skip <dir>...] [--q]",
-            args[0]
-        );
-        std::process::exit(1);
+/// Hashes `files` into a new manifest at `output_file`. Entries are written
+/// out as they are hashed rather than collected first, except for
+/// canonical JSON and text which have to be sorted, and --dir-digests which
+/// need them all. `key` turns the path a file was read from into its
+/// manifest key, or None for a file --policy skips; special files go by it
+/// too. `started` is when the scan did, the manifest's `created`.
+#[allow(clippy::too_many_arguments)]
+fn create(
+    dir: &Path,
+    volume: Option<&Volume>,
+    files: agent::Files,
+    started: u64,
+    key: &(dyn Fn(String) -> Option<String> + Sync),
+    run: &RunArgs,
+    opts: &HashOptions,
+    progress: &Progress,
+    output_file: &Path,
+) -> std::io::Result<()> {
+    let keyed = |special: BTreeMap<String, Special>| -> BTreeMap<String, Special> {
+        special
+            .into_iter()
+            .filter_map(|(path, s)| Some((key(path)?, s)))
+            .collect()
+    };
+    if run.format == ManifestFormat::Kv {
+        let store = store::create_kv(output_file, run)?;
+        let failed = Mutex::new(None);
+        let totals = Mutex::new(Totals::default());
+        let seen = devices::Seen::default();
+        let (_, special) = files.hash_each(opts, progress, |path, meta| {
+            seen.add(&path, &meta);
+            let Some(path) = key(path) else {
+                return;
+            };
+            if let Err(e) = store.add(&path, &meta) {
+                failed.lock().unwrap().get_or_insert(e);
+            }
+            totals.lock().unwrap().add(&meta);
+        })?;
+        let special = keyed(special);
+        if let Some(e) = failed.into_inner().unwrap() {
+            return Err(e);
+        }
+        // The entries are in the store, the header only says where from
+        let mut header = Manifest::new(dir, HashMap::new(), special, started);
+        header.volume = volume.cloned();
+        header.ignore = run.ignore_changes.clone();
+        header.totals = Some(totals.into_inner().unwrap());
+        header.devices = seen.describe([], &BTreeMap::new());
+        header.checksum = None;
+        return store.finish(&header);
     }
 
-    let dir = PathBuf::from(&args[1]);
-    let show_progress = args.contains(&"--progress".to_string());
-    let update = args.contains(&"--update".to_string());
-    let verify_mode = args.contains(&"--verify".to_string());
-    let quiet = args.contains(&"--q".to_string());
-
-    let verify_file = args
-        .iter()
-        .position(|x| x == "--verify")
-        .and_then(|i| args.get(i + 1))
-        .map(PathBuf::from);
-    let output_file = if !verify_mode {
-        Some(PathBuf::from(&args[2]))
+    // With --include only what differs from the included manifests is kept
+    let (includes, base) = if run.include.is_empty() {
+        Default::default()
     } else {
-        None
+        store::includes_for(
+            output_file,
+            &run.include,
+            run.encryption.identity.as_deref(),
+        )?
     };
-
-    // Parse skipped dirs
-    let skip_dirs: Vec<String> = args
-        .windows(2)
-        .filter(|w| w[0] == "--skip")
-        .map(|w| w[1].clone())
-        .collect();
-
-    if !dir.is_dir() {
-        eprintln!("Error: {} is not a directory", dir.display());
-        std::process::exit(1);
-    }
-
-    let files = walk_files(&dir, &skip_dirs);
-    let current_hashes = hash_files_parallel(files, show_progress);
-
-    if verify_mode {
-        let verify_file = verify_file.expect("Missing argument for --verify");
-        let data = fs::read_to_string(&verify_file)?;
-        let FileHashMap(mut reference_hashes) = serde_json::from_str(&data)?;
-        let had_mismatches = verify_and_update(
-            &current_hashes,
-            &mut reference_hashes,
-            &verify_file,
-            update,
-            quiet,
-        );
-        if had_mismatches {
-            eprintln!("{}", "❌ One or more mismatches found!".red().bold());
-            exit(2);
-        }
-    } else if let Some(output_file) = output_file {
-        let file_map = FileHashMap(current_hashes);
-        let json = serde_json::to_string_pretty(&file_map).expect("Serialization failed");
-        fs::write(&output_file, json)?;
-
-        if !quiet {
-            println!("Hash table written to {}", output_file.display());
+    let inherited =
+        |path: &str, meta: &FileMeta| base.get(path).is_some_and(|b| b.hash == meta.hash);
+
+    if run.json_style == JsonStyle::Canonical
+        || run.paths == PathLayout::Tree
+        || run.format == ManifestFormat::Text
+        || run.dir_digests
+    {
+        let current_hashes = Mutex::new(HashMap::new());
+        let seen = devices::Seen::default();
+        let (_, special) = files.hash_each(opts, progress, |path, meta| {
+            seen.add(&path, &meta);
+            let Some(path) = key(path) else {
+                return;
+            };
+            if !inherited(&path, &meta) {
+                current_hashes.lock().unwrap().insert(path, meta);
+            }
+        })?;
+        let current_hashes = current_hashes.into_inner().unwrap();
+        let mut manifest = Manifest::new(dir, current_hashes, keyed(special), started);
+        manifest.volume = volume.cloned();
+        manifest.includes = includes;
+        manifest.ignore = run.ignore_changes.clone();
+        manifest.devices = seen.describe([], &BTreeMap::new());
+        if run.dir_digests {
+            manifest.digest_dirs();
         }
+        return store::save(&manifest, output_file, run);
     }
 
-    Ok(())
+    store::save_with(output_file, run, |out| {
+        // The first write error stops further writes, it is returned below
+        let writer = Mutex::new((
+            ManifestWriter::new(
+                out,
+                dir,
+                volume,
+                &includes,
+                &run.ignore_changes,
+                run.json_style,
+                started,
+            )?,
+            None,
+        ));
+        let seen = devices::Seen::default();
+        let (_, special) = files.hash_each(opts, progress, |path, meta| {
+            seen.add(&path, &meta);
+            let Some(path) = key(path) else {
+                return;
+            };
+            if inherited(&path, &meta) {
+                return;
+            }
+            let (writer, failed) = &mut *writer.lock().unwrap();
+            if failed.is_none()
+                && let Err(e) = writer.add(&path, &meta)
+            {
+                *failed = Some(e);
+            }
+        })?;
+        let (writer, failed) = writer.into_inner().unwrap();
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        writer.finish(&keyed(special), &seen.describe([], &BTreeMap::new()))?;
+        Ok(())
+    })
 }
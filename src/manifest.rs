@@ -0,0 +1,883 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::ser::Formatter;
+
+use crate::{
+    hash::{Digest, FileMeta},
+    tree::Tree,
+};
+
+pub const MANIFEST_VERSION: u32 = 1;
+/// Version of manifests with nested files (--paths tree), the newest this
+/// checkyoself reads.
+pub const NESTED_VERSION: u32 = 2;
+
+/// The reference file: a small header plus one entry per file, keyed by
+/// path as it was walked (so including the scan root as typed).
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Manifest {
+    /// Format version, 1, or 2 when `files` is nested. Older manifests are a
+    /// bare `files` map.
+    pub version: u32,
+    /// Directory the scan started from. Absent in pre-header manifests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    /// When the scan started, in seconds since the epoch. A file modified
+    /// since may or may not be as recorded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// The filesystem the scan root was on, when the system could tell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<Volume>,
+    /// Position in the --update chain, see `chain`. Absent means 0.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    /// BLAKE3 of the manifest this one replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+    /// Manifests whose entries this one builds on, see `Include`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub includes: Vec<Include>,
+    /// Globs of paths, relative to the root, whose changes verify
+    /// tolerates (--ignore-changes)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ignore: Vec<String>,
+    /// How many entries `files` has and their bytes, so they're known
+    /// without going through them. Absent in manifests from before it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totals: Option<Totals>,
+    /// FIFOs, sockets and device nodes, keyed by path. They have no contents
+    /// to hash, only what they are is recorded.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub special: BTreeMap<String, Special>,
+    /// The filesystems entries were read from with --devices, by the
+    /// device number they have
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub devices: BTreeMap<u64, Filesystem>,
+    /// A digest of each directory under the root with --dir-digests, made
+    /// of the names and digests of what's in it, see `merkle`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dirs: BTreeMap<String, Digest>,
+    /// One entry per file, keyed by path. Nested by directory in version 2:
+    /// a key ending in '/' holds the entries whose paths start with it
+    #[serde(deserialize_with = "crate::tree::deserialize")]
+    pub files: HashMap<String, FileMeta>,
+    /// `Checksum` of `special` and `files`, checked on load. Written last
+    /// so a streamed manifest can add it up as entries go out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+}
+
+/// The files of a manifest counted up. Those of included manifests aren't.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl Totals {
+    pub fn of(files: &HashMap<String, FileMeta>) -> Self {
+        let mut totals = Totals::default();
+        files.values().for_each(|meta| totals.add(meta));
+        totals
+    }
+
+    pub fn add(&mut self, meta: &FileMeta) {
+        self.files += 1;
+        self.bytes += meta.size.max(0) as u64;
+    }
+}
+
+/// Another manifest layered under this one: its entries count too, unless
+/// this manifest lists the same path itself.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Include {
+    /// Path of the included manifest, relative to the including one
+    pub path: String,
+    /// BLAKE3 of the included manifest file, refused if it differs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blake3: Option<String>,
+}
+
+/// A filesystem, as it was formatted: the same wherever it's mounted.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Volume {
+    /// Filesystem UUID (Linux, macOS) or volume GUID (Windows)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (&self.label, &self.uuid) {
+            (Some(label), Some(uuid)) => write!(f, "{label} ({uuid})"),
+            (Some(name), None) | (None, Some(name)) => f.write_str(name),
+            (None, None) => f.write_str("unknown volume"),
+        }
+    }
+}
+
+/// A filesystem files were read from, as the system had it then: device
+/// numbers can change between boots, the UUID doesn't.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Filesystem {
+    /// The device number as the system shows it, `major:minor` on Linux
+    pub id: String,
+    /// What was mounted from it, e.g. /dev/sdb1 (Linux)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub volume: Option<Volume>,
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{source} ({})", self.id)?,
+            None => f.write_str(&self.id)?,
+        }
+        match &self.volume {
+            Some(volume) => write!(f, " {volume}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What kind of special file an entry is.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpecialKind {
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl SpecialKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SpecialKind::Fifo => "fifo",
+            SpecialKind::Socket => "socket",
+            SpecialKind::CharDevice => "char_device",
+            SpecialKind::BlockDevice => "block_device",
+        }
+    }
+}
+
+/// A file without contents to hash.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Special {
+    pub kind: SpecialKind,
+    /// Device number of a device node, `major:minor` on Linux
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+impl fmt::Display for Special {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.kind.as_str())?;
+        if let Some(device) = &self.device {
+            write!(f, " {device}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Order independent digest of manifest entries: the sum, modulo 2^256, of
+/// the BLAKE3 hash of each entry. Catches entries damaged or lost after the
+/// manifest was written, whatever order they were written in.
+#[derive(Default, Clone, Copy)]
+pub struct Checksum([u64; 4]);
+
+impl Checksum {
+    pub fn of(files: &HashMap<String, FileMeta>, special: &BTreeMap<String, Special>) -> Self {
+        let mut sum = Checksum::default();
+        for (path, meta) in files {
+            sum.add(path, meta);
+        }
+        for (path, special) in special {
+            sum.add_special(path, special);
+        }
+        sum
+    }
+
+    pub fn add_special(&mut self, path: &str, special: &Special) {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(b"special");
+        hasher.update(path.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(special.to_string().as_bytes());
+        self.add_digest(hasher.finalize());
+    }
+
+    pub fn add(&mut self, path: &str, meta: &FileMeta) {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(path.as_bytes());
+        hasher.update(&[0]);
+        // Hashes go in as hex, as they did when they were held as strings
+        hasher.update(meta.hash.to_hex().as_bytes());
+        hasher.update(&[0]);
+        hasher.update(&meta.modified.to_le_bytes());
+        hasher.update(&meta.size.to_le_bytes());
+        // Entries without chunks hash the same as before chunks existed
+        if let Some(chunks) = &meta.chunks {
+            hasher.update(&chunks.size.to_le_bytes());
+            for hash in &chunks.hashes {
+                hasher.update(hash.to_hex().as_bytes());
+            }
+        }
+        if let Some(cdc) = &meta.cdc {
+            hasher.update(b"cdc");
+            for chunk in cdc {
+                hasher.update(chunk.as_bytes());
+                hasher.update(&[0]);
+            }
+        }
+        if let Some(xattrs) = &meta.xattrs {
+            hasher.update(b"xattrs");
+            for (name, hash) in xattrs {
+                hasher.update(name.as_bytes());
+                hasher.update(&[0]);
+                hasher.update(hash.as_bytes());
+            }
+        }
+        if let Some(md5) = &meta.md5 {
+            hasher.update(b"md5");
+            hasher.update(md5.as_bytes());
+        }
+        if let Some(sha256) = &meta.sha256 {
+            hasher.update(b"sha256");
+            hasher.update(sha256.as_bytes());
+        }
+        if let Some(crc32) = &meta.crc32 {
+            hasher.update(b"crc32");
+            hasher.update(crc32.as_bytes());
+        }
+        if !meta.tags.is_empty() {
+            hasher.update(b"tags");
+            for tag in &meta.tags {
+                hasher.update(tag.as_bytes());
+                hasher.update(&[0]);
+            }
+        }
+        if let Some(note) = &meta.note {
+            hasher.update(b"note");
+            hasher.update(note.as_bytes());
+        }
+        if let Some(mode) = meta.mode {
+            hasher.update(b"mode");
+            hasher.update(&mode.to_le_bytes());
+        }
+        if let Some(owner) = &meta.owner {
+            hasher.update(b"owner");
+            hasher.update(owner.as_bytes());
+        }
+        if let Some(entropy) = meta.entropy {
+            hasher.update(b"entropy");
+            hasher.update(&entropy.to_le_bytes());
+        }
+        if let Some(mime) = &meta.mime {
+            hasher.update(b"mime");
+            hasher.update(mime.as_bytes());
+        }
+        if let Some(device) = meta.device {
+            hasher.update(b"device");
+            hasher.update(&device.to_le_bytes());
+        }
+        if let Some(born) = meta.born {
+            hasher.update(b"born");
+            hasher.update(&born.to_le_bytes());
+        }
+        self.add_digest(hasher.finalize());
+    }
+
+    fn add_digest(&mut self, digest: blake3::Hash) {
+        let mut carry = false;
+        for (limb, chunk) in self.0.iter_mut().zip(digest.as_bytes().chunks_exact(8)) {
+            let word = u64::from_le_bytes(chunk.try_into().expect("8 byte chunk"));
+            let (s1, c1) = limb.overflowing_add(word);
+            let (s2, c2) = s1.overflowing_add(carry as u64);
+            *limb = s2;
+            carry = c1 || c2;
+        }
+    }
+
+    pub fn hex(&self) -> String {
+        self.0
+            .iter()
+            .flat_map(|limb| limb.to_le_bytes())
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
+/// What a manifest is written as, see --format.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ManifestFormat {
+    /// One JSON file (the default)
+    #[default]
+    Json,
+    /// A key-value store (a directory) that verify looks entries up in
+    /// without loading it, for trees with hundreds of millions of files
+    Kv,
+    /// Text, a sorted line per file, for manifests kept in git
+    Text,
+}
+
+/// How manifests are written, see --json.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum JsonStyle {
+    /// Indented, for humans (the default)
+    #[default]
+    Pretty,
+    /// No whitespace
+    Compact,
+    /// No whitespace, sorted keys and only ASCII (everything else \u
+    /// escaped), so the same manifest always has the same bytes
+    Canonical,
+}
+
+/// How the paths of a JSON manifest are written, see --paths.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum PathLayout {
+    /// Each in full (the default)
+    #[default]
+    Flat,
+    /// Nested by directory, each directory written once
+    Tree,
+}
+
+/// A manifest as `PathLayout::Tree` writes it: the fields of `Manifest`, in
+/// its order, with `files` nested.
+#[derive(Serialize)]
+struct Nested<'a> {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<&'a String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<&'a Volume>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous: Option<&'a String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    includes: &'a [Include],
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    ignore: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals: Option<Totals>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    special: &'a BTreeMap<String, Special>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    devices: &'a BTreeMap<u64, Filesystem>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    dirs: &'a BTreeMap<String, Digest>,
+    files: Tree<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<&'a String>,
+}
+
+/// Compact JSON that escapes every non-ASCII character as `\uXXXX`.
+struct AsciiFormatter;
+
+impl Formatter for AsciiFormatter {
+    fn write_string_fragment<W: ?Sized + io::Write>(
+        &mut self,
+        writer: &mut W,
+        fragment: &str,
+    ) -> io::Result<()> {
+        let mut start = 0;
+        for (i, c) in fragment.char_indices() {
+            if c.is_ascii() {
+                continue;
+            }
+            writer.write_all(&fragment.as_bytes()[start..i])?;
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                write!(writer, "\\u{unit:04x}")?;
+            }
+            start = i + c.len_utf8();
+        }
+        writer.write_all(&fragment.as_bytes()[start..])
+    }
+}
+
+/// `path` as a manifest key. On Windows without the `\\?\` long path
+/// prefix and with `/` between components, so a manifest reads the same
+/// whichever OS made it.
+pub fn path_key(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        portable(&path)
+    } else {
+        path.into_owned()
+    }
+}
+
+/// Rewrites the manifest keys of a tree read from one place as if it were
+/// at another.
+pub struct Rebase {
+    from: String,
+    to: String,
+}
+
+impl Rebase {
+    pub fn new(from: &Path, to: &Path) -> Self {
+        Rebase {
+            from: path_key(from),
+            to: path_key(to),
+        }
+    }
+
+    /// `key` with `from` swapped for `to`, if it's under `from`.
+    pub fn key(&self, key: String) -> String {
+        match key.strip_prefix(&self.from) {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') || self.from.ends_with('/') => {
+                format!("{}{rest}", self.to)
+            }
+            _ => key,
+        }
+    }
+}
+
+/// A Windows path in the form `path_key` gives it.
+fn portable(path: &str) -> String {
+    let path = if let Some(unc) = path.strip_prefix(r"\\?\UNC\") {
+        format!(r"\\{unc}")
+    } else {
+        path.strip_prefix(r"\\?\").unwrap_or(path).to_string()
+    };
+    path.replace('\\', "/")
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Manifest {
+    /// A manifest of `files` hashed by a scan that started at `created`,
+    /// from `now_secs`.
+    pub fn new(
+        root: &Path,
+        files: HashMap<String, FileMeta>,
+        special: BTreeMap<String, Special>,
+        created: u64,
+    ) -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            root: Some(path_key(root)),
+            created: Some(created),
+            volume: None,
+            sequence: None,
+            previous: None,
+            includes: Vec::new(),
+            ignore: Vec::new(),
+            totals: Some(Totals::of(&files)),
+            checksum: Some(Checksum::of(&files, &special).hex()),
+            special,
+            devices: BTreeMap::new(),
+            dirs: BTreeMap::new(),
+            files,
+        }
+    }
+
+    pub fn from_json(data: &str) -> io::Result<Self> {
+        let truncated = |e: serde_json::Error| {
+            if e.is_eof() {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("manifest is truncated ({e})"),
+                )
+            } else {
+                e.into()
+            }
+        };
+        // Parsed straight into a manifest: trying it as one, then as a
+        // legacy one in the same pass (untagged) would first copy the whole
+        // document into serde's own tree, which costs more than the
+        // manifest itself
+        let mut manifest = match serde_json::from_str::<Manifest>(data) {
+            Ok(manifest) => manifest,
+            // Manifests used to be a bare path -> entry map, those still load
+            Err(e) => match serde_json::from_str::<HashMap<String, FileMeta>>(data) {
+                Ok(files) => Manifest {
+                    version: 0,
+                    root: None,
+                    created: None,
+                    volume: None,
+                    sequence: None,
+                    previous: None,
+                    includes: Vec::new(),
+                    ignore: Vec::new(),
+                    totals: None,
+                    special: BTreeMap::new(),
+                    devices: BTreeMap::new(),
+                    dirs: BTreeMap::new(),
+                    files,
+                    checksum: None,
+                },
+                Err(_) => return Err(truncated(e)),
+            },
+        };
+        manifest.check()?;
+        // The version says how the files were laid out on disk, written
+        // again they are flat unless --paths says otherwise
+        manifest.version = manifest.version.min(MANIFEST_VERSION);
+        // Made on Windows before keys were made portable
+        if cfg!(windows)
+            && (manifest.files.keys().chain(manifest.special.keys())).any(|k| k.contains('\\'))
+        {
+            manifest.root = manifest.root.as_deref().map(portable);
+            manifest.files = manifest
+                .files
+                .into_iter()
+                .map(|(k, v)| (portable(&k), v))
+                .collect();
+            manifest.special = manifest
+                .special
+                .into_iter()
+                .map(|(k, v)| (portable(&k), v))
+                .collect();
+            manifest.dirs = manifest
+                .dirs
+                .into_iter()
+                .map(|(k, v)| (portable(&k), v))
+                .collect();
+        }
+        Ok(manifest)
+    }
+
+    /// Refuses a manifest from a newer checkyoself, or one whose entries
+    /// don't add up to its checksum.
+    pub fn check(&self) -> io::Result<()> {
+        if self.version > NESTED_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "manifest version {} is newer than this checkyoself understands ({})",
+                    self.version, NESTED_VERSION
+                ),
+            ));
+        }
+        if let Some(expected) = &self.checksum
+            && *expected != Checksum::of(&self.files, &self.special).hex()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "manifest is damaged: its checksum doesn't match its {} entries (bit rot, truncation or a hand edit?)",
+                    self.files.len()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self, style: JsonStyle, layout: PathLayout) -> String {
+        if layout == PathLayout::Flat {
+            return to_json(self, style);
+        }
+        let mut entries: Vec<_> = self.files.iter().map(|(p, m)| (p.as_str(), m)).collect();
+        entries.sort_unstable_by_key(|(path, _)| *path);
+        let nested = Nested {
+            version: NESTED_VERSION,
+            root: self.root.as_ref(),
+            created: self.created,
+            volume: self.volume.as_ref(),
+            sequence: self.sequence,
+            previous: self.previous.as_ref(),
+            includes: &self.includes,
+            ignore: &self.ignore,
+            totals: self.totals,
+            special: &self.special,
+            devices: &self.devices,
+            dirs: &self.dirs,
+            files: Tree::new(&entries),
+            checksum: self.checksum.as_ref(),
+        };
+        to_json(&nested, style)
+    }
+
+    /// Fills in `dirs` from `files`, for the directories under `root`.
+    pub fn digest_dirs(&mut self) {
+        self.dirs = match &self.root {
+            Some(root) => crate::merkle::digests(
+                root,
+                self.files
+                    .iter()
+                    .map(|(path, meta)| (path.as_str(), &meta.hash)),
+            ),
+            None => BTreeMap::new(),
+        };
+    }
+}
+
+fn to_json(manifest: &impl Serialize, style: JsonStyle) -> String {
+    match style {
+        JsonStyle::Pretty => serde_json::to_string_pretty(manifest).expect("Serialization failed"),
+        JsonStyle::Compact => serde_json::to_string(manifest).expect("Serialization failed"),
+        JsonStyle::Canonical => {
+            // serde_json's Map is ordered, so going through a Value sorts
+            // every object's keys
+            let value = serde_json::to_value(manifest).expect("Serialization failed");
+            let mut out = Vec::new();
+            value
+                .serialize(&mut serde_json::Serializer::with_formatter(
+                    &mut out,
+                    AsciiFormatter,
+                ))
+                .expect("Serialization failed");
+            String::from_utf8(out).expect("ASCII output")
+        }
+    }
+}
+
+/// Writes a manifest one entry at a time, so creating one never needs all
+/// entries in memory. The result is formatted like `Manifest::to_json` in
+/// the pretty and compact styles and loads the same, but `totals`,
+/// `special` and `devices` come after `files` rather than before, since
+/// they're only known once the walk is done. Canonical output needs every
+/// entry up front to sort them, so it isn't supported here.
+pub struct ManifestWriter<W: Write> {
+    out: W,
+    pretty: bool,
+    entries: usize,
+    totals: Totals,
+    checksum: Checksum,
+}
+
+impl<W: Write> ManifestWriter<W> {
+    pub fn new(
+        mut out: W,
+        root: &Path,
+        volume: Option<&Volume>,
+        includes: &[Include],
+        ignore: &[String],
+        style: JsonStyle,
+        created: u64,
+    ) -> io::Result<Self> {
+        assert!(
+            style != JsonStyle::Canonical,
+            "canonical manifests can't be streamed"
+        );
+        let pretty = style == JsonStyle::Pretty;
+        let root = serde_json::to_string(&path_key(root))?;
+        if pretty {
+            write!(
+                out,
+                "{{\n  \"version\": {MANIFEST_VERSION},\n  \"root\": {root},\n  \"created\": {created},"
+            )?;
+            if let Some(volume) = volume {
+                let volume = serde_json::to_string_pretty(volume)?.replace('\n', "\n  ");
+                write!(out, "\n  \"volume\": {volume},")?;
+            }
+            if !includes.is_empty() {
+                let includes = serde_json::to_string_pretty(includes)?.replace('\n', "\n  ");
+                write!(out, "\n  \"includes\": {includes},")?;
+            }
+            if !ignore.is_empty() {
+                let ignore = serde_json::to_string_pretty(ignore)?.replace('\n', "\n  ");
+                write!(out, "\n  \"ignore\": {ignore},")?;
+            }
+            write!(out, "\n  \"files\": {{")?;
+        } else {
+            write!(
+                out,
+                "{{\"version\":{MANIFEST_VERSION},\"root\":{root},\"created\":{created},"
+            )?;
+            if let Some(volume) = volume {
+                write!(out, "\"volume\":{},", serde_json::to_string(volume)?)?;
+            }
+            if !includes.is_empty() {
+                write!(out, "\"includes\":{},", serde_json::to_string(includes)?)?;
+            }
+            if !ignore.is_empty() {
+                write!(out, "\"ignore\":{},", serde_json::to_string(ignore)?)?;
+            }
+            write!(out, "\"files\":{{")?;
+        }
+        Ok(ManifestWriter {
+            out,
+            pretty,
+            entries: 0,
+            totals: Totals::default(),
+            checksum: Checksum::default(),
+        })
+    }
+
+    pub fn add(&mut self, path: &str, meta: &FileMeta) -> io::Result<()> {
+        if self.entries > 0 {
+            self.out.write_all(b",")?;
+        }
+        let key = serde_json::to_string(path)?;
+        if self.pretty {
+            let value = serde_json::to_string_pretty(meta)?.replace('\n', "\n    ");
+            write!(self.out, "\n    {key}: {value}")?;
+        } else {
+            write!(self.out, "{key}:{}", serde_json::to_string(meta)?)?;
+        }
+        self.entries += 1;
+        self.totals.add(meta);
+        self.checksum.add(path, meta);
+        Ok(())
+    }
+
+    /// Writes the footer (the totals, the special files and devices, known
+    /// by the time the walk is done, and the checksum) and returns the
+    /// output.
+    pub fn finish(
+        mut self,
+        special: &BTreeMap<String, Special>,
+        devices: &BTreeMap<u64, Filesystem>,
+    ) -> io::Result<W> {
+        for (path, special) in special {
+            self.checksum.add_special(path, special);
+        }
+        let checksum = self.checksum.hex();
+        if !self.pretty {
+            write!(
+                self.out,
+                "}},\"totals\":{}",
+                serde_json::to_string(&self.totals)?
+            )?;
+            if !special.is_empty() {
+                write!(self.out, ",\"special\":{}", serde_json::to_string(special)?)?;
+            }
+            if !devices.is_empty() {
+                write!(self.out, ",\"devices\":{}", serde_json::to_string(devices)?)?;
+            }
+            write!(self.out, ",\"checksum\":\"{checksum}\"}}")?;
+        } else {
+            if self.entries > 0 {
+                self.out.write_all(b"\n  ")?;
+            }
+            let totals = serde_json::to_string_pretty(&self.totals)?.replace('\n', "\n  ");
+            write!(self.out, "}},\n  \"totals\": {totals}")?;
+            if !special.is_empty() {
+                let special = serde_json::to_string_pretty(special)?.replace('\n', "\n  ");
+                write!(self.out, ",\n  \"special\": {special}")?;
+            }
+            if !devices.is_empty() {
+                let devices = serde_json::to_string_pretty(devices)?.replace('\n', "\n  ");
+                write!(self.out, ",\n  \"devices\": {devices}")?;
+            }
+            write!(self.out, ",\n  \"checksum\": \"{checksum}\"\n}}")?;
+        }
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(contents: &str, modified: u64) -> FileMeta {
+        serde_json::from_value(serde_json::json!({
+            "hash": blake3::hash(contents.as_bytes()).to_hex().as_str(),
+            "modified": modified,
+            "size": contents.len(),
+        }))
+        .unwrap()
+    }
+
+    fn files() -> HashMap<String, FileMeta> {
+        [
+            ("a", meta("a", 1)),
+            ("b/c", meta("c", 2)),
+            ("d", meta("d", 3)),
+        ]
+        .into_iter()
+        .map(|(path, meta)| (path.to_string(), meta))
+        .collect()
+    }
+
+    #[test]
+    fn checksum_ignores_order() {
+        let files = files();
+        let mut paths: Vec<&String> = files.keys().collect();
+        paths.sort();
+        let mut forward = Checksum::default();
+        for path in &paths {
+            forward.add(path, &files[*path]);
+        }
+        let mut backward = Checksum::default();
+        for path in paths.iter().rev() {
+            backward.add(path, &files[*path]);
+        }
+        assert_eq!(forward.hex(), backward.hex());
+        assert_eq!(forward.hex(), Checksum::of(&files, &BTreeMap::new()).hex());
+    }
+
+    #[test]
+    fn checksum_covers_every_entry() {
+        let special = BTreeMap::new();
+        let sum = Checksum::of(&files(), &special).hex();
+
+        let mut touched = files();
+        touched.get_mut("d").unwrap().modified += 1;
+        assert_ne!(Checksum::of(&touched, &special).hex(), sum);
+
+        let mut renamed = files();
+        let entry = renamed.remove("a").unwrap();
+        renamed.insert("A".to_string(), entry);
+        assert_ne!(Checksum::of(&renamed, &special).hex(), sum);
+
+        let mut lost = files();
+        lost.remove("b/c");
+        assert_ne!(Checksum::of(&lost, &special).hex(), sum);
+    }
+
+    #[test]
+    fn damaged_manifests_are_refused() {
+        let manifest = Manifest::new(Path::new("root"), files(), BTreeMap::new(), now_secs());
+        let json = manifest.to_json(JsonStyle::Pretty, PathLayout::Flat);
+        assert!(Manifest::from_json(&json).is_ok());
+
+        let mut value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        value["files"]["d"]["size"] = 2.into();
+        let e = Manifest::from_json(&value.to_string()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn streamed_manifests_add_up() {
+        for style in [JsonStyle::Pretty, JsonStyle::Compact] {
+            let mut writer = ManifestWriter::new(
+                Vec::new(),
+                Path::new("root"),
+                None,
+                &[],
+                &[],
+                style,
+                now_secs(),
+            )
+            .unwrap();
+            for (path, meta) in files() {
+                writer.add(&path, &meta).unwrap();
+            }
+            let out = writer.finish(&BTreeMap::new(), &BTreeMap::new()).unwrap();
+            let manifest = Manifest::from_json(std::str::from_utf8(&out).unwrap()).unwrap();
+            assert_eq!(manifest.files, files());
+            assert_eq!(
+                manifest.checksum,
+                Some(Checksum::of(&files(), &BTreeMap::new()).hex())
+            );
+        }
+    }
+}
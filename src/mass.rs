@@ -0,0 +1,52 @@
+use colored::*;
+use log::warn;
+
+use crate::{style, verify::Summary};
+
+// Ransomware encrypts files where they are: the names stay, or as good as,
+// and the contents become something else. One verify run finding most of
+// a tree rewritten like that is rarely anyone's editing, and --update would
+// record the encrypted files as the new truth. Files it renames show up as
+// missing and extra instead, those aren't counted here; nor are changes the
+// ignore list or a policy tolerates.
+
+/// Below this many rewritten files a share of the tree says little.
+const FEW: usize = 10;
+
+/// More of the tree than `--mass-change` rewritten in one run.
+pub struct MassChange {
+    rewritten: usize,
+    compared: usize,
+    threshold: f64,
+}
+
+impl MassChange {
+    /// Whether `summary` found more than `threshold` percent of the files
+    /// that were still where they were rewritten.
+    pub fn detect(summary: &Summary, threshold: f64) -> Option<MassChange> {
+        let (rewritten, compared) = (summary.rewritten, summary.compared);
+        (rewritten >= FEW && rewritten as f64 * 100.0 > compared as f64 * threshold).then_some(
+            MassChange {
+                rewritten,
+                compared,
+                threshold,
+            },
+        )
+    }
+
+    /// Warns about it.
+    pub fn report(&self) {
+        warn!(
+            event = "mass_change",
+            rewritten = self.rewritten,
+            compared = self.compared,
+            threshold = self.threshold;
+            "\n{} {} of {} files ({:.1}%, more than {}%) kept their names but have different contents: was this tree encrypted by ransomware?",
+            style::pick("🚨 MASS CHANGE", "MASS CHANGE").red().bold(),
+            self.rewritten,
+            self.compared,
+            self.rewritten as f64 * 100.0 / self.compared as f64,
+            self.threshold
+        );
+    }
+}
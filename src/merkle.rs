@@ -0,0 +1,154 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use colored::*;
+use log::{info, warn};
+
+use crate::{hash::Digest, manifest::Manifest, style};
+
+// --dir-digests records a digest per directory in the manifest's `dirs`,
+// Merkle style: a file's is its hash, a directory's is made of the names
+// and digests of what's in it. Equal digests mean everything under the
+// two directories is the same, so two manifests can be compared a branch
+// at a time, leaving out the branches that didn't change without looking
+// at their files, and what did change is found by following the
+// directories whose digests differ. Directories only count what the
+// manifest has: an empty one has no digest. A verify against a manifest
+// with them adds up the tree it found the same way and names the branches
+// that changed, or says the tree is the same as a whole.
+
+/// The digest of each directory under `root` with files in `files`, by
+/// its manifest key. Files not under `root` are left out.
+pub fn digests<'a>(
+    root: &str,
+    files: impl IntoIterator<Item = (&'a str, &'a Digest)>,
+) -> BTreeMap<String, Digest> {
+    let mut entries: Vec<(&str, &Digest)> = files
+        .into_iter()
+        .filter_map(|(key, hash)| {
+            let relative = Path::new(key).strip_prefix(root).ok()?.to_str()?;
+            Some((relative, hash)).filter(|(relative, _)| !relative.is_empty())
+        })
+        .collect();
+    entries.sort_unstable_by_key(|(path, _)| *path);
+    let mut dirs = BTreeMap::new();
+    let digest = walk(&entries, 0, &mut |dir, digest| {
+        dirs.insert(dir_key(root, dir), digest);
+    });
+    if !entries.is_empty() {
+        dirs.insert(dir_key(root, ""), digest);
+    }
+    dirs
+}
+
+/// The digest of the directory `entries` are in, their paths sorted and
+/// the first `skip` bytes of each being the directory. Subdirectories are
+/// handed to `found` by their path as it was relative to the root.
+fn walk(entries: &[(&str, &Digest)], skip: usize, found: &mut dyn FnMut(&str, Digest)) -> Digest {
+    let mut hasher = blake3::Hasher::new();
+    let mut rest = entries;
+    while let Some(&(path, hash)) = rest.first() {
+        let name = &path[skip..];
+        // Sorted, the paths under the same directory follow each other
+        let (kind, name, digest, under) = match name.find('/') {
+            None => (b'f', name, *hash, 1),
+            Some(end) => {
+                let dir = &path[..skip + end + 1];
+                let under = rest.partition_point(|(path, _)| path.starts_with(dir));
+                let digest = walk(&rest[..under], dir.len(), found);
+                found(&dir[..dir.len() - 1], digest);
+                (b'd', &name[..end], digest, under)
+            }
+        };
+        hasher.update(&[kind]);
+        hasher.update(name.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(digest.as_bytes());
+        rest = &rest[under..];
+    }
+    hasher.finalize().into()
+}
+
+/// The manifest key of directory `dir`, relative to `root` (the root
+/// itself when empty), as the keys of its files have it.
+fn dir_key(root: &str, dir: &str) -> String {
+    let root = root.strip_suffix('/').unwrap_or(root);
+    match (root, dir) {
+        ("", "") => "/".to_string(),
+        (root, "") => root.to_string(),
+        (root, dir) => format!("{root}/{dir}"),
+    }
+}
+
+/// The parent directory of the entry `key`.
+pub fn parent(key: &str) -> Option<&str> {
+    key.rfind('/').map(|end| &key[..end.max(1)])
+}
+
+/// The directories whose digests differ between `old` and `new`, or that
+/// only one has, and that have no such directory under them: where the
+/// changes are.
+pub fn changed_branches(
+    old: &BTreeMap<String, Digest>,
+    new: &BTreeMap<String, Digest>,
+) -> Vec<String> {
+    let changed: BTreeSet<&str> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|dir| old.get(*dir) != new.get(*dir))
+        .map(String::as_str)
+        .collect();
+    changed
+        .iter()
+        .filter(|dir| {
+            let under = format!("{}/", dir.strip_suffix('/').unwrap_or(dir));
+            !changed
+                .range(under.as_str()..)
+                .next()
+                .is_some_and(|sub| sub.starts_with(&under))
+        })
+        .map(|dir| dir.to_string())
+        .collect()
+}
+
+/// Whether the file `key` is in a directory with the same digest in `old`
+/// and `new`, so it's the same in both.
+pub fn unchanged(
+    key: &str,
+    old: &BTreeMap<String, Digest>,
+    new: &BTreeMap<String, Digest>,
+) -> bool {
+    parent(key).is_some_and(|dir| {
+        old.get(dir)
+            .is_some_and(|digest| new.get(dir) == Some(digest))
+    })
+}
+
+/// Reports how the files verify `found` under the root of `recorded` add
+/// up against its directory digests.
+pub fn report(recorded: &Manifest, found: &[(String, Digest)]) {
+    let Some(root) = &recorded.root else {
+        return;
+    };
+    let now = digests(root, found.iter().map(|(path, hash)| (path.as_str(), hash)));
+    let branches = changed_branches(&recorded.dirs, &now);
+    if branches.is_empty() {
+        info!(
+            event = "dirs_match",
+            dirs = now.len();
+            "{} all {} directories match their digests",
+            style::pick("🌳", "*").green(),
+            now.len()
+        );
+    }
+    for branch in branches {
+        warn!(
+            event = "changed_branch",
+            path = branch.as_str();
+            "{} {branch}",
+            style::pick("🌿 CHANGED BRANCH", "CHANGED BRANCH").yellow()
+        );
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+};
+
+use log::debug;
+
+use crate::store::with_path;
+
+// On a server most of the tree belongs to the package manager, which can
+// already verify it (`rpm -V`, `debsums`, `pacman -Qk`). --skip-packaged
+// leaves those files out so a manifest covers what nothing else does.
+
+const DPKG_INFO: &str = "/var/lib/dpkg/info";
+const PACMAN_LOCAL: &str = "/var/lib/pacman/local";
+
+/// How a package manager recorded a file's contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgo {
+    Md5,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+/// An installed file with the digest its package recorded.
+pub struct PackagedFile {
+    pub path: PathBuf,
+    pub package: String,
+    pub algo: DigestAlgo,
+    /// Lowercase hex
+    pub digest: String,
+    /// A configuration file, which admins are expected to edit
+    pub config: bool,
+}
+
+/// The files the system's package manager installed.
+pub struct Packaged {
+    paths: HashSet<PathBuf>,
+    /// Directories resolved so far, symlinks and all
+    real_dirs: Mutex<HashMap<PathBuf, Option<PathBuf>>>,
+}
+
+/// Every `*.list` under /var/lib/dpkg/info, one path per line.
+fn dpkg() -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let dir = Path::new(DPKG_INFO);
+    for entry in fs::read_dir(dir).map_err(|e| with_path(dir, e))? {
+        let path = entry.map_err(|e| with_path(dir, e))?.path();
+        if path.extension().is_some_and(|e| e == "list") {
+            let list = fs::read_to_string(&path).map_err(|e| with_path(&path, e))?;
+            paths.extend(list.lines().filter(|l| !l.is_empty()).map(PathBuf::from));
+        }
+    }
+    Ok(paths)
+}
+
+/// The `%FILES%` section of every package's `files` under
+/// /var/lib/pacman/local, paths relative to /.
+fn pacman() -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let dir = Path::new(PACMAN_LOCAL);
+    for entry in fs::read_dir(dir).map_err(|e| with_path(dir, e))? {
+        let path = entry.map_err(|e| with_path(dir, e))?.path().join("files");
+        let Ok(files) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let section = files
+            .lines()
+            .skip_while(|l| *l != "%FILES%")
+            .skip(1)
+            .take_while(|l| !l.is_empty());
+        paths.extend(section.map(|l| Path::new("/").join(l)));
+    }
+    Ok(paths)
+}
+
+/// The `*.md5sums` under /var/lib/dpkg/info, `<md5>  <path relative to />`
+/// per line. Files listed in the package's `*.conffiles` count as config.
+fn dpkg_digests() -> io::Result<Vec<PackagedFile>> {
+    let mut files = Vec::new();
+    let dir = Path::new(DPKG_INFO);
+    for entry in fs::read_dir(dir).map_err(|e| with_path(dir, e))? {
+        let path = entry.map_err(|e| with_path(dir, e))?.path();
+        if path.extension().is_none_or(|e| e != "md5sums") {
+            continue;
+        }
+        let Some(package) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+            continue;
+        };
+        let sums = fs::read_to_string(&path).map_err(|e| with_path(&path, e))?;
+        let conffiles: HashSet<String> = fs::read_to_string(path.with_extension("conffiles"))
+            .unwrap_or_default()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        for line in sums.lines() {
+            let Some((digest, file)) = line.split_once("  ") else {
+                continue;
+            };
+            let file = format!("/{file}");
+            files.push(PackagedFile {
+                config: conffiles.contains(&file),
+                path: PathBuf::from(file),
+                package: package.clone(),
+                algo: DigestAlgo::Md5,
+                digest: digest.to_lowercase(),
+            });
+        }
+    }
+    Ok(files)
+}
+
+// rpm file flags
+const RPMFILE_CONFIG: u32 = 1;
+const RPMFILE_GHOST: u32 = 64;
+
+/// Every file of every installed rpm that has a digest, ghosts (files a
+/// package owns but doesn't ship) left out.
+fn rpm_digests() -> io::Result<Vec<PackagedFile>> {
+    let output = Command::new("rpm")
+        .args([
+            "-qa",
+            "--queryformat",
+            "[%{=NAME}\t%{=FILEDIGESTALGO}\t%{FILEFLAGS}\t%{FILEDIGESTS}\t%{FILENAMES}\n]",
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "rpm -qa failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    let mut files = Vec::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<&str> = line.splitn(5, '\t').collect();
+        let [package, algo, flags, digest, path] = fields[..] else {
+            continue;
+        };
+        // Directories and symlinks have no digest
+        if digest.is_empty() {
+            continue;
+        }
+        let flags: u32 = flags.parse().unwrap_or(0);
+        if flags & RPMFILE_GHOST != 0 {
+            continue;
+        }
+        // Packages from before digest algorithms were recorded used MD5
+        let algo = match algo {
+            "1" | "(none)" | "" => DigestAlgo::Md5,
+            "8" => DigestAlgo::Sha256,
+            "9" => DigestAlgo::Sha384,
+            "10" => DigestAlgo::Sha512,
+            _ => continue,
+        };
+        files.push(PackagedFile {
+            path: PathBuf::from(path),
+            package: package.to_string(),
+            algo,
+            digest: digest.to_lowercase(),
+            config: flags & RPMFILE_CONFIG != 0,
+        });
+    }
+    Ok(files)
+}
+
+/// The installed files whose contents the package database records, and
+/// the package manager it came from.
+pub fn recorded_digests() -> io::Result<(&'static str, Vec<PackagedFile>)> {
+    if Path::new(DPKG_INFO).is_dir() {
+        return Ok(("dpkg", dpkg_digests()?));
+    }
+    if Path::new(PACMAN_LOCAL).is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pacman's file digests aren't supported, use pacman -Qkk",
+        ));
+    }
+    match rpm_digests() {
+        Ok(files) => Ok(("rpm", files)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no dpkg or rpm package database found",
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+fn rpm() -> io::Result<Vec<PathBuf>> {
+    let output = Command::new("rpm")
+        .args(["-qa", "--queryformat", "[%{FILENAMES}\\n]"])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "rpm -qa failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|l| !l.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+impl Packaged {
+    /// Reads the package database of dpkg, pacman or rpm, whichever the
+    /// system has.
+    pub fn load() -> io::Result<Packaged> {
+        let (manager, listed) = if Path::new(DPKG_INFO).is_dir() {
+            ("dpkg", dpkg()?)
+        } else if Path::new(PACMAN_LOCAL).is_dir() {
+            ("pacman", pacman()?)
+        } else {
+            match rpm() {
+                Ok(paths) => ("rpm", paths),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "--skip-packaged: no dpkg, pacman or rpm package database found",
+                    ));
+                }
+                Err(e) => return Err(e),
+            }
+        };
+
+        let packaged = Packaged {
+            paths: HashSet::with_capacity(listed.len()),
+            real_dirs: Mutex::new(HashMap::new()),
+        };
+        // Packages name files by the path they were installed to, which may
+        // go through a symlink (/bin on a merged /usr), so both count
+        let mut paths = HashSet::with_capacity(listed.len() * 2);
+        for path in listed {
+            if let Some(real) = packaged.real_path(&path) {
+                paths.insert(real);
+            }
+            paths.insert(path);
+        }
+        debug!(
+            event = "packaged",
+            manager = manager,
+            files = paths.len();
+            "{} paths belong to {manager} packages",
+            paths.len()
+        );
+        Ok(Packaged { paths, ..packaged })
+    }
+
+    /// `path` with its directory resolved to an absolute path without
+    /// symlinks. The file itself isn't resolved, a packaged symlink is the
+    /// package's, not its target.
+    fn real_path(&self, path: &Path) -> Option<PathBuf> {
+        let dir = path.parent()?;
+        let name = path.file_name()?;
+        let mut real_dirs = self.real_dirs.lock().unwrap();
+        let real_dir = real_dirs.entry(dir.to_path_buf()).or_insert_with(|| {
+            fs::canonicalize(if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            })
+            .ok()
+        });
+        real_dir.as_ref().map(|d| d.join(name))
+    }
+
+    /// Whether the file at `path` was installed by a package.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.contains(path)
+            || self
+                .real_path(path)
+                .is_some_and(|p| self.paths.contains(&p))
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::{
+    chain,
+    cli::RunArgs,
+    crypt, devices,
+    hash::FileMeta,
+    manifest::Manifest,
+    store::{self, Loaded},
+    tags,
+};
+
+// A policy file (--policy) says how parts of a tree are treated, one rule
+// per line, the first rule whose glob matches a path (relative to the root)
+// applies:
+//
+//   # logs churn, keep the manifest up to date with them
+//   logs/** = auto-update
+//   # originals must never change, nor be rewritten in the manifest
+//   originals/** = strict, never-update
+//   cache/** = skip
+//
+// `skip` leaves paths out of create, verify and update alike, as if they
+// weren't there. `auto-update` tolerates changes, like --ignore-changes,
+// and writes them to the manifest after every verify. `strict` compares
+// contents whatever the modification time says and isn't subject to ignore
+// lists. `never-update` keeps the manifest's entries as they are through
+// --update and auto-update, nothing under it is added or removed.
+
+/// What a rule does to the paths it matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behavior {
+    Skip,
+    AutoUpdate,
+    Strict,
+    NeverUpdate,
+}
+
+const NAMES: [(&str, Behavior); 4] = [
+    ("skip", Behavior::Skip),
+    ("auto-update", Behavior::AutoUpdate),
+    ("strict", Behavior::Strict),
+    ("never-update", Behavior::NeverUpdate),
+];
+
+impl Behavior {
+    fn parse(name: &str) -> Option<Behavior> {
+        NAMES.iter().find(|(n, _)| *n == name).map(|&(_, b)| b)
+    }
+
+    fn name(self) -> &'static str {
+        NAMES
+            .iter()
+            .find(|(_, b)| *b == self)
+            .map_or("", |(n, _)| n)
+    }
+}
+
+/// Behaviors that make no sense together in one rule.
+const CONFLICTS: [(Behavior, Behavior); 5] = [
+    (Behavior::Skip, Behavior::AutoUpdate),
+    (Behavior::Skip, Behavior::Strict),
+    (Behavior::Skip, Behavior::NeverUpdate),
+    (Behavior::AutoUpdate, Behavior::Strict),
+    (Behavior::AutoUpdate, Behavior::NeverUpdate),
+];
+
+pub struct Policy {
+    root: PathBuf,
+    globs: GlobSet,
+    /// The behaviors of each rule, in the order of `globs`
+    rules: Vec<Vec<Behavior>>,
+}
+
+fn invalid(path: &Path, line: usize, msg: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}:{line}: {msg}", path.display()),
+    )
+}
+
+impl Policy {
+    /// Reads the policy file at `path`, for a tree recorded as `root`.
+    pub fn load(path: &Path, root: &str) -> io::Result<Policy> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let mut globs = GlobSetBuilder::new();
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let n = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((pattern, behaviors)) = line.rsplit_once('=') else {
+                return Err(invalid(path, n, "expected GLOB = BEHAVIOR".to_string()));
+            };
+            let pattern = pattern.trim();
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| invalid(path, n, format!("{pattern}: {e}")))?;
+            let behaviors = behaviors
+                .split(',')
+                .map(|name| {
+                    Behavior::parse(name.trim()).ok_or_else(|| {
+                        invalid(
+                            path,
+                            n,
+                            format!(
+                                "unknown behavior '{}', expected skip, auto-update, strict or never-update",
+                                name.trim()
+                            ),
+                        )
+                    })
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+            if let Some((a, b)) = CONFLICTS
+                .iter()
+                .find(|(a, b)| behaviors.contains(a) && behaviors.contains(b))
+            {
+                return Err(invalid(
+                    path,
+                    n,
+                    format!("{} and {} contradict each other", a.name(), b.name()),
+                ));
+            }
+            globs.add(glob);
+            rules.push(behaviors);
+        }
+        let globs = globs
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Policy {
+            root: PathBuf::from(root),
+            globs,
+            rules,
+        })
+    }
+
+    /// Whether the rule for `path`, a manifest key, has `behavior`.
+    pub fn is(&self, path: &str, behavior: Behavior) -> bool {
+        let path = Path::new(path);
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        // The first matching rule wins
+        self.globs
+            .matches(relative)
+            .first()
+            .is_some_and(|&i| self.rules[i].contains(&behavior))
+    }
+
+    /// Whether any rule has `behavior`.
+    pub fn has(&self, behavior: Behavior) -> bool {
+        self.rules.iter().any(|rule| rule.contains(&behavior))
+    }
+}
+
+/// Writes what the files under `auto-update` rules are now, `current`, to
+/// the manifest at `path` that `reference` was loaded from, as its
+/// successor in the history chain. Entries `in_scope` of the walk that are
+/// gone are dropped. `started` is when the scan that found them did.
+/// Returns how many entries changed, the manifest is only written if any
+/// did.
+#[allow(clippy::too_many_arguments)]
+pub fn auto_update(
+    policy: &Policy,
+    reference: &Loaded,
+    path: &Path,
+    root: &Path,
+    started: u64,
+    mut current: HashMap<String, FileMeta>,
+    in_scope: &dyn Fn(&str, Option<&FileMeta>) -> bool,
+    run: &RunArgs,
+) -> io::Result<usize> {
+    let previous = &reference.manifest.files;
+    let mut files = previous.clone();
+    let mut changed = 0;
+    files.retain(|key, meta| {
+        let gone = policy.is(key, Behavior::AutoUpdate)
+            && in_scope(key, Some(meta))
+            && !current.contains_key(key);
+        changed += usize::from(gone);
+        !gone
+    });
+    tags::carry(previous, &mut current);
+    for (key, meta) in current {
+        if previous.get(&key) != Some(&meta) {
+            changed += 1;
+            files.insert(key, meta);
+        }
+    }
+    if changed == 0 {
+        return Ok(0);
+    }
+
+    // Same as for --update
+    if crypt::is_encrypted(&reference.raw) && run.encryption.encrypt_to.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is encrypted, pass --encrypt-to to keep it that way",
+                path.display()
+            ),
+        ));
+    }
+    if !reference.manifest.includes.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} includes other manifests, writing it would fold them in",
+                path.display()
+            ),
+        ));
+    }
+    let mut updated = Manifest::new(root, files, reference.manifest.special.clone(), started);
+    updated.volume = reference.manifest.volume.clone();
+    updated.ignore = reference.manifest.ignore.clone();
+    updated.devices =
+        devices::Seen::default().describe(updated.files.values(), &reference.manifest.devices);
+    if run.dir_digests || !reference.manifest.dirs.is_empty() {
+        updated.digest_dirs();
+    }
+    chain::extend(&mut updated, reference, path)?;
+    store::save_as(&updated, path, run, reference.format)?;
+    Ok(changed)
+}
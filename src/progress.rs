@@ -0,0 +1,282 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use serde::Serialize;
+
+use crate::manifest::Totals;
+
+// Files at least this big get their size shown in the progress message, as
+// they are the usual reason the bar appears to stall.
+const LARGE_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+// How often JSON progress records are emitted while work is ongoing.
+const JSON_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ProgressFormat {
+    Bar,
+    Json,
+}
+
+#[derive(Serialize)]
+struct ProgressRecord<'a> {
+    phase: &'a str,
+    files_done: u64,
+    files_total: u64,
+    bytes_done: u64,
+    /// Known up front when verifying against a manifest with totals
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_total: Option<u64>,
+    elapsed_secs: f64,
+    bytes_per_sec: f64,
+}
+
+struct JsonState {
+    out: Box<dyn Write + Send>,
+    phase: &'static str,
+    last_emit: Instant,
+}
+
+struct JsonProgress {
+    state: Mutex<JsonState>,
+    started: Instant,
+    files_total: AtomicU64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    /// 0 while not known
+    bytes_total: AtomicU64,
+}
+
+impl JsonProgress {
+    fn emit(&self, state: &mut JsonState) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let bytes_done = self.bytes_done.load(Ordering::Relaxed);
+        let record = ProgressRecord {
+            phase: state.phase,
+            files_done: self.files_done.load(Ordering::Relaxed),
+            files_total: self.files_total.load(Ordering::Relaxed),
+            bytes_done,
+            bytes_total: Some(self.bytes_total.load(Ordering::Relaxed)).filter(|&b| b > 0),
+            elapsed_secs: elapsed,
+            bytes_per_sec: if elapsed > 0.0 {
+                bytes_done as f64 / elapsed
+            } else {
+                0.0
+            },
+        };
+        // Progress is best effort, a closed pipe must not abort the scan.
+        if let Ok(line) = serde_json::to_string(&record) {
+            let _ = writeln!(state.out, "{line}");
+            let _ = state.out.flush();
+        }
+        state.last_emit = Instant::now();
+    }
+}
+
+enum Inner {
+    Hidden,
+    Bar(ProgressBar),
+    Json(JsonProgress),
+}
+
+/// Reports scan progress either as an interactive bar or as periodic JSON
+/// records for wrappers that draw their own UI.
+pub struct Progress {
+    inner: Inner,
+    /// The totals were given by `expect`, the walk doesn't change them
+    expected: AtomicBool,
+}
+
+impl Progress {
+    pub fn hidden() -> Self {
+        Progress {
+            inner: Inner::Hidden,
+            expected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn bar() -> Self {
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("##-"),
+        );
+        Progress {
+            inner: Inner::Bar(bar),
+            expected: AtomicBool::new(false),
+        }
+    }
+
+    /// JSON records go to stderr, or to the already open file descriptor
+    /// `fd` when given.
+    pub fn json(fd: Option<i32>) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = match fd {
+            Some(fd) => Box::new(open_fd(fd)?),
+            None => Box::new(io::stderr()),
+        };
+        Ok(Progress {
+            inner: Inner::Json(JsonProgress {
+                state: Mutex::new(JsonState {
+                    out,
+                    phase: "walk",
+                    last_emit: Instant::now(),
+                }),
+                started: Instant::now(),
+                files_total: AtomicU64::new(0),
+                files_done: AtomicU64::new(0),
+                bytes_done: AtomicU64::new(0),
+                bytes_total: AtomicU64::new(0),
+            }),
+            expected: AtomicBool::new(false),
+        })
+    }
+
+    /// Start a new phase of work ("walk", "hash", ...).
+    pub fn set_phase(&self, phase: &'static str) {
+        if let Inner::Json(p) = &self.inner {
+            let mut state = p.state.lock().unwrap();
+            state.phase = phase;
+            p.emit(&mut state);
+        }
+    }
+
+    pub fn set_total(&self, files: u64) {
+        if self.expected.load(Ordering::Relaxed) {
+            return;
+        }
+        match &self.inner {
+            Inner::Hidden => {}
+            Inner::Bar(bar) => bar.set_length(files),
+            Inner::Json(p) => p.files_total.store(files, Ordering::Relaxed),
+        }
+    }
+
+    /// `files` more turned up, for work that starts before it's all known.
+    pub fn add_total(&self, files: u64) {
+        if self.expected.load(Ordering::Relaxed) {
+            return;
+        }
+        match &self.inner {
+            Inner::Hidden => {}
+            Inner::Bar(bar) => bar.inc_length(files),
+            Inner::Json(p) => {
+                p.files_total.fetch_add(files, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The work is about `totals`, known before the walk is: the bar goes
+    /// by bytes from then on.
+    pub fn expect(&self, totals: Totals) {
+        self.expected.store(true, Ordering::Relaxed);
+        match &self.inner {
+            Inner::Hidden => {}
+            Inner::Bar(bar) => {
+                bar.set_style(
+                    ProgressStyle::with_template(
+                        "[{elapsed_precise}] {bar:40.cyan/blue} {bytes}/{total_bytes} ({eta}) {wide_msg}",
+                    )
+                    .unwrap()
+                    .progress_chars("##-"),
+                );
+                bar.set_length(totals.bytes);
+            }
+            Inner::Json(p) => {
+                p.files_total.store(totals.files, Ordering::Relaxed);
+                p.bytes_total.store(totals.bytes, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// A worker picked up `path`.
+    pub fn start_file(&self, path: &Path) {
+        if let Inner::Bar(bar) = &self.inner {
+            // Show what a worker just picked up, so a long pause on a huge
+            // file doesn't look like a hang.
+            match std::fs::metadata(path) {
+                Ok(m) if m.len() >= LARGE_FILE_BYTES => {
+                    bar.set_message(format!("{} ({})", path.display(), HumanBytes(m.len())))
+                }
+                _ => bar.set_message(path.display().to_string()),
+            }
+        }
+    }
+
+    /// A file is done, `bytes` of it were read.
+    pub fn finish_file(&self, bytes: u64) {
+        match &self.inner {
+            Inner::Hidden => {}
+            Inner::Bar(bar) if self.expected.load(Ordering::Relaxed) => bar.inc(bytes),
+            Inner::Bar(bar) => bar.inc(1),
+            Inner::Json(p) => {
+                p.files_done.fetch_add(1, Ordering::Relaxed);
+                p.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+                // With millions of small files hashing threads would queue
+                // here, whoever has it emits for everyone
+                if let Ok(mut state) = p.state.try_lock()
+                    && state.last_emit.elapsed() >= JSON_INTERVAL
+                {
+                    p.emit(&mut state);
+                }
+            }
+        }
+    }
+
+    pub fn finish(&self, msg: &'static str) {
+        match &self.inner {
+            Inner::Hidden => {}
+            Inner::Bar(bar) => bar.finish_with_message(msg),
+            Inner::Json(p) => {
+                let mut state = p.state.lock().unwrap();
+                state.phase = "done";
+                p.emit(&mut state);
+            }
+        }
+    }
+}
+
+/// A duplicate of the caller's open descriptor `fd`, so records can go to
+/// it (stdout, say) without it being closed when we're done.
+#[cfg(unix)]
+fn open_fd(fd: i32) -> io::Result<File> {
+    use std::{ffi::c_int, os::fd::BorrowedFd};
+
+    const F_GETFD: c_int = 1;
+
+    unsafe extern "C" {
+        fn fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+    }
+
+    // SAFETY: F_GETFD only asks after the descriptor, any value will do
+    if fd < 0 || unsafe { fcntl(fd, F_GETFD) } < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{fd} isn't an open file descriptor"),
+        ));
+    }
+    // SAFETY: fd was just seen to be open, and is only borrowed for as long
+    // as it takes to duplicate it
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    Ok(File::from(borrowed.try_clone_to_owned()?))
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--progress-fd is only supported on unix",
+    ))
+}
@@ -0,0 +1,139 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+use crate::hash::{Digest, FileMeta, HashOptions, calculate_blake3};
+
+// --reread reads a mismatched file a second time before it's reported. A
+// second read with the recorded contents means the first one went wrong on
+// the way (a cable, a controller, memory), not the file; the same contents
+// as the first read mean the file did change; other contents again mean
+// reads of it can't be trusted at all. Without dropping the file from the
+// page cache first the second read would only read memory: on Linux it is
+// dropped, elsewhere it may still be. --reread-from reads it from another
+// mount of the same disk, or a replica, as well.
+
+/// What reading a mismatched file again came to.
+pub enum Again {
+    /// The recorded contents
+    Recorded(Box<FileMeta>),
+    /// What the first read found
+    Same,
+    /// Neither
+    Other(Digest),
+    Failed(io::Error),
+}
+
+impl Again {
+    fn of(read: io::Result<FileMeta>, expected: &Digest, found: &Digest) -> Again {
+        match read {
+            Ok(meta) if meta.hash == *expected => Again::Recorded(Box::new(meta)),
+            Ok(meta) if meta.hash == *found => Again::Same,
+            Ok(meta) => Again::Other(meta.hash),
+            Err(e) => Again::Failed(e),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Again::Recorded(_) => "recorded",
+            Again::Same => "same",
+            Again::Other(_) => "other",
+            Again::Failed(_) => "failed",
+        }
+    }
+}
+
+/// A mismatched file read again, and from the other mount if there's one.
+pub struct Reading {
+    pub again: Again,
+    pub elsewhere: Option<Again>,
+}
+
+impl Reading {
+    /// Whether only the first read was wrong.
+    pub fn transient(&self) -> bool {
+        matches!(self.again, Again::Recorded(_))
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.again {
+            Again::Recorded(_) => write!(f, "\n  read again: the recorded contents")?,
+            Again::Same => write!(f, "\n  read again: the same, changed on disk")?,
+            Again::Other(hash) => write!(f, "\n  read again: {hash}, reads are unreliable")?,
+            Again::Failed(e) => write!(f, "\n  read again: failed, {e}")?,
+        }
+        match &self.elsewhere {
+            Some(Again::Recorded(_)) => write!(f, "\n  elsewhere:  the recorded contents"),
+            Some(Again::Same) => write!(f, "\n  elsewhere:  the same as here"),
+            Some(Again::Other(hash)) => write!(f, "\n  elsewhere:  {hash}"),
+            Some(Again::Failed(e)) => write!(f, "\n  elsewhere:  can't read, {e}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where mismatched files are read again from.
+pub struct Reread {
+    /// The tree as given, and its manifest key that entries start with
+    dir: PathBuf,
+    dir_key: String,
+    elsewhere: Option<PathBuf>,
+    opts: HashOptions,
+}
+
+impl Reread {
+    pub fn new(dir: &Path, dir_key: String, elsewhere: Option<&Path>, opts: HashOptions) -> Self {
+        Reread {
+            dir: dir.to_path_buf(),
+            dir_key,
+            elsewhere: elsewhere.map(Path::to_path_buf),
+            opts,
+        }
+    }
+
+    /// Reads the file recorded as `key` again, `expected` being what the
+    /// manifest has and `found` what the first read came to. None when
+    /// it's not under the tree.
+    pub fn read(&self, key: &str, expected: &Digest, found: &Digest) -> Option<Reading> {
+        let relative = Path::new(key).strip_prefix(&self.dir_key).ok()?;
+        let read = |root: &Path| {
+            let path = root.join(relative);
+            uncache(&path);
+            calculate_blake3(&path, &self.opts).map(|(meta, _)| meta)
+        };
+        Some(Reading {
+            again: Again::of(read(&self.dir), expected, found),
+            elsewhere: self
+                .elsewhere
+                .as_deref()
+                .map(|elsewhere| Again::of(read(elsewhere), expected, found)),
+        })
+    }
+}
+
+/// Drops what the page cache has of the file at `path`, for the next read
+/// to go to the disk. Best effort, and left out where off_t may be 32 bits
+/// and the declaration below wouldn't match.
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn uncache(path: &Path) {
+    use std::{ffi::c_int, fs::File, os::fd::AsRawFd};
+
+    const POSIX_FADV_DONTNEED: c_int = 4;
+
+    unsafe extern "C" {
+        fn posix_fadvise(fd: c_int, offset: i64, len: i64, advice: c_int) -> c_int;
+    }
+
+    if let Ok(file) = File::open(path) {
+        // SAFETY: a plain call on an open descriptor, the whole file (len
+        // 0) is advised
+        unsafe { posix_fadvise(file.as_raw_fd(), 0, 0, POSIX_FADV_DONTNEED) };
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn uncache(_path: &Path) {}
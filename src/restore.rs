@@ -0,0 +1,96 @@
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use colored::*;
+use log::info;
+
+use crate::{
+    cli::RestoreTool,
+    dupes::{comment, quote},
+    hash::FileMeta,
+    style,
+};
+
+// --emit-restore <replica> writes a shell script to stdout that copies
+// every mismatched or missing file back from a replica of the tree, the
+// same relative path under it, for review before running it. Nothing is
+// copied by checkyoself itself. A replica can be damaged too: the
+// recorded hash of each file is in a comment above its command, `b3sum`
+// checks it.
+
+/// Where to restore from, and to.
+pub struct Restore {
+    replica: PathBuf,
+    /// The tree as given, and its manifest key that entries start with
+    dir: PathBuf,
+    dir_key: String,
+    with: RestoreTool,
+}
+
+impl Restore {
+    pub fn new(replica: &Path, dir: &Path, dir_key: String, with: RestoreTool) -> Self {
+        Restore {
+            replica: replica.to_path_buf(),
+            dir: dir.to_path_buf(),
+            dir_key,
+            with,
+        }
+    }
+
+    /// The script that restores the `damaged` entries, each a manifest key,
+    /// what's recorded of it, and whether it's missing rather than
+    /// mismatched.
+    fn script(&self, damaged: &[(&str, &FileMeta, bool)]) -> String {
+        let mut out = format!(
+            "#!/bin/sh\n\
+             # checkyoself --emit-restore: {} mismatched or missing files under {}\n\
+             # copied back from {}\n\
+             # A file the replica doesn't have, or that can't be copied, is left alone.\n\
+             set -u\n",
+            damaged.len(),
+            comment(&quote(&self.dir.to_string_lossy())),
+            comment(&quote(&self.replica.to_string_lossy()))
+        );
+        for (key, meta, missing) in damaged {
+            let Ok(relative) = Path::new(key).strip_prefix(&self.dir_key) else {
+                out.push_str(&format!(
+                    "# not under {}: {}\n",
+                    comment(&quote(&self.dir.to_string_lossy())),
+                    comment(&quote(key))
+                ));
+                continue;
+            };
+            let from = quote(&self.replica.join(relative).to_string_lossy());
+            let to = self.dir.join(relative);
+            let parent = quote(&to.parent().unwrap_or(&self.dir).to_string_lossy());
+            let to = quote(&to.to_string_lossy());
+            let copy = match self.with {
+                RestoreTool::Cp => format!("cp -p -- {from} {to}"),
+                RestoreTool::Rsync => format!("rsync -a -- {from} {to}"),
+            };
+            out.push_str(&format!(
+                "# {}, blake3 {}\nmkdir -p -- {parent} && {copy} || echo 'not restored:' {to} >&2\n",
+                if *missing { "missing" } else { "mismatched" },
+                meta.hash
+            ));
+        }
+        out
+    }
+
+    /// Prints the script for the `damaged` entries to stdout.
+    pub fn emit(&self, damaged: &[(&str, &FileMeta, bool)]) -> io::Result<()> {
+        io::stdout().write_all(self.script(damaged).as_bytes())?;
+        info!(
+            event = "restore_script",
+            files = damaged.len(),
+            replica:% = self.replica.display();
+            "\n{} Wrote a script restoring {} files from {} to stdout, for review",
+            style::pick("📝", "*").bold(),
+            damaged.len(),
+            self.replica.display()
+        );
+        Ok(())
+    }
+}
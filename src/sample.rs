@@ -0,0 +1,181 @@
+use std::{collections::HashSet, io};
+
+use colored::*;
+use log::{info, warn};
+
+use crate::{
+    hash::FileMeta,
+    style,
+    verify::{Reference, Summary},
+};
+
+// --sample-confidence verifies a random sample of the manifest instead of
+// the whole tree, as big as it takes to say with that confidence that fewer
+// than --sample-tolerance of the files are bad, should none in the sample
+// be. That's the hypergeometric distribution: with D bad files among N, the
+// chance a sample of n has none is
+//
+//   (N-D)/N * (N-D-1)/(N-1) * ... * (N-D-n+1)/(N-n+1)
+//
+// and the sample grows until that chance is below 1 - confidence. It
+// hardly depends on N once that's big: about 460 files rule out 1% at 99%
+// confidence, about 46000 rule out 0.01%, whether of a million files or a
+// hundred million.
+
+/// The smallest sample of `population` files in which finding no bad file
+/// rules out `bad` or more of them being bad, with `confidence` (0 to 1).
+fn sample_size(population: u64, bad: u64, confidence: f64) -> u64 {
+    let alpha = 1.0 - confidence;
+    let mut none_bad = 1.0;
+    for i in 0..population {
+        none_bad *= population.saturating_sub(bad + i) as f64 / (population - i) as f64;
+        if none_bad <= alpha {
+            return i + 1;
+        }
+    }
+    population
+}
+
+/// A small, fast generator for picking the sample, seeded from the system.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn seeded() -> io::Result<SplitMix> {
+        let mut seed = [0u8; 8];
+        getrandom::fill(&mut seed).map_err(|e| io::Error::other(e.to_string()))?;
+        Ok(SplitMix(u64::from_le_bytes(seed)))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`, near enough for a sample.
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next() as u128 * n as u128) >> 64) as u64
+    }
+}
+
+/// The reference entries a sampled verify checks.
+pub struct Sample {
+    paths: HashSet<String>,
+    population: u64,
+    /// The least number of bad files the sample rules out
+    bad: u64,
+    confidence: f64,
+    tolerance: f64,
+}
+
+impl Sample {
+    /// Picks the sample of the entries of `reference` that are `in_scope`,
+    /// for `confidence` that fewer than `tolerance` of them are bad (both
+    /// in percent).
+    pub fn pick(
+        reference: &dyn Reference,
+        in_scope: &dyn Fn(&str, Option<&FileMeta>) -> bool,
+        confidence: f64,
+        tolerance: f64,
+    ) -> io::Result<Sample> {
+        if !(confidence > 0.0 && confidence < 100.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--sample-confidence has to be above 0 and below 100",
+            ));
+        }
+        if !(tolerance > 0.0 && tolerance <= 100.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--sample-tolerance has to be above 0 and at most 100",
+            ));
+        }
+        let mut population = 0;
+        reference.for_each(&mut |path, meta| {
+            if in_scope(path, Some(meta)) {
+                population += 1;
+            }
+        })?;
+        // "Fewer than 0.01%" of 5000 files is none at all
+        let bad = ((population as f64 * tolerance / 100.0).ceil() as u64).max(1);
+        let size = sample_size(population, bad, confidence / 100.0) as usize;
+
+        // Reservoir sampling, one pass whatever the reference is
+        let mut rng = SplitMix::seeded()?;
+        let mut picked: Vec<String> = Vec::with_capacity(size);
+        let mut seen = 0;
+        reference.for_each(&mut |path, meta| {
+            if !in_scope(path, Some(meta)) {
+                return;
+            }
+            if picked.len() < size {
+                picked.push(path.to_string());
+            } else {
+                let j = rng.below(seen + 1) as usize;
+                if j < size {
+                    picked[j] = path.to_string();
+                }
+            }
+            seen += 1;
+        })?;
+        Ok(Sample {
+            paths: picked.into_iter().collect(),
+            population,
+            bad,
+            confidence,
+            tolerance,
+        })
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.paths.contains(path)
+    }
+
+    /// Says what the sample, verified as `summary`, tells about the tree.
+    pub fn report(&self, summary: &Summary) {
+        let size = self.paths.len();
+        let bad = summary.mismatched + summary.missing;
+        if bad == 0 {
+            info!(
+                event = "sample",
+                sampled = size,
+                files = self.population,
+                bad = 0,
+                confidence = self.confidence,
+                tolerance = self.tolerance;
+                "\n{} Sampled {} of {} files, none bad: {}% confidence that fewer than {}% of them ({} files) are",
+                style::pick("🎲", "*"),
+                size,
+                self.population,
+                self.confidence,
+                self.tolerance,
+                self.bad
+            );
+        } else {
+            let rate = bad as f64 / size as f64;
+            warn!(
+                event = "sample",
+                sampled = size,
+                files = self.population,
+                bad = bad,
+                confidence = self.confidence,
+                tolerance = self.tolerance;
+                "\n{} {} of {} sampled files are bad, about {:.2}% of the tree ({} of {} files): verify it in full",
+                style::pick("🎲", "*"),
+                bad,
+                size,
+                rate * 100.0,
+                (rate * self.population as f64).round(),
+                self.population
+            );
+        }
+        if size as u64 == self.population {
+            info!(
+                "{}",
+                "The sample took every file, no fewer would do".dimmed()
+            );
+        }
+    }
+}
@@ -0,0 +1,608 @@
+use std::{
+    collections::{BTreeMap, BinaryHeap, HashSet},
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    time::UNIX_EPOCH,
+};
+
+use colored::*;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, trace, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::{
+    ads,
+    busy::InUse,
+    cli::FilterArgs,
+    hash::{FileMeta, HashOptions, calculate_blake3, is_busy, is_volatile},
+    health::{self, ErrorClass, MediumErrors},
+    magic,
+    manifest::{Special, SpecialKind, path_key},
+    packages::Packaged,
+    progress::Progress,
+    style,
+};
+
+/// Which entries a walk takes, from `FilterArgs`. The default takes all.
+#[derive(Default)]
+pub struct Filter {
+    skip_dirs: Vec<String>,
+    skip_hidden: bool,
+    keep_hidden: GlobSet,
+    /// Lowercase, without the dot
+    ext: HashSet<String>,
+    skip_ext: HashSet<String>,
+    /// Modification time bounds, in seconds since the epoch
+    newer_than: Option<u64>,
+    older_than: Option<u64>,
+    packaged: Option<Packaged>,
+    ads: bool,
+    /// MIME types, `image/*` for any image
+    types: Vec<String>,
+}
+
+impl Filter {
+    pub fn new(args: &FilterArgs) -> io::Result<Filter> {
+        let mut keep_hidden = GlobSetBuilder::new();
+        for pattern in &args.keep_hidden {
+            let glob = Glob::new(pattern).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--keep-hidden {pattern}: {e}"),
+                )
+            })?;
+            keep_hidden.add(glob);
+        }
+        // `.JPG`, `jpg` and `Jpg` are all the same
+        let extensions = |list: &[String]| {
+            list.iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect()
+        };
+        if args.ads && !cfg!(windows) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "--ads is only supported on Windows",
+            ));
+        }
+        Ok(Filter {
+            ads: args.ads,
+            types: args.only_type.iter().map(|t| t.to_lowercase()).collect(),
+            ext: extensions(&args.ext),
+            skip_ext: extensions(&args.skip_ext),
+            newer_than: args.newer_than,
+            older_than: args.older_than,
+            packaged: args.skip_packaged.then(Packaged::load).transpose()?,
+            skip_dirs: args.skip_dirs.clone(),
+            skip_hidden: args.skip_hidden,
+            keep_hidden: keep_hidden
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?,
+        })
+    }
+
+    /// Whether the entry called `name` is left out, along with everything
+    /// under it.
+    fn hides(&self, name: &OsStr) -> bool {
+        self.skip_hidden
+            && name.as_encoded_bytes().starts_with(b".")
+            && !self.keep_hidden.is_match(name)
+    }
+
+    /// Whether the file at `path` has an extension the filter takes. A
+    /// stream goes by its file's.
+    fn takes_ext(&self, path: &Path) -> bool {
+        if self.ext.is_empty() && self.skip_ext.is_empty() {
+            return true;
+        }
+        let path = path.to_string_lossy();
+        let ext = Path::new(ads::base(&path))
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        (self.ext.is_empty() || self.ext.contains(&ext)) && !self.skip_ext.contains(&ext)
+    }
+
+    /// Whether contents of type `mime` are of one the filter takes.
+    fn takes_mime(&self, mime: &str) -> bool {
+        self.types.is_empty()
+            || self.types.iter().any(|t| match t.strip_suffix("/*") {
+                Some(group) => mime.split('/').next() == Some(group),
+                None => t == "*" || t == mime,
+            })
+    }
+
+    /// Whether the file at `path` is of a type the filter takes, by its
+    /// `recorded` type or else how it starts now. One that can't be read
+    /// is, for the hashing to say why.
+    fn takes_type(&self, path: &Path, recorded: Option<&str>) -> bool {
+        if self.types.is_empty() {
+            return true;
+        }
+        match recorded {
+            Some(mime) => self.takes_mime(mime),
+            None => magic::of_file(path).is_none_or(|mime| self.takes_mime(mime)),
+        }
+    }
+
+    fn is_packaged(&self, path: &Path) -> bool {
+        self.packaged.as_ref().is_some_and(|p| p.contains(path))
+    }
+
+    fn by_age(&self) -> bool {
+        self.newer_than.is_some() || self.older_than.is_some()
+    }
+
+    /// Whether a file last modified at `modified` is of an age the filter
+    /// takes.
+    fn takes_age(&self, modified: u64) -> bool {
+        self.newer_than.is_none_or(|t| modified > t) && self.older_than.is_none_or(|t| modified < t)
+    }
+
+    /// Whether it leaves out anything `takes` checks for. Without --ads on
+    /// Windows, streams a manifest has are.
+    pub fn narrows(&self) -> bool {
+        (cfg!(windows) && !self.ads)
+            || self.skip_hidden
+            || !self.ext.is_empty()
+            || !self.skip_ext.is_empty()
+            || self.by_age()
+            || self.packaged.is_some()
+            || !self.types.is_empty()
+    }
+
+    /// Whether the walk of `root` would take the manifest entry `path`,
+    /// `recorded` as it is. Ages go by the file as it is now, or as
+    /// recorded once it's gone; types by the recorded one, so a file whose
+    /// contents became something else isn't left out. Entries outside
+    /// `root` aren't this filter's business.
+    pub fn takes(&self, root: &Path, path: &str, recorded: Option<&FileMeta>) -> bool {
+        if !self.ads && ads::is_stream(path) {
+            return false;
+        }
+        let root = path_key(root);
+        let hidden = match Path::new(path).strip_prefix(&root) {
+            Ok(relative) => relative.iter().any(|name| self.hides(name)),
+            Err(_) => false,
+        };
+        if hidden || !self.takes_ext(Path::new(path)) || self.is_packaged(Path::new(path)) {
+            return false;
+        }
+        if !self.takes_type(Path::new(path), recorded.and_then(|m| m.mime.as_deref())) {
+            return false;
+        }
+        if !self.by_age() {
+            return true;
+        }
+        fs::symlink_metadata(path)
+            .ok()
+            .and_then(|m| modified_secs(&m))
+            .or(recorded.map(|m| m.modified))
+            .is_none_or(|modified| self.takes_age(modified))
+    }
+}
+
+fn modified_secs(meta: &fs::Metadata) -> Option<u64> {
+    let modified = meta.modified().ok()?;
+    Some(
+        modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    )
+}
+
+pub fn walk_files(dir: &Path, filter: &Filter) -> Vec<PathBuf> {
+    walk(dir, filter).0
+}
+
+/// What a walked entry is, if it's a FIFO, socket or device node.
+#[cfg(unix)]
+fn special(entry: &walkdir::DirEntry) -> Option<Special> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let file_type = entry.file_type();
+    let kind = if file_type.is_fifo() {
+        SpecialKind::Fifo
+    } else if file_type.is_socket() {
+        SpecialKind::Socket
+    } else if file_type.is_char_device() {
+        SpecialKind::CharDevice
+    } else if file_type.is_block_device() {
+        SpecialKind::BlockDevice
+    } else {
+        return None;
+    };
+    let device = matches!(kind, SpecialKind::CharDevice | SpecialKind::BlockDevice)
+        .then(|| entry.metadata().ok())
+        .flatten()
+        .map(|meta| health::device_number(meta.rdev()));
+    Some(Special { kind, device })
+}
+
+#[cfg(not(unix))]
+fn special(_entry: &walkdir::DirEntry) -> Option<Special> {
+    None
+}
+
+/// Walks `dir`, returning the regular files to hash and the special files
+/// found along the way. Symbolic links aren't followed and are left out.
+pub fn walk(dir: &Path, filter: &Filter) -> (Vec<PathBuf>, BTreeMap<String, Special>) {
+    let mut files = Vec::new();
+    let specials = walk_each(dir, filter, |path| files.push(path));
+    (files, specials)
+}
+
+/// `walk`, handing each regular file to `on_file` as soon as it's found.
+/// Returns the special files.
+fn walk_each(
+    dir: &Path,
+    filter: &Filter,
+    mut on_file: impl FnMut(PathBuf),
+) -> BTreeMap<String, Special> {
+    let mut specials = BTreeMap::new();
+    let entries = WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            // The root itself is walked whatever its name
+            if entry.depth() > 0 && filter.hides(entry.file_name()) {
+                return false;
+            }
+            // Skip directory if its name matches one of the skip_dirs
+            if entry.file_type().is_dir() {
+                if let Some(name) = entry.file_name().to_str() {
+                    !filter.skip_dirs.iter().any(|skip| name == skip)
+                } else {
+                    true
+                }
+            } else {
+                true
+            }
+        })
+        .filter_map(Result::ok);
+    for entry in entries {
+        if !filter.takes_ext(entry.path()) || filter.is_packaged(entry.path()) {
+            continue;
+        }
+        if filter.by_age() {
+            let modified = entry.metadata().ok().and_then(|m| modified_secs(&m));
+            if modified.is_some_and(|m| !filter.takes_age(m)) {
+                continue;
+            }
+        }
+        if entry.file_type().is_file() {
+            if !filter.takes_type(entry.path(), None) {
+                continue;
+            }
+            if filter.ads {
+                match ads::streams(entry.path()) {
+                    Ok(streams) => streams.into_iter().for_each(&mut on_file),
+                    Err(e) => warn!(
+                        event = "error",
+                        path:% = entry.path().display(),
+                        error:% = e;
+                        "{} {}: can't list streams: {}",
+                        ErrorClass::of(&e).marker(),
+                        entry.path().display(),
+                        e
+                    ),
+                }
+            }
+            on_file(entry.into_path());
+        } else if let Some(special) = special(&entry) {
+            specials.insert(path_key(entry.path()), special);
+        }
+    }
+    specials
+}
+
+/// How many walked files wait to be hashed at most: enough for the
+/// biggest of them to go first, few enough that memory stays flat however
+/// big the tree.
+const WAITING: usize = 4096;
+
+/// A tree to walk and hash at the same time, the files going to the
+/// hashing threads as the walk finds them.
+pub struct Walk<'a> {
+    root: PathBuf,
+    filter: &'a Filter,
+    keep: Box<dyn Fn(&Path) -> bool + Sync + 'a>,
+}
+
+impl<'a> Walk<'a> {
+    pub fn new(root: &Path, filter: &'a Filter) -> Self {
+        Walk {
+            root: root.to_path_buf(),
+            filter,
+            keep: Box::new(|_| true),
+        }
+    }
+
+    /// Only hashes the files `keep` takes, on top of what it took already.
+    pub fn keep(self, keep: impl Fn(&Path) -> bool + Sync + 'a) -> Self {
+        let before = self.keep;
+        Walk {
+            keep: Box::new(move |path| before(path) && keep(path)),
+            ..self
+        }
+    }
+
+    /// Walks and hashes the tree like `hash_files_until`. Returns the files
+    /// it had trouble with, and the special files found.
+    pub fn hash_until(
+        self,
+        opts: &HashOptions,
+        progress: &Progress,
+        stop: &AtomicBool,
+        on_file: impl Fn(String, FileMeta) + Sync,
+    ) -> (Trouble, BTreeMap<String, Special>) {
+        let (found, walked) = mpsc::sync_channel(WAITING);
+        let Walk { root, filter, keep } = &self;
+        let (trouble, (files, special)) = std::thread::scope(|s| {
+            let walker = s.spawn(move || {
+                let mut files = 0;
+                let special = walk_each(root, filter, |path| {
+                    if stop.load(Ordering::Relaxed) || !keep(&path) {
+                        return;
+                    }
+                    let size = fs::metadata(&path).map_or(0, |m| m.len());
+                    progress.add_total(1);
+                    files += 1;
+                    // Hashing only stops early once `stop` is set
+                    let _ = found.send((size, path));
+                });
+                (files, special)
+            });
+            let trouble = hash_queue(
+                Biggest {
+                    walked,
+                    waiting: BinaryHeap::new(),
+                },
+                opts,
+                progress,
+                stop,
+                on_file,
+            );
+            (trouble, walker.join().unwrap())
+        });
+        debug!(event = "walked", files = files, special = special.len(); "Found {} files and {} special files", files, special.len());
+        (trouble, special)
+    }
+}
+
+/// The files a walk found, the biggest of those waiting first.
+struct Biggest {
+    walked: mpsc::Receiver<(u64, PathBuf)>,
+    waiting: BinaryHeap<(u64, PathBuf)>,
+}
+
+impl Iterator for Biggest {
+    type Item = PathBuf;
+
+    fn next(&mut self) -> Option<PathBuf> {
+        while self.waiting.len() < WAITING
+            && let Ok(found) = self.walked.try_recv()
+        {
+            self.waiting.push(found);
+        }
+        if self.waiting.is_empty() {
+            // Waits for the walk, until it's done
+            self.waiting.push(self.walked.recv().ok()?);
+        }
+        self.waiting.pop().map(|(_, path)| path)
+    }
+}
+
+/// Files hashing had trouble with.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Trouble {
+    /// Couldn't be read, no hash
+    pub unreadable: usize,
+    /// Of those, for lack of permission
+    pub denied: usize,
+    /// Of those, because the storage failed to read them
+    pub medium: usize,
+    /// Changed while they were being hashed, no hash
+    pub volatile: usize,
+    /// Only read after retries, hashed
+    pub retried: usize,
+    /// Open for writing elsewhere with --skip-busy, not hashed; by path
+    #[serde(default)]
+    pub busy: Vec<String>,
+}
+
+/// Hashes `paths` on all cores, handing each file's entry to `on_file` as
+/// soon as it is done. Returns the files it had trouble with.
+pub fn hash_files_each(
+    paths: &[PathBuf],
+    opts: &HashOptions,
+    progress: &Progress,
+    on_file: impl Fn(String, FileMeta) + Sync,
+) -> Trouble {
+    hash_files_until(paths, opts, progress, &AtomicBool::new(false), on_file)
+}
+
+/// `hash_files_each`, leaving the files not started yet alone once `stop`
+/// is set. Files another process holds locked are put off until everything
+/// else is done and tried once more then, a database or a sharing
+/// violation having moved on by that time more often than not.
+pub fn hash_files_until(
+    paths: &[PathBuf],
+    opts: &HashOptions,
+    progress: &Progress,
+    stop: &AtomicBool,
+    on_file: impl Fn(String, FileMeta) + Sync,
+) -> Trouble {
+    progress.set_total(paths.len() as u64);
+    // Biggest first: a huge file that happened to be walked last would hash
+    // on one core while the others sat idle, small files fill in around the
+    // big ones instead
+    let mut queue: Vec<(u64, &PathBuf)> = paths
+        .par_iter()
+        .map(|path| (fs::metadata(path).map_or(0, |m| m.len()), path))
+        .collect();
+    queue.sort_by_key(|&(size, _)| std::cmp::Reverse(size));
+    hash_queue(
+        queue.into_iter().map(|(_, path)| path.clone()),
+        opts,
+        progress,
+        stop,
+        on_file,
+    )
+}
+
+/// Hashes the files `queue` hands out, one at a time to each thread as it
+/// comes free.
+fn hash_queue(
+    queue: impl Iterator<Item = PathBuf> + Send,
+    opts: &HashOptions,
+    progress: &Progress,
+    stop: &AtomicBool,
+    on_file: impl Fn(String, FileMeta) + Sync,
+) -> Trouble {
+    let unreadable = AtomicUsize::new(0);
+    let denied = AtomicUsize::new(0);
+    let medium = Mutex::new(MediumErrors::default());
+    let volatile = AtomicUsize::new(0);
+    let retried = AtomicUsize::new(0);
+    let put_off = Mutex::new(Vec::new());
+    let in_use = opts.skip_busy.then(InUse::scan);
+    let skipped = Mutex::new(Vec::new());
+
+    progress.set_phase("hash");
+
+    let hash_one = |path: &PathBuf, second_pass: bool| {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        progress.start_file(path);
+
+        if in_use.as_ref().is_some_and(|in_use| in_use.holds(path)) {
+            warn!(
+                event = "busy",
+                path:% = path.display();
+                "{} {}: open for writing elsewhere, skipped",
+                style::pick("⏳ BUSY", "BUSY").yellow(),
+                path.display()
+            );
+            skipped.lock().unwrap().push(path_key(path));
+            progress.finish_file(0);
+            return;
+        }
+
+        let mut bytes = 0;
+        match calculate_blake3(path, opts) {
+            Ok((meta, retries)) => {
+                if second_pass {
+                    retried.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        event = "retried",
+                        path:% = path.display(),
+                        retries = retries + 1;
+                        "{} {}: read on the second pass",
+                        style::pick("🔁 RETRIED", "RETRIED").yellow(),
+                        path.display()
+                    );
+                } else if retries > 0 {
+                    retried.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        event = "retried",
+                        path:% = path.display(),
+                        retries = retries;
+                        "{} {}: read after {} retries",
+                        style::pick("🔁 RETRIED", "RETRIED").yellow(),
+                        path.display(),
+                        retries
+                    );
+                }
+                trace!(
+                    event = "hashed",
+                    path:% = path.display(),
+                    hash:% = meta.hash;
+                    "hashed {}",
+                    path.display()
+                );
+                bytes = meta.size as u64;
+                on_file(path_key(path), meta);
+            }
+            Err(e) if !second_pass && is_busy(&e) => {
+                debug!(
+                    event = "put_off",
+                    path:% = path.display(),
+                    error:% = e;
+                    "{} is busy, trying it again at the end: {e}",
+                    path.display()
+                );
+                put_off.lock().unwrap().push(path.clone());
+                // Counted as done on the second pass
+                return;
+            }
+            Err(e) if is_volatile(&e) => {
+                volatile.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    event = "volatile",
+                    path:% = path.display();
+                    "{} {}: {}",
+                    style::pick("🌀 VOLATILE", "VOLATILE").yellow(),
+                    path.display(),
+                    e
+                );
+            }
+            Err(e) => {
+                unreadable.fetch_add(1, Ordering::Relaxed);
+                let class = ErrorClass::of(&e);
+                match class {
+                    ErrorClass::Permission => {
+                        denied.fetch_add(1, Ordering::Relaxed);
+                    }
+                    ErrorClass::Medium => medium.lock().unwrap().add(path),
+                    ErrorClass::Gone | ErrorClass::Other => {}
+                }
+                warn!(
+                    event = "error",
+                    path:% = path.display(),
+                    class = class.as_str(),
+                    error:% = e;
+                    "{} {}: {}",
+                    class.marker(),
+                    path.display(),
+                    e
+                );
+            }
+        }
+
+        progress.finish_file(bytes);
+    };
+    queue.par_bridge().for_each(|path| hash_one(&path, false));
+    let put_off = std::mem::take(&mut *put_off.lock().unwrap());
+    if !put_off.is_empty() {
+        debug!(event = "second_pass", files = put_off.len(); "Trying {} busy files again", put_off.len());
+    }
+    for path in &put_off {
+        hash_one(path, true);
+    }
+
+    progress.finish(if stop.load(Ordering::Relaxed) {
+        "Hashing stopped"
+    } else {
+        "Hashing complete"
+    });
+    let medium = medium.into_inner().unwrap();
+    let trouble = Trouble {
+        unreadable: unreadable.into_inner(),
+        denied: denied.into_inner(),
+        medium: medium.count(),
+        volatile: volatile.into_inner(),
+        retried: retried.into_inner(),
+        busy: skipped.into_inner().unwrap(),
+    };
+    medium.report();
+    trouble
+}
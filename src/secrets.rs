@@ -0,0 +1,55 @@
+use std::{fs, io, path::Path};
+
+use keyring::Entry;
+
+// Secret keys can live in the platform keyring (Secret Service, macOS
+// Keychain, Windows Credential Manager) instead of a file. Anywhere a secret
+// key file is expected, `keyring:<name>` reads the entry `<name>` of the
+// `checkyoself` service.
+
+const SERVICE: &str = "checkyoself";
+const PREFIX: &str = "keyring:";
+
+/// The keyring entry name if `path` is `keyring:<name>`.
+pub fn keyring_name(path: &Path) -> Option<&str> {
+    path.to_str()?
+        .strip_prefix(PREFIX)
+        .filter(|name| !name.is_empty())
+}
+
+fn keyring_error(name: &str, e: keyring::Error) -> io::Error {
+    let kind = match e {
+        keyring::Error::NoEntry => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, format!("{PREFIX}{name}: {e}"))
+}
+
+/// Reads the secret at `path`, a file or a `keyring:<name>` entry.
+pub fn read(path: &Path) -> io::Result<String> {
+    match keyring_name(path) {
+        Some(name) => Entry::new(SERVICE, name)
+            .and_then(|entry| entry.get_password())
+            .map_err(|e| keyring_error(name, e)),
+        None => fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))),
+    }
+}
+
+/// Stores `secret` as the keyring entry `name`, refusing to replace one.
+pub fn store(name: &str, secret: &str) -> io::Result<()> {
+    let entry = Entry::new(SERVICE, name).map_err(|e| keyring_error(name, e))?;
+    match entry.get_password() {
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{PREFIX}{name} already exists"),
+            ));
+        }
+        Err(keyring::Error::NoEntry) => {}
+        Err(e) => return Err(keyring_error(name, e)),
+    }
+    entry
+        .set_password(secret)
+        .map_err(|e| keyring_error(name, e))
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use colored::*;
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
+
+use crate::{
+    cli::{SfvExportArgs, SfvVerifyArgs},
+    hash::{FileMeta, HashOptions, calculate_blake3},
+    health::ErrorClass,
+    manifest::{self, Manifest},
+    store, style, volume,
+};
+
+// Archives annotated by older tools come with an .sfv file: a line
+// `<path> <CRC-32>` per file, in 8 hex digits, paths relative to the .sfv
+// and `;` starting a comment. `sfv verify` checks the files against it,
+// reading each once for its CRC-32 and BLAKE3 both, and --upgrade writes
+// those that matched into a manifest: from then on they're checked by
+// BLAKE3, with the CRC-32 kept in `crc32`. `sfv export` goes the other way,
+// for tools that only read .sfv, from a manifest made with --crc32.
+
+/// An entry of an .sfv file.
+struct Entry {
+    /// As the file has it, `\` turned into `/`
+    name: String,
+    crc32: String,
+}
+
+fn parse(data: &str) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        // Names can have spaces, the CRC comes last
+        let entry = line.rsplit_once(' ').and_then(|(name, crc32)| {
+            let name = name.trim_end();
+            (crc32.len() == 8 && crc32.bytes().all(|b| b.is_ascii_hexdigit()) && !name.is_empty())
+                .then(|| Entry {
+                    name: name.replace('\\', "/"),
+                    crc32: crc32.to_ascii_lowercase(),
+                })
+        });
+        let Some(entry) = entry else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {}: expected a path and 8 hex digits", n + 1),
+            ));
+        };
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[derive(Default)]
+struct Counts {
+    matched: usize,
+    mismatched: usize,
+    missing: usize,
+    errors: usize,
+}
+
+/// Checks the file `entry` names, under `dir`, adding it to `matched` if it
+/// does.
+fn check(
+    dir: &Path,
+    entry: &Entry,
+    opts: &HashOptions,
+    counts: &Mutex<Counts>,
+    matched: &Mutex<HashMap<String, FileMeta>>,
+) {
+    let path = dir.join(&entry.name);
+    let name = entry.name.as_str();
+    match calculate_blake3(&path, opts) {
+        Ok((meta, _)) if meta.crc32.as_deref() == Some(entry.crc32.as_str()) => {
+            counts.lock().unwrap().matched += 1;
+            debug!(event = "matched", path = name; "{} {name}", style::pick("✅ MATCHED", "MATCHED").green());
+            matched
+                .lock()
+                .unwrap()
+                .insert(manifest::path_key(&path), meta);
+        }
+        Ok((meta, _)) => {
+            counts.lock().unwrap().mismatched += 1;
+            let found = meta.crc32.as_deref().unwrap_or_default();
+            error!(
+                event = "mismatch",
+                path = name,
+                expected = entry.crc32.as_str(),
+                found = found;
+                "{} {name}\n  expected: {} (CRC-32)\n  found:    {found}",
+                style::pick("❌ MISMATCH", "MISMATCH").red(),
+                entry.crc32
+            );
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            counts.lock().unwrap().missing += 1;
+            error!(event = "missing", path = name; "{} {name}", style::pick("❓ MISSING", "MISSING").magenta());
+        }
+        Err(e) => {
+            counts.lock().unwrap().errors += 1;
+            let class = ErrorClass::of(&e);
+            warn!(
+                event = "error",
+                path = name,
+                class = class.as_str(),
+                error:% = e;
+                "{} {name}: {e}",
+                class.marker()
+            );
+        }
+    }
+}
+
+fn verify(args: &SfvVerifyArgs) -> io::Result<Counts> {
+    let data = fs::read_to_string(&args.sfv)
+        .and_then(|data| parse(&data))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", args.sfv.display(), e)))?;
+    // Paths are relative to where the .sfv is
+    let dir = match args.sfv.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let mut opts = HashOptions::from_args(&args.run.hashing, &args.run.filter);
+    opts.crc32 = true;
+
+    let started = manifest::now_secs();
+    let counts = Mutex::new(Counts::default());
+    let matched = Mutex::new(HashMap::new());
+    data.par_iter()
+        .for_each(|entry| check(&dir, entry, &opts, &counts, &matched));
+    let counts = counts.into_inner().unwrap();
+    info!(
+        event = "sfv_summary",
+        matched = counts.matched,
+        mismatched = counts.mismatched,
+        missing = counts.missing,
+        errors = counts.errors;
+        "\n=== {} ===\n{} {}\n{} {}\n{} {}\n{} {}",
+        "SFV".bold().underline(),
+        style::pick("✅ Matched:", "Matched:").green(),
+        counts.matched,
+        style::pick("❌ Mismatched:", "Mismatched:").red(),
+        counts.mismatched,
+        style::pick("❓ Missing:", "Missing:").magenta(),
+        counts.missing,
+        style::pick("⚠️ Unreadable:", "Unreadable:").yellow(),
+        counts.errors
+    );
+
+    let Some(output) = &args.upgrade else {
+        return Ok(counts);
+    };
+    let matched = matched.into_inner().unwrap();
+    let mut upgraded = Manifest::new(&dir, matched, BTreeMap::new(), started);
+    upgraded.volume = volume::of(&dir);
+    store::save(&upgraded, output, &args.run)?;
+    info!(
+        event = "written",
+        output:% = output.display(),
+        entries = upgraded.files.len();
+        "{} files that matched written to {}",
+        upgraded.files.len(),
+        output.display()
+    );
+    let left_out = counts.mismatched + counts.missing + counts.errors;
+    if left_out > 0 {
+        warn!("{left_out} file(s) that didn't match were left out of it");
+    }
+    Ok(counts)
+}
+
+/// `sfv verify`: exit code 0, 2 if anything is missing, differs or can't
+/// be read, 1 on errors.
+pub fn verify_command(args: &SfvVerifyArgs) -> i32 {
+    match verify(args) {
+        Ok(counts) if counts.mismatched > 0 || counts.missing > 0 || counts.errors > 0 => 2,
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+/// The .sfv lines of a manifest's files, by path relative to its root.
+fn export(args: &SfvExportArgs) -> io::Result<String> {
+    let data = fs::read(&args.manifest)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", args.manifest.display(), e)))?;
+    let manifest = store::decode(&args.manifest, data, args.identity.as_deref())?;
+    let root = manifest.root.clone().unwrap_or_default();
+    let mut lines = Vec::new();
+    let mut without = 0;
+    for (path, meta) in &manifest.files {
+        let relative = Path::new(path)
+            .strip_prefix(&root)
+            .ok()
+            .and_then(|p| p.to_str())
+            .unwrap_or(path);
+        // A line break would end the line, a `;` make it a comment
+        if relative.contains(['\n', '\r']) || relative.starts_with(';') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{path}: an .sfv file can't have this path"),
+            ));
+        }
+        match &meta.crc32 {
+            Some(crc32) => lines.push((relative, crc32.to_ascii_uppercase())),
+            None => without += 1,
+        }
+    }
+    if without > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{without} entries have no CRC-32, create the manifest with --crc32"),
+        ));
+    }
+    lines.sort_unstable();
+    let mut sfv = format!("; made by checkyoself from {}\n", args.manifest.display());
+    for (path, crc32) in lines {
+        sfv.push_str(&format!("{path} {crc32}\n"));
+    }
+    Ok(sfv)
+}
+
+/// `sfv export`: exit code 0, 1 on errors.
+pub fn export_command(args: &SfvExportArgs) -> i32 {
+    let written = export(args).and_then(|sfv| match &args.output {
+        Some(output) => fs::write(output, sfv)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", output.display(), e))),
+        None => {
+            print!("{sfv}");
+            Ok(())
+        }
+    });
+    match written {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
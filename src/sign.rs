@@ -0,0 +1,144 @@
+use std::{
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+use crate::secrets;
+
+// Key and signature files are two lines, a free text comment and the base64
+// payload, the same layout minisign uses.
+const SECRET_COMMENT: &str = "untrusted comment: checkyoself ed25519 secret key";
+const PUBLIC_COMMENT: &str = "untrusted comment: checkyoself ed25519 public key";
+const SIGNATURE_COMMENT: &str = "untrusted comment: checkyoself ed25519 signature";
+
+fn invalid(path: &Path, what: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), what),
+    )
+}
+
+/// Reads the base64 payload of a comment + payload file.
+fn read_payload<const N: usize>(path: &Path) -> io::Result<[u8; N]> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    parse_payload(path, &data)
+}
+
+fn parse_payload<const N: usize>(path: &Path, data: &str) -> io::Result<[u8; N]> {
+    let line = data
+        .lines()
+        .map(str::trim)
+        .find(|l| !l.is_empty() && !l.starts_with("untrusted comment:"))
+        .ok_or_else(|| invalid(path, "no key or signature found"))?;
+    let bytes = STANDARD
+        .decode(line)
+        .map_err(|e| invalid(path, format!("bad base64: {e}")))?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| invalid(path, format!("expected {N} bytes, found {}", b.len())))
+}
+
+fn write_payload(path: &Path, comment: &str, bytes: &[u8]) -> io::Result<()> {
+    fs::write(path, payload(comment, bytes))
+}
+
+fn payload(comment: &str, bytes: &[u8]) -> String {
+    format!("{comment}\n{}\n", STANDARD.encode(bytes))
+}
+
+/// Creates `path` for a new key, never overwriting one. Secret keys are
+/// only readable by their owner from the start.
+fn create_key_file(path: &Path, secret: bool) -> io::Result<fs::File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if secret {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = secret;
+    options
+        .open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+/// The detached signature that goes with `manifest`: `<manifest>.sig`.
+pub fn signature_path(manifest: &Path) -> PathBuf {
+    let mut name = OsString::from(manifest.as_os_str());
+    name.push(".sig");
+    PathBuf::from(name)
+}
+
+/// `prefix` with `.ext` added, a dot in its name or not.
+fn key_path(prefix: &Path, ext: &str) -> PathBuf {
+    let mut name = OsString::from(prefix.as_os_str());
+    name.push(format!(".{ext}"));
+    PathBuf::from(name)
+}
+
+/// Loads a secret key from a file or a `keyring:<name>` entry.
+pub fn load_signing_key(path: &Path) -> io::Result<SigningKey> {
+    let data = secrets::read(path)?;
+    Ok(SigningKey::from_bytes(&parse_payload(path, &data)?))
+}
+
+pub fn load_verifying_key(path: &Path) -> io::Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&read_payload(path)?).map_err(|e| invalid(path, e))
+}
+
+/// Writes a new key pair to `<prefix>.key` and `<prefix>.pub`, or with
+/// `keyring` set, the secret key to that keyring entry instead. Returns
+/// where the secret and public keys went.
+pub fn keygen(prefix: &Path, keyring: Option<&str>) -> io::Result<(String, PathBuf)> {
+    let mut seed = [0u8; 32];
+    getrandom::fill(&mut seed).map_err(|e| io::Error::other(e.to_string()))?;
+    let key = SigningKey::from_bytes(&seed);
+
+    let public = key_path(prefix, "pub");
+    if public.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} already exists", public.display()),
+        ));
+    }
+
+    let secret = match keyring {
+        Some(name) => {
+            secrets::store(name, &payload(SECRET_COMMENT, &seed))?;
+            format!("keyring:{name}")
+        }
+        None => {
+            let path = key_path(prefix, "key");
+            create_key_file(&path, true)?.write_all(payload(SECRET_COMMENT, &seed).as_bytes())?;
+            path.display().to_string()
+        }
+    };
+    create_key_file(&public, false)?
+        .write_all(payload(PUBLIC_COMMENT, key.verifying_key().as_bytes()).as_bytes())?;
+
+    Ok((secret, public))
+}
+
+/// Signs `data`, the bytes of `manifest` as written, putting the detached
+/// signature next to it.
+pub fn sign_manifest(manifest: &Path, data: &[u8], key: &SigningKey) -> io::Result<PathBuf> {
+    let signature = key.sign(data);
+    let path = signature_path(manifest);
+    write_payload(&path, SIGNATURE_COMMENT, &signature.to_bytes())?;
+    Ok(path)
+}
+
+/// Checks `data`, the bytes read from `manifest`, against its detached
+/// signature. Anything short of a valid signature by `key` is an error.
+pub fn verify_manifest(manifest: &Path, data: &[u8], key: &VerifyingKey) -> io::Result<()> {
+    let signature = Signature::from_bytes(&read_payload(&signature_path(manifest))?);
+    key.verify(data, &signature)
+        .map_err(|_| invalid(manifest, "signature doesn't match, refusing to trust it"))
+}
@@ -0,0 +1,312 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use colored::*;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::style;
+
+// A job with `smart` set reads the SMART data of the disks under its root
+// before and after the scan. Sectors the disk reallocated or can't read
+// while being scrubbed tie a mismatch to the hardware rather than to
+// software or people, and grow before the disk gives up entirely. Read with
+// smartctl (smartmontools 7 or later, for --json); NVMe drives have no such
+// counts and are left out.
+
+/// ATA attribute ids
+const REALLOCATED: u64 = 5;
+const PENDING: u64 = 197;
+
+/// Which disks a job's SMART data is read from: `smart = true` for those
+/// its root is on (found on Linux), or the devices themselves.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Disks {
+    Root(bool),
+    Devices(Vec<PathBuf>),
+}
+
+impl Disks {
+    /// The whole disks to read, none when off.
+    pub fn resolve(&self, root: &Path) -> Vec<PathBuf> {
+        match self {
+            Disks::Root(false) => Vec::new(),
+            Disks::Root(true) => {
+                let disks = under(root);
+                if disks.is_empty() {
+                    warn!(
+                        event = "smart_failed",
+                        root:% = root.display();
+                        "can't tell which disks {} is on for SMART data, list them with smart = [\"/dev/...\"]",
+                        root.display()
+                    );
+                }
+                disks
+            }
+            Disks::Devices(devices) => devices.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Disks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Disks::Root(true) => f.write_str("the disks under root"),
+            Disks::Root(false) => f.write_str("off"),
+            Disks::Devices(devices) => {
+                let devices: Vec<String> =
+                    devices.iter().map(|d| d.display().to_string()).collect();
+                f.write_str(&devices.join(", "))
+            }
+        }
+    }
+}
+
+/// The counts a disk reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Counts {
+    pub reallocated: u64,
+    pub pending: u64,
+}
+
+#[derive(Deserialize)]
+struct Report {
+    ata_smart_attributes: Option<Attributes>,
+}
+
+#[derive(Deserialize)]
+struct Attributes {
+    table: Vec<Attribute>,
+}
+
+#[derive(Deserialize)]
+struct Attribute {
+    id: u64,
+    raw: Raw,
+}
+
+#[derive(Deserialize)]
+struct Raw {
+    value: u64,
+}
+
+/// The SMART counts of `disk`, None when it has none (NVMe).
+pub fn read(disk: &Path) -> io::Result<Option<Counts>> {
+    let output = Command::new("smartctl")
+        .arg("--json")
+        .arg("-A")
+        .arg(disk)
+        .output()?;
+    // The exit status is a bit mask of what's wrong with the disk, the
+    // JSON is there regardless; none means it couldn't be asked at all
+    let report: Report = serde_json::from_slice(&output.stdout).map_err(|e| {
+        io::Error::other(format!(
+            "smartctl: {e}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    })?;
+    let Some(attributes) = report.ata_smart_attributes else {
+        return Ok(None);
+    };
+    let raw = |id| {
+        attributes
+            .table
+            .iter()
+            .find(|a| a.id == id)
+            .map_or(0, |a| a.raw.value)
+    };
+    Ok(Some(Counts {
+        reallocated: raw(REALLOCATED),
+        pending: raw(PENDING),
+    }))
+}
+
+/// A disk's counts before the scan, and after.
+pub struct Change {
+    pub disk: PathBuf,
+    pub before: Counts,
+    pub after: Counts,
+}
+
+impl Change {
+    /// Whether the disk got worse during the scan.
+    pub fn worse(&self) -> bool {
+        self.after.reallocated > self.before.reallocated || self.after.pending > self.before.pending
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let delta = |before: u64, after: u64| match after.cmp(&before) {
+            std::cmp::Ordering::Equal => format!("{after}"),
+            std::cmp::Ordering::Greater => format!("{before} -> {after} (+{})", after - before),
+            std::cmp::Ordering::Less => format!("{before} -> {after} (-{})", before - after),
+        };
+        write!(
+            f,
+            "reallocated sectors {}, pending {}",
+            delta(self.before.reallocated, self.after.reallocated),
+            delta(self.before.pending, self.after.pending)
+        )
+    }
+}
+
+/// The counts of `disks` before a scan.
+pub struct Before(Vec<(PathBuf, Counts)>);
+
+impl Before {
+    /// Reads `disks`, leaving out those that can't be read (warned about)
+    /// or have no counts.
+    pub fn read(disks: Vec<PathBuf>) -> Before {
+        Before(
+            disks
+                .into_iter()
+                .filter_map(|disk| match read(&disk) {
+                    Ok(Some(counts)) => Some((disk, counts)),
+                    Ok(None) => {
+                        info!(
+                            event = "smart_skipped",
+                            disk:% = disk.display();
+                            "{}: no reallocated or pending sector counts (NVMe?), not followed",
+                            disk.display()
+                        );
+                        None
+                    }
+                    Err(e) => {
+                        warn!(
+                            event = "smart_failed",
+                            disk:% = disk.display();
+                            "can't read SMART data of {}: {e}",
+                            disk.display()
+                        );
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// Reads the disks again after the scan of job `job`, which ended with
+    /// exit code `code`, and reports how they changed. Whether any got
+    /// worse.
+    pub fn report(self, job: &str, code: i32) -> bool {
+        let mut worse = false;
+        for (disk, before) in self.0 {
+            let after = match read(&disk) {
+                Ok(Some(after)) => after,
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!(
+                        event = "smart_failed",
+                        disk:% = disk.display();
+                        "can't read SMART data of {} after the scan: {e}",
+                        disk.display()
+                    );
+                    continue;
+                }
+            };
+            let change = Change {
+                disk,
+                before,
+                after,
+            };
+            if !change.worse() {
+                info!(
+                    event = "smart",
+                    job = job,
+                    disk:% = change.disk.display(),
+                    reallocated = change.after.reallocated,
+                    pending = change.after.pending;
+                    "{} {}: {}",
+                    style::pick("💽 SMART", "SMART").green(),
+                    change.disk.display(),
+                    change
+                );
+                continue;
+            }
+            worse = true;
+            warn!(
+                event = "smart_worse",
+                job = job,
+                disk:% = change.disk.display(),
+                reallocated_before = change.before.reallocated,
+                reallocated = change.after.reallocated,
+                pending_before = change.before.pending,
+                pending = change.after.pending;
+                "{} {} during the scan: {}{}",
+                style::pick("🚨 SMART WORSE", "SMART WORSE").red().bold(),
+                change.disk.display(),
+                change,
+                if code == 2 {
+                    ", the mismatches above may well be the disk's doing"
+                } else {
+                    ", back it up"
+                }
+            );
+        }
+        worse
+    }
+}
+
+/// The whole disks under `root`, through device mapper and md layers, from
+/// /sys.
+#[cfg(target_os = "linux")]
+fn under(root: &Path) -> Vec<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    use crate::health;
+
+    let Ok(meta) = root.metadata() else {
+        return Vec::new();
+    };
+    let id = health::device_number(meta.dev());
+    // btrfs and the like hand out device numbers of their own, what's
+    // mounted is a real one
+    let block = std::fs::canonicalize(format!("/sys/dev/block/{id}")).or_else(|_| {
+        let source = health::mount_source(&id).ok_or(io::ErrorKind::NotFound)?;
+        let name = Path::new(&source)
+            .file_name()
+            .ok_or(io::ErrorKind::NotFound)?;
+        std::fs::canonicalize(Path::new("/sys/class/block").join(name))
+    });
+    let mut disks = Vec::new();
+    if let Ok(block) = block {
+        disks_of(&block, &mut disks);
+    }
+    disks.sort();
+    disks.dedup();
+    disks
+}
+
+/// Adds the disks under the block device at `block` in /sys to `disks`.
+#[cfg(target_os = "linux")]
+fn disks_of(block: &Path, disks: &mut Vec<PathBuf>) {
+    let slaves: Vec<PathBuf> = std::fs::read_dir(block.join("slaves"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| std::fs::canonicalize(entry.ok()?.path()).ok())
+        .collect();
+    if !slaves.is_empty() {
+        for slave in slaves {
+            disks_of(&slave, disks);
+        }
+        return;
+    }
+    // A partition's directory is in its disk's
+    let disk = match block.join("partition").exists() {
+        true => block.parent(),
+        false => Some(block),
+    };
+    if let Some(name) = disk.and_then(Path::file_name) {
+        disks.push(Path::new("/dev").join(name));
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn under(_root: &Path) -> Vec<PathBuf> {
+    Vec::new()
+}
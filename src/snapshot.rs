@@ -0,0 +1,634 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use colored::*;
+use indicatif::HumanBytes;
+use log::{error, info};
+
+use crate::{
+    cli::{
+        KeepArgs, SnapshotCreateArgs, SnapshotDiffArgs, SnapshotListArgs, SnapshotPruneArgs,
+        SnapshotTagArgs, SnapshotVerifyArgs,
+    },
+    execute,
+    hash::FileMeta,
+    journal::{self, ChangeKind},
+    manifest::Manifest,
+    merkle, store, style,
+};
+
+// A snapshot directory holds one manifest per scan, named after the UTC time
+// it was taken: `2024-06-10T08-30-00Z.json` (no colons, for Windows). Names
+// sort by time, and anything else in the directory is left alone.
+//
+// Snapshots can be tagged too, `pre-migration` or `2024-Q4`, and picked by
+// tag wherever a name goes. Tags are kept in `tags.json` next to them, each
+// naming one snapshot; a snapshot can have several. Pruning never deletes a
+// tagged snapshot.
+
+struct Snapshot {
+    name: String,
+    path: PathBuf,
+    taken: u64,
+}
+
+fn name_for(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time)
+        .to_string()
+        .replace(':', "-")
+}
+
+/// When the snapshot called `name` was taken, if it is one.
+fn parse_name(name: &str) -> Option<u64> {
+    let mut rfc3339 = name.to_string();
+    if rfc3339.len() != 20 || !rfc3339.is_char_boundary(13) || !rfc3339.is_char_boundary(16) {
+        return None;
+    }
+    rfc3339.replace_range(13..14, ":");
+    rfc3339.replace_range(16..17, ":");
+    let time = humantime::parse_rfc3339(&rfc3339).ok()?;
+    Some(time.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// The snapshots in `dir`, oldest first.
+fn snapshots(dir: &Path) -> io::Result<Vec<Snapshot>> {
+    let entries = fs::read_dir(dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?;
+    let mut snapshots: Vec<Snapshot> = entries
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let name = e.file_name().to_str()?.strip_suffix(".json")?.to_string();
+            let taken = parse_name(&name)?;
+            Some(Snapshot {
+                path: e.path(),
+                name,
+                taken,
+            })
+        })
+        .collect();
+    snapshots.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(snapshots)
+}
+
+/// Tags by name, each with the snapshot it's on.
+type Tags = BTreeMap<String, String>;
+
+fn tags_path(dir: &Path) -> PathBuf {
+    dir.join("tags.json")
+}
+
+/// The tags of the snapshots in `dir`, none if it has no `tags.json`.
+fn tags(dir: &Path) -> io::Result<Tags> {
+    let path = tags_path(dir);
+    match fs::read(&path) {
+        Ok(data) => serde_json::from_slice(&data).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Tags::new()),
+        Err(e) => Err(io::Error::new(e.kind(), format!("{}: {e}", path.display()))),
+    }
+}
+
+/// Writes `tags` to `dir`, through a temporary file so a crash can't leave
+/// them half written.
+fn save_tags(dir: &Path, tags: &Tags) -> io::Result<()> {
+    let path = tags_path(dir);
+    let temp = dir.join("tags.json.tmp");
+    let data = serde_json::to_vec_pretty(tags)?;
+    fs::write(&temp, data)
+        .and_then(|_| fs::rename(&temp, &path))
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display())))
+}
+
+/// The tags on the snapshot called `name`.
+fn tags_of<'a>(tags: &'a Tags, name: &str) -> Vec<&'a str> {
+    tags.iter()
+        .filter(|(_, on)| *on == name)
+        .map(|(tag, _)| tag.as_str())
+        .collect()
+}
+
+/// Whether `tag` can be a tag: not empty, not a snapshot name or `latest`
+/// it would be mistaken for, no path separators.
+fn check_tag(tag: &str) -> io::Result<()> {
+    if tag.is_empty()
+        || tag.starts_with("latest")
+        || parse_name(tag).is_some()
+        || tag.contains(['/', '\\'])
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{tag}' can't be a tag"),
+        ));
+    }
+    Ok(())
+}
+
+/// Puts `new` tags on the snapshot called `name`, refusing to move one
+/// that's on another snapshot unless `force`.
+fn add_tags(tags: &mut Tags, name: &str, new: &[String], force: bool) -> io::Result<()> {
+    for tag in new {
+        check_tag(tag)?;
+        match tags.get(tag) {
+            Some(on) if on != name && !force => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("tag '{tag}' is on snapshot {on}, --force moves it"),
+                ));
+            }
+            _ => {
+                tags.insert(tag.clone(), name.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds `spec` among `snapshots`: a name, a tag, `latest` or `latest~N`
+/// (the one N before the latest).
+fn resolve<'a>(snapshots: &'a [Snapshot], tags: &Tags, spec: &str) -> io::Result<&'a Snapshot> {
+    let back = match spec.strip_prefix("latest") {
+        Some("") => Some(0),
+        Some(n) => n.strip_prefix('~').and_then(|n| n.parse::<usize>().ok()),
+        None => None,
+    };
+    let found = match back {
+        Some(back) => snapshots.len().checked_sub(back + 1).map(|i| &snapshots[i]),
+        None => {
+            let name = tags
+                .get(spec)
+                .map(String::as_str)
+                .unwrap_or_else(|| spec.strip_suffix(".json").unwrap_or(spec));
+            snapshots.iter().find(|s| s.name == name)
+        }
+    };
+    found.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no snapshot '{spec}'")))
+}
+
+fn load(snapshot: &Snapshot, identity: Option<&Path>) -> io::Result<Manifest> {
+    let data = fs::read(&snapshot.path)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", snapshot.path.display(), e)))?;
+    store::decode(&snapshot.path, data, identity)
+}
+
+/// The day, week or month a snapshot falls in.
+type Period = fn(&Snapshot) -> String;
+
+/// Which of `snapshots` (oldest first) the policy keeps: the newest
+/// `keep_last`, the ones taken within `keep_within` of `now`, plus the
+/// newest of each of the last `keep_daily` days, `keep_weekly` weeks,
+/// `keep_monthly` months and `keep_yearly` years that have one, and the
+/// tagged ones. The newest snapshot is always kept. Without any --keep-*
+/// option everything is kept.
+fn kept(snapshots: &[Snapshot], tags: &Tags, keep: &KeepArgs, now: u64) -> HashSet<usize> {
+    if keep.keep_last.is_none()
+        && keep.keep_daily.is_none()
+        && keep.keep_weekly.is_none()
+        && keep.keep_monthly.is_none()
+        && keep.keep_yearly.is_none()
+        && keep.keep_within.is_none()
+    {
+        return (0..snapshots.len()).collect();
+    }
+
+    let mut kept: HashSet<usize> = (0..snapshots.len())
+        .rev()
+        .take(keep.keep_last.unwrap_or(0).max(1))
+        .collect();
+    kept.extend((0..snapshots.len()).filter(|&i| tags.values().any(|on| *on == snapshots[i].name)));
+    if let Some(within) = keep.keep_within {
+        let since = now.saturating_sub(within.as_secs());
+        kept.extend((0..snapshots.len()).filter(|&i| snapshots[i].taken >= since));
+    }
+
+    let periods: [(Option<usize>, Period); 4] = [
+        (keep.keep_daily, |s| s.name[..10].to_string()),
+        // 1970-01-01 was a Thursday, weeks start on Monday
+        (keep.keep_weekly, |s| {
+            ((s.taken / 86_400 + 3) / 7).to_string()
+        }),
+        (keep.keep_monthly, |s| s.name[..7].to_string()),
+        (keep.keep_yearly, |s| s.name[..4].to_string()),
+    ];
+    for (count, period) in periods {
+        let mut seen = BTreeSet::new();
+        for i in (0..snapshots.len()).rev() {
+            if seen.len() == count.unwrap_or(0) {
+                break;
+            }
+            if seen.insert(period(&snapshots[i])) {
+                kept.insert(i);
+            }
+        }
+    }
+    kept
+}
+
+/// Deletes the snapshots in `dir` the policy doesn't keep, along with their
+/// signatures and timestamp tokens. Returns the names of those it deleted,
+/// or would have with `dry_run`.
+fn prune(dir: &Path, keep: &KeepArgs, dry_run: bool) -> io::Result<Vec<String>> {
+    let snapshots = snapshots(dir)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let kept = kept(&snapshots, &tags(dir)?, keep, now);
+    let mut removed = Vec::new();
+    for (i, snapshot) in snapshots.iter().enumerate() {
+        if kept.contains(&i) {
+            continue;
+        }
+        if !dry_run {
+            fs::remove_file(&snapshot.path).map_err(|e| {
+                io::Error::new(e.kind(), format!("{}: {}", snapshot.path.display(), e))
+            })?;
+            for ext in ["sig", "asc", "tsr"] {
+                let _ = fs::remove_file(dir.join(format!("{}.json.{ext}", snapshot.name)));
+            }
+        }
+        removed.push(snapshot.name.clone());
+        info!(
+            event = "pruned",
+            snapshot = snapshot.name.as_str(),
+            dry_run = dry_run;
+            "{} {}{}",
+            style::pick("🗑️ PRUNED", "PRUNED").yellow(),
+            snapshot.name,
+            if dry_run { " (dry run)" } else { "" }
+        );
+    }
+    Ok(removed)
+}
+
+/// What pruning and interrupted runs leave behind in `dir`: signatures and
+/// timestamp tokens of snapshots that are gone, tags on them, and a
+/// half-written `tags.json`. Deletes it unless `dry_run`, and returns how
+/// much there was. The `pruned` snapshots count as gone, for a dry run to
+/// show what pruning them would leave.
+fn clear_leftovers(dir: &Path, pruned: &[String], dry_run: bool) -> io::Result<usize> {
+    let names: HashSet<String> = snapshots(dir)?
+        .into_iter()
+        .map(|s| s.name)
+        .filter(|name| !pruned.contains(name))
+        .collect();
+    let mut leftovers: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", dir.display(), e)))?
+        .filter_map(|e| e.ok())
+        .filter(|e| {
+            let Some(file) = e.file_name().to_str().map(str::to_string) else {
+                return false;
+            };
+            if file == "tags.json.tmp" {
+                return true;
+            }
+            let Some((name, ext)) = file.rsplit_once(".json.") else {
+                return false;
+            };
+            ["sig", "asc", "tsr"].contains(&ext)
+                && parse_name(name).is_some()
+                && !names.contains(name)
+        })
+        .map(|e| e.path())
+        .collect();
+    leftovers.sort();
+    for path in &leftovers {
+        if !dry_run {
+            fs::remove_file(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        }
+        info!(
+            event = "gc",
+            path:% = path.display(),
+            dry_run = dry_run;
+            "{} {}{}",
+            style::pick("🧹 REMOVED", "REMOVED").yellow(),
+            path.display(),
+            if dry_run { " (dry run)" } else { "" }
+        );
+    }
+
+    let mut tags = tags(dir)?;
+    let stale: Vec<String> = tags
+        .iter()
+        .filter(|(_, on)| !names.contains(*on))
+        .map(|(tag, _)| tag.clone())
+        .collect();
+    for tag in &stale {
+        let on = tags.remove(tag).unwrap_or_default();
+        info!(
+            event = "gc",
+            tag = tag.as_str(),
+            snapshot = on.as_str(),
+            dry_run = dry_run;
+            "{} tag {tag}, its snapshot {on} is gone{}",
+            style::pick("🧹 REMOVED", "REMOVED").yellow(),
+            if dry_run { " (dry run)" } else { "" }
+        );
+    }
+    if !stale.is_empty() && !dry_run {
+        save_tags(dir, &tags)?;
+    }
+    Ok(leftovers.len() + stale.len())
+}
+
+/// `snapshot create`: scans into a new snapshot, then prunes.
+pub fn create(args: &SnapshotCreateArgs) -> i32 {
+    if let Err(e) = fs::create_dir_all(&args.snapshots) {
+        eprintln!("Error: {}: {e}", args.snapshots.display());
+        return 1;
+    }
+    // A tag that can't go on is better found out before the scan
+    let mut tags = match tags(&args.snapshots).and_then(|tags| {
+        let mut check = tags.clone();
+        add_tags(&mut check, "", &args.tag, false)?;
+        Ok(tags)
+    }) {
+        Ok(tags) => tags,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+    let name = name_for(SystemTime::now());
+    let path = args.snapshots.join(format!("{name}.json"));
+    // Two scans within a second would otherwise overwrite each other
+    if path.exists() {
+        eprintln!(
+            "Error: {} already exists, a snapshot was just taken",
+            path.display()
+        );
+        return 1;
+    }
+
+    let code = execute(&args.dir, &args.run, &[], Some(&path));
+    if code != 0 {
+        return code;
+    }
+    info!(event = "snapshot", snapshot = name.as_str(); "{} Snapshot {name} taken", style::pick("📸", "*").bold());
+    if !args.tag.is_empty() {
+        let tagged = add_tags(&mut tags, &name, &args.tag, false)
+            .and_then(|_| save_tags(&args.snapshots, &tags));
+        if let Err(e) = tagged {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+        info!(
+            event = "tagged",
+            snapshot = name.as_str(),
+            tags = args.tag.join(",");
+            "{} Tagged {}",
+            style::pick("🏷️", "*").bold(),
+            args.tag.join(", ")
+        );
+    }
+
+    match prune(&args.snapshots, &args.keep, false) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+/// `snapshot prune`
+pub fn prune_command(args: &SnapshotPruneArgs) -> i32 {
+    match prune(&args.snapshots, &args.keep, args.dry_run) {
+        Ok(removed) => {
+            let removed = removed.len();
+            info!(event = "pruned_total", removed = removed; "{removed} snapshot(s) pruned");
+            0
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+/// `snapshot gc`: prunes, then clears out the leftovers.
+pub fn gc(args: &SnapshotPruneArgs) -> i32 {
+    let result = prune(&args.snapshots, &args.keep, args.dry_run).and_then(|removed| {
+        let leftovers = clear_leftovers(&args.snapshots, &removed, args.dry_run)?;
+        Ok((removed.len(), leftovers))
+    });
+    match result {
+        Ok((removed, leftovers)) => {
+            info!(
+                event = "gc_total",
+                removed = removed,
+                leftovers = leftovers,
+                dry_run = args.dry_run;
+                "{removed} snapshot(s) pruned, {leftovers} leftover(s) removed{}",
+                if args.dry_run { " (dry run)" } else { "" }
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+/// `snapshot list`: every snapshot with its file count and size. Exit code
+/// 1 if any of them can't be read.
+pub fn list(args: &SnapshotListArgs) -> i32 {
+    let (snapshots, tags) =
+        match snapshots(&args.snapshots).and_then(|s| Ok((s, tags(&args.snapshots)?))) {
+            Ok(found) => found,
+            Err(e) => {
+                eprintln!("Error: {e}");
+                return 1;
+            }
+        };
+    if snapshots.is_empty() {
+        info!("No snapshots in {}", args.snapshots.display());
+    }
+
+    let mut code = 0;
+    for snapshot in &snapshots {
+        let tagged = tags_of(&tags, &snapshot.name).join(", ");
+        match load(snapshot, args.identity.as_deref()) {
+            Ok(manifest) => {
+                let bytes: u64 = manifest.files.values().map(|m| m.size.max(0) as u64).sum();
+                info!(
+                    event = "snapshot",
+                    snapshot = snapshot.name.as_str(),
+                    files = manifest.files.len(),
+                    bytes = bytes,
+                    tags = tagged.as_str();
+                    "{}  {} files, {}{}",
+                    snapshot.name.bold(),
+                    manifest.files.len(),
+                    HumanBytes(bytes),
+                    if tagged.is_empty() {
+                        String::new()
+                    } else {
+                        format!("  [{}]", tagged.cyan())
+                    }
+                );
+            }
+            Err(e) => {
+                code = 1;
+                error!(
+                    event = "snapshot",
+                    snapshot = snapshot.name.as_str(),
+                    error:% = e;
+                    "{}  {} {e}",
+                    snapshot.name.bold(),
+                    style::pick("⚠️", "ERROR").red()
+                );
+            }
+        }
+    }
+    code
+}
+
+/// `snapshot tag`: puts tags on a snapshot, or takes them off with
+/// `--delete`.
+pub fn tag(args: &SnapshotTagArgs) -> i32 {
+    let result = snapshots(&args.snapshots).and_then(|snapshots| {
+        let mut tags = tags(&args.snapshots)?;
+        if args.delete {
+            // The tags to delete are SNAPSHOT and TAG alike
+            for tag in std::iter::once(&args.snapshot).chain(&args.tags) {
+                let Some(on) = tags.remove(tag) else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("no tag '{tag}'"),
+                    ));
+                };
+                info!(
+                    event = "untagged",
+                    tag = tag.as_str(),
+                    snapshot = on.as_str();
+                    "{} Tag {tag} taken off {on}",
+                    style::pick("🏷️", "*").bold()
+                );
+            }
+        } else {
+            let snapshot = resolve(&snapshots, &tags, &args.snapshot)?;
+            add_tags(&mut tags, &snapshot.name, &args.tags, args.force)?;
+            info!(
+                event = "tagged",
+                snapshot = snapshot.name.as_str(),
+                tags = args.tags.join(",");
+                "{} Tagged {} {}",
+                style::pick("🏷️", "*").bold(),
+                snapshot.name,
+                args.tags.join(", ")
+            );
+        }
+        save_tags(&args.snapshots, &tags)
+    });
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+/// `snapshot verify`: verifies a tree against a snapshot picked by name or
+/// tag.
+pub fn verify(args: &SnapshotVerifyArgs) -> i32 {
+    let found = snapshots(&args.snapshots).and_then(|snapshots| {
+        let tags = tags(&args.snapshots)?;
+        let snapshot = resolve(&snapshots, &tags, &args.snapshot)?;
+        Ok((snapshot.name.clone(), snapshot.path.clone()))
+    });
+    let (name, path) = match found {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+    info!(
+        "{} Verifying against snapshot {name}",
+        style::pick("📸", "*").bold()
+    );
+    execute(&args.dir, &args.run, &[path], None)
+}
+
+/// `snapshot diff`
+pub fn diff_command(args: &SnapshotDiffArgs) -> i32 {
+    let result = snapshots(&args.snapshots).and_then(|snapshots| {
+        let tags = tags(&args.snapshots)?;
+        let from = resolve(&snapshots, &tags, &args.from)?;
+        let to = resolve(&snapshots, &tags, &args.to)?;
+        let old = load(from, args.identity.as_deref())?;
+        let new = load(to, args.identity.as_deref())?;
+        info!(
+            "{} {} → {}",
+            style::pick("📸", "*").bold(),
+            from.name,
+            to.name
+        );
+        // With directory digests on both sides the directories that didn't
+        // change are left out, only the branches that did are gone through
+        let changes = if old.dirs.is_empty() || new.dirs.is_empty() {
+            journal::changes(&old.files, &new.files, new.created.unwrap_or(0))
+        } else {
+            for branch in merkle::changed_branches(&old.dirs, &new.dirs) {
+                info!(
+                    event = "diff_branch",
+                    path = branch.as_str();
+                    "{} {branch}",
+                    style::pick("🌿 BRANCH", "BRANCH").cyan()
+                );
+            }
+            let changing = |files: &HashMap<String, FileMeta>| -> HashMap<String, FileMeta> {
+                files
+                    .iter()
+                    .filter(|(path, _)| !merkle::unchanged(path, &old.dirs, &new.dirs))
+                    .map(|(path, meta)| (path.clone(), meta.clone()))
+                    .collect()
+            };
+            journal::changes(
+                &changing(&old.files),
+                &changing(&new.files),
+                new.created.unwrap_or(0),
+            )
+        };
+        for change in &changes {
+            change.log("");
+        }
+        Ok((changes, new.files.len()))
+    });
+    match result {
+        Ok((changes, files)) => {
+            let count = |kind| changes.iter().filter(|c| c.change == kind).count();
+            let (added, changed, moved, removed) = (
+                count(ChangeKind::Added),
+                count(ChangeKind::Changed),
+                count(ChangeKind::Moved),
+                count(ChangeKind::Removed),
+            );
+            let unchanged = files - added - changed - moved;
+            info!(
+                event = "diff_summary",
+                added = added,
+                removed = removed,
+                changed = changed,
+                moved = moved,
+                unchanged = unchanged;
+                "{added} added, {removed} removed, {changed} changed, {moved} moved, {unchanged} unchanged"
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// A set of paths too big to keep in memory: what a verify against a
+// reference on disk saw of it, a hundred million paths being gigabytes.
+// Paths are gathered in memory and written out sorted, a run at a time, to
+// temporary files. They are only asked about once all are in, in sorted
+// order (as the reference hands its entries out), so the runs are merged
+// back as the questions go and the set is never in memory as a whole.
+
+/// Bytes of paths gathered before they are written out as a run.
+pub const RUN_BYTES: usize = 64 * 1024 * 1024;
+
+pub struct Spill {
+    gathered: Vec<String>,
+    bytes: usize,
+    run_bytes: usize,
+    runs: Vec<PathBuf>,
+    /// The smallest path of each run not passed yet, once asked about
+    merging: Option<Merge>,
+}
+
+struct Merge {
+    heads: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    readers: Vec<BufReader<File>>,
+}
+
+/// A path in a run ends with a NUL, which paths never have.
+fn next(reader: &mut BufReader<File>) -> io::Result<Option<Vec<u8>>> {
+    let mut path = Vec::new();
+    match reader.read_until(0, &mut path)? {
+        0 => Ok(None),
+        _ => {
+            path.pop();
+            Ok(Some(path))
+        }
+    }
+}
+
+impl Spill {
+    /// An empty set, written out every `run_bytes` of paths, `RUN_BYTES`
+    /// unless testing.
+    pub fn new(run_bytes: usize) -> Self {
+        Spill {
+            gathered: Vec::new(),
+            bytes: 0,
+            run_bytes,
+            runs: Vec::new(),
+            merging: None,
+        }
+    }
+
+    pub fn insert(&mut self, path: String) -> io::Result<()> {
+        assert!(self.merging.is_none(), "insert after contains");
+        self.bytes += path.len() + size_of::<String>();
+        self.gathered.push(path);
+        if self.bytes >= self.run_bytes {
+            self.write_run()?;
+        }
+        Ok(())
+    }
+
+    fn write_run(&mut self) -> io::Result<()> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "checkyoself-{}-{}.seen",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        // Removed on drop from here on
+        self.runs.push(path);
+        self.gathered.sort_unstable();
+        let mut out = BufWriter::new(file);
+        for path in self.gathered.drain(..) {
+            out.write_all(path.as_bytes())?;
+            out.write_all(&[0])?;
+        }
+        out.flush()?;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    /// Whether `path` was inserted. Asked about in ascending order, and
+    /// once asked nothing can be inserted any more.
+    pub fn contains(&mut self, path: &str) -> io::Result<bool> {
+        if self.merging.is_none() {
+            if !self.gathered.is_empty() {
+                self.write_run()?;
+            }
+            let mut merge = Merge {
+                heads: BinaryHeap::new(),
+                readers: Vec::new(),
+            };
+            for (i, run) in self.runs.iter().enumerate() {
+                let mut reader = BufReader::new(File::open(run)?);
+                if let Some(head) = next(&mut reader)? {
+                    merge.heads.push(Reverse((head, i)));
+                }
+                merge.readers.push(reader);
+            }
+            self.merging = Some(merge);
+        }
+        let merge = self.merging.as_mut().expect("merging");
+        while let Some(Reverse((head, i))) = merge.heads.peek() {
+            match head.as_slice().cmp(path.as_bytes()) {
+                std::cmp::Ordering::Less => {
+                    let i = *i;
+                    merge.heads.pop();
+                    if let Some(head) = next(&mut merge.readers[i])? {
+                        merge.heads.push(Reverse((head, i)));
+                    }
+                }
+                std::cmp::Ordering::Equal => return Ok(true),
+                std::cmp::Ordering::Greater => return Ok(false),
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        // Closed first, Windows won't remove open files
+        self.merging = None;
+        for run in &self.runs {
+            let _ = fs::remove_file(run);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_merge_back() {
+        let mut spill = Spill::new(256);
+        // Inserted out of order, every third path of 0..3000
+        for i in (0..3000).rev().filter(|i| i % 3 == 0) {
+            spill.insert(format!("d/{i:05}")).unwrap();
+        }
+        assert!(spill.runs.len() > 10, "{} runs", spill.runs.len());
+        for i in 0..3000 {
+            let path = format!("d/{i:05}");
+            assert_eq!(spill.contains(&path).unwrap(), i % 3 == 0, "{path}");
+        }
+        let runs = spill.runs.clone();
+        drop(spill);
+        assert!(runs.iter().all(|run| !run.exists()));
+    }
+}
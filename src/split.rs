@@ -0,0 +1,158 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use log::info;
+
+use crate::{
+    cli::SplitArgs,
+    devices,
+    hash::FileMeta,
+    manifest::{Manifest, Special},
+    store,
+};
+
+// Paths in a manifest are stored as walked, root included, so a part split
+// off for `photos/` still verifies with `checkyoself <root>/photos --verify`:
+// its root becomes that directory and its paths stay the same.
+
+/// Entries directly in the root, not in any top-level directory.
+const TOP_PART: &str = "_top";
+/// Entries that no --glob matched.
+const REST_PART: &str = "_rest";
+
+/// The files and special files of one part.
+type Part = (HashMap<String, FileMeta>, BTreeMap<String, Special>);
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// Parses `NAME=PATTERN` arguments of --glob.
+fn globs(specs: &[String]) -> io::Result<(Vec<String>, GlobSet)> {
+    let mut names = Vec::new();
+    let mut set = GlobSetBuilder::new();
+    for spec in specs {
+        let Some((name, pattern)) = spec.split_once('=') else {
+            return Err(invalid(format!("--glob {spec}: expected NAME=PATTERN")));
+        };
+        // NAME becomes NAME.json in the output directory and nowhere else
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(invalid(format!(
+                "--glob {spec}: NAME must be a plain file name"
+            )));
+        }
+        // `*` stays within a directory, `**` crosses them
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| invalid(format!("--glob {spec}: {e}")))?;
+        names.push(name.to_string());
+        set.add(glob);
+    }
+    let set = set.build().map_err(|e| invalid(e.to_string()))?;
+    Ok((names, set))
+}
+
+/// Which part `relative`, a path relative to the root, goes into.
+fn part_of(relative: &Path, globs: Option<&(Vec<String>, GlobSet)>) -> String {
+    match globs {
+        // The first matching --glob wins
+        Some((names, set)) => set
+            .matches(relative)
+            .first()
+            .map(|&i| names[i].clone())
+            .unwrap_or_else(|| REST_PART.to_string()),
+        None => {
+            let mut components = relative.components();
+            match (components.next(), components.next()) {
+                (Some(dir), Some(_)) => dir.as_os_str().to_string_lossy().to_string(),
+                _ => TOP_PART.to_string(),
+            }
+        }
+    }
+}
+
+fn split(args: &SplitArgs) -> io::Result<()> {
+    let globs = (!args.globs.is_empty())
+        .then(|| globs(&args.globs))
+        .transpose()?;
+    let reference = store::load(&args.manifest, &args.run)?;
+    let root = reference.manifest.root.as_deref().map(PathBuf::from);
+    if root.is_none() && globs.is_none() {
+        return Err(invalid(format!(
+            "{} doesn't record its root, split it with --glob",
+            args.manifest.display()
+        )));
+    }
+
+    let part = |path: &str| {
+        let relative = root
+            .as_deref()
+            .and_then(|root| Path::new(path).strip_prefix(root).ok())
+            .unwrap_or(Path::new(path));
+        part_of(relative, globs.as_ref())
+    };
+    let mut parts: BTreeMap<String, Part> = BTreeMap::new();
+    for (path, meta) in reference.manifest.files {
+        parts.entry(part(&path)).or_default().0.insert(path, meta);
+    }
+    for (path, special) in reference.manifest.special {
+        parts
+            .entry(part(&path))
+            .or_default()
+            .1
+            .insert(path, special);
+    }
+
+    fs::create_dir_all(&args.out_dir)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", args.out_dir.display(), e)))?;
+    for (name, (files, special)) in parts {
+        // A top-level directory becomes the root of its part
+        let part_root = match (&root, &globs) {
+            (Some(root), None) if name != TOP_PART => root.join(&name),
+            (Some(root), _) => root.clone(),
+            (None, _) => PathBuf::new(),
+        };
+        let count = files.len();
+        let mut manifest = Manifest::new(&part_root, files, special, 0);
+        // The entries were hashed when the whole was
+        manifest.created = reference.manifest.created;
+        if reference.manifest.root.is_none() {
+            manifest.root = None;
+        }
+        manifest.volume = reference.manifest.volume.clone();
+        manifest.devices =
+            devices::Seen::default().describe(manifest.files.values(), &reference.manifest.devices);
+        if args.run.dir_digests || !reference.manifest.dirs.is_empty() {
+            manifest.digest_dirs();
+        }
+
+        let path = args.out_dir.join(format!("{name}.json"));
+        store::save(&manifest, &path, &args.run)?;
+        info!(
+            event = "split",
+            part = name.as_str(),
+            files = count,
+            output:% = path.display();
+            "{} files written to {}",
+            count,
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// `split`: exit code 0, or 1 on errors.
+pub fn run(args: &SplitArgs) -> i32 {
+    match split(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    fs, io,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use colored::*;
+use indicatif::HumanBytes;
+use log::info;
+
+use crate::{
+    cli::StatsArgs,
+    kv,
+    manifest::{Manifest, Totals},
+    store, style,
+};
+
+// `checkyoself stats` answers the quick questions about a manifest, how many
+// files and how big, from the totals in its header. A --format kv manifest
+// keeps its header apart from the entries, those aren't even loaded.
+// Manifests from before the totals have their entries counted instead.
+
+/// The header of the manifest at `args.manifest`, and whether its totals
+/// had to be counted.
+fn header(args: &StatsArgs) -> io::Result<(Manifest, bool)> {
+    let path = &args.manifest;
+    let mut manifest = if kv::is_store(path) {
+        kv::Store::open(path)?.1
+    } else {
+        let data = fs::read(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        store::decode(path, data, args.identity.as_deref())?
+    };
+    let counted = manifest.totals.is_none();
+    if counted {
+        manifest.totals = Some(Totals::of(&manifest.files));
+    }
+    Ok((manifest, counted))
+}
+
+fn report(args: &StatsArgs) -> io::Result<()> {
+    let (manifest, counted) = header(args)?;
+    let totals = manifest.totals.unwrap_or_default();
+    info!(
+        event = "stats",
+        files = totals.files,
+        bytes = totals.bytes,
+        special = manifest.special.len(),
+        counted = counted;
+        "{} {}: {} files, {}{}",
+        style::pick("📊", "STATS").bold(),
+        args.manifest.display(),
+        totals.files,
+        HumanBytes(totals.bytes),
+        if manifest.special.is_empty() {
+            String::new()
+        } else {
+            format!(", {} special files", manifest.special.len())
+        }
+    );
+    if let Some(root) = &manifest.root {
+        info!("root: {root}");
+    }
+    if let Some(created) = manifest.created {
+        info!(
+            "created: {}",
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(created))
+        );
+    }
+    if let Some(volume) = &manifest.volume {
+        info!("volume: {volume}");
+    }
+    if let Some(sequence) = manifest.sequence {
+        info!("sequence: {sequence}");
+    }
+    if !manifest.includes.is_empty() {
+        info!(
+            "includes: {} manifests, their files not counted",
+            manifest.includes.len()
+        );
+    }
+    if counted {
+        info!(
+            "{}",
+            "No totals in the header, it's from an older version: counted".dimmed()
+        );
+    }
+    Ok(())
+}
+
+/// `stats`: exit code 0, or 1 if the manifest can't be read.
+pub fn run(args: &StatsArgs) -> i32 {
+    match report(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
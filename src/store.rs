@@ -0,0 +1,511 @@
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use colored::*;
+use log::{debug, info, warn};
+
+use crate::{
+    cli::RunArgs,
+    crypt, gpg,
+    hash::FileMeta,
+    kv,
+    manifest::{Include, Manifest, ManifestFormat, path_key},
+    sign, style, text, timestamp,
+};
+
+/// `e` with the path it came from in front of its message.
+pub fn with_path(path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
+
+/// A manifest and the exact bytes it was read from (still encrypted, if
+/// it was).
+pub struct Loaded {
+    pub manifest: Manifest,
+    pub raw: Vec<u8>,
+    /// JSON or text, what --update writes it back as
+    pub format: ManifestFormat,
+}
+
+/// Reads the manifest at `path`. With --verify-key, --gpg-keyring or
+/// --tsa-ca its detached signatures and timestamp are checked first,
+/// against the same bytes that then get decrypted (with --identity) and
+/// parsed.
+pub fn load(path: &Path, run: &RunArgs) -> io::Result<Loaded> {
+    if kv::is_store(path) {
+        return Err(with_path(
+            path,
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "a --format kv manifest can only be verified, on its own",
+            ),
+        ));
+    }
+    let data = fs::read(path).map_err(|e| with_path(path, e))?;
+    check_signatures(path, &data, run)?;
+
+    // --update would otherwise quietly replace it with plaintext
+    if run.update && run.encryption.encrypt_to.is_empty() && crypt::is_encrypted(&data) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is encrypted, pass --encrypt-to to keep it that way with --update",
+                path.display()
+            ),
+        ));
+    }
+    // Kept as it is for `raw`, so only copied if it has to be decrypted
+    let (mut manifest, format) = if crypt::is_encrypted(&data) {
+        decode_as(path, data.clone(), run.encryption.identity.as_deref())?
+    } else {
+        parse(path, &data)?
+    };
+
+    if !manifest.includes.is_empty() && run.update {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} includes other manifests, --update would fold them in; recreate it with --include instead",
+                path.display()
+            ),
+        ));
+    }
+    resolve_includes(path, &mut manifest, run.encryption.identity.as_deref())?;
+    Ok(Loaded {
+        manifest,
+        raw: data,
+        format,
+    })
+}
+
+/// Checks the detached signatures and timestamp of the manifest at `path`,
+/// whose bytes are `data`, as asked with --verify-key, --gpg-keyring and
+/// --tsa-ca.
+fn check_signatures(path: &Path, data: &[u8], run: &RunArgs) -> io::Result<()> {
+    if let Some(key) = &run.signing.verify_key {
+        let key = sign::load_verifying_key(key)?;
+        sign::verify_manifest(path, data, &key)?;
+        info!(
+            event = "signature",
+            manifest:% = path.display();
+            "{} Signature of {} is valid",
+            style::pick("🔏", "*").bold(),
+            path.display()
+        );
+    }
+    if let Some(keyring) = &run.signing.gpg_keyring {
+        let sig = gpg::verify_manifest(path, data, keyring)?;
+        info!(
+            event = "signature",
+            manifest:% = path.display(),
+            signature:% = sig.display();
+            "{} OpenPGP signature {} is good",
+            style::pick("🔏", "*").bold(),
+            sig.display()
+        );
+    }
+    if let Some(ca) = &run.signing.tsa_ca {
+        let time = timestamp::verify(path, data, ca)?;
+        info!(
+            event = "timestamp",
+            manifest:% = path.display(),
+            time = time.as_str();
+            "{} Timestamped by a trusted TSA at {time}",
+            style::pick("🕰️", "*").bold()
+        );
+    }
+    Ok(())
+}
+
+/// Adds the entries of the manifests `manifest`, read from `path`,
+/// includes to its own.
+pub fn resolve_includes(
+    path: &Path,
+    manifest: &mut Manifest,
+    identity: Option<&Path>,
+) -> io::Result<()> {
+    if manifest.includes.is_empty() {
+        return Ok(());
+    }
+    let mut stack = vec![fs::canonicalize(path).map_err(|e| with_path(path, e))?];
+    let mut files = included_files(path, &manifest.includes, identity, &mut stack)?;
+    files.extend(std::mem::take(&mut manifest.files));
+    manifest.files = files;
+    Ok(())
+}
+
+/// The index `checkyoself index` made of the manifest at `path`, to verify
+/// against in its place, if there's one and it's of the manifest as it is
+/// now. Signatures and timestamps cover the manifest's bytes, not the
+/// index, so with --verify-key, --gpg-keyring or --tsa-ca there's none and
+/// the manifest is loaded.
+pub fn load_index(path: &Path, run: &RunArgs) -> io::Result<Option<(kv::Store, Manifest)>> {
+    let index = kv::index_path(path);
+    // --update needs every entry anyway, to write the new manifest
+    if run.update || !kv::is_store(&index) {
+        return Ok(None);
+    }
+    let signed = run.signing.verify_key.is_some()
+        || run.signing.gpg_keyring.is_some()
+        || run.signing.tsa_ca.is_some();
+    if signed {
+        debug!(
+            event = "index",
+            index:% = index.display();
+            "Not using {}, its entries aren't covered by the manifest's signatures",
+            index.display()
+        );
+        return Ok(None);
+    }
+    let file = File::open(path).map_err(|e| with_path(path, e))?;
+    let hash = blake3::Hasher::new()
+        .update_reader(file)
+        .map_err(|e| with_path(path, e))?
+        .finalize();
+    let (store, header) = kv::Store::open(&index)?;
+    if store.source()?.as_deref() != Some(hash.to_hex().as_str()) {
+        warn!(
+            event = "stale_index",
+            manifest:% = path.display();
+            "{} was made of an older {}, loading the manifest instead; run `checkyoself index` again",
+            index.display(),
+            path.display()
+        );
+        return Ok(None);
+    }
+    debug!(event = "index", index:% = index.display(); "Verifying against {}", index.display());
+    Ok(Some((store, header)))
+}
+
+/// Opens the --format kv manifest at `path` to verify against, along with
+/// its header. Signatures and timestamps are of a file's bytes and --update
+/// rewrites a file, none of them apply to a store.
+pub fn load_kv(path: &Path, run: &RunArgs) -> io::Result<(kv::Store, Manifest)> {
+    let unsupported = [
+        (run.update, "--update"),
+        (run.signing.verify_key.is_some(), "--verify-key"),
+        (run.signing.gpg_keyring.is_some(), "--gpg-keyring"),
+        (run.signing.tsa_ca.is_some(), "--tsa-ca"),
+    ];
+    if let Some((_, option)) = unsupported.iter().find(|(given, _)| *given) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{option} doesn't work with a --format kv manifest"),
+        ));
+    }
+    kv::Store::open(path)
+}
+
+/// Creates a --format kv manifest at `path`. It's a directory, there are
+/// no bytes to sign or encrypt, and no file to include others from.
+pub fn create_kv(path: &Path, run: &RunArgs) -> io::Result<kv::Store> {
+    let unsupported = [
+        (!run.include.is_empty(), "--include"),
+        (!run.encryption.encrypt_to.is_empty(), "--encrypt-to"),
+        (run.signing.sign_key.is_some(), "--sign-key"),
+        (run.signing.gpg_sign.is_some(), "--gpg-sign"),
+        (run.signing.tsa_url.is_some(), "--tsa-url"),
+    ];
+    if let Some((_, option)) = unsupported.iter().find(|(given, _)| *given) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{option} doesn't work with --format kv"),
+        ));
+    }
+    kv::Store::create(path)
+}
+
+/// Parses `data`, read from `path`, decrypting it first if needed.
+pub fn decode(path: &Path, data: Vec<u8>, identity: Option<&Path>) -> io::Result<Manifest> {
+    decode_as(path, data, identity).map(|(manifest, _)| manifest)
+}
+
+/// `decode`, also saying whether the manifest was JSON or text.
+fn decode_as(
+    path: &Path,
+    data: Vec<u8>,
+    identity: Option<&Path>,
+) -> io::Result<(Manifest, ManifestFormat)> {
+    let data = crypt::decrypt_if_needed(path, data, identity)?;
+    parse(path, &data)
+}
+
+/// A plaintext manifest, JSON or text.
+fn parse(path: &Path, data: &[u8]) -> io::Result<(Manifest, ManifestFormat)> {
+    let data = std::str::from_utf8(data)
+        .map_err(|e| with_path(path, io::Error::new(io::ErrorKind::InvalidData, e)))?;
+    let decoded = if text::is_text(data) {
+        text::parse(data).map(|m| (m, ManifestFormat::Text))
+    } else {
+        Manifest::from_json(data).map(|m| (m, ManifestFormat::Json))
+    };
+    decoded.map_err(|e| with_path(path, e))
+}
+
+/// The entries of the manifests that the manifest at `path` includes,
+/// their own includes first and later includes overriding earlier ones.
+/// `stack` holds the manifests being included, to catch cycles.
+fn included_files(
+    path: &Path,
+    includes: &[Include],
+    identity: Option<&Path>,
+    stack: &mut Vec<PathBuf>,
+) -> io::Result<HashMap<String, FileMeta>> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut merged = HashMap::new();
+    for include in includes {
+        let included = dir.join(&include.path);
+        let data = fs::read(&included).map_err(|e| with_path(&included, e))?;
+        if let Some(expected) = &include.blake3
+            && blake3::hash(&data).to_hex().as_str() != expected
+        {
+            return Err(with_path(
+                path,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "included {} changed since it was included",
+                        included.display()
+                    ),
+                ),
+            ));
+        }
+        let canonical = fs::canonicalize(&included).map_err(|e| with_path(&included, e))?;
+        if stack.contains(&canonical) {
+            return Err(with_path(
+                &included,
+                io::Error::new(io::ErrorKind::InvalidData, "includes itself"),
+            ));
+        }
+
+        stack.push(canonical);
+        let manifest = decode(&included, data, identity)?;
+        let mut files = included_files(&included, &manifest.includes, identity, stack)?;
+        files.extend(manifest.files);
+        stack.pop();
+
+        debug!(
+            event = "included",
+            manifest:% = included.display(),
+            files = files.len();
+            "Included {} ({} files)",
+            included.display(),
+            files.len()
+        );
+        merged.extend(files);
+    }
+    Ok(merged)
+}
+
+/// `target` relative to the directory `from`, both existing.
+fn relative_path(from: &Path, target: &Path) -> io::Result<PathBuf> {
+    let from = fs::canonicalize(from).map_err(|e| with_path(from, e))?;
+    let target = fs::canonicalize(target).map_err(|e| with_path(target, e))?;
+    let from: Vec<Component> = from.components().collect();
+    let to: Vec<Component> = target.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    // Nothing in common, say on another drive
+    if common == 0 {
+        return Ok(target);
+    }
+    let mut relative: PathBuf = from[common..].iter().map(|_| "..").collect();
+    relative.extend(&to[common..]);
+    Ok(relative)
+}
+
+/// What a manifest about to be written to `output` records to include
+/// `bases` (pinned by hash), and the entries it gets from them.
+pub fn includes_for(
+    output: &Path,
+    bases: &[PathBuf],
+    identity: Option<&Path>,
+) -> io::Result<(Vec<Include>, HashMap<String, FileMeta>)> {
+    let dir = match output.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let includes = bases
+        .iter()
+        .map(|base| {
+            let data = fs::read(base).map_err(|e| with_path(base, e))?;
+            Ok(Include {
+                path: path_key(&relative_path(dir, base)?),
+                blake3: Some(blake3::hash(&data).to_hex().to_string()),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let files = included_files(output, &includes, identity, &mut Vec::new())?;
+    Ok((includes, files))
+}
+
+/// How `merge` settles a path that manifests disagree about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OnConflict {
+    /// Report every conflict and give up
+    Error,
+    /// The manifest given first wins
+    First,
+    /// The manifest given last wins
+    Last,
+}
+
+/// The union of the entries of several manifests. A path listed with the
+/// same hash more than once is fine; with different hashes it's a
+/// conflict, reported and settled by `on_conflict`.
+pub fn merge<'a>(
+    parts: impl IntoIterator<Item = (&'a Path, HashMap<String, FileMeta>)>,
+    on_conflict: OnConflict,
+) -> io::Result<HashMap<String, FileMeta>> {
+    let mut merged: HashMap<String, (FileMeta, &Path)> = HashMap::new();
+    let mut conflicts = 0;
+    for (source, files) in parts {
+        for (path, meta) in files {
+            let Some((kept, kept_from)) = merged.get_mut(&path) else {
+                merged.insert(path, (meta, source));
+                continue;
+            };
+            if kept.hash == meta.hash {
+                continue;
+            }
+            conflicts += 1;
+            warn!(
+                event = "conflict",
+                path = path.as_str(),
+                first:% = kept_from.display(),
+                second:% = source.display();
+                "{} {path}\n  {}: {}\n  {}: {}",
+                style::pick("⚔️ CONFLICT", "CONFLICT").red(),
+                kept_from.display(),
+                kept.hash,
+                source.display(),
+                meta.hash
+            );
+            if on_conflict == OnConflict::Last {
+                *kept = meta;
+                *kept_from = source;
+            }
+        }
+    }
+    if conflicts > 0 && on_conflict == OnConflict::Error {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{conflicts} path(s) have different hashes in different --verify manifests, \
+                 pick a winner with --on-conflict first|last"
+            ),
+        ));
+    }
+    Ok(merged
+        .into_iter()
+        .map(|(path, (meta, _))| (path, meta))
+        .collect())
+}
+
+/// Writes `manifest` to `path` as --format says, see `save_with`.
+pub fn save(manifest: &Manifest, path: &Path, run: &RunArgs) -> io::Result<()> {
+    save_as(manifest, path, run, run.format)
+}
+
+/// Writes `manifest` to `path` as JSON (in the --json style) or text, see
+/// `save_with`.
+pub fn save_as(
+    manifest: &Manifest,
+    path: &Path,
+    run: &RunArgs,
+    format: ManifestFormat,
+) -> io::Result<()> {
+    save_with(path, run, |out| match format {
+        ManifestFormat::Text => out.write_all(text::write(manifest).as_bytes()),
+        _ => out.write_all(manifest.to_json(run.json_style, run.paths).as_bytes()),
+    })
+}
+
+/// Creates `path` and has `write` fill it in, encrypting on the way with
+/// --encrypt-to. It's written next to it first and renamed over it when
+/// complete, so a failed or interrupted run leaves the old manifest alone
+/// rather than half a new one. Then signs it with --sign-key and --gpg-sign and
+/// timestamps it with --tsa-url if given; signatures cover the bytes on
+/// disk.
+pub fn save_with(
+    path: &Path,
+    run: &RunArgs,
+    write: impl FnOnce(&mut (dyn Write + Send)) -> io::Result<()>,
+) -> io::Result<()> {
+    // Load the key before writing anything, a bad key shouldn't leave an
+    // unsigned manifest behind
+    let key = run
+        .signing
+        .sign_key
+        .as_deref()
+        .map(sign::load_signing_key)
+        .transpose()?;
+
+    let recipients = crypt::parse_recipients(&run.encryption.encrypt_to)?;
+
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let written = (|| {
+        let file = File::create(&temp).map_err(|e| with_path(path, e))?;
+        let mut out = BufWriter::new(file);
+        if recipients.is_empty() {
+            write(&mut out)?;
+        } else {
+            let mut encrypted = crypt::encrypting_writer(out, &recipients)?;
+            write(&mut encrypted)?;
+            out = encrypted.finish()?;
+        }
+        out.into_inner()
+            .map_err(|e| e.into_error())
+            .and_then(|f| f.sync_all())
+            .and_then(|_| fs::rename(&temp, path))
+            .map_err(|e| with_path(path, e))
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    let signing = key.is_some() || run.signing.gpg_sign.is_some() || run.signing.tsa_url.is_some();
+    if !signing {
+        return Ok(());
+    }
+    // Streamed manifests were never in memory as a whole, sign what's on disk
+    let data = fs::read(path).map_err(|e| with_path(path, e))?;
+
+    if let Some(key) = key {
+        let sig = sign::sign_manifest(path, &data, &key)?;
+        info!(
+            event = "signed",
+            signature:% = sig.display();
+            "Signature written to {}",
+            sig.display()
+        );
+    }
+    if let Some(gpg_key) = &run.signing.gpg_sign {
+        let sig = gpg::sign_manifest(path, &data, gpg_key)?;
+        info!(
+            event = "signed",
+            signature:% = sig.display();
+            "OpenPGP signature written to {}",
+            sig.display()
+        );
+    }
+    if let Some(url) = &run.signing.tsa_url {
+        let (token, time) = timestamp::stamp(path, &data, url)?;
+        info!(
+            event = "timestamped",
+            token:% = token.display(),
+            time = time.as_str();
+            "Timestamp token written to {} ({time})",
+            token.display()
+        );
+    }
+    Ok(())
+}
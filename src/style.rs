@@ -0,0 +1,46 @@
+use std::{
+    env,
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use clap::ValueEnum;
+
+static ASCII: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Turns colored output on or off for the rest of the run. `Auto` colors
+    /// only when stdout is a terminal and `NO_COLOR` isn't set.
+    pub fn apply(self) {
+        let enabled = match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                io::stdout().is_terminal() && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+            }
+        };
+        colored::control::set_override(enabled);
+    }
+}
+
+/// Use plain ASCII tags instead of emoji markers.
+pub fn set_ascii(ascii: bool) {
+    ASCII.store(ascii, Ordering::Relaxed);
+}
+
+/// Picks the emoji decorated `fancy` text, or its plain `ascii` stand-in when
+/// `--ascii` was given.
+pub fn pick(fancy: &'static str, ascii: &'static str) -> &'static str {
+    if ASCII.load(Ordering::Relaxed) {
+        ascii
+    } else {
+        fancy
+    }
+}
@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use colored::*;
+use log::info;
+
+use crate::{cli::SyncCheckArgs, execute, store, style};
+
+// After rsync or robocopy: is the destination really a copy of the source?
+// The source's manifest says what should be there, so only the destination
+// is read. Its paths are taken as the source's (`--record-as` the
+// manifest's root), modification times don't excuse different contents
+// and a file under another name is no copy of it.
+
+/// `sync-check`: exit code 0 when the destination has every file of the
+/// source intact, 2 when it doesn't, 1 on errors.
+pub fn run(args: &SyncCheckArgs) -> i32 {
+    let root = match store::load(&args.source, &args.run) {
+        Ok(loaded) => loaded.manifest.root,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            return 1;
+        }
+    };
+    let Some(root) = root else {
+        eprintln!(
+            "Error: {} doesn't record where the source was, create it again",
+            args.source.display()
+        );
+        return 1;
+    };
+
+    info!(
+        "{} Checking {} as a copy of {root}",
+        style::pick("🔁", "*").bold(),
+        args.destination.display()
+    );
+    let mut run = args.run.clone();
+    run.record_as = Some(PathBuf::from(root));
+    run.copy = true;
+    execute(
+        &args.destination,
+        &run,
+        std::slice::from_ref(&args.source),
+        None,
+    )
+}
@@ -0,0 +1,145 @@
+use std::{collections::HashMap, io};
+
+use globset::{GlobBuilder, GlobSetBuilder};
+use log::info;
+
+use crate::{
+    cli::TagArgs,
+    crypt,
+    hash::{Digest, FileMeta},
+    manifest::Checksum,
+    store, style,
+};
+
+// Entries can carry tags and a note ("original scan from camera card
+// 2021-07"), set with `checkyoself tag`. They're part of the entry, so
+// they're covered by the checksum and signatures, --update keeps them for
+// files still there or moved, and verify findings mention them.
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+/// The tags of `meta`, comma separated, if it has any.
+pub fn joined(meta: &FileMeta) -> Option<String> {
+    (!meta.tags.is_empty()).then(|| meta.tags.iter().cloned().collect::<Vec<_>>().join(", "))
+}
+
+/// Lines to add under a finding about `meta`: its tags and note.
+pub fn describe(meta: &FileMeta) -> String {
+    let mut lines = String::new();
+    if let Some(tags) = joined(meta) {
+        lines.push_str(&format!("\n  tags:     {tags}"));
+    }
+    if let Some(note) = &meta.note {
+        lines.push_str(&format!("\n  note:     {note}"));
+    }
+    lines
+}
+
+/// Gives the entries of `updated`, the tree as it is now, the tags and
+/// notes they had in `previous`: by path, or for a moved file, those of
+/// the path it was moved from.
+pub fn carry(previous: &HashMap<String, FileMeta>, updated: &mut HashMap<String, FileMeta>) {
+    let tagged = |meta: &&FileMeta| !meta.tags.is_empty() || meta.note.is_some();
+    // Tagged entries whose path is gone, by contents
+    let mut gone: HashMap<Digest, &FileMeta> = HashMap::new();
+    for (path, meta) in previous.iter().filter(|(_, meta)| tagged(meta)) {
+        if !updated.contains_key(path) {
+            gone.entry(meta.hash).or_insert(meta);
+        }
+    }
+    for (path, meta) in updated.iter_mut() {
+        let from = previous
+            .get(path)
+            .filter(tagged)
+            .or_else(|| gone.get(&meta.hash).copied());
+        if let Some(from) = from {
+            meta.tags = from.tags.clone();
+            meta.note = from.note.clone();
+        }
+    }
+}
+
+fn tag(args: &TagArgs) -> io::Result<()> {
+    let path = &args.manifest;
+    let mut reference = store::load(path, &args.run)?;
+    if crypt::is_encrypted(&reference.raw) && args.run.encryption.encrypt_to.is_empty() {
+        return Err(invalid(format!(
+            "{} is encrypted, pass --encrypt-to to keep it that way",
+            path.display()
+        )));
+    }
+    let manifest = &mut reference.manifest;
+    // Its entries include theirs, writing them back would fold them in
+    if !manifest.includes.is_empty() {
+        return Err(invalid(format!(
+            "{} includes other manifests, tag entries in the manifest they're in",
+            path.display()
+        )));
+    }
+
+    let mut globs = GlobSetBuilder::new();
+    for pattern in &args.paths {
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| invalid(format!("{pattern}: {e}")))?;
+        globs.add(glob);
+    }
+    let globs = globs.build().map_err(|e| invalid(e.to_string()))?;
+    let mut matched: Vec<&String> = manifest
+        .files
+        .keys()
+        .filter(|key| args.paths.contains(key) || globs.is_match(key))
+        .collect();
+    if matched.is_empty() {
+        return Err(invalid(format!(
+            "no entry of {} matches {}",
+            path.display(),
+            args.paths.join(" ")
+        )));
+    }
+    matched.sort_unstable();
+
+    // Without changes to make, list what's there
+    if args.add.is_empty() && args.remove.is_empty() && args.note.is_none() {
+        for key in matched {
+            println!("{key}{}", describe(&manifest.files[key]));
+        }
+        return Ok(());
+    }
+
+    let matched: Vec<String> = matched.into_iter().cloned().collect();
+    for key in &matched {
+        let meta = manifest.files.get_mut(key).expect("matched entry");
+        meta.tags.extend(args.add.iter().cloned());
+        meta.tags.retain(|tag| !args.remove.contains(tag));
+        if let Some(note) = &args.note {
+            meta.note = (!note.is_empty()).then(|| note.as_str().into());
+        }
+    }
+    manifest.checksum = Some(Checksum::of(&manifest.files, &manifest.special).hex());
+    store::save_as(&reference.manifest, path, &args.run, reference.format)?;
+    info!(
+        event = "tagged",
+        manifest:% = path.display(),
+        files = matched.len();
+        "{} Tagged {} file(s) in {}",
+        style::pick("🏷️", "*"),
+        matched.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// `tag`: exit code 0, or 1 on errors.
+pub fn run(args: &TagArgs) -> i32 {
+    match tag(args) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::kv::Source;
+
+use crate::logging;
+
+// --line-format replaces the line printed for each file (a mismatch, an
+// extra file, a hashed one with -vv...) with one made from a template, so
+// the output fits whatever already parses logs:
+//
+//   --line-format '{status} {path} {expected} {found}'
+//
+// Placeholders are filled from the record's fields, `-` when it has none;
+// `{{` and `}}` are literal braces. Summaries and other lines that aren't
+// about a file are left as they are.
+
+/// What can go between braces.
+const PLACEHOLDERS: &[&str] = &[
+    "status", "path", "expected", "found", "size", "modified", "age", "tags", "note",
+];
+
+enum Part {
+    Text(String),
+    Field(&'static str),
+}
+
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err("a { without its } in --line-format".to_string()),
+                        }
+                    }
+                    let Some(field) = PLACEHOLDERS.iter().find(|p| **p == name) else {
+                        return Err(format!(
+                            "unknown placeholder {{{name}}} in --line-format, there are {}",
+                            PLACEHOLDERS
+                                .iter()
+                                .map(|p| format!("{{{p}}}"))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ));
+                    };
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err("a lone } in --line-format, write }} for one".to_string()),
+                c => text.push(c),
+            }
+        }
+        parts.push(Part::Text(text));
+        Ok(Template { parts })
+    }
+
+    /// The line for a record with these fields, if it's about a file.
+    pub fn render(&self, fields: &dyn Source) -> Option<String> {
+        let get = |key: &str| logging::field(fields, key);
+        let event = get("event")?;
+        let path = get("path")?;
+        let mut line = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => {
+                    line.push_str(text);
+                    continue;
+                }
+                Part::Field("status") => Some(event.to_uppercase()),
+                Part::Field("path") => Some(path.clone()),
+                Part::Field("size") => get("size")
+                    .or_else(|| get("found_size"))
+                    .or_else(|| get("expected_size")),
+                Part::Field("modified") => get("modified").and_then(|m| {
+                    let secs = m.parse().ok()?;
+                    let time = UNIX_EPOCH + Duration::from_secs(secs);
+                    Some(humantime::format_rfc3339_seconds(time).to_string())
+                }),
+                Part::Field("age") => get("modified").and_then(|m| Some(age(m.parse().ok()?))),
+                Part::Field(field) => get(field),
+            };
+            line.push_str(value.as_deref().unwrap_or("-"));
+        }
+        Some(line)
+    }
+}
+
+/// How long ago `modified` (seconds since the epoch) was, in its largest
+/// unit: `40s`, `12m`, `5h`, `3d`.
+fn age(modified: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let secs = now.saturating_sub(modified);
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
@@ -0,0 +1,313 @@
+use std::{collections::HashMap, fmt::Write, io};
+
+use serde_json::Value as Json;
+
+use crate::{
+    hash::FileMeta,
+    manifest::{Manifest, Special, SpecialKind, Totals},
+};
+
+// Manifests written with --format text are meant to be committed to git:
+// a header, a blank line, then one line per file sorted by path, so a
+// changed file shows up in a diff as its line changing, a moved one as a
+// line going and one with the same hash coming, a deleted one as a line
+// going. Nothing else in the file moves about between runs but `created`
+// and `checksum`.
+//
+//   # checkyoself manifest
+//   version 1
+//   root photos
+//   created 1714557600
+//   volume {"uuid":"...","label":"Backup"}
+//   sequence 3
+//   previous <blake3>
+//   include {"path":"base.json","blake3":"..."}
+//   ignore logs/**
+//   totals <files> <bytes>
+//   special <path>\tfifo
+//   special <path>\tblock_device\t8:0
+//   device 2065 {"id":"8:17","source":"/dev/sdb1"}
+//   dir <path>\t<blake3>
+//   checksum <hex>
+//
+//   <path>\t<blake3>\t<size>\t<modified>[\t<JSON of md5, chunks, ...>]
+//
+// Fields are tab separated and the path comes first; tabs, line breaks and
+// backslashes in paths are escaped (`\t`, `\n`, `\r`, `\\`).
+
+/// The first line of a text manifest, how one is told from JSON.
+const MAGIC: &str = "# checkyoself manifest";
+
+fn invalid(line: usize, msg: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {line}: {msg}"))
+}
+
+/// Whether `data` is a text manifest.
+pub fn is_text(data: &str) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> Option<String> {
+    let mut path = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            path.push(c);
+            continue;
+        }
+        path.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(path)
+}
+
+/// `manifest` as text, entries sorted by path.
+pub fn write(manifest: &Manifest) -> String {
+    let mut out = String::new();
+    // Writing to a String can't fail
+    let _ = write_to(&mut out, manifest);
+    out
+}
+
+fn write_to(out: &mut String, manifest: &Manifest) -> std::fmt::Result {
+    writeln!(out, "{MAGIC}")?;
+    writeln!(out, "version {}", manifest.version)?;
+    if let Some(root) = &manifest.root {
+        writeln!(out, "root {}", escape(root))?;
+    }
+    if let Some(created) = manifest.created {
+        writeln!(out, "created {created}")?;
+    }
+    if let Some(volume) = &manifest.volume {
+        let volume = serde_json::to_string(volume).map_err(|_| std::fmt::Error)?;
+        writeln!(out, "volume {volume}")?;
+    }
+    if let Some(sequence) = manifest.sequence {
+        writeln!(out, "sequence {sequence}")?;
+    }
+    if let Some(previous) = &manifest.previous {
+        writeln!(out, "previous {previous}")?;
+    }
+    for include in &manifest.includes {
+        let include = serde_json::to_string(include).map_err(|_| std::fmt::Error)?;
+        writeln!(out, "include {include}")?;
+    }
+    for pattern in &manifest.ignore {
+        writeln!(out, "ignore {pattern}")?;
+    }
+    if let Some(totals) = manifest.totals {
+        writeln!(out, "totals {} {}", totals.files, totals.bytes)?;
+    }
+    for (path, special) in &manifest.special {
+        write!(out, "special {}\t{}", escape(path), special.kind.as_str())?;
+        if let Some(device) = &special.device {
+            write!(out, "\t{device}")?;
+        }
+        writeln!(out)?;
+    }
+    for (device, filesystem) in &manifest.devices {
+        let filesystem = serde_json::to_string(filesystem).map_err(|_| std::fmt::Error)?;
+        writeln!(out, "device {device} {filesystem}")?;
+    }
+    for (path, digest) in &manifest.dirs {
+        writeln!(out, "dir {}\t{digest}", escape(path))?;
+    }
+    if let Some(checksum) = &manifest.checksum {
+        writeln!(out, "checksum {checksum}")?;
+    }
+    writeln!(out)?;
+
+    let mut files: Vec<_> = manifest.files.iter().collect();
+    files.sort_unstable_by_key(|(path, _)| *path);
+    for (path, meta) in files {
+        write!(
+            out,
+            "{}\t{}\t{}\t{}",
+            escape(path),
+            meta.hash,
+            meta.size,
+            meta.modified
+        )?;
+        // Whatever else there is (--md5, --chunk-size, ...) goes as JSON
+        if let Ok(Json::Object(mut rest)) = serde_json::to_value(meta) {
+            for field in ["hash", "size", "modified"] {
+                rest.remove(field);
+            }
+            if !rest.is_empty() {
+                write!(out, "\t{}", Json::Object(rest))?;
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(line: usize, value: &str) -> io::Result<T> {
+    serde_json::from_str(value).map_err(|e| invalid(line, e))
+}
+
+fn special_kind(kind: &str) -> Option<SpecialKind> {
+    [
+        SpecialKind::Fifo,
+        SpecialKind::Socket,
+        SpecialKind::CharDevice,
+        SpecialKind::BlockDevice,
+    ]
+    .into_iter()
+    .find(|k| k.as_str() == kind)
+}
+
+/// Parses a text manifest, checking it like a JSON one.
+pub fn parse(data: &str) -> io::Result<Manifest> {
+    let mut manifest = Manifest {
+        version: 0,
+        root: None,
+        created: None,
+        volume: None,
+        sequence: None,
+        previous: None,
+        includes: Vec::new(),
+        ignore: Vec::new(),
+        totals: None,
+        special: Default::default(),
+        devices: Default::default(),
+        dirs: Default::default(),
+        files: HashMap::new(),
+        checksum: None,
+    };
+    let mut lines = data.lines().enumerate().map(|(i, line)| (i + 1, line));
+    let mut ended = false;
+    for (n, line) in lines.by_ref() {
+        if line.is_empty() {
+            ended = true;
+            break;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        let number = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|e| invalid(n, format!("{key}: {e}")))
+        };
+        match key {
+            "version" => manifest.version = u32::try_from(number(value)?).unwrap_or(u32::MAX),
+            "root" => {
+                manifest.root = Some(unescape(value).ok_or_else(|| invalid(n, "bad escape"))?)
+            }
+            "created" => manifest.created = Some(number(value)?),
+            "volume" => manifest.volume = Some(from_json(n, value)?),
+            "sequence" => manifest.sequence = Some(number(value)?),
+            "previous" => manifest.previous = Some(value.to_string()),
+            "include" => manifest.includes.push(from_json(n, value)?),
+            "ignore" => manifest.ignore.push(value.to_string()),
+            "totals" => {
+                let (files, bytes) = value
+                    .split_once(' ')
+                    .ok_or_else(|| invalid(n, "totals: expected files and bytes"))?;
+                manifest.totals = Some(Totals {
+                    files: number(files)?,
+                    bytes: number(bytes)?,
+                });
+            }
+            "special" => {
+                let mut fields = value.split('\t');
+                let path = fields.next().and_then(unescape);
+                let kind = fields.next().and_then(special_kind);
+                let (Some(path), Some(kind)) = (path, kind) else {
+                    return Err(invalid(n, "bad special file"));
+                };
+                let device = fields.next().map(str::to_string);
+                manifest.special.insert(path, Special { kind, device });
+            }
+            "device" => {
+                let (device, filesystem) = value
+                    .split_once(' ')
+                    .ok_or_else(|| invalid(n, "device: expected a number and JSON"))?;
+                manifest
+                    .devices
+                    .insert(number(device)?, from_json(n, filesystem)?);
+            }
+            "dir" => {
+                let (path, digest) = value
+                    .split_once('\t')
+                    .ok_or_else(|| invalid(n, "dir: expected a path and a digest"))?;
+                let path = unescape(path).ok_or_else(|| invalid(n, "bad escape in dir"))?;
+                let digest = digest
+                    .parse()
+                    .map_err(|e| invalid(n, format!("dir: {e}")))?;
+                manifest.dirs.insert(path, digest);
+            }
+            "checksum" => manifest.checksum = Some(value.to_string()),
+            // Added by a later version, and harmless to skip
+            _ => {}
+        }
+    }
+    if !ended {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "manifest is truncated (no end to its header)",
+        ));
+    }
+
+    for (n, line) in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.splitn(5, '\t');
+        let (Some(path), Some(hash), Some(size), Some(modified)) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(invalid(n, "expected path, hash, size and modified time"));
+        };
+        let path = unescape(path).ok_or_else(|| invalid(n, "bad escape in path"))?;
+        let mut meta = match fields.next() {
+            Some(rest) => match serde_json::from_str(rest).map_err(|e| invalid(n, e))? {
+                Json::Object(rest) => rest,
+                _ => return Err(invalid(n, "expected a JSON object after the modified time")),
+            },
+            None => Default::default(),
+        };
+        meta.insert("hash".to_string(), hash.into());
+        meta.insert(
+            "size".to_string(),
+            size.parse::<i64>()
+                .map_err(|e| invalid(n, format!("size: {e}")))?
+                .into(),
+        );
+        meta.insert(
+            "modified".to_string(),
+            modified
+                .parse::<u64>()
+                .map_err(|e| invalid(n, format!("modified: {e}")))?
+                .into(),
+        );
+        let meta: FileMeta =
+            serde_json::from_value(Json::Object(meta)).map_err(|e| invalid(n, e))?;
+        if manifest.files.insert(path, meta).is_some() {
+            return Err(invalid(n, "path listed twice"));
+        }
+    }
+    manifest.check()?;
+    Ok(manifest)
+}
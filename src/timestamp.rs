@@ -0,0 +1,133 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use sha2::{Digest, Sha256};
+
+// RFC 3161 tokens are requested and checked with `openssl ts`, and fetched
+// with `curl`. Both work from a SHA-256 digest of the manifest bytes, so the
+// bytes that get parsed are the bytes that were stamped.
+
+/// The timestamp token that goes with `manifest`: `<manifest>.tsr`.
+pub fn token_path(manifest: &Path) -> PathBuf {
+    let mut name = OsString::from(manifest.as_os_str());
+    name.push(".tsr");
+    PathBuf::from(name)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Runs `cmd` with `input` on stdin, returning its stdout.
+fn run(tool: &str, mut cmd: Command, input: &[u8]) -> io::Result<Vec<u8>> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("can't run {tool}: {e}")))?;
+    let mut stdin = child.stdin.take().expect("piped stdin");
+    // Small inputs, the pipe buffer takes them without a writer thread
+    let _ = stdin.write_all(input);
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(io::Error::other(format!(
+            "{tool} failed\n{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// The "Time stamp:" of the token at `token`, as openssl prints it.
+fn token_time(token: &Path) -> io::Result<String> {
+    let mut cmd = Command::new("openssl");
+    cmd.args(["ts", "-reply", "-text", "-in"]).arg(token);
+    let text = String::from_utf8_lossy(&run("openssl", cmd, &[])?).to_string();
+
+    if !text.lines().any(|l| l.trim() == "Status: Granted.") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: the TSA didn't grant the timestamp", token.display()),
+        ));
+    }
+    text.lines()
+        .find_map(|l| l.strip_prefix("Time stamp: "))
+        .map(str::to_string)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: no time in the timestamp token", token.display()),
+            )
+        })
+}
+
+/// Asks the TSA at `url` to timestamp `data`, the bytes of `manifest` as
+/// written, and stores the token next to it. Returns the token path and
+/// the time the TSA vouches for.
+pub fn stamp(manifest: &Path, data: &[u8], url: &str) -> io::Result<(PathBuf, String)> {
+    let mut query = Command::new("openssl");
+    query.args([
+        "ts",
+        "-query",
+        "-sha256",
+        "-cert",
+        "-digest",
+        &sha256_hex(data),
+    ]);
+    let request = run("openssl", query, &[])?;
+
+    let mut post = Command::new("curl");
+    post.args([
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--header",
+        "Content-Type: application/timestamp-query",
+        "--data-binary",
+        "@-",
+        url,
+    ]);
+    let reply = run("curl", post, &request)?;
+
+    let token = token_path(manifest);
+    fs::write(&token, reply)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", token.display(), e)))?;
+    let time = token_time(&token)?;
+    Ok((token, time))
+}
+
+/// Checks that the token next to `manifest` covers `data` and was issued
+/// by a TSA chaining up to `ca`. Returns the time it vouches for.
+pub fn verify(manifest: &Path, data: &[u8], ca: &Path) -> io::Result<String> {
+    let token = token_path(manifest);
+    if !token.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{}: no timestamp token next to it", manifest.display()),
+        ));
+    }
+
+    let mut cmd = Command::new("openssl");
+    cmd.args(["ts", "-verify", "-digest", &sha256_hex(data), "-in"])
+        .arg(&token)
+        .arg("-CAfile")
+        .arg(ca);
+    run("openssl", cmd, &[]).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}: timestamp doesn't verify, refusing to trust it\n{e}",
+                manifest.display()
+            ),
+        )
+    })?;
+    token_time(&token)
+}
@@ -0,0 +1,146 @@
+use std::{collections::HashMap, fmt, marker::PhantomData};
+
+use serde::{
+    Deserialize, Deserializer, Serialize, Serializer,
+    de::{DeserializeSeed, MapAccess, Visitor},
+    ser::SerializeMap,
+};
+
+use crate::hash::FileMeta;
+
+// --paths tree writes the files of a manifest nested by directory: a key
+// ending in '/' holds the entries under it, each path being the keys on
+// the way to it put together. A directory with a single subdirectory and
+// nothing else is one key ("home/me/photos/"), a directory with a single
+// file is none ("2024/trip.jpg"). Flat keys never end in '/', so a flat
+// files map reads the same way and loading needn't know which it is.
+
+/// Nesting stops this deep, further down the rest of a path is one key:
+/// parsers limit how deep JSON can nest, serde_json to 128.
+const MAX_DEPTH: usize = 64;
+
+/// Entries, sorted by path, serialized as a tree.
+pub struct Tree<'a> {
+    entries: &'a [(&'a str, &'a FileMeta)],
+    /// Bytes of every path the keys above already cover
+    skip: usize,
+    depth: usize,
+}
+
+impl<'a> Tree<'a> {
+    pub fn new(entries: &'a [(&'a str, &'a FileMeta)]) -> Self {
+        debug_assert!(entries.is_sorted_by_key(|(path, _)| *path));
+        Tree {
+            entries,
+            skip: 0,
+            depth: 0,
+        }
+    }
+}
+
+impl Serialize for Tree<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        let mut rest = self.entries;
+        while let Some(&(path, meta)) = rest.first() {
+            let key = &path[self.skip..];
+            // Sorted, the paths under the same directory as this one follow it
+            let under = match key.find('/') {
+                Some(end) if self.depth < MAX_DEPTH => {
+                    let dir = &key[..=end];
+                    rest.partition_point(|(path, _)| path[self.skip..].starts_with(dir))
+                }
+                _ => 1,
+            };
+            if under == 1 {
+                map.serialize_entry(key, meta)?;
+                rest = &rest[1..];
+                continue;
+            }
+            let (group, after) = rest.split_at(under);
+            // As deep as the directories they all are in go
+            let last = &group[under - 1].0.as_bytes()[self.skip..];
+            let common = key.bytes().zip(last).take_while(|(a, b)| a == *b).count();
+            let end = key.as_bytes()[..common]
+                .iter()
+                .rposition(|&b| b == b'/')
+                .expect("a directory in common");
+            let dir = &key[..=end];
+            map.serialize_entry(
+                dir,
+                &Tree {
+                    entries: group,
+                    skip: self.skip + dir.len(),
+                    depth: self.depth + 1,
+                },
+            )?;
+            rest = after;
+        }
+        map.end()
+    }
+}
+
+/// Reads a files map, flat or nested, giving `entry` every entry with its
+/// full path, in file order (duplicates included).
+pub struct Entries<'f, T, F> {
+    prefix: String,
+    entry: &'f mut F,
+    value: PhantomData<T>,
+}
+
+impl<'f, T, F> Entries<'f, T, F> {
+    pub fn new(entry: &'f mut F) -> Self {
+        Entries {
+            prefix: String::new(),
+            entry,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>, F: FnMut(String, T)> DeserializeSeed<'de> for Entries<'_, T, F> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, T: Deserialize<'de>, F: FnMut(String, T)> Visitor<'de> for Entries<'_, T, F> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map of path to file entry")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            let path = match self.prefix.is_empty() {
+                true => key,
+                false => format!("{}{key}", self.prefix),
+            };
+            if path.ends_with('/') {
+                map.next_value_seed(Entries {
+                    prefix: path,
+                    entry: &mut *self.entry,
+                    value: PhantomData::<T>,
+                })?;
+            } else {
+                (self.entry)(path, map.next_value()?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `Manifest::files` from either layout; later duplicates win.
+pub fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<HashMap<String, FileMeta>, D::Error> {
+    let mut files = HashMap::new();
+    Entries::new(&mut |path, meta| {
+        files.insert(path, meta);
+    })
+    .deserialize(deserializer)?;
+    Ok(files)
+}
@@ -0,0 +1,38 @@
+use std::borrow::Cow;
+
+use unicode_normalization::UnicodeNormalization;
+
+// The same name can be spelled in different bytes: macOS (HFS+, and what
+// most Mac apps hand APFS) decomposes `é` into `e` + U+0301, Linux and
+// Windows keep it composed, and Windows and macOS don't mind case either.
+// A tree copied between them then comes up as MISSING/EXTRA pairs unless
+// paths are compared the way those filesystems compare them. Normalizing
+// to NFD (fully decomposed, combining marks in canonical order) makes
+// canonically equivalent names equal.
+
+/// How loosely paths are compared. The default compares them byte for
+/// byte.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PathFold {
+    pub ignore_case: bool,
+    pub normalize: bool,
+}
+
+impl PathFold {
+    pub fn is_exact(&self) -> bool {
+        !self.ignore_case && !self.normalize
+    }
+
+    /// What `path` is compared by.
+    pub fn key<'p>(&self, path: &'p str) -> Cow<'p, str> {
+        let mut key = Cow::Borrowed(path);
+        // ASCII is in every normal form already
+        if self.normalize && !path.is_ascii() {
+            key = Cow::Owned(key.nfd().collect());
+        }
+        if self.ignore_case && key.chars().any(|c| c.is_uppercase()) {
+            key = Cow::Owned(key.to_lowercase());
+        }
+        key
+    }
+}
@@ -0,0 +1,1996 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use colored::*;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use indicatif::HumanBytes;
+use log::{Level, debug, error, info, log, warn};
+
+use crate::{
+    bytediff::{ByteDiff, CompareBytes},
+    cli::Check,
+    devices, dupes, entropy, fscheck,
+    hash::{Digest, FileMeta},
+    hooks::Hooks,
+    logging,
+    manifest::{Filesystem, Special},
+    policy::{Behavior, Policy},
+    reread::{Again, Reading, Reread},
+    restore::Restore,
+    scan::Trouble,
+    spill::{self, Spill},
+    style, tags,
+    unicode::PathFold,
+    xattrs,
+};
+
+/// Outcome counts of a verify run.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub matched: usize,
+    pub moved: usize,
+    pub mismatched: usize,
+    pub extra: usize,
+    pub missing: usize,
+    pub errors: usize,
+    /// Of `errors`, for lack of permission
+    pub denied: usize,
+    /// Of `errors`, because the storage failed to read them
+    pub medium: usize,
+    pub volatile: usize,
+    pub retried: usize,
+    /// Special files that appeared, disappeared or changed type
+    pub special: usize,
+    /// Changes to paths on the ignore list, see `IgnoreList`
+    pub ignored: usize,
+    /// Open for writing elsewhere with --skip-busy, not checked
+    pub busy: usize,
+    /// Of `mismatched`, files that got smaller, emptied ones included
+    pub truncated: usize,
+    /// Files that read wrong once and right the second time, with
+    /// --reread; counted as matched
+    pub transient: usize,
+    /// Of `mismatched`, files that read differently the second time too
+    pub unreliable: usize,
+    /// Of `mismatched`, how many differed in each attribute --check looks at
+    pub checks: BTreeMap<Check, usize>,
+    /// Reference files found where they were, and of those the ones whose
+    /// contents changed, mismatched or owned up to by the modification
+    /// time; see `mass`
+    pub compared: usize,
+    pub rewritten: usize,
+    /// Of `rewritten`, the files whose contents became near random, with
+    /// --entropy
+    pub random: usize,
+    /// Of `rewritten`, the files whose contents became another type, with
+    /// --magic
+    pub retyped: usize,
+    /// Files with the same contents but created anew, with --btime
+    pub recreated: usize,
+    /// With --fail-fast, missing files fail the run as well
+    pub missing_fails: bool,
+    /// Checking stopped at the first failure, with --fail-fast
+    pub stopped: bool,
+}
+
+impl Summary {
+    pub fn failed(&self) -> bool {
+        self.mismatched > 0 || (self.missing_fails && self.missing > 0)
+    }
+
+    /// One `key=value` line whose layout is stable, for scripts to parse.
+    pub fn line(&self) -> String {
+        format!(
+            "result={} matched={} moved={} mismatched={} extra={} missing={} errors={} volatile={} retried={} special={} ignored={} busy={} truncated={}",
+            if self.failed() { "fail" } else { "ok" },
+            self.matched,
+            self.moved,
+            self.mismatched,
+            self.extra,
+            self.missing,
+            self.errors,
+            self.volatile,
+            self.retried,
+            self.special,
+            self.ignored,
+            self.busy,
+            self.truncated
+        )
+    }
+
+    /// Prints `line()` to stdout, unless stdout carries JSON records (the
+    /// summary record already has these counts). Along with the rest of
+    /// the console output when that goes to stderr.
+    pub fn print_line(&self) {
+        // Best effort, like the logger: a closed pipe isn't worth a panic
+        if logging::is_stderr() {
+            let _ = writeln!(io::stderr(), "{}", self.line());
+        } else if !logging::is_json() {
+            let _ = writeln!(io::stdout(), "{}", self.line());
+        }
+    }
+
+    /// Whether a copy checked with `Verifier::copy` is incomplete or wrong.
+    pub fn copy_failed(&self) -> bool {
+        self.mismatched > 0 || self.missing > 0 || self.errors > 0 || self.volatile > 0
+    }
+
+    /// The counts the way a copy tool would put them.
+    pub fn print_transfer(&self) {
+        info!(
+            event = "transfer_summary",
+            identical = self.matched,
+            differs = self.mismatched,
+            missing = self.missing,
+            extra = self.extra,
+            unreadable = self.errors + self.volatile;
+            "\n=== {} ===\n{} {}\n{} {}\n{} {}\n{} {}\n{} {}",
+            "TRANSFER".bold().underline(),
+            style::pick("✅ Identical:", "Identical:").green(),
+            self.matched,
+            style::pick("❌ Differs:", "Differs:").red(),
+            self.mismatched,
+            style::pick("❓ Missing at destination:", "Missing at destination:").magenta(),
+            self.missing,
+            style::pick("⚠️ Extra at destination:", "Extra at destination:").blue(),
+            self.extra,
+            style::pick("🚫 Unreadable at destination:", "Unreadable at destination:").red(),
+            self.errors + self.volatile
+        );
+    }
+}
+
+/// How the size of a file with other contents changed. One that went from
+/// something to nothing is what an interrupted copy leaves behind.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SizeChange {
+    Emptied,
+    Truncated,
+    Grew,
+}
+
+impl SizeChange {
+    fn of(expected: i64, found: i64) -> Option<SizeChange> {
+        match found.cmp(&expected) {
+            std::cmp::Ordering::Less if found == 0 => Some(SizeChange::Emptied),
+            std::cmp::Ordering::Less => Some(SizeChange::Truncated),
+            std::cmp::Ordering::Greater => Some(SizeChange::Grew),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SizeChange::Emptied => "emptied",
+            SizeChange::Truncated => "truncated",
+            SizeChange::Grew => "grew",
+        }
+    }
+
+    fn marker(self) -> ColoredString {
+        match self {
+            SizeChange::Emptied => style::pick("🕳️ EMPTIED", "EMPTIED").red().bold(),
+            SizeChange::Truncated => style::pick("✂️ TRUNCATED", "TRUNCATED").red(),
+            SizeChange::Grew => style::pick("📈 GREW", "GREW").red(),
+        }
+    }
+}
+
+/// What a file turned out to be, for counting.
+#[derive(Clone, Copy)]
+enum Finding {
+    Matched,
+    Moved,
+    Mismatched,
+    Extra,
+    Missing,
+    /// Changed, extra or missing, but on the ignore list
+    Ignored,
+}
+
+impl Summary {
+    fn count(&mut self, finding: Finding) {
+        *match finding {
+            Finding::Matched => &mut self.matched,
+            Finding::Moved => &mut self.moved,
+            Finding::Mismatched => &mut self.mismatched,
+            Finding::Extra => &mut self.extra,
+            Finding::Missing => &mut self.missing,
+            Finding::Ignored => &mut self.ignored,
+        } += 1;
+    }
+
+    /// How many of the unreadable files were denied or hit medium errors,
+    /// ` (2 permission denied, 1 medium error)`, empty when none were.
+    fn error_classes(&self) -> String {
+        let classes: Vec<String> = [
+            (self.denied, "permission denied"),
+            (self.medium, "medium error"),
+        ]
+        .into_iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, what)| format!("{n} {what}"))
+        .collect();
+        if classes.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", classes.join(", "))
+        }
+    }
+
+    /// The non-zero counts other than matches, `2 mismatched, 14 extra`.
+    fn findings(&self) -> String {
+        [
+            (self.mismatched, "mismatched"),
+            (self.moved, "moved"),
+            (self.extra, "extra"),
+            (self.missing, "missing"),
+        ]
+        .iter()
+        .filter(|(n, _)| *n > 0)
+        .map(|(n, what)| format!("{n} {what}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// What --group-by rolls findings up by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    /// Directory, relative to the scanned one, cut at --depth
+    Dir,
+    /// File extension, lowercased
+    Ext,
+    /// Size class, from under 64 KiB to over 1 GiB
+    Size,
+    /// Device (filesystem) the file was read from, see --devices
+    Device,
+}
+
+impl GroupBy {
+    fn title(self) -> &'static str {
+        match self {
+            GroupBy::Dir => "DIRECTORY",
+            GroupBy::Ext => "EXTENSION",
+            GroupBy::Size => "SIZE",
+            GroupBy::Device => "DEVICE",
+        }
+    }
+}
+
+/// Upper bounds (exclusive) of the size classes and their names, the last
+/// one is everything bigger.
+const SIZE_CLASSES: [(u64, &str); 6] = [
+    (64 << 10, "< 64 KiB"),
+    (1 << 20, "64 KiB-1 MiB"),
+    (10 << 20, "1-10 MiB"),
+    (100 << 20, "10-100 MiB"),
+    (1 << 30, "100 MiB-1 GiB"),
+    (u64::MAX, ">= 1 GiB"),
+];
+
+/// A group: one part per --group-by, each with a number to sort it by
+/// (size classes sort by size, the rest by name).
+type GroupKey = Vec<(usize, String)>;
+
+/// Turns paths into the group they are counted in.
+pub struct Grouping {
+    pub by: Vec<GroupBy>,
+    pub root: PathBuf,
+    pub depth: Option<usize>,
+    /// The filesystems the references' entries were read from
+    pub devices: BTreeMap<u64, Filesystem>,
+}
+
+impl Grouping {
+    /// The group of the file at `path`, as `meta` has it. A device goes by
+    /// its number, named in `name`.
+    fn key(&self, path: &str, meta: &FileMeta) -> GroupKey {
+        let path = Path::new(path);
+        self.by
+            .iter()
+            .map(|by| match by {
+                GroupBy::Dir => {
+                    let dir = path
+                        .strip_prefix(&self.root)
+                        .unwrap_or(path)
+                        .parent()
+                        .unwrap_or(Path::new(""));
+                    let dir: PathBuf = dir
+                        .components()
+                        .take(self.depth.unwrap_or(usize::MAX))
+                        .collect();
+                    if dir.as_os_str().is_empty() {
+                        (0, ".".to_string())
+                    } else {
+                        (0, dir.to_string_lossy().to_string())
+                    }
+                }
+                GroupBy::Ext => match path.extension() {
+                    Some(ext) => (0, format!(".{}", ext.to_string_lossy().to_lowercase())),
+                    None => (0, "(no extension)".to_string()),
+                },
+                GroupBy::Size => {
+                    let size = meta.size.max(0) as u64;
+                    let class = SIZE_CLASSES
+                        .iter()
+                        .position(|(limit, _)| size < *limit)
+                        .unwrap_or(SIZE_CLASSES.len() - 1);
+                    (class, SIZE_CLASSES[class].1.to_string())
+                }
+                GroupBy::Device => match meta.device {
+                    Some(device) => (1, device.to_string()),
+                    None => (0, "(device not recorded)".to_string()),
+                },
+            })
+            .collect()
+    }
+
+    /// The name of group `key`, devices named once here rather than for
+    /// every file.
+    fn name(&self, key: &GroupKey) -> String {
+        let parts: Vec<String> = key
+            .iter()
+            .zip(&self.by)
+            .map(|((_, part), by)| match (by, part.parse()) {
+                (GroupBy::Device, Ok(device)) => devices::label(device, &self.devices),
+                _ => part.clone(),
+            })
+            .collect();
+        parts.join(", ")
+    }
+
+    fn title(&self) -> String {
+        let by: Vec<&str> = self.by.iter().map(|by| by.title()).collect();
+        format!("BY {}", by.join(", "))
+    }
+}
+
+/// Paths whose changes are tolerated: log files, thumbnail caches and the
+/// like, expected to churn. Globs are matched against paths relative to
+/// the root (`logs/**`, `**/Thumbs.db`), `*` stays within a directory.
+pub struct IgnoreList {
+    root: PathBuf,
+    globs: GlobSet,
+}
+
+impl IgnoreList {
+    /// The list of `patterns` for a tree recorded as `root`, None if empty.
+    pub fn new(root: &str, patterns: &[String]) -> io::Result<Option<Self>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        let mut globs = GlobSetBuilder::new();
+        for pattern in patterns {
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("{pattern}: {e}"))
+                })?;
+            globs.add(glob);
+        }
+        let globs = globs
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Some(IgnoreList {
+            root: PathBuf::from(root),
+            globs,
+        }))
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        self.globs
+            .is_match(path.strip_prefix(&self.root).unwrap_or(path))
+    }
+}
+
+/// The byte ranges of a mismatched file whose chunk hashes differ.
+struct ChangedChunks {
+    changed: usize,
+    total: usize,
+    /// Half open byte ranges, adjacent chunks merged
+    ranges: Vec<(u64, u64)>,
+}
+
+impl ChangedChunks {
+    /// `start-end,start-end` (end exclusive), for structured output.
+    fn ranges_field(&self) -> String {
+        self.ranges
+            .iter()
+            .map(|(start, end)| format!("{start}-{end}"))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl fmt::Display for ChangedChunks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: u64 = self.ranges.iter().map(|(start, end)| end - start).sum();
+        write!(
+            f,
+            "{} of {} chunks ({}), bytes ",
+            self.changed,
+            self.total,
+            HumanBytes(bytes)
+        )?;
+        for (i, (start, end)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{start}..{end}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Points out a file whose new contents look like encryption, see
+/// `entropy`.
+fn near_random(path: &str, expected: &FileMeta, current: &FileMeta) {
+    let now = current.entropy.unwrap_or_default();
+    warn!(
+        event = "near_random",
+        path = path,
+        expected_entropy = expected.entropy,
+        found_entropy = now;
+        "{} {}: {} bits per byte, near random",
+        style::pick("🔐 NEAR RANDOM", "NEAR RANDOM").red(),
+        path,
+        match expected.entropy {
+            Some(was) => format!("entropy {was} -> {now}"),
+            None => format!("entropy {now} (not recorded before)"),
+        }
+    );
+}
+
+/// Warns that the file at `path`, born `was`, was born again at `now`
+/// with the same contents.
+fn recreated(path: &str, was: u64, now: u64) {
+    let time = |secs| {
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs)).to_string()
+    };
+    warn!(
+        event = "recreated",
+        path = path,
+        expected_born = was,
+        found_born = now;
+        "{} {}: created {}, was {}, same contents: deleted and written back rather than edited",
+        style::pick("🐣 RECREATED", "RECREATED").yellow(),
+        path,
+        time(now),
+        time(was)
+    );
+}
+
+/// Which chunks differ, when both sides were hashed with the same chunk
+/// size.
+fn changed_chunks(expected: &FileMeta, current: &FileMeta) -> Option<ChangedChunks> {
+    let (Some(old), Some(new)) = (&expected.chunks, &current.chunks) else {
+        return None;
+    };
+    if old.size != new.size {
+        return None;
+    }
+
+    let total = old.hashes.len().max(new.hashes.len());
+    let file_end = expected.size.max(current.size).max(0) as u64;
+    let mut changed = 0;
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for i in 0..total {
+        if old.hashes.get(i) == new.hashes.get(i) {
+            continue;
+        }
+        changed += 1;
+        let start = i as u64 * old.size;
+        let end = (start + old.size).min(file_end);
+        match ranges.last_mut() {
+            Some(last) if last.1 == start => last.1 = end,
+            _ => ranges.push((start, end)),
+        }
+    }
+    Some(ChangedChunks {
+        changed,
+        total,
+        ranges,
+    })
+}
+
+/// Writes test point `n`, for the reference entry of `path` with contents
+/// `expected`.
+fn tap_point(
+    out: &mut impl Write,
+    n: usize,
+    path: &str,
+    expected: &Digest,
+    state: &State,
+) -> io::Result<()> {
+    // `#` starts a directive, a line break a line of its own
+    let name = path
+        .replace('\\', "\\\\")
+        .replace('#', "\\#")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r");
+    let failure = match state.points.get(path) {
+        Some(Point::Matched) => None,
+        Some(Point::Skipped) => {
+            return writeln!(
+                out,
+                "ok {n} - {name} # SKIP modified time differs, contents not compared"
+            );
+        }
+        Some(Point::Ignored) => {
+            return writeln!(out, "ok {n} - {name} # SKIP on the ignore list");
+        }
+        Some(Point::Mismatched { found }) => Some(format!(
+            "message: mismatch\n  expected: {expected}\n  found: {found}"
+        )),
+        Some(Point::Metadata(attrs)) => Some(format!(
+            "message: metadata mismatch\n  attrs: {}",
+            serde_json::Value::from(attrs.as_str())
+        )),
+        Some(Point::Busy) => {
+            return writeln!(out, "ok {n} - {name} # SKIP open for writing elsewhere");
+        }
+        Some(Point::Missing) => Some("message: missing".to_string()),
+        None if state.moved_hashes.contains(expected) => {
+            return writeln!(out, "ok {n} - {name} (moved)");
+        }
+        // Neither seen nor missing: --fail-fast stopped before it
+        None => {
+            return writeln!(
+                out,
+                "ok {n} - {name} # SKIP not checked, stopped at the first failure"
+            );
+        }
+    };
+    match failure {
+        None => writeln!(out, "ok {n} - {name}"),
+        Some(yaml) => writeln!(out, "not ok {n} - {name}\n  ---\n  {yaml}\n  ..."),
+    }
+}
+
+/// Prints the `n` biggest mismatches and the `n` oldest failures.
+fn report_offenders(failures: &mut [(Cow<str>, Cow<FileMeta>, Finding)], n: usize) {
+    let mut largest: Vec<_> = failures
+        .iter()
+        .filter(|(_, _, finding)| matches!(finding, Finding::Mismatched))
+        .collect();
+    largest.sort_by(|a, b| b.1.size.cmp(&a.1.size).then(a.0.cmp(&b.0)));
+    if !largest.is_empty() {
+        info!("\n=== {} ===", "LARGEST MISMATCHES".bold().underline());
+    }
+    for (path, meta, _) in largest.into_iter().take(n) {
+        info!(
+            event = "offender",
+            kind = "largest",
+            path = path.as_ref(),
+            size = meta.size;
+            "{:>10}  {path}",
+            HumanBytes(meta.size.max(0) as u64).to_string()
+        );
+    }
+
+    failures.sort_by(|a, b| a.1.modified.cmp(&b.1.modified).then(a.0.cmp(&b.0)));
+    if !failures.is_empty() {
+        info!("\n=== {} ===", "OLDEST FAILURES".bold().underline());
+    }
+    for (path, meta, finding) in failures.iter().take(n) {
+        let modified =
+            humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(meta.modified));
+        let what = match finding {
+            Finding::Missing => "missing",
+            _ => "mismatched",
+        };
+        info!(
+            event = "offender",
+            kind = "oldest",
+            path = path.as_ref(),
+            modified = meta.modified,
+            finding = what;
+            "{modified}  {path} ({what})"
+        );
+    }
+}
+
+/// When two modification times count as the same. A file whose contents
+/// changed while its mtime stayed the same is a MISMATCH; if the mtime
+/// moved too, it was presumably edited on purpose.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MtimeRule {
+    /// Seconds either way, 2 covers FAT/exFAT rounding
+    pub tolerance: u64,
+    /// Also forgive whole hour shifts of up to 14 hours (DST, or a copy
+    /// tool that stored local time as UTC)
+    pub tz_shifts: bool,
+}
+
+impl MtimeRule {
+    fn same(&self, a: u64, b: u64) -> bool {
+        let diff = a.abs_diff(b);
+        if diff <= self.tolerance {
+            return true;
+        }
+        if !self.tz_shifts {
+            return false;
+        }
+        let hours = (diff + self.tolerance) / 3600;
+        (1..=14).contains(&hours) && diff.abs_diff(hours * 3600) <= self.tolerance
+    }
+}
+
+/// How a reference entry fared, for --tap.
+enum Point {
+    Matched,
+    /// Modification time differs, the contents weren't compared
+    Skipped,
+    Mismatched {
+        found: Digest,
+    },
+    /// Same contents, the extended attributes named differ
+    Metadata(String),
+    /// Changed or missing, but on the ignore list
+    Ignored,
+    Missing,
+    /// Open for writing elsewhere, not hashed
+    Busy,
+}
+
+#[derive(Default)]
+struct State<'a> {
+    summary: Summary,
+    /// Reference paths that turned up in the scan
+    seen: Seen<'a>,
+    /// Content that turned up under a new path isn't missing
+    moved_hashes: HashSet<Digest>,
+    /// Counts per group, with --group-by
+    groups: BTreeMap<GroupKey, Summary>,
+    /// Mismatched and missing reference entries, with --offenders or
+    /// --emit-restore
+    failures: Vec<(Cow<'a, str>, Cow<'a, FileMeta>, Finding)>,
+    /// Paths whose contents mismatched, with --fs-check
+    mismatched: Vec<String>,
+    /// Reference entries by path, and the extra files, with --tap
+    points: HashMap<Cow<'a, str>, Point>,
+    extras: Vec<String>,
+    /// The first error looking the reference up, returned by `finish`
+    failed: Option<io::Error>,
+}
+
+type Specials = BTreeMap<String, Special>;
+
+/// A set of reference paths, in memory or, for a reference that's on disk
+/// itself, on disk (see `Verifier::seen_on_disk`).
+enum Seen<'a> {
+    Memory(HashSet<Cow<'a, str>>),
+    Disk(Spill),
+}
+
+impl Default for Seen<'_> {
+    fn default() -> Self {
+        Seen::Memory(HashSet::new())
+    }
+}
+
+impl<'a> Seen<'a> {
+    fn insert(&mut self, path: Cow<'a, str>) -> io::Result<()> {
+        match self {
+            Seen::Memory(seen) => {
+                seen.insert(path);
+            }
+            Seen::Disk(seen) => seen.insert(path.into_owned())?,
+        }
+        Ok(())
+    }
+
+    /// Whether `path` was seen. Asked about in path order, once inserting
+    /// is over.
+    fn contains(&mut self, path: &str) -> io::Result<bool> {
+        match self {
+            Seen::Memory(seen) => Ok(seen.contains(path)),
+            Seen::Disk(seen) => seen.contains(path),
+        }
+    }
+}
+
+/// An entry of a reference: borrowed from one in memory, read from one on
+/// disk.
+pub type Entry<'a> = (Cow<'a, str>, Cow<'a, FileMeta>);
+
+/// The entries a `Verifier` checks against, looked up as files turn up
+/// rather than loaded as a whole, so a reference can live on disk.
+pub trait Reference<'a>: Sync {
+    /// The entry recorded for exactly `path`.
+    fn get(&self, path: &str) -> io::Result<Option<Entry<'a>>>;
+    /// The paths recorded with contents `hash`.
+    fn paths_with_hash(&self, hash: &Digest) -> io::Result<Vec<Cow<'a, str>>>;
+    /// Hands every entry to `f`, in path order.
+    fn for_each(&self, f: &mut dyn FnMut(&str, &FileMeta)) -> io::Result<()>;
+}
+
+/// A reference loaded from manifests. Everything it indexes borrows from
+/// the manifest's own strings.
+pub struct InMemory<'a> {
+    files: &'a HashMap<String, FileMeta>,
+    /// Paths in order, for `for_each`
+    paths: Vec<&'a str>,
+    /// Every entry as (hash, path), in order, for looking paths up by
+    /// hash without a list per hash
+    by_hash: Vec<(Digest, &'a str)>,
+}
+
+impl<'a> InMemory<'a> {
+    pub fn new(files: &'a HashMap<String, FileMeta>) -> Self {
+        let mut paths: Vec<&str> = files.keys().map(String::as_str).collect();
+        paths.sort_unstable();
+        let mut by_hash: Vec<(Digest, &str)> = files
+            .iter()
+            .map(|(path, meta)| (meta.hash, path.as_str()))
+            .collect();
+        by_hash.sort_unstable();
+        InMemory {
+            files,
+            paths,
+            by_hash,
+        }
+    }
+}
+
+impl<'a> Reference<'a> for InMemory<'a> {
+    fn get(&self, path: &str) -> io::Result<Option<Entry<'a>>> {
+        Ok(self
+            .files
+            .get_key_value(path)
+            .map(|(path, meta)| (Cow::Borrowed(path.as_str()), Cow::Borrowed(meta))))
+    }
+
+    fn paths_with_hash(&self, hash: &Digest) -> io::Result<Vec<Cow<'a, str>>> {
+        let start = self.by_hash.partition_point(|(h, _)| h < hash);
+        Ok(self.by_hash[start..]
+            .iter()
+            .take_while(|(h, _)| h == hash)
+            .map(|&(_, path)| Cow::Borrowed(path))
+            .collect())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &FileMeta)) -> io::Result<()> {
+        for &path in &self.paths {
+            f(path, &self.files[path]);
+        }
+        Ok(())
+    }
+}
+
+/// Part of a reference: only the entries `in_scope` takes, given their
+/// path and what's recorded of them.
+pub struct Scoped<'a> {
+    pub reference: &'a dyn Reference<'a>,
+    pub in_scope: &'a (dyn Fn(&str, Option<&FileMeta>) -> bool + Sync),
+}
+
+impl<'a> Reference<'a> for Scoped<'a> {
+    fn get(&self, path: &str) -> io::Result<Option<Entry<'a>>> {
+        Ok(self
+            .reference
+            .get(path)?
+            .filter(|(path, meta)| (self.in_scope)(path, Some(meta))))
+    }
+
+    fn paths_with_hash(&self, hash: &Digest) -> io::Result<Vec<Cow<'a, str>>> {
+        let mut paths = Vec::new();
+        for path in self.reference.paths_with_hash(hash)? {
+            if self.get(&path)?.is_some() {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&str, &FileMeta)) -> io::Result<()> {
+        self.reference.for_each(&mut |path, meta| {
+            if (self.in_scope)(path, Some(meta)) {
+                f(path, meta);
+            }
+        })
+    }
+}
+
+/// Compares files against the reference one at a time, as they are hashed,
+/// so the current tree never has to be held in memory as a whole. Only the
+/// set of reference paths seen so far is kept, and the reference itself if
+/// it's in memory; when it's not, the set isn't either.
+pub struct Verifier<'a> {
+    reference: &'a dyn Reference<'a>,
+    update: bool,
+    grouping: Option<Grouping>,
+    offenders: Option<usize>,
+    mtime: MtimeRule,
+    /// Special files in the reference
+    special: Option<&'a Specials>,
+    fold: PathFold,
+    ignore: Option<IgnoreList>,
+    policy: Option<&'a Policy>,
+    /// Checking a copy, see `copy`
+    copy: bool,
+    tap: bool,
+    fail_fast: bool,
+    fs_check: bool,
+    /// The attributes that fail a file, with --check; the contents unless
+    /// the modification time owns up to them without
+    checks: Vec<Check>,
+    /// Commands run per finding, with --on-mismatch and the like
+    hooks: Hooks,
+    /// Where to copy the failures back from, with --emit-restore
+    restore: Option<Restore>,
+    /// Where to read mismatched files again from, with --reread
+    reread: Option<Reread>,
+    /// The replica to compare mismatched files with, with --compare-bytes
+    compare_bytes: Option<CompareBytes>,
+    /// Set at the first failure with `fail_fast`, for hashing to stop
+    stop: AtomicBool,
+    /// Reference paths by `fold` key, unless paths are compared exactly;
+    /// only those whose key isn't the path itself
+    folded: HashMap<String, String>,
+    state: Mutex<State<'a>>,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(reference: &'a dyn Reference<'a>, update: bool) -> Self {
+        Verifier {
+            reference,
+            update,
+            grouping: None,
+            offenders: None,
+            mtime: MtimeRule::default(),
+            special: None,
+            fold: PathFold::default(),
+            ignore: None,
+            policy: None,
+            copy: false,
+            tap: false,
+            fail_fast: false,
+            fs_check: false,
+            checks: Vec::new(),
+            hooks: Hooks::default(),
+            restore: None,
+            reread: None,
+            compare_bytes: None,
+            stop: AtomicBool::new(false),
+            folded: HashMap::new(),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Rolls findings up into groups, and only lists mismatches one by one.
+    pub fn group(mut self, grouping: Option<Grouping>) -> Self {
+        self.grouping = grouping;
+        self
+    }
+
+    /// Lists the `n` biggest mismatched files and the `n` failed entries
+    /// recorded longest ago when done, to restore first.
+    pub fn offenders(mut self, n: Option<usize>) -> Self {
+        self.offenders = n;
+        self
+    }
+
+    /// How loosely modification times are compared.
+    pub fn mtime(mut self, rule: MtimeRule) -> Self {
+        self.mtime = rule;
+        self
+    }
+
+    /// Also compares the special files of the reference, `expected`, with
+    /// those the walk finds, given to `finish`.
+    pub fn special(mut self, expected: &'a Specials) -> Self {
+        self.special = Some(expected);
+        self
+    }
+
+    /// Checks a copy: every file has to be there with the same contents,
+    /// whatever its modification time says, and contents under another
+    /// path don't count as moved.
+    pub fn copy(mut self, copy: bool) -> Self {
+        self.copy = copy;
+        self
+    }
+
+    /// Prints a TAP stream to stdout when done, a test point per reference
+    /// entry.
+    pub fn tap(mut self, tap: bool) -> Self {
+        self.tap = tap;
+        self
+    }
+
+    /// Stops at the first mismatch or missing file: `stop()` is set for
+    /// hashing to stop, and what wasn't hashed by then isn't reported.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Runs `hooks` for the mismatched, extra and missing files, see
+    /// `hooks`.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Prints a script when done that copies the mismatched and missing
+    /// files back from a replica, see `restore`.
+    pub fn restore(mut self, restore: Option<Restore>) -> Self {
+        self.restore = restore;
+        self
+    }
+
+    /// Reads mismatched files again before reporting them.
+    pub fn reread(mut self, reread: Option<Reread>) -> Self {
+        self.reread = reread;
+        self
+    }
+
+    /// Compares mismatched files byte by byte with a replica's, see
+    /// `bytediff`.
+    pub fn compare_bytes(mut self, compare_bytes: Option<CompareBytes>) -> Self {
+        self.compare_bytes = compare_bytes;
+        self
+    }
+
+    /// Keeps track of the reference entries seen on disk rather than in
+    /// memory, for a reference too big to be in memory itself.
+    pub fn seen_on_disk(self, on_disk: bool) -> Self {
+        if on_disk {
+            self.state.lock().unwrap().seen = Seen::Disk(Spill::new(spill::RUN_BYTES));
+        }
+        self
+    }
+
+    /// Whether the mismatched and missing entries are kept for the end.
+    fn keeps_failures(&self) -> bool {
+        self.offenders.is_some() || self.restore.is_some()
+    }
+
+    /// Fails a file on exactly the `checks` attributes differing, each
+    /// reported on its own. Attributes the reference didn't record aren't
+    /// compared.
+    pub fn checks(mut self, checks: &[Check]) -> Self {
+        self.checks = checks.to_vec();
+        self.checks.sort_unstable();
+        self.checks.dedup();
+        self
+    }
+
+    /// Asks btrfs or ZFS about the mismatched files when done, see
+    /// `fscheck`.
+    pub fn fs_check(mut self, fs_check: bool) -> Self {
+        self.fs_check = fs_check;
+        self
+    }
+
+    /// Set once checking can stop, see `fail_fast`.
+    pub fn stop(&self) -> &AtomicBool {
+        &self.stop
+    }
+
+    /// Compares paths case-insensitively or by their Unicode normal form.
+    /// A path that's in the reference as it is still matches exactly.
+    pub fn fold(mut self, fold: PathFold) -> Self {
+        self.fold = fold;
+        if !fold.is_exact() {
+            let mut folded = HashMap::new();
+            let listed = self.reference.for_each(&mut |path, _| {
+                if let Cow::Owned(key) = fold.key(path)
+                    && key != path
+                {
+                    folded.insert(key, path.to_string());
+                }
+            });
+            if let Err(e) = listed {
+                self.state.get_mut().unwrap().failed = Some(e);
+            }
+            self.folded = folded;
+        }
+        self
+    }
+
+    /// Tolerates changes to the paths on `list`: they're counted as
+    /// ignored, and only logged at debug level.
+    pub fn ignore(mut self, list: Option<IgnoreList>) -> Self {
+        self.ignore = list;
+        self
+    }
+
+    /// Applies the rules of a --policy file: `strict` and `auto-update`
+    /// paths, see `policy`.
+    pub fn policy(mut self, policy: Option<&'a Policy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    fn strict(&self, path: &str) -> bool {
+        self.policy.is_some_and(|p| p.is(path, Behavior::Strict))
+    }
+
+    /// Whether changes to `path` are tolerated: it's on the ignore list or
+    /// auto-updated, and not strict.
+    fn ignored(&self, path: &str) -> bool {
+        if self.strict(path) {
+            return false;
+        }
+        self.ignore.as_ref().is_some_and(|list| list.matches(path))
+            || self
+                .policy
+                .is_some_and(|p| p.is(path, Behavior::AutoUpdate))
+    }
+
+    /// Logs what happened to `path`, which is on the ignore list.
+    fn tolerate(&self, path: &str, what: &str) {
+        debug!(
+            event = "ignored",
+            path = path,
+            change = what;
+            "{} {path} ({what})",
+            style::pick("🙈 IGNORED", "IGNORED")
+        );
+    }
+
+    /// The reference entry for the file at `path`.
+    fn find(&self, path: &str) -> io::Result<Option<Entry<'a>>> {
+        if let Some(entry) = self.reference.get(path)? {
+            return Ok(Some(entry));
+        }
+        if self.fold.is_exact() {
+            return Ok(None);
+        }
+        // A reference path that's its own key isn't in `folded`
+        let key = self.fold.key(path);
+        if let Some(entry) = self.reference.get(&key)? {
+            return Ok(Some(entry));
+        }
+        match self.folded.get(key.as_ref()) {
+            Some(found) => self.reference.get(found),
+            None => Ok(None),
+        }
+    }
+
+    /// Reports special files that appeared, disappeared or changed, along
+    /// with paths that turned from regular files into special ones or back.
+    /// Returns how many there were.
+    fn compare_special(&self, current: &Specials) -> usize {
+        let Some(expected) = self.special else {
+            return 0;
+        };
+        let mut changes = 0;
+        let mut report = |path: &str, was: Option<String>, now: Option<String>| {
+            changes += 1;
+            let (marker, change, what) = match (&was, &now) {
+                (None, Some(now)) => (
+                    style::pick("🔌 NEW SPECIAL", "NEW SPECIAL"),
+                    "appeared",
+                    now.clone(),
+                ),
+                (Some(was), None) => (
+                    style::pick("🔌 SPECIAL GONE", "SPECIAL GONE"),
+                    "gone",
+                    was.clone(),
+                ),
+                (Some(was), Some(now)) => (
+                    style::pick("🔌 TYPE CHANGED", "TYPE CHANGED"),
+                    "changed",
+                    format!("{was} → {now}"),
+                ),
+                (None, None) => unreachable!("a special file is on one side"),
+            };
+            warn!(
+                event = "special",
+                path = path,
+                change = change,
+                was = was.as_deref(),
+                now = now.as_deref();
+                "{} {path}: {what}",
+                marker.yellow()
+            );
+        };
+
+        let key = |path: &str| self.fold.key(path).into_owned();
+        let expected_by_key: HashMap<String, &Special> =
+            expected.iter().map(|(path, s)| (key(path), s)).collect();
+        let current_keys: HashSet<String> = current.keys().map(|path| key(path)).collect();
+        for (path, now) in current {
+            match expected
+                .get(path)
+                .or_else(|| expected_by_key.get(&key(path)).copied())
+            {
+                Some(was) if was == now => {}
+                Some(was) => report(path, Some(was.to_string()), Some(now.to_string())),
+                // A regular file before
+                None if matches!(self.find(path), Ok(Some(_))) => {
+                    report(path, Some("file".to_string()), Some(now.to_string()))
+                }
+                None => report(path, None, Some(now.to_string())),
+            }
+        }
+        for (path, was) in expected {
+            if current_keys.contains(&key(path)) {
+                continue;
+            }
+            let now = fs::symlink_metadata(path)
+                .is_ok_and(|m| m.is_file())
+                .then(|| "file".to_string());
+            report(path, Some(was.to_string()), now);
+        }
+        changes
+    }
+
+    /// Prints the TAP stream: a test point per reference entry, by path,
+    /// with the details of failures as YAML, and the extra files as
+    /// comments.
+    fn print_tap(&self, out: &mut impl Write, state: &State) -> io::Result<()> {
+        let mut entries = 0;
+        self.reference.for_each(&mut |_, _| entries += 1)?;
+        writeln!(out, "TAP version 13")?;
+        writeln!(out, "1..{entries}")?;
+        let mut n = 0;
+        let mut written = Ok(());
+        self.reference.for_each(&mut |path, meta| {
+            n += 1;
+            if written.is_ok() {
+                written = tap_point(out, n, path, &meta.hash, state);
+            }
+        })?;
+        written?;
+        let mut extras: Vec<&String> = state.extras.iter().collect();
+        extras.sort_unstable();
+        for path in extras {
+            writeln!(out, "# extra: {}", dupes::comment(path))?;
+        }
+        out.flush()
+    }
+
+    /// Per-file findings other than mismatches go to debug when grouped.
+    fn detail(&self) -> Level {
+        if self.grouping.is_some() {
+            Level::Debug
+        } else {
+            Level::Info
+        }
+    }
+
+    fn tally(&self, state: &mut State, path: &str, meta: &FileMeta, finding: Finding) {
+        state.summary.count(finding);
+        if self.fail_fast && matches!(finding, Finding::Mismatched | Finding::Missing) {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+        if let Some(grouping) = &self.grouping {
+            state
+                .groups
+                .entry(grouping.key(path, meta))
+                .or_default()
+                .count(finding);
+        }
+    }
+
+    /// Reports how the file at `path` compares to the reference. Safe to
+    /// call from several hashing threads at once.
+    pub fn check(&self, path: &str, current_meta: &FileMeta) {
+        if let Err(e) = self.compare(path, current_meta) {
+            self.state.lock().unwrap().failed.get_or_insert(e);
+        }
+    }
+
+    /// How attribute `check` went from `expected` to `found`, if it differs
+    /// and both sides have it.
+    fn differs(
+        &self,
+        check: Check,
+        expected: &FileMeta,
+        found: &FileMeta,
+    ) -> Option<(String, String)> {
+        match check {
+            Check::Hash => (expected.hash != found.hash)
+                .then(|| (expected.hash.to_string(), found.hash.to_string())),
+            Check::Size => (expected.size != found.size)
+                .then(|| (expected.size.to_string(), found.size.to_string())),
+            Check::Mtime => (!self.mtime.same(found.modified, expected.modified)).then(|| {
+                let time = |secs| {
+                    humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs))
+                        .to_string()
+                };
+                (time(expected.modified), time(found.modified))
+            }),
+            Check::Perms => match (expected.mode, found.mode) {
+                (Some(a), Some(b)) if a != b => Some((format!("{a:o}"), format!("{b:o}"))),
+                _ => None,
+            },
+            Check::Owner => match (&expected.owner, &found.owner) {
+                (Some(a), Some(b)) if a != b => Some((a.to_string(), b.to_string())),
+                _ => None,
+            },
+        }
+    }
+
+    fn compare(&self, path: &str, current_meta: &FileMeta) -> io::Result<()> {
+        match self.find(path)? {
+            Some((ref_path, expected_meta)) => {
+                // Whether contents that differ count as a mismatch: with
+                // --check if it names the hash, otherwise unless the
+                // modification time owns up to an edit
+                let compared = match self.checks.is_empty() {
+                    true => {
+                        self.copy
+                            || self.strict(path)
+                            || self
+                                .mtime
+                                .same(current_meta.modified, expected_meta.modified)
+                    }
+                    false => self.checks.contains(&Check::Hash),
+                };
+                let reading = self
+                    .reread
+                    .as_ref()
+                    .filter(|_| {
+                        compared && expected_meta.hash != current_meta.hash && !self.ignored(path)
+                    })
+                    .and_then(|reread| reread.read(path, &expected_meta.hash, &current_meta.hash));
+                // Right the second time, the file goes by that read
+                let transient = reading.as_ref().is_some_and(Reading::transient);
+                let current_meta = match &reading {
+                    Some(Reading {
+                        again: Again::Recorded(meta),
+                        ..
+                    }) => {
+                        warn!(
+                            event = "transient",
+                            path = path,
+                            expected:% = expected_meta.hash,
+                            found:% = current_meta.hash;
+                            "{} {}: read as {} once, right the second time (cable, controller or memory?)",
+                            style::pick("⚡ TRANSIENT", "TRANSIENT").yellow(),
+                            path,
+                            current_meta.hash
+                        );
+                        meta
+                    }
+                    _ => current_meta,
+                };
+                let rewritten = expected_meta.hash != current_meta.hash && !self.ignored(path);
+                let random =
+                    rewritten && entropy::jumped(expected_meta.entropy, current_meta.entropy);
+                if random {
+                    near_random(path, &expected_meta, current_meta);
+                }
+                let retyped = match (&expected_meta.mime, &current_meta.mime) {
+                    (Some(was), Some(now)) if rewritten && was != now => {
+                        warn!(
+                            event = "type_changed",
+                            path = path,
+                            expected_type = &**was,
+                            found_type = &**now;
+                            "{} {}: {} -> {}",
+                            style::pick("🧬 TYPE CHANGED", "TYPE CHANGED").red(),
+                            path,
+                            was,
+                            now
+                        );
+                        true
+                    }
+                    _ => false,
+                };
+                let recreated = match (expected_meta.born, current_meta.born) {
+                    (Some(was), Some(now))
+                        if was != now && expected_meta.hash == current_meta.hash =>
+                    {
+                        recreated(path, was, now);
+                        true
+                    }
+                    _ => false,
+                };
+                let mut state = self.state.lock().unwrap();
+                state.seen.insert(ref_path.clone())?;
+                state.summary.compared += 1;
+                state.summary.recreated += usize::from(recreated);
+                state.summary.transient += usize::from(transient);
+                state.summary.rewritten += usize::from(rewritten);
+                state.summary.random += usize::from(random);
+                state.summary.retyped += usize::from(retyped);
+                let contents = compared && current_meta.hash != expected_meta.hash;
+                // What --check names differs too, the hash aside
+                let attrs: Vec<(Check, String, String)> = self
+                    .checks
+                    .iter()
+                    .filter(|&&check| check != Check::Hash)
+                    .filter_map(|&check| {
+                        let (was, now) = self.differs(check, &expected_meta, current_meta)?;
+                        Some((check, was, now))
+                    })
+                    .collect();
+                // Metadata recorded on both sides has to match as well
+                let metadata = match (&expected_meta.xattrs, &current_meta.xattrs) {
+                    (Some(expected), Some(current)) => xattrs::changes(expected, current),
+                    _ => Vec::new(),
+                };
+                let changed = contents || !attrs.is_empty() || !metadata.is_empty();
+                if changed && self.ignored(path) {
+                    self.tally(&mut state, path, current_meta, Finding::Ignored);
+                    if self.tap {
+                        state.points.insert(ref_path, Point::Ignored);
+                    }
+                    drop(state);
+                    self.tolerate(path, "changed");
+                } else if !contents && attrs.is_empty() && !metadata.is_empty() {
+                    self.tally(&mut state, path, current_meta, Finding::Mismatched);
+                    if self.keeps_failures() {
+                        state.failures.push((
+                            ref_path.clone(),
+                            expected_meta.clone(),
+                            Finding::Mismatched,
+                        ));
+                    }
+                    if self.tap {
+                        state
+                            .points
+                            .insert(ref_path, Point::Metadata(metadata.join(", ")));
+                    }
+                    drop(state);
+                    error!(
+                        event = "metadata_mismatch",
+                        path = path,
+                        expected:% = expected_meta.hash,
+                        found:% = current_meta.hash,
+                        size = current_meta.size,
+                        modified = current_meta.modified,
+                        attrs = metadata.join(", "),
+                        tags = tags::joined(&expected_meta),
+                        note = expected_meta.note.as_deref();
+                        "{} {}: {}{}",
+                        style::pick("🏷️ METADATA MISMATCH", "METADATA MISMATCH").red(),
+                        path,
+                        metadata.join(", "),
+                        tags::describe(&expected_meta)
+                    );
+                    self.hooks.mismatch(path, &expected_meta, current_meta);
+                } else if !changed
+                    && (current_meta.hash == expected_meta.hash || !self.checks.is_empty())
+                {
+                    // With --check, contents it doesn't name don't count
+                    self.tally(&mut state, path, current_meta, Finding::Matched);
+                    if self.tap {
+                        state.points.insert(ref_path, Point::Matched);
+                    }
+                    drop(state);
+                    debug!(event = "matched", path = path, found:% = current_meta.hash, size = current_meta.size, modified = current_meta.modified; "{} {}", style::pick("✅ MATCHED", "MATCHED").green(), path);
+                } else if changed {
+                    let resized = SizeChange::of(expected_meta.size, current_meta.size)
+                        .filter(|_| contents || attrs.iter().any(|(c, _, _)| *c == Check::Size));
+                    // Every attribute --check found differing, for the
+                    // summary and the log
+                    let checked: Vec<Check> = self
+                        .checks
+                        .iter()
+                        .copied()
+                        .filter(|&check| check == Check::Hash && contents)
+                        .chain(attrs.iter().map(|(check, _, _)| *check))
+                        .collect();
+                    let names = checked
+                        .iter()
+                        .map(|check| check.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    self.tally(&mut state, path, current_meta, Finding::Mismatched);
+                    for check in &checked {
+                        *state.summary.checks.entry(*check).or_default() += 1;
+                    }
+                    if matches!(resized, Some(SizeChange::Emptied | SizeChange::Truncated)) {
+                        state.summary.truncated += 1;
+                    }
+                    if let Some(Again::Other(_)) = reading.as_ref().map(|r| &r.again) {
+                        state.summary.unreliable += 1;
+                    }
+                    if self.fs_check && contents {
+                        state.mismatched.push(path.to_string());
+                    }
+                    if self.keeps_failures() {
+                        state.failures.push((
+                            ref_path.clone(),
+                            expected_meta.clone(),
+                            Finding::Mismatched,
+                        ));
+                    }
+                    if self.tap {
+                        let point = match contents {
+                            true => Point::Mismatched {
+                                found: current_meta.hash,
+                            },
+                            false => Point::Metadata(names.clone()),
+                        };
+                        state.points.insert(ref_path, point);
+                    }
+                    drop(state);
+                    let (changed, bytes) = match contents {
+                        true => (
+                            changed_chunks(&expected_meta, current_meta),
+                            self.compare_bytes
+                                .as_ref()
+                                .and_then(|compare| compare.compare(path)),
+                        ),
+                        false => (None, None),
+                    };
+                    let hashes = match contents {
+                        true => format!(
+                            "\n  expected: {}\n  found:    {}",
+                            expected_meta.hash, current_meta.hash
+                        ),
+                        false => String::new(),
+                    };
+                    // The size has a line of its own when it changed
+                    let lines: String = attrs
+                        .iter()
+                        .filter(|(check, _, _)| *check != Check::Size)
+                        .map(|(check, was, now)| {
+                            format!("\n  {:10}{was} -> {now}", format!("{}:", check.as_str()))
+                        })
+                        .chain(
+                            (!metadata.is_empty())
+                                .then(|| format!("\n  xattrs:   {}", metadata.join(", "))),
+                        )
+                        .collect();
+                    error!(
+                        event = "mismatch",
+                        path = path,
+                        attrs = (!names.is_empty()).then_some(names.as_str()),
+                        xattrs = (!metadata.is_empty()).then(|| metadata.join(", ")),
+                        expected:% = expected_meta.hash,
+                        found:% = current_meta.hash,
+                        changed_ranges = changed.as_ref().map(|c| c.ranges_field()),
+                        size_change = resized.map(SizeChange::as_str),
+                        expected_size = expected_meta.size,
+                        found_size = current_meta.size,
+                        modified = current_meta.modified,
+                        reread = reading.as_ref().map(|r| r.again.as_str()),
+                        elsewhere = reading.as_ref().and_then(|r| r.elsewhere.as_ref()).map(Again::as_str),
+                        replica = bytes.as_ref().map(ByteDiff::as_str),
+                        first_difference = bytes.as_ref().and_then(ByteDiff::first),
+                        differing_ranges = bytes.as_ref().and_then(ByteDiff::ranges),
+                        tags = tags::joined(&expected_meta),
+                        note = expected_meta.note.as_deref();
+                        "{} {}{}{}{}{}{}{}{}",
+                        resized.map_or_else(
+                            || style::pick("❌ MISMATCH", "MISMATCH").red(),
+                            SizeChange::marker
+                        ),
+                        path,
+                        hashes,
+                        resized
+                            .map(|_| format!(
+                                "\n  size:     {} -> {} bytes",
+                                expected_meta.size, current_meta.size
+                            ))
+                            .unwrap_or_default(),
+                        changed
+                            .as_ref()
+                            .map(|c| format!("\n  changed:  {c}"))
+                            .unwrap_or_default(),
+                        lines,
+                        reading.as_ref().map(Reading::to_string).unwrap_or_default(),
+                        bytes.as_ref().map(ByteDiff::to_string).unwrap_or_default(),
+                        tags::describe(&expected_meta)
+                    );
+                    self.hooks.mismatch(path, &expected_meta, current_meta);
+                } else {
+                    if self.tap {
+                        state.points.insert(ref_path, Point::Skipped);
+                    }
+                    drop(state);
+                    // An edit doesn't usually leave nothing behind, worth a
+                    // look even though the modification time owns up to it
+                    if SizeChange::of(expected_meta.size, current_meta.size)
+                        == Some(SizeChange::Emptied)
+                    {
+                        warn!(
+                            event = "emptied",
+                            path = path,
+                            expected_size = expected_meta.size;
+                            "{} {}: was {} bytes, now empty (modified time differs, not counted as a mismatch)",
+                            SizeChange::Emptied.marker(),
+                            path,
+                            expected_meta.size
+                        );
+                    }
+                    log!(
+                        self.detail(),
+                        event = "skipped",
+                        path = path,
+                        expected:% = expected_meta.hash,
+                        found:% = current_meta.hash,
+                        size = current_meta.size,
+                        modified = current_meta.modified;
+                        "{} {} (modified time differs, hash ignored)",
+                        style::pick("ℹ️ SKIPPED", "SKIPPED").blue(),
+                        path
+                    );
+                    if self.update {
+                        log!(self.detail(), event = "added", path = path; "{} Added to reference list", style::pick("➕", "+").cyan());
+                    }
+                }
+            }
+            None => {
+                let prev_paths = if self.copy {
+                    Vec::new()
+                } else {
+                    self.reference.paths_with_hash(&current_meta.hash)?
+                };
+                if !prev_paths.is_empty() {
+                    // Files of zero size have same hash ...
+                    if current_meta.size != 0 {
+                        {
+                            let mut state = self.state.lock().unwrap();
+                            self.tally(&mut state, path, current_meta, Finding::Moved);
+                            state.moved_hashes.insert(current_meta.hash);
+                        }
+
+                        if prev_paths.len() < 3 {
+                            log!(
+                                self.detail(),
+                                event = "moved",
+                                path = path,
+                                found:% = current_meta.hash,
+                                size = current_meta.size,
+                                modified = current_meta.modified,
+                                previously = prev_paths.join(", ");
+                                "{} {}\n  previously: {}",
+                                style::pick("🔀 MOVED", "MOVED").yellow(),
+                                path,
+                                prev_paths.join(", ")
+                            );
+                        }
+                    }
+                } else if self.ignored(path) {
+                    let mut state = self.state.lock().unwrap();
+                    self.tally(&mut state, path, current_meta, Finding::Ignored);
+                    drop(state);
+                    self.tolerate(path, "extra");
+                } else {
+                    {
+                        let mut state = self.state.lock().unwrap();
+                        self.tally(&mut state, path, current_meta, Finding::Extra);
+                        if self.tap {
+                            state.extras.push(path.to_string());
+                        }
+                    }
+                    log!(self.detail(), event = "extra", path = path, found:% = current_meta.hash, size = current_meta.size, modified = current_meta.modified; "{} {}", style::pick("⚠️ EXTRA", "EXTRA").blue(), path);
+                    self.hooks.extra(path, current_meta);
+
+                    if self.update {
+                        log!(self.detail(), event = "added", path = path; "{} Added to reference list", style::pick("➕", "+").cyan());
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports reference files that never turned up, then the summary.
+    /// `trouble` is what hashing had trouble with, `special` the special
+    /// files found.
+    pub fn finish(self, trouble: Trouble, special: &Specials) -> io::Result<Summary> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(e) = state.failed.take() {
+            return Err(e);
+        }
+        state.summary.errors = trouble.unreadable;
+        state.summary.denied = trouble.denied;
+        state.summary.medium = trouble.medium;
+        state.summary.volatile = trouble.volatile;
+        state.summary.retried = trouble.retried;
+        state.summary.missing_fails = self.fail_fast;
+        // Neither checked nor missing
+        state.summary.busy = trouble.busy.len();
+        for path in &trouble.busy {
+            if let Some((ref_path, _)) = self.find(path)? {
+                if self.tap {
+                    state.points.insert(ref_path.clone(), Point::Busy);
+                }
+                state.seen.insert(ref_path)?;
+            }
+        }
+
+        let mut failed = None;
+        self.reference.for_each(&mut |path, expected_meta| {
+            // Files that weren't hashed before a mismatch aren't missing,
+            // and after the first missing one the rest can go unreported
+            if self.stop.load(Ordering::Relaxed) {
+                return;
+            }
+            let seen = match state.seen.contains(path) {
+                Ok(seen) => seen,
+                Err(e) => {
+                    failed.get_or_insert(e);
+                    return;
+                }
+            };
+            if seen || state.moved_hashes.contains(&expected_meta.hash) {
+                return;
+            }
+            if self.ignored(path) {
+                self.tally(&mut state, path, expected_meta, Finding::Ignored);
+                if self.tap {
+                    state
+                        .points
+                        .insert(Cow::Owned(path.to_string()), Point::Ignored);
+                }
+                self.tolerate(path, "missing");
+            } else {
+                log!(
+                    self.detail(),
+                    event = "missing",
+                    path = path,
+                    expected:% = expected_meta.hash,
+                    size = expected_meta.size,
+                    modified = expected_meta.modified,
+                    tags = tags::joined(expected_meta),
+                    note = expected_meta.note.as_deref();
+                    "{} {}{}",
+                    style::pick("❓ MISSING", "MISSING").magenta(),
+                    path,
+                    tags::describe(expected_meta)
+                );
+                self.tally(&mut state, path, expected_meta, Finding::Missing);
+                self.hooks.missing(path, expected_meta);
+                if self.tap {
+                    state
+                        .points
+                        .insert(Cow::Owned(path.to_string()), Point::Missing);
+                }
+                if self.keeps_failures() {
+                    state.failures.push((
+                        Cow::Owned(path.to_string()),
+                        Cow::Owned(expected_meta.clone()),
+                        Finding::Missing,
+                    ));
+                }
+            }
+        })?;
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        state.summary.stopped = self.stop.load(Ordering::Relaxed);
+        if state.summary.stopped {
+            warn!(
+                event = "stopped";
+                "{} stopped at the first failure (--fail-fast), the rest wasn't checked",
+                style::pick("⏹️", "*")
+            );
+        }
+        state.summary.special = self.compare_special(special);
+        if self.tap {
+            // Best effort, like the logger: a closed pipe isn't worth a panic
+            let mut out = io::BufWriter::new(io::stdout().lock());
+            let _ = self.print_tap(&mut out, &state);
+        }
+        let State {
+            mut summary,
+            groups,
+            mut failures,
+            mut mismatched,
+            ..
+        } = std::mem::take(&mut *state);
+
+        // Groups where everything matched aren't worth a line
+        let mut groups = groups
+            .iter()
+            .filter_map(|(key, counts)| {
+                let grouping = self.grouping.as_ref()?;
+                let findings = counts.findings();
+                (!findings.is_empty()).then(|| (grouping.name(key), counts, findings))
+            })
+            .peekable();
+        if let (Some(grouping), Some(_)) = (&self.grouping, groups.peek()) {
+            info!("\n=== {} ===", grouping.title().bold().underline());
+        }
+        for (group, counts, findings) in groups {
+            info!(
+                event = "group",
+                group = group.as_str(),
+                matched = counts.matched,
+                moved = counts.moved,
+                mismatched = counts.mismatched,
+                extra = counts.extra,
+                missing = counts.missing;
+                "{}: {findings} ({} verified)",
+                group.bold(),
+                counts.matched
+            );
+        }
+
+        if let Some(n) = self.offenders {
+            report_offenders(&mut failures, n);
+        }
+        if let Some(restore) = &self.restore {
+            failures.sort_unstable_by(|(a, _, _), (b, _, _)| a.cmp(b));
+            let damaged: Vec<(&str, &FileMeta, bool)> = failures
+                .iter()
+                .map(|(path, meta, finding)| {
+                    (
+                        path.as_ref(),
+                        meta.as_ref(),
+                        matches!(finding, Finding::Missing),
+                    )
+                })
+                .collect();
+            restore.emit(&damaged)?;
+        }
+        if self.fs_check {
+            mismatched.sort_unstable();
+            fscheck::report(&mismatched);
+        }
+
+        info!(
+            event = "summary",
+            matched = summary.matched,
+            moved = summary.moved,
+            mismatched = summary.mismatched,
+            extra = summary.extra,
+            missing = summary.missing,
+            errors = summary.errors,
+            denied = summary.denied,
+            medium = summary.medium,
+            volatile = summary.volatile,
+            retried = summary.retried,
+            special = summary.special,
+            ignored = summary.ignored,
+            busy = summary.busy,
+            truncated = summary.truncated;
+            "\n=== {} ===\n{} {}\n{} {}\n{} {}{}\n{} {}\n{} {}\n{} {}{}\n{} {}\n{} {}\n{} {}\n{} {}\n{} {}",
+            "SUMMARY".bold().underline(),
+            style::pick("✅ Verified:", "Verified:").green(),
+            summary.matched,
+            style::pick("🔀 Moved:", "Moved:").yellow(),
+            summary.moved,
+            style::pick("❌ Mismatched:", "Mismatched:").red(),
+            summary.mismatched,
+            match summary.truncated {
+                0 => String::new(),
+                n => format!(" ({n} truncated)"),
+            },
+            style::pick("⚠️ Extra:", "Extra:").blue(),
+            summary.extra,
+            style::pick("❓ Missing:", "Missing:").magenta(),
+            summary.missing,
+            style::pick("🚫 Unreadable:", "Unreadable:").red(),
+            summary.errors,
+            summary.error_classes(),
+            style::pick("🌀 Volatile:", "Volatile:").yellow(),
+            summary.volatile,
+            style::pick("🔁 Retried:", "Retried:").yellow(),
+            summary.retried,
+            style::pick("🔌 Special changed:", "Special changed:").yellow(),
+            summary.special,
+            style::pick("🙈 Ignored:", "Ignored:"),
+            summary.ignored,
+            style::pick("⏳ Busy:", "Busy:").yellow(),
+            summary.busy
+        );
+        if summary.random > 0 {
+            warn!(
+                event = "near_random",
+                files = summary.random;
+                "{} {} changed files became near random: encrypted, or overwritten with garbage?",
+                style::pick("🔐", "*").red(),
+                summary.random
+            );
+        }
+        if summary.transient > 0 {
+            warn!(
+                event = "transient",
+                files = summary.transient;
+                "{} {} files read wrong once and right the second time: the files are fine, the way to them may not be",
+                style::pick("⚡", "*").yellow(),
+                summary.transient
+            );
+        }
+        if summary.unreliable > 0 {
+            warn!(
+                event = "unreliable",
+                files = summary.unreliable;
+                "{} {} mismatched files read differently every time: check the disk and the way to it before anything else",
+                style::pick("⚡", "*").red(),
+                summary.unreliable
+            );
+        }
+        if summary.retyped > 0 {
+            warn!(
+                event = "type_changed",
+                files = summary.retyped;
+                "{} {} changed files aren't the type they were any more",
+                style::pick("🧬", "*").red(),
+                summary.retyped
+            );
+        }
+        if summary.recreated > 0 {
+            warn!(
+                event = "recreated",
+                files = summary.recreated;
+                "{} {} files have the same contents but were created anew: deleted and written back, by a sync tool, a restore or someone else",
+                style::pick("🐣", "*").yellow(),
+                summary.recreated
+            );
+        }
+        if !self.checks.is_empty() {
+            for check in &self.checks {
+                summary.checks.entry(*check).or_default();
+            }
+            let counts: Vec<String> = summary
+                .checks
+                .iter()
+                .map(|(check, n)| format!("{} {n}", check.as_str()))
+                .collect();
+            info!(
+                event = "checks",
+                hash = summary.checks.get(&Check::Hash).copied(),
+                size = summary.checks.get(&Check::Size).copied(),
+                mtime = summary.checks.get(&Check::Mtime).copied(),
+                perms = summary.checks.get(&Check::Perms).copied(),
+                owner = summary.checks.get(&Check::Owner).copied();
+                "{} {}",
+                style::pick("🔎 Differed in:", "Differed in:").red(),
+                counts.join(", ")
+            );
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An entry for a file holding `contents`, last modified at `modified`.
+    fn meta(contents: &str, modified: u64) -> FileMeta {
+        serde_json::from_value(serde_json::json!({
+            "hash": blake3::hash(contents.as_bytes()).to_hex().as_str(),
+            "modified": modified,
+            "size": contents.len(),
+        }))
+        .unwrap()
+    }
+
+    /// Verifies the files `found` against `recorded`, failing files on
+    /// `checks`.
+    fn verify(
+        recorded: &[(&str, FileMeta)],
+        found: &[(&str, FileMeta)],
+        checks: &[Check],
+    ) -> Summary {
+        let files: HashMap<String, FileMeta> = recorded
+            .iter()
+            .map(|(path, meta)| (path.to_string(), meta.clone()))
+            .collect();
+        let reference = InMemory::new(&files);
+        let verifier = Verifier::new(&reference, false).checks(checks);
+        for (path, meta) in found {
+            verifier.check(path, meta);
+        }
+        verifier
+            .finish(Trouble::default(), &Specials::new())
+            .unwrap()
+    }
+
+    #[test]
+    fn sorts_files_into_findings() {
+        let summary = verify(
+            &[
+                ("same", meta("a", 10)),
+                ("rotted", meta("b", 10)),
+                ("edited", meta("c", 10)),
+                ("gone", meta("d", 10)),
+                ("old-name", meta("e", 10)),
+            ],
+            &[
+                ("same", meta("a", 10)),
+                ("rotted", meta("B", 10)),
+                ("edited", meta("C", 20)),
+                ("new-name", meta("e", 10)),
+                ("new", meta("f", 10)),
+            ],
+            &[],
+        );
+        assert_eq!(summary.matched, 1);
+        assert_eq!(summary.mismatched, 1);
+        assert_eq!(summary.moved, 1);
+        assert_eq!(summary.extra, 1);
+        assert_eq!(summary.missing, 1);
+        // The edit is owned up to by its modification time
+        assert_eq!(summary.compared, 3);
+        assert_eq!(summary.rewritten, 2);
+        assert!(summary.failed());
+    }
+
+    #[test]
+    fn flags_size_changes() {
+        let summary = verify(
+            &[("log", meta("longer", 10))],
+            &[("log", meta("short", 10))],
+            &[],
+        );
+        assert_eq!(summary.mismatched, 1);
+        assert_eq!(summary.truncated, 1);
+    }
+
+    #[test]
+    fn check_hash_fails_edits() {
+        let recorded = [("edited", meta("c", 10))];
+        let found = [("edited", meta("C", 20))];
+        assert_eq!(verify(&recorded, &found, &[]).mismatched, 0);
+        let summary = verify(&recorded, &found, &[Check::Hash]);
+        assert_eq!(summary.mismatched, 1);
+        assert_eq!(summary.checks.get(&Check::Hash), Some(&1));
+    }
+
+    #[test]
+    fn check_only_fails_what_it_names() {
+        // Same size, other contents: not --check size's business
+        let summary = verify(
+            &[("rotted", meta("b", 10))],
+            &[("rotted", meta("B", 10))],
+            &[Check::Size],
+        );
+        assert_eq!((summary.matched, summary.mismatched), (1, 0));
+
+        let summary = verify(
+            &[("grown", meta("b", 10)), ("touched", meta("t", 10))],
+            &[("grown", meta("bb", 10)), ("touched", meta("t", 20))],
+            &[Check::Size, Check::Mtime],
+        );
+        assert_eq!((summary.matched, summary.mismatched), (0, 2));
+        assert_eq!(summary.checks.get(&Check::Size), Some(&1));
+        assert_eq!(summary.checks.get(&Check::Mtime), Some(&1));
+        assert_eq!(summary.checks.get(&Check::Hash), None);
+    }
+
+    #[test]
+    fn tap_lines_stay_whole() {
+        let files: HashMap<String, FileMeta> = [
+            ("gone\nok 2 - fine".to_string(), meta("a", 10)),
+            ("rotted\r\nBail out!".to_string(), meta("b", 10)),
+        ]
+        .into_iter()
+        .collect();
+        let reference = InMemory::new(&files);
+        let verifier = Verifier::new(&reference, false).tap(true);
+        verifier.check("rotted\r\nBail out!", &meta("B", 10));
+        verifier.check("new\nnot ok 3 - extra", &meta("c", 10));
+        let mut state = verifier.state.lock().unwrap();
+        state
+            .points
+            .insert(Cow::Borrowed("gone\nok 2 - fine"), Point::Missing);
+
+        let mut out = Vec::new();
+        verifier.print_tap(&mut out, &state).unwrap();
+        let tap = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = tap.lines().collect();
+        assert!(lines.contains(&r"not ok 1 - gone\nok 2 - fine"), "{tap}");
+        assert!(lines.contains(&r"not ok 2 - rotted\r\nBail out!"), "{tap}");
+        assert!(lines.contains(&r"# extra: new\nnot ok 3 - extra"), "{tap}");
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|l| l.starts_with("ok") || l.starts_with("not ok"))
+                .count(),
+            2,
+            "{tap}"
+        );
+        assert!(!lines.iter().any(|l| l.starts_with("Bail out!")), "{tap}");
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::Path;
+
+use crate::manifest::Volume;
+
+// Which filesystem a tree is on, by the UUID and label it was formatted
+// with, so a manifest of a removable drive can be matched with the drive
+// wherever it's mounted next time.
+
+/// The volume `path` is on, if the system says.
+pub fn of(path: &Path) -> Option<Volume> {
+    let volume = detect(path)?;
+    (volume.uuid.is_some() || volume.label.is_some()).then_some(volume)
+}
+
+/// The `/dev/disk/by-*` entry naming the device `path` is on, unescaped
+/// (udev writes a space in a label as `\x20`).
+#[cfg(target_os = "linux")]
+fn by(kind: &str, dev: u64, source: Option<&Path>) -> Option<String> {
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    fs::read_dir(Path::new("/dev/disk").join(kind))
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|link| {
+            let target = fs::canonicalize(link.path()).ok();
+            fs::metadata(link.path()).is_ok_and(|m| m.rdev() == dev)
+                || (source.is_some() && target.as_deref() == source)
+        })
+        .map(|link| {
+            let name = link.file_name().to_string_lossy().to_string();
+            let mut unescaped = Vec::with_capacity(name.len());
+            let mut rest = name.as_bytes();
+            while let Some((&b, tail)) = rest.split_first() {
+                let code = tail
+                    .strip_prefix(b"x")
+                    .and_then(|hex| hex.get(..2))
+                    .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+                match code {
+                    Some(code) if b == b'\\' => {
+                        unescaped.push(code);
+                        rest = &tail[3..];
+                    }
+                    _ => {
+                        unescaped.push(b);
+                        rest = tail;
+                    }
+                }
+            }
+            String::from_utf8_lossy(&unescaped).to_string()
+        })
+}
+
+#[cfg(target_os = "linux")]
+fn detect(path: &Path) -> Option<Volume> {
+    use std::{fs, os::unix::fs::MetadataExt};
+
+    let dev = fs::metadata(path).ok()?.dev();
+    // btrfs and friends report an anonymous device, what's mounted tells
+    let source = crate::health::mount_source(&crate::health::device_number(dev))
+        .and_then(|source| fs::canonicalize(source).ok());
+    Some(Volume {
+        uuid: by("by-uuid", dev, source.as_deref()),
+        label: by("by-label", dev, source.as_deref()),
+    })
+}
+
+/// From `diskutil info`, which takes any path on the volume.
+#[cfg(target_os = "macos")]
+fn detect(path: &Path) -> Option<Volume> {
+    let output = std::process::Command::new("diskutil")
+        .arg("info")
+        .arg(path)
+        .output()
+        .ok()?;
+    let info = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| {
+        info.lines()
+            .find_map(|line| line.trim().strip_prefix(name)?.strip_prefix(':'))
+            .map(str::trim)
+            .filter(|v| !v.is_empty() && !v.starts_with("Not applicable"))
+            .map(str::to_string)
+    };
+    Some(Volume {
+        uuid: field("Volume UUID"),
+        label: field("Volume Name"),
+    })
+}
+
+/// From `Get-Volume`: the volume GUID and the label.
+#[cfg(windows)]
+fn detect(path: &Path) -> Option<Volume> {
+    let path = path.canonicalize().ok()?;
+    let path = path.to_string_lossy();
+    let drive = path.strip_prefix(r"\\?\").unwrap_or(&path).chars().next()?;
+    let output = std::process::Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-NonInteractive",
+            "-Command",
+            &format!(
+                "$v = Get-Volume -DriveLetter {drive}; \"$($v.UniqueId)`t$($v.FileSystemLabel)\""
+            ),
+        ])
+        .output()
+        .ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let (id, label) = output.trim().split_once('\t')?;
+    let non_empty = |s: &str| (!s.is_empty()).then(|| s.to_string());
+    Some(Volume {
+        uuid: non_empty(
+            id.trim_start_matches(r"\\?\Volume")
+                .trim_end_matches('\\')
+                .trim_matches(['{', '}']),
+        ),
+        label: non_empty(label),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn detect(_path: &Path) -> Option<Volume> {
+    None
+}
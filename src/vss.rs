@@ -0,0 +1,140 @@
+use std::{
+    io,
+    path::{Component, Path, PathBuf, Prefix},
+    process::Command,
+};
+
+use log::{debug, warn};
+
+use crate::manifest::Rebase;
+
+// A Volume Shadow Copy freezes the volume as it was at one instant, and
+// files other programs hold locked (Outlook PSTs, database files) can be
+// read from it. --vss scans from a copy made for the run and records the
+// paths as they are on the live volume. The copy is made and deleted
+// through WMI's Win32_ShadowCopy, which needs an elevated prompt.
+
+/// A shadow copy of the volume a scan root is on, deleted when dropped.
+pub struct Shadow {
+    id: String,
+    /// The scan root, inside the copy
+    root: PathBuf,
+    /// From the copy to the live volume
+    rebase: Rebase,
+}
+
+fn powershell(script: &str) -> io::Result<String> {
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "powershell failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// The drive `path` is on and the rest of it, `D:\` and `data\photos` for
+/// `D:\data\photos`.
+fn split_volume(path: &Path) -> io::Result<(String, PathBuf)> {
+    let path = path.canonicalize()?;
+    let mut components = path.components();
+    let volume = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::VerbatimDisk(drive) | Prefix::Disk(drive) => {
+                format!("{}:\\", drive as char)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("--vss: {} isn't on a local drive", path.display()),
+                ));
+            }
+        },
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "--vss is only supported on Windows",
+            ));
+        }
+    };
+    let rest = components
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect();
+    Ok((volume, rest))
+}
+
+impl Shadow {
+    /// Makes a shadow copy of the volume `root` is on.
+    pub fn create(root: &Path) -> io::Result<Shadow> {
+        if !cfg!(windows) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "--vss is only supported on Windows",
+            ));
+        }
+        let (volume, rest) = split_volume(root)?;
+        let created = powershell(&format!(
+            "$r = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create \
+             -Arguments @{{Volume='{volume}'; Context='ClientAccessible'}}; \
+             if ($r.ReturnValue -ne 0) {{ \
+             [Console]::Error.WriteLine(\"Win32_ShadowCopy.Create returned $($r.ReturnValue)\"); exit 1 }}; \
+             $s = Get-CimInstance Win32_ShadowCopy -Filter \"ID='$($r.ShadowID)'\"; \
+             \"$($s.ID)`t$($s.DeviceObject)\""
+        ))
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("can't create a shadow copy of {volume} (run from an elevated prompt?): {e}"),
+            )
+        })?;
+        let Some((id, device)) = created.split_once('\t') else {
+            return Err(io::Error::other(format!(
+                "unexpected answer creating a shadow copy: {created}"
+            )));
+        };
+        let shadow_root = PathBuf::from(format!(r"{device}\")).join(rest);
+        debug!(
+            event = "vss_created",
+            id = id,
+            volume = volume.as_str(),
+            device = device;
+            "Shadow copy {id} of {volume} is {device}"
+        );
+        Ok(Shadow {
+            id: id.to_string(),
+            rebase: Rebase::new(&shadow_root, root),
+            root: shadow_root,
+        })
+    }
+
+    /// Where the scan root is in the copy.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The manifest key for the file in the copy whose key is `key`.
+    pub fn key(&self, key: String) -> String {
+        self.rebase.key(key)
+    }
+}
+
+impl Drop for Shadow {
+    fn drop(&mut self) {
+        let id = &self.id;
+        let deleted = powershell(&format!(
+            "Get-CimInstance Win32_ShadowCopy -Filter \"ID='{id}'\" | Remove-CimInstance"
+        ));
+        match deleted {
+            Ok(_) => debug!(event = "vss_deleted", id = id.as_str(); "Shadow copy {id} deleted"),
+            Err(e) => warn!(
+                event = "vss_left",
+                id = id.as_str(),
+                error:% = e;
+                "Couldn't delete shadow copy {id}, remove it with `vssadmin delete shadows /Shadow={id}`: {e}"
+            ),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    io,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use colored::*;
+use log::{info, warn};
+
+use crate::{cli::WhatsNewArgs, hash::file_metadata, manifest::path_key, scan, store, style};
+
+/// Lists files modified after the manifest was created, from metadata only.
+fn report(args: &WhatsNewArgs) -> io::Result<usize> {
+    if !args.dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a directory", args.dir.display()),
+        ));
+    }
+    let reference = store::load(&args.manifest, &args.run)?;
+    let Some(created) = reference.manifest.created else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} doesn't record when it was created",
+                args.manifest.display()
+            ),
+        ));
+    };
+
+    let filter = scan::Filter::new(&args.run.filter)?;
+    let mut files = scan::walk_files(&args.dir, &filter);
+    files.sort_unstable();
+    let mut newer = 0;
+    for path in files {
+        let modified = match file_metadata(&path) {
+            Ok((modified, _)) => modified,
+            Err(e) => {
+                warn!(
+                    event = "error",
+                    path:% = path.display(),
+                    error:% = e;
+                    "{} {}: {}",
+                    style::pick("⚠️ UNREADABLE", "UNREADABLE").red(),
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        // Times are in whole seconds, a file changed in the second the
+        // scan started may or may not be in it, so it's listed
+        if modified < created {
+            continue;
+        }
+        newer += 1;
+        let path = path_key(&path);
+        if reference.manifest.files.contains_key(path.as_str()) {
+            info!(event = "modified", path = path.as_str(), modified = modified; "{} {path}", style::pick("✏️ MODIFIED", "MODIFIED").yellow());
+        } else {
+            info!(event = "new", path = path.as_str(), modified = modified; "{} {path}", style::pick("🆕 NEW", "NEW").green());
+        }
+    }
+
+    info!(
+        event = "whats_new",
+        files = newer,
+        since = created;
+        "{newer} file(s) modified since {}",
+        humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(created))
+    );
+    Ok(newer)
+}
+
+/// `whats-new`: exit code 0, or 1 on errors.
+pub fn run(args: &WhatsNewArgs) -> i32 {
+    match report(args) {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fs,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{
+        cli::{Cli, Command},
+        manifest::{JsonStyle, Manifest, PathLayout},
+    };
+
+    #[test]
+    fn files_changed_while_scanning_are_new() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        let file = tree.join("a");
+        fs::write(&file, "a").unwrap();
+        // Changed after the scan started, long before it was done
+        let started = 1_700_000_000;
+        fs::File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(started + 60))
+            .unwrap();
+        let manifest = Manifest::new(&tree, HashMap::new(), BTreeMap::new(), started);
+        let reference = dir.path().join("ref.json");
+        fs::write(
+            &reference,
+            manifest.to_json(JsonStyle::Compact, PathLayout::Flat),
+        )
+        .unwrap();
+
+        let cli = Cli::parse_from([
+            "checkyoself".as_ref(),
+            "whats-new".as_ref(),
+            tree.as_os_str(),
+            reference.as_os_str(),
+        ]);
+        let Some(Command::WhatsNew(args)) = cli.command else {
+            unreachable!();
+        };
+        assert_eq!(report(&args).unwrap(), 1);
+    }
+}
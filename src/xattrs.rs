@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, io, path::Path};
+
+// Finder keeps its flags (hidden, locked, color labels) and the classic type
+// and creator codes in com.apple.FinderInfo, Gatekeeper marks downloads in
+// com.apple.quarantine, and old Mac files keep half their data in the
+// resource fork. A copy can get the contents right and lose all of that,
+// so --mac-metadata records a hash of each.
+
+/// The extended attributes --mac-metadata records.
+#[cfg(target_os = "macos")]
+const KEY_ATTRS: [&str; 5] = [
+    "com.apple.FinderInfo",
+    "com.apple.ResourceFork",
+    "com.apple.quarantine",
+    "com.apple.metadata:_kMDItemUserTags",
+    "com.apple.metadata:kMDItemWhereFroms",
+];
+
+/// BLAKE3 of each of `KEY_ATTRS` the file at `path` has, lowercase hex by
+/// name.
+#[cfg(target_os = "macos")]
+pub fn key_attrs(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    use std::{
+        ffi::{CString, c_char, c_int, c_void},
+        os::unix::ffi::OsStrExt,
+        ptr,
+    };
+
+    const XATTR_NOFOLLOW: c_int = 1;
+    const ENOATTR: i32 = 93;
+    const ERANGE: i32 = 34;
+
+    unsafe extern "C" {
+        fn getxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *mut c_void,
+            size: usize,
+            position: u32,
+            options: c_int,
+        ) -> isize;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut attrs = BTreeMap::new();
+    for name in KEY_ATTRS {
+        let c_name = CString::new(name)?;
+        let value = loop {
+            // SAFETY: both strings are NUL terminated, a null buffer asks
+            // for the size
+            let size = unsafe {
+                getxattr(
+                    c_path.as_ptr(),
+                    c_name.as_ptr(),
+                    ptr::null_mut(),
+                    0,
+                    0,
+                    XATTR_NOFOLLOW,
+                )
+            };
+            if size < 0 {
+                break Err(io::Error::last_os_error());
+            }
+            let mut value = vec![0u8; size as usize];
+            // SAFETY: `value` has room for `size` bytes
+            let read = unsafe {
+                getxattr(
+                    c_path.as_ptr(),
+                    c_name.as_ptr(),
+                    value.as_mut_ptr().cast(),
+                    value.len(),
+                    0,
+                    XATTR_NOFOLLOW,
+                )
+            };
+            if read >= 0 {
+                value.truncate(read as usize);
+                break Ok(value);
+            }
+            // Grew in between, ask again
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() != Some(ERANGE) {
+                break Err(e);
+            }
+        };
+        match value {
+            Ok(value) => {
+                attrs.insert(name.to_string(), blake3::hash(&value).to_hex().to_string());
+            }
+            Err(e) if e.raw_os_error() == Some(ENOATTR) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(attrs)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub fn key_attrs(_path: &Path) -> io::Result<BTreeMap<String, String>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--mac-metadata is only supported on macOS",
+    ))
+}
+
+/// How the attributes recorded as `expected` differ from `current`, one
+/// `name (added|removed|changed)` each.
+pub fn changes(
+    expected: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut changes = Vec::new();
+    for (name, hash) in expected {
+        match current.get(name) {
+            Some(now) if now == hash => {}
+            Some(_) => changes.push(format!("{name} (changed)")),
+            None => changes.push(format!("{name} (removed)")),
+        }
+    }
+    for name in current.keys() {
+        if !expected.contains_key(name) {
+            changes.push(format!("{name} (added)"));
+        }
+    }
+    changes
+}
@@ -0,0 +1,104 @@
+// Verifying against a --format kv store, or an index of a JSON manifest,
+// looks entries up on disk and keeps the paths it found in sorted runs
+// there too (see src/spill.rs, whose tests merge many small runs). A few
+// thousand entries go through it here, and what went missing, moved or
+// changed still has to be found.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const FILES: usize = 4_000;
+const PER_DIR: usize = 100;
+
+fn checkyoself(args: &[&Path]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_checkyoself"))
+        .args(args)
+        .env("NO_COLOR", "1")
+        .output()
+        .expect("checkyoself runs");
+    let mut text = String::from_utf8_lossy(&output.stdout).into_owned();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    (output.status.code().expect("exit code"), text)
+}
+
+fn file(tree: &Path, i: usize) -> PathBuf {
+    tree.join(format!("d{:03}", i / PER_DIR))
+        .join(format!("f{i:06}"))
+}
+
+/// A tree of `FILES` small files, each with contents of its own.
+fn make_tree(tree: &Path) {
+    for i in 0..FILES {
+        let path = file(tree, i);
+        if i % PER_DIR == 0 {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+        }
+        fs::write(path, format!("{i}\n")).unwrap();
+    }
+}
+
+/// Deletes, moves and rewrites a few files: 3 missing, 2 moved and 2
+/// mismatched (the modification time put back, so it isn't an edit).
+fn damage(tree: &Path) -> ([PathBuf; 3], [PathBuf; 2], [PathBuf; 2]) {
+    let missing = [file(tree, 7), file(tree, 2_001), file(tree, FILES - 1)];
+    for path in &missing {
+        fs::remove_file(path).unwrap();
+    }
+    let moved = [tree.join("d000/moved-a"), tree.join("d030/moved-b")];
+    fs::rename(file(tree, 12), &moved[0]).unwrap();
+    fs::rename(file(tree, 3_012), &moved[1]).unwrap();
+    let mismatched = [file(tree, 1_555), file(tree, 3_900)];
+    for path in &mismatched {
+        // The same size, a mismatch rather than a file that grew
+        let metadata = fs::metadata(path).unwrap();
+        fs::write(path, "x".repeat(metadata.len() as usize)).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(metadata.modified().unwrap())
+            .unwrap();
+    }
+    (missing, moved, mismatched)
+}
+
+fn assert_found(report: &str, what: &str, paths: &[PathBuf]) {
+    for path in paths {
+        let line = format!("{what} {}", path.display());
+        assert!(report.contains(&line), "no `{line}` in:\n{report}");
+    }
+}
+
+#[test]
+fn verify_finds_damage_through_kv_and_index() {
+    let dir = tempfile::tempdir().unwrap();
+    let tree = dir.path().join("tree");
+    make_tree(&tree);
+
+    let store = dir.path().join("ref.kv");
+    let kv = Path::new("kv");
+    let (code, report) = checkyoself(&[&tree, &store, Path::new("--format"), kv]);
+    assert_eq!(code, 0, "{report}");
+    let manifest = dir.path().join("ref.json");
+    let (code, report) = checkyoself(&[&tree, &manifest]);
+    assert_eq!(code, 0, "{report}");
+    let (code, report) = checkyoself(&[Path::new("index"), &manifest]);
+    assert_eq!(code, 0, "{report}");
+
+    let (missing, moved, mismatched) = damage(&tree);
+    let counts = format!(
+        "matched={} moved=2 mismatched=2 extra=0 missing=3 ",
+        FILES - 7
+    );
+    for reference in [&store, &manifest] {
+        let (code, report) = checkyoself(&[&tree, Path::new("--verify"), reference]);
+        assert_eq!(code, 2, "{report}");
+        assert!(report.contains(&counts), "no `{counts}` in:\n{report}");
+        assert_found(&report, "MISSING", &missing);
+        assert_found(&report, "MOVED", &moved);
+        assert_found(&report, "MISMATCH", &mismatched);
+    }
+}